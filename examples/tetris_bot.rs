@@ -0,0 +1,290 @@
+//! A bot that plays Tetris, as an example of driving the emulator without a frontend.
+//!
+//! It reads the board from VRAM and the falling piece from OAM, picks a placement with a simple heuristic, and
+//! steers the piece with scripted button presses. The game seeds its random number generator from the divider, so
+//! with the same inputs every run is identical: the bot plays two games and checks that they end the same way.
+//!
+//! ```text
+//! cargo run --release --example tetris_bot -- path/to/tetris.gb [max frames]
+//! ```
+//!
+//! The ROM isn't included. Without one, the example only explains how to run it.
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::path::Path;
+
+use gbrs::joypad::Button;
+use gbrs::Emulator;
+
+// Addresses in Tetris (World) (Rev 1)
+/// The game state, 0 while a game is being played
+const GAME_STATE: u16 = 0xFFE1;
+const GAME_STATE_PLAYING: u8 = 0x00;
+/// The score, as 3 little endian BCD bytes
+const SCORE: u16 = 0xC0A0;
+/// The board is drawn in columns 2-11 of the first background tile map
+const BOARD_TILE_MAP: u16 = 0x9800;
+const BOARD_FIRST_COLUMN: u16 = 2;
+const EMPTY_TILE: u8 = 0x2F;
+const ROWS: usize = 18;
+const COLUMNS: usize = 10;
+const OAM: u16 = 0xFE00;
+
+type Board = [[bool; COLUMNS]; ROWS];
+
+/// The cells of a piece as (row, column), relative to its top left corner and sorted
+type Shape = Vec<(i32, i32)>;
+
+#[derive(Debug, PartialEq, Eq)]
+struct Outcome {
+    frames: u64,
+    pieces: u32,
+    score: u32,
+    board: Board,
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let mut args = std::env::args().skip(1);
+    let Some(rom_path) = args.next() else {
+        eprintln!("usage: tetris_bot <tetris.gb> [max frames]");
+        return Ok(());
+    };
+    let max_frames = match args.next() {
+        Some(frames) => frames.parse()?,
+        None => 60 * 60 * 5,
+    };
+    let rom = std::fs::read(&rom_path)?;
+
+    let first = play(&rom, Path::new(&rom_path), max_frames)?;
+    let second = play(&rom, Path::new(&rom_path), max_frames)?;
+    for row in &first.board {
+        let row: String = row
+            .iter()
+            .map(|&filled| if filled { '#' } else { '.' })
+            .collect();
+        println!("|{row}|");
+    }
+    println!(
+        "placed {} pieces and scored {} in {} frames",
+        first.pieces, first.score, first.frames
+    );
+    assert_eq!(
+        first, second,
+        "runs with the same inputs should be identical"
+    );
+    println!("a second run ended identically");
+    Ok(())
+}
+
+/// Play one game from power on, until it's over or `max_frames` have passed.
+fn play(rom: &[u8], rom_path: &Path, max_frames: u64) -> Result<Outcome, Box<dyn Error>> {
+    let mut emu = Emulator::for_rom(rom, rom_path, None)?;
+    let mut seen_menu = false;
+    let mut cooldown = 0;
+    let mut pieces = 0;
+    // the board when the current piece's placement was picked, and the placement
+    let mut plan: Option<(Board, Shape, i32)> = None;
+    while emu.frame_count() < max_frames {
+        emu.run_frame()?;
+        if emu.in_boot_rom() {
+            continue;
+        }
+        if cooldown > 0 {
+            cooldown -= 1;
+            continue;
+        }
+        let state = emu.read_memory(GAME_STATE);
+        if state != GAME_STATE_PLAYING || !seen_menu {
+            if seen_menu && state != GAME_STATE_PLAYING && pieces > 0 {
+                // game over
+                break;
+            }
+            seen_menu |= state != GAME_STATE_PLAYING;
+            // skip the title screen and accept the default game type, music, and level
+            tap(&mut emu, Button::Start);
+            cooldown = 30;
+            continue;
+        }
+
+        let board = read_board(&emu);
+        if plan
+            .as_ref()
+            .is_some_and(|(planned_on, _, _)| *planned_on != board)
+        {
+            // the piece landed
+            plan = None;
+            pieces += 1;
+        }
+        let Some((column, shape)) = falling_piece(&emu) else {
+            continue;
+        };
+        let (_, target_shape, target_column) = plan.get_or_insert_with(|| {
+            let (shape, column) = best_placement(&board, &shape);
+            (board, shape, column)
+        });
+        if shape != *target_shape {
+            tap(&mut emu, Button::A);
+            cooldown = 3;
+        } else if column > *target_column {
+            tap(&mut emu, Button::Left);
+            cooldown = 3;
+        } else if column < *target_column {
+            tap(&mut emu, Button::Right);
+            cooldown = 3;
+        } else {
+            emu.hold_button(Button::Down, 1);
+        }
+    }
+    Ok(Outcome {
+        frames: emu.frame_count(),
+        pieces,
+        score: read_score(&emu),
+        board: read_board(&emu),
+    })
+}
+
+/// Press `button` for 2 frames. The game only reacts to a new press after it was released.
+fn tap(emu: &mut Emulator, button: Button) {
+    emu.hold_button(button, 2);
+}
+
+fn read_board(emu: &Emulator) -> Board {
+    let mut board = [[false; COLUMNS]; ROWS];
+    for (row_idx, row) in board.iter_mut().enumerate() {
+        for (column_idx, cell) in row.iter_mut().enumerate() {
+            let addr =
+                BOARD_TILE_MAP + row_idx as u16 * 32 + BOARD_FIRST_COLUMN + column_idx as u16;
+            *cell = emu.read_memory(addr) != EMPTY_TILE;
+        }
+    }
+    board
+}
+
+fn read_score(emu: &Emulator) -> u32 {
+    (0..3).rev().fold(0, |score, idx| {
+        let bcd = emu.read_memory(SCORE + idx);
+        score * 100 + (bcd >> 4) as u32 * 10 + (bcd & 0xF) as u32
+    })
+}
+
+/// The left column of the falling piece, and its shape. The piece is made of the objects inside the
+/// board, while the preview of the next piece is drawn next to it.
+fn falling_piece(emu: &Emulator) -> Option<(i32, Shape)> {
+    let cells: Vec<(i32, i32)> = (0..40)
+        .filter_map(|idx| {
+            let y = emu.read_memory(OAM + idx * 4) as i32;
+            let x = emu.read_memory(OAM + idx * 4 + 1) as i32;
+            let (row, column) = ((y - 16) / 8, (x - 8) / 8 - BOARD_FIRST_COLUMN as i32);
+            let visible = (16..160).contains(&y) && (8..168).contains(&x);
+            (visible && (0..COLUMNS as i32).contains(&column)).then_some((row, column))
+        })
+        .collect();
+    if cells.len() != 4 {
+        return None;
+    }
+    let column = cells.iter().map(|&(_, column)| column).min()?;
+    Some((column, normalize(cells)))
+}
+
+fn normalize(mut cells: Vec<(i32, i32)>) -> Shape {
+    let min_row = cells.iter().map(|&(row, _)| row).min().unwrap_or(0);
+    let min_column = cells.iter().map(|&(_, column)| column).min().unwrap_or(0);
+    for (row, column) in &mut cells {
+        *row -= min_row;
+        *column -= min_column;
+    }
+    cells.sort();
+    cells
+}
+
+/// The shape and left column to drop `shape` at, in any of its rotations.
+fn best_placement(board: &Board, shape: &Shape) -> (Shape, i32) {
+    let mut rotations = BTreeSet::new();
+    let mut rotation = shape.clone();
+    for _ in 0..4 {
+        rotation = normalize(
+            rotation
+                .iter()
+                .map(|&(row, column)| (column, -row))
+                .collect(),
+        );
+        rotations.insert(rotation.clone());
+    }
+    let mut best: Option<(f64, Shape, i32)> = None;
+    for rotation in rotations {
+        let width = rotation
+            .iter()
+            .map(|&(_, column)| column)
+            .max()
+            .unwrap_or(0)
+            + 1;
+        for column in 0..=(COLUMNS as i32 - width) {
+            let Some(landed) = land(board, &rotation, column) else {
+                continue;
+            };
+            let score = evaluate(&landed);
+            if best
+                .as_ref()
+                .is_none_or(|(best_score, _, _)| score > *best_score)
+            {
+                best = Some((score, rotation.clone(), column));
+            }
+        }
+    }
+    best.map(|(_, shape, column)| (shape, column))
+        .unwrap_or((shape.clone(), 0))
+}
+
+/// The board after dropping `shape` at `column` and clearing full lines, or `None` if it doesn't fit.
+fn land(board: &Board, shape: &Shape, column: i32) -> Option<(Board, u32)> {
+    let fits = |top: i32| {
+        shape.iter().all(|&(row, cell_column)| {
+            let (row, cell_column) = ((top + row) as usize, (column + cell_column) as usize);
+            row < ROWS && !board[row][cell_column]
+        })
+    };
+    if !fits(0) {
+        return None;
+    }
+    let mut top = 0;
+    while fits(top + 1) {
+        top += 1;
+    }
+    let mut landed = *board;
+    for &(row, cell_column) in shape {
+        landed[(top + row) as usize][(column + cell_column) as usize] = true;
+    }
+    let remaining: Vec<_> = landed
+        .into_iter()
+        .filter(|row| !row.iter().all(|&filled| filled))
+        .collect();
+    let lines = (ROWS - remaining.len()) as u32;
+    let mut cleared = [[false; COLUMNS]; ROWS];
+    cleared[lines as usize..].copy_from_slice(&remaining);
+    Some((cleared, lines))
+}
+
+/// Prefer low, flat boards without holes, and clearing lines.
+fn evaluate((board, lines): &(Board, u32)) -> f64 {
+    let heights: Vec<usize> = (0..COLUMNS)
+        .map(|column| {
+            (0..ROWS)
+                .find(|&row| board[row][column])
+                .map_or(0, |row| ROWS - row)
+        })
+        .collect();
+    let holes = (0..COLUMNS)
+        .map(|column| {
+            (ROWS - heights[column]..ROWS)
+                .filter(|&row| !board[row][column])
+                .count()
+        })
+        .sum::<usize>();
+    let bumpiness = heights
+        .windows(2)
+        .map(|pair| pair[0].abs_diff(pair[1]))
+        .sum::<usize>();
+    -0.51 * heights.iter().sum::<usize>() as f64 + 0.76 * *lines as f64
+        - 0.36 * holes as f64
+        - 0.18 * bumpiness as f64
+}
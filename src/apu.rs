@@ -0,0 +1,1041 @@
+//! Audio processing unit.
+//!
+//! The APU has four channels: two pulse channels (CH1 also has a period sweep), a wave channel (CH3) that plays back
+//! 4-bit samples from wave RAM, and a noise channel (CH4). Each channel produces a 4-bit digital value which the
+//! channel's DAC converts to an analog value, and the analog values are mixed into the output samples.
+//!
+//! See https://gbdev.io/pandocs/Audio.html
+use enumset::{EnumSet, EnumSetType};
+use serde::{Deserialize, Serialize};
+
+use crate::util::U8Ext;
+use crate::wav::WavWriter;
+
+/// The rate at which output samples are generated, unless configured otherwise with `EmulatorBuilder::sample_rate`.
+pub const DEFAULT_SAMPLE_RATE: u32 = 44100;
+/// The output is stereo, with the left and right samples interleaved.
+pub const CHANNELS: u16 = 2;
+pub(crate) const T_CYCLES_PER_SECOND: u32 = 4194304;
+/// The frame sequencer steps on the falling edge of this bit of the internal DIV counter (bit 4 of DIV), i.e. at 512 Hz.
+///
+/// It clocks the length counters, the envelopes, and the sweep.
+const DIV_APU_BIT: u16 = 1 << 12;
+/// Stop buffering samples if nobody has drained them for this many seconds.
+const MAX_BUFFERED_SECONDS: usize = 1;
+/// When a channel is triggered, there is a delay of 3 APU cycles before its period timer starts counting down.
+const TRIGGER_DELAY_T_CYCLES: u32 = 6;
+
+/// The waveforms for the 12.5%, 25%, 50%, and 75% duty cycles
+const DUTY_CYCLES: [[u8; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 1],
+    [1, 0, 0, 0, 0, 0, 0, 1],
+    [1, 0, 0, 0, 0, 1, 1, 1],
+    [0, 1, 1, 1, 1, 1, 1, 0],
+];
+
+/// The APU's sound channels
+#[derive(Debug, EnumSetType)]
+pub enum Channel {
+    Pulse1,
+    Pulse2,
+    Wave,
+    Noise,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Apu {
+    /// NR52 bit 7. When the APU is off, all registers except NR52 and wave RAM are cleared and read-only.
+    powered: bool,
+    ch1: PulseChannel,
+    ch1_sweep: Sweep,
+    ch2: PulseChannel,
+    ch3: WaveChannel,
+    ch4: NoiseChannel,
+    /// NR50
+    master_volume: u8,
+    /// NR51
+    panning: u8,
+    /// The step (0-7) that the frame sequencer will execute next
+    frame_sequencer_step: u8,
+    /// Whether this is a CGB's APU, which doesn't corrupt wave RAM when channel 3 is retriggered
+    pub(crate) cgb: bool,
+    /// The rate of the output samples, in Hz
+    sample_rate: u32,
+    /// T-cycles multiplied by the sample rate, so that samples are generated at exactly `sample_rate`
+    sample_timer: u32,
+    /// The sum of the left and right outputs over the current output sample, weighted by t-cycles.
+    ///
+    /// Each output sample is the average of the outputs over its period (a box filter), rather than the output at a
+    /// single point in time, which removes most of the aliasing when resampling the ~1 MHz channel output.
+    sample_accumulator: [f32; 2],
+    /// The number of t-cycles summed in `sample_accumulator`
+    accumulated_t_cycles: u32,
+    /// Models the capacitors that remove the DC offset from the left and right outputs
+    high_pass_capacitors: [f32; 2],
+    /// Channels that are left out of the mix. The channels keep running, so unmuting them is seamless.
+    #[serde(skip)]
+    pub(crate) muted_channels: EnumSet<Channel>,
+    #[serde(skip)]
+    pub(crate) samples: Vec<i16>,
+    /// Receives every generated sample while audio is being captured, even if nobody drains `samples`
+    #[serde(skip)]
+    pub(crate) capture: Option<WavWriter>,
+}
+
+impl Apu {
+    pub(crate) fn new() -> Self {
+        Apu {
+            powered: false,
+            ch1: PulseChannel::new(),
+            ch1_sweep: Sweep::default(),
+            ch2: PulseChannel::new(),
+            ch3: WaveChannel::new(),
+            ch4: NoiseChannel::new(),
+            master_volume: 0,
+            panning: 0,
+            frame_sequencer_step: 0,
+            cgb: false,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            sample_timer: 0,
+            sample_accumulator: [0.0; 2],
+            accumulated_t_cycles: 0,
+            high_pass_capacitors: [0.0; 2],
+            muted_channels: EnumSet::empty(),
+            samples: Vec::new(),
+            capture: None,
+        }
+    }
+
+    pub(crate) fn read_register(&self, addr: u16) -> u8 {
+        // Write-only bits and unused bits read as 1
+        match addr {
+            0xFF10 => 0x80 | self.ch1_sweep.to_register(),
+            0xFF11 => 0x3F | (self.ch1.duty << 6),
+            0xFF12 => self.ch1.envelope.to_register(),
+            0xFF13 => 0xFF,
+            0xFF14 => 0xBF | ((self.ch1.length.enabled as u8) << 6),
+            0xFF15 => 0xFF,
+            0xFF16 => 0x3F | (self.ch2.duty << 6),
+            0xFF17 => self.ch2.envelope.to_register(),
+            0xFF18 => 0xFF,
+            0xFF19 => 0xBF | ((self.ch2.length.enabled as u8) << 6),
+            0xFF1A => 0x7F | ((self.ch3.dac_enabled as u8) << 7),
+            0xFF1B => 0xFF,
+            0xFF1C => 0x9F | (self.ch3.output_level << 5),
+            0xFF1D => 0xFF,
+            0xFF1E => 0xBF | ((self.ch3.length.enabled as u8) << 6),
+            0xFF1F => 0xFF,
+            0xFF20 => 0xFF,
+            0xFF21 => self.ch4.envelope.to_register(),
+            0xFF22 => self.ch4.to_register(),
+            0xFF23 => 0xBF | ((self.ch4.length.enabled as u8) << 6),
+            0xFF24 => self.master_volume,
+            0xFF25 => self.panning,
+            0xFF26 => u8::from_bits([
+                self.powered,
+                true,
+                true,
+                true,
+                self.ch4.enabled,
+                self.ch3.enabled,
+                self.ch2.enabled,
+                self.ch1.enabled,
+            ]),
+            0xFF27..=0xFF2F => 0xFF,
+            0xFF30..=0xFF3F => self.ch3.read_wave_ram(addr),
+            _ => panic!("BUG: invalid APU register read: {addr:X}"),
+        }
+    }
+
+    pub(crate) fn write_register(&mut self, addr: u16, byte: u8) {
+        if !self.powered {
+            // While the APU is off, only NR52 and wave RAM can be written.
+            // On the DMG, the length timers can also be written.
+            match addr {
+                0xFF11 | 0xFF16 | 0xFF20 => {
+                    self.channel_length_mut(addr).load(byte & 0x3F);
+                    return;
+                }
+                0xFF1B => {
+                    self.ch3.length.load(byte);
+                    return;
+                }
+                0xFF26 | 0xFF30..=0xFF3F => {}
+                _ => return,
+            }
+        }
+        match addr {
+            0xFF10 => self.ch1_sweep.write_register(byte, &mut self.ch1),
+            0xFF11 => self.ch1.write_length_and_duty(byte),
+            0xFF12 => self.ch1.write_envelope(byte),
+            0xFF13 => self.ch1.period = (self.ch1.period & 0x700) | byte as u16,
+            0xFF14 => {
+                self.ch1.write_control(byte);
+                if byte.bit(7) {
+                    self.ch1_sweep.trigger(&mut self.ch1);
+                }
+            }
+            0xFF15 => {}
+            0xFF16 => self.ch2.write_length_and_duty(byte),
+            0xFF17 => self.ch2.write_envelope(byte),
+            0xFF18 => self.ch2.period = (self.ch2.period & 0x700) | byte as u16,
+            0xFF19 => self.ch2.write_control(byte),
+            0xFF1A => {
+                self.ch3.dac_enabled = byte.bit(7);
+                if !self.ch3.dac_enabled {
+                    self.ch3.enabled = false;
+                }
+            }
+            0xFF1B => self.ch3.length.load(byte),
+            0xFF1C => self.ch3.output_level = (byte >> 5) & 0b11,
+            0xFF1D => self.ch3.period = (self.ch3.period & 0x700) | byte as u16,
+            0xFF1E => self.ch3.write_control(byte, !self.cgb),
+            0xFF1F => {}
+            0xFF20 => self.ch4.length.load(byte & 0x3F),
+            0xFF21 => {
+                self.ch4.envelope = Envelope::from_register(byte);
+                if !self.ch4.envelope.dac_enabled() {
+                    self.ch4.enabled = false;
+                }
+            }
+            0xFF22 => self.ch4.write_register(byte),
+            0xFF23 => self.ch4.write_control(byte),
+            0xFF24 => self.master_volume = byte,
+            0xFF25 => self.panning = byte,
+            0xFF26 => {
+                let powered = byte.bit(7);
+                if self.powered && !powered {
+                    self.power_off();
+                } else if !self.powered && powered {
+                    self.frame_sequencer_step = 0;
+                }
+                self.powered = powered;
+            }
+            0xFF27..=0xFF2F => {}
+            0xFF30..=0xFF3F => self.ch3.write_wave_ram(addr, byte),
+            _ => panic!("BUG: invalid APU register write: {addr:X} <- {byte:X}"),
+        }
+    }
+
+    fn channel_length_mut(&mut self, addr: u16) -> &mut LengthCounter {
+        match addr {
+            0xFF11 => &mut self.ch1.length,
+            0xFF16 => &mut self.ch2.length,
+            0xFF20 => &mut self.ch4.length,
+            _ => panic!("BUG: {addr:X} is not a length register"),
+        }
+    }
+
+    fn power_off(&mut self) {
+        // Clear every register, but keep wave RAM and (on DMG) the length timers
+        let wave_ram = self.ch3.wave_ram;
+        let lengths = [
+            self.ch1.length.remaining,
+            self.ch2.length.remaining,
+            self.ch3.length.remaining,
+            self.ch4.length.remaining,
+        ];
+        self.ch1 = PulseChannel::new();
+        self.ch1_sweep = Sweep::default();
+        self.ch2 = PulseChannel::new();
+        self.ch3 = WaveChannel::new();
+        self.ch4 = NoiseChannel::new();
+        self.ch3.wave_ram = wave_ram;
+        self.ch1.length.remaining = lengths[0];
+        self.ch2.length.remaining = lengths[1];
+        self.ch3.length.remaining = lengths[2];
+        self.ch4.length.remaining = lengths[3];
+        self.master_volume = 0;
+        self.panning = 0;
+    }
+
+    pub(crate) fn step(&mut self, t_cycles: u8) {
+        if self.powered {
+            self.ch1.step(t_cycles as u32);
+            self.ch2.step(t_cycles as u32);
+            self.ch3.step(t_cycles as u32);
+            self.ch4.step(t_cycles as u32);
+        }
+
+        let analog = self.mix();
+        let mut remaining = t_cycles as u32;
+        while remaining > 0 {
+            let until_sample = (T_CYCLES_PER_SECOND - self.sample_timer).div_ceil(self.sample_rate);
+            let t_cycles = remaining.min(until_sample);
+            remaining -= t_cycles;
+            for (sum, output) in self.sample_accumulator.iter_mut().zip(analog) {
+                *sum += output * t_cycles as f32;
+            }
+            self.accumulated_t_cycles += t_cycles;
+            self.sample_timer += t_cycles * self.sample_rate;
+            if self.sample_timer >= T_CYCLES_PER_SECOND {
+                self.sample_timer -= T_CYCLES_PER_SECOND;
+                self.emit_sample();
+            }
+        }
+    }
+
+    /// Output the average of the accumulated outputs, with the DC offset removed
+    fn emit_sample(&mut self) {
+        // 0.999958 per t-cycle, from https://gbdev.gg8.se/wiki/articles/Gameboy_sound_hardware#Obscure_Behavior
+        let t_cycles_per_sample = T_CYCLES_PER_SECOND as f32 / self.sample_rate as f32;
+        let charge_factor = 0.999958f32.powf(t_cycles_per_sample);
+        let samples: [i16; 2] = std::array::from_fn(|side| {
+            let average = self.sample_accumulator[side] / self.accumulated_t_cycles as f32;
+            let out = average - self.high_pass_capacitors[side];
+            self.high_pass_capacitors[side] = average - out * charge_factor;
+            (out * i16::MAX as f32) as i16
+        });
+        self.sample_accumulator = [0.0; 2];
+        self.accumulated_t_cycles = 0;
+
+        if let Some(capture) = &mut self.capture {
+            samples
+                .iter()
+                .for_each(|&sample| capture.write_sample(sample));
+        }
+        let max_buffered_samples =
+            MAX_BUFFERED_SECONDS * self.sample_rate as usize * CHANNELS as usize;
+        if self.samples.len() < max_buffered_samples {
+            self.samples.extend(samples);
+        }
+    }
+
+    pub(crate) fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub(crate) fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        self.sample_timer = 0;
+        self.sample_accumulator = [0.0; 2];
+        self.accumulated_t_cycles = 0;
+    }
+
+    /// Step the frame sequencer if DIV changing from `before` to `after` produced a falling edge on the DIV-APU bit.
+    ///
+    /// Besides the divider ticking, this happens when DIV is reset while the bit is set, which clocks the frame sequencer early.
+    pub(crate) fn observe_div(&mut self, before: u16, after: u16) {
+        if self.powered && before & DIV_APU_BIT != 0 && after & DIV_APU_BIT == 0 {
+            self.step_frame_sequencer();
+        }
+    }
+
+    fn step_frame_sequencer(&mut self) {
+        let step = self.frame_sequencer_step;
+        if step.is_multiple_of(2) {
+            self.ch1.length_clock();
+            self.ch2.length_clock();
+            self.ch3.length_clock();
+            self.ch4.length_clock();
+        }
+        if step == 2 || step == 6 {
+            self.ch1_sweep.clock(&mut self.ch1);
+        }
+        if step == 7 {
+            self.ch1.envelope.clock();
+            self.ch2.envelope.clock();
+            self.ch4.envelope.clock();
+        }
+        self.frame_sequencer_step = (step + 1) % 8;
+    }
+
+    /// Mix the analog output of each channel into the left and right outputs, in [-1, 1]
+    fn mix(&self) -> [f32; 2] {
+        let any_dac_enabled = self.ch1.envelope.dac_enabled()
+            || self.ch2.envelope.dac_enabled()
+            || self.ch3.dac_enabled
+            || self.ch4.envelope.dac_enabled();
+        if !any_dac_enabled {
+            return [0.0; 2];
+        }
+        let analog_outputs = [
+            (
+                Channel::Pulse1,
+                dac(self.ch1.envelope.dac_enabled(), self.ch1.output()),
+            ),
+            (
+                Channel::Pulse2,
+                dac(self.ch2.envelope.dac_enabled(), self.ch2.output()),
+            ),
+            (Channel::Wave, dac(self.ch3.dac_enabled, self.ch3.output())),
+            (
+                Channel::Noise,
+                dac(self.ch4.envelope.dac_enabled(), self.ch4.output()),
+            ),
+        ];
+        let mut outputs = [0.0; 2];
+        // NR51 bits 4-7 send CH1-4 to the left output and bits 0-3 send them to the right output.
+        // NR50 bits 4-6 are the left volume and bits 0-2 are the right volume.
+        for (side, shift) in [4, 0].into_iter().enumerate() {
+            let mixed = analog_outputs
+                .iter()
+                .zip(shift..)
+                .filter(|((channel, _), bit)| {
+                    self.panning.bit(*bit) && !self.muted_channels.contains(*channel)
+                })
+                .map(|((_, output), _)| output)
+                .sum::<f32>()
+                / 4.0;
+            let volume = ((self.master_volume >> shift) & 0x07) as f32 + 1.0;
+            outputs[side] = mixed * volume / 8.0;
+        }
+        outputs
+    }
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Apu::new()
+    }
+}
+
+/// Convert a channel's digital output (0-15) to an analog value in [-1, 1].
+fn dac(enabled: bool, digital: u8) -> f32 {
+    if enabled {
+        1.0 - (digital as f32 / 7.5)
+    } else {
+        0.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct LengthCounter {
+    enabled: bool,
+    /// The channel is turned off when this reaches 0
+    remaining: u16,
+    /// 64 for the pulse and noise channels, 256 for the wave channel
+    max: u16,
+}
+
+impl LengthCounter {
+    fn new(max: u16) -> Self {
+        LengthCounter {
+            enabled: false,
+            remaining: 0,
+            max,
+        }
+    }
+
+    /// Load the initial length timer value written to NRx1
+    fn load(&mut self, initial: u8) {
+        self.remaining = self.max - initial as u16;
+    }
+
+    /// Clock the length counter, returning whether the channel should be turned off.
+    fn clock(&mut self) -> bool {
+        if self.enabled && self.remaining > 0 {
+            self.remaining -= 1;
+            return self.remaining == 0;
+        }
+        false
+    }
+
+    fn trigger(&mut self) {
+        if self.remaining == 0 {
+            self.remaining = self.max;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+struct Envelope {
+    initial_volume: u8,
+    increase: bool,
+    /// The envelope is clocked every `pace` ticks of the 64 Hz envelope clock. 0 disables the envelope.
+    pace: u8,
+    volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    fn from_register(byte: u8) -> Self {
+        Envelope {
+            initial_volume: byte >> 4,
+            increase: byte.bit(3),
+            pace: byte & 0b111,
+            volume: 0,
+            timer: 0,
+        }
+    }
+
+    fn to_register(self) -> u8 {
+        (self.initial_volume << 4) | ((self.increase as u8) << 3) | self.pace
+    }
+
+    /// The DAC is on if any of the upper 5 bits of NRx2 are set
+    fn dac_enabled(self) -> bool {
+        self.initial_volume != 0 || self.increase
+    }
+
+    fn trigger(&mut self) {
+        self.volume = self.initial_volume;
+        self.timer = self.pace;
+    }
+
+    fn clock(&mut self) {
+        if self.pace == 0 {
+            return;
+        }
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer == 0 {
+            self.timer = self.pace;
+            if self.increase && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.increase && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PulseChannel {
+    enabled: bool,
+    duty: u8,
+    duty_step: u8,
+    length: LengthCounter,
+    envelope: Envelope,
+    /// 11 bit period value from NRx3 and NRx4
+    period: u16,
+    /// T-cycles until the next duty step
+    period_timer: u32,
+}
+
+impl PulseChannel {
+    fn new() -> Self {
+        PulseChannel {
+            enabled: false,
+            duty: 0,
+            duty_step: 0,
+            length: LengthCounter::new(64),
+            envelope: Envelope::default(),
+            period: 0,
+            period_timer: 0,
+        }
+    }
+
+    fn t_cycles_per_step(&self) -> u32 {
+        (2048 - self.period as u32) * 4
+    }
+
+    fn write_length_and_duty(&mut self, byte: u8) {
+        self.duty = byte >> 6;
+        self.length.load(byte & 0x3F);
+    }
+
+    fn write_envelope(&mut self, byte: u8) {
+        self.envelope = Envelope::from_register(byte);
+        if !self.envelope.dac_enabled() {
+            self.enabled = false;
+        }
+    }
+
+    fn write_control(&mut self, byte: u8) {
+        self.period = (self.period & 0xFF) | (((byte & 0b111) as u16) << 8);
+        self.length.enabled = byte.bit(6);
+        if byte.bit(7) {
+            self.enabled = self.envelope.dac_enabled();
+            self.length.trigger();
+            self.envelope.trigger();
+            self.period_timer = self.t_cycles_per_step() + TRIGGER_DELAY_T_CYCLES;
+        }
+    }
+
+    fn step(&mut self, t_cycles: u32) {
+        let mut remaining = t_cycles;
+        while remaining >= self.period_timer {
+            remaining -= self.period_timer;
+            self.period_timer = self.t_cycles_per_step();
+            self.duty_step = (self.duty_step + 1) % 8;
+        }
+        self.period_timer -= remaining;
+    }
+
+    fn length_clock(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        DUTY_CYCLES[self.duty as usize][self.duty_step as usize] * self.envelope.volume
+    }
+}
+
+/// CH1's period sweep
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+struct Sweep {
+    /// The sweep is clocked every `pace` ticks of the 128 Hz sweep clock. 0 disables the sweep.
+    pace: u8,
+    decrease: bool,
+    step: u8,
+    timer: u8,
+    enabled: bool,
+    shadow_period: u16,
+}
+
+impl Sweep {
+    fn to_register(self) -> u8 {
+        (self.pace << 4) | ((self.decrease as u8) << 3) | self.step
+    }
+
+    fn write_register(&mut self, byte: u8, channel: &mut PulseChannel) {
+        let was_decreasing = self.decrease;
+        self.pace = (byte >> 4) & 0b111;
+        self.decrease = byte.bit(3);
+        self.step = byte & 0b111;
+        // Switching from subtraction to addition after a subtraction has been calculated turns off the channel
+        if was_decreasing && !self.decrease && self.enabled {
+            channel.enabled = false;
+        }
+    }
+
+    fn trigger(&mut self, channel: &mut PulseChannel) {
+        self.shadow_period = channel.period;
+        self.timer = if self.pace == 0 { 8 } else { self.pace };
+        self.enabled = self.pace != 0 || self.step != 0;
+        if self.step != 0 {
+            self.next_period(channel);
+        }
+    }
+
+    fn clock(&mut self, channel: &mut PulseChannel) {
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer > 0 {
+            return;
+        }
+        self.timer = if self.pace == 0 { 8 } else { self.pace };
+        if !self.enabled || self.pace == 0 {
+            return;
+        }
+        let period = self.next_period(channel);
+        if period <= 2047 && self.step != 0 {
+            self.shadow_period = period;
+            channel.period = period;
+            // the new period is immediately checked for overflow again
+            self.next_period(channel);
+        }
+    }
+
+    /// Calculate the next period, turning off the channel if it overflows
+    fn next_period(&self, channel: &mut PulseChannel) -> u16 {
+        let delta = self.shadow_period >> self.step;
+        let period = if self.decrease {
+            self.shadow_period - delta
+        } else {
+            self.shadow_period + delta
+        };
+        if period > 2047 {
+            channel.enabled = false;
+        }
+        period
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct WaveChannel {
+    enabled: bool,
+    /// NR30 bit 7
+    dac_enabled: bool,
+    length: LengthCounter,
+    /// 0: mute, 1: 100%, 2: 50%, 3: 25%
+    output_level: u8,
+    period: u16,
+    /// T-cycles until the channel advances to the next sample
+    period_timer: u32,
+    /// The index (0-31) of the 4-bit sample that is being played.
+    ///
+    /// The upper nibble of each wave RAM byte is played first.
+    position: u8,
+    /// The wave RAM byte that was read when the channel last advanced.
+    ///
+    /// Triggering the channel doesn't refill this buffer, so the old sample is played until the channel first advances.
+    sample_buffer: u8,
+    wave_ram: [u8; 16],
+}
+
+impl WaveChannel {
+    fn new() -> Self {
+        WaveChannel {
+            enabled: false,
+            dac_enabled: false,
+            length: LengthCounter::new(256),
+            output_level: 0,
+            period: 0,
+            period_timer: 0,
+            position: 0,
+            sample_buffer: 0,
+            wave_ram: [0; 16],
+        }
+    }
+
+    fn t_cycles_per_sample(&self) -> u32 {
+        (2048 - self.period as u32) * 2
+    }
+
+    /// While the channel is playing, the CPU can only access the wave RAM byte that the channel is reading.
+    ///
+    /// On the DMG this only works within a couple of cycles of the channel's own read, and other reads return 0xFF,
+    /// but this emulator doesn't time memory accesses within an instruction finely enough to model that window.
+    fn wave_ram_idx(&self, addr: u16) -> usize {
+        if self.enabled {
+            self.position as usize / 2
+        } else {
+            (addr - 0xFF30) as usize
+        }
+    }
+
+    fn read_wave_ram(&self, addr: u16) -> u8 {
+        self.wave_ram[self.wave_ram_idx(addr)]
+    }
+
+    fn write_wave_ram(&mut self, addr: u16, byte: u8) {
+        let idx = self.wave_ram_idx(addr);
+        self.wave_ram[idx] = byte;
+    }
+
+    fn write_control(&mut self, byte: u8, dmg: bool) {
+        self.period = (self.period & 0xFF) | (((byte & 0b111) as u16) << 8);
+        self.length.enabled = byte.bit(6);
+        if byte.bit(7) {
+            self.trigger(dmg);
+        }
+    }
+
+    fn trigger(&mut self, dmg: bool) {
+        // On the DMG, retriggering the channel on the cycle that it reads wave RAM corrupts the first bytes of wave RAM
+        if dmg && self.enabled && self.period_timer <= 2 {
+            let next_idx = ((self.position as usize + 1) % 32) / 2;
+            if next_idx < 4 {
+                self.wave_ram[0] = self.wave_ram[next_idx];
+            } else {
+                let block_start = next_idx & !0b11;
+                self.wave_ram.copy_within(block_start..block_start + 4, 0);
+            }
+        }
+        self.enabled = self.dac_enabled;
+        self.length.trigger();
+        self.position = 0;
+        self.period_timer = self.t_cycles_per_sample() + TRIGGER_DELAY_T_CYCLES;
+    }
+
+    fn step(&mut self, t_cycles: u32) {
+        if !self.enabled {
+            return;
+        }
+        let mut remaining = t_cycles;
+        while remaining >= self.period_timer {
+            remaining -= self.period_timer;
+            self.period_timer = self.t_cycles_per_sample();
+            self.position = (self.position + 1) % 32;
+            self.sample_buffer = self.wave_ram[self.position as usize / 2];
+        }
+        self.period_timer -= remaining;
+    }
+
+    fn length_clock(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        let sample = if self.position.is_multiple_of(2) {
+            self.sample_buffer >> 4
+        } else {
+            self.sample_buffer & 0xF
+        };
+        match self.output_level {
+            0 => 0,
+            level => sample >> (level - 1),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct NoiseChannel {
+    enabled: bool,
+    length: LengthCounter,
+    envelope: Envelope,
+    clock_shift: u8,
+    /// When set, the LFSR is 7 bits instead of 15, which produces a more periodic sound
+    short_mode: bool,
+    clock_divider: u8,
+    period_timer: u32,
+    lfsr: u16,
+}
+
+impl NoiseChannel {
+    fn new() -> Self {
+        NoiseChannel {
+            enabled: false,
+            length: LengthCounter::new(64),
+            envelope: Envelope::default(),
+            clock_shift: 0,
+            short_mode: false,
+            clock_divider: 0,
+            period_timer: 0,
+            lfsr: 0,
+        }
+    }
+
+    fn to_register(&self) -> u8 {
+        (self.clock_shift << 4) | ((self.short_mode as u8) << 3) | self.clock_divider
+    }
+
+    fn write_register(&mut self, byte: u8) {
+        self.clock_shift = byte >> 4;
+        self.short_mode = byte.bit(3);
+        self.clock_divider = byte & 0b111;
+    }
+
+    fn t_cycles_per_shift(&self) -> u32 {
+        let divisor = match self.clock_divider {
+            0 => 8,
+            divider => divider as u32 * 16,
+        };
+        divisor << self.clock_shift
+    }
+
+    fn write_control(&mut self, byte: u8) {
+        self.length.enabled = byte.bit(6);
+        if byte.bit(7) {
+            self.enabled = self.envelope.dac_enabled();
+            self.length.trigger();
+            self.envelope.trigger();
+            self.lfsr = 0x7FFF;
+            self.period_timer = self.t_cycles_per_shift();
+        }
+    }
+
+    fn step(&mut self, t_cycles: u32) {
+        if !self.enabled {
+            return;
+        }
+        let mut remaining = t_cycles;
+        while remaining >= self.period_timer {
+            remaining -= self.period_timer;
+            self.period_timer = self.t_cycles_per_shift();
+            let feedback = (self.lfsr & 0b01) ^ ((self.lfsr >> 1) & 0b01);
+            self.lfsr = (self.lfsr >> 1) | (feedback << 14);
+            if self.short_mode {
+                self.lfsr = (self.lfsr & !(1 << 6)) | (feedback << 6);
+            }
+        }
+        self.period_timer -= remaining;
+    }
+
+    fn length_clock(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        (!self.lfsr & 0b01) as u8 * self.envelope.volume
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Power on the APU and fill both halves of wave RAM with 0x01, 0x23, 0x45, ... 0xEF
+    fn apu_with_wave() -> Apu {
+        let mut apu = Apu::new();
+        apu.write_register(0xFF26, 0x80);
+        for (idx, addr) in (0xFF30..=0xFF3F).enumerate() {
+            let hi = (2 * idx % 16) as u8;
+            let lo = hi + 1;
+            apu.write_register(addr, (hi << 4) | lo);
+        }
+        apu.write_register(0xFF1A, 0x80); // DAC on
+        apu.write_register(0xFF1C, 0x20); // 100% volume
+        apu.write_register(0xFF24, 0x77); // max master volume
+        apu.write_register(0xFF25, 0xFF); // all channels to both outputs
+        apu
+    }
+
+    #[test]
+    fn wave_ram_reads_current_byte_while_playing() {
+        let mut apu = apu_with_wave();
+        assert_eq!(apu.read_register(0xFF35), 0xAB);
+        // period 0x7FF: the channel advances every 2 t-cycles
+        apu.write_register(0xFF1D, 0xFF);
+        apu.write_register(0xFF1E, 0x87);
+        // After the trigger delay, the channel advances to the lower nibble of the first byte
+        apu.step(2 + 6);
+        assert_eq!(apu.ch3.position, 1);
+        for addr in 0xFF30..=0xFF3F {
+            assert_eq!(apu.read_register(addr), 0x01);
+        }
+        apu.step(4);
+        assert_eq!(apu.ch3.position, 3);
+        assert_eq!(apu.read_register(0xFF3F), 0x23);
+        // writes also go to the byte that is being played
+        apu.write_register(0xFF30, 0x99);
+        assert_eq!(apu.ch3.wave_ram[1], 0x99);
+        assert_eq!(apu.ch3.wave_ram[0], 0x01);
+
+        // turning the DAC off stops the channel and restores normal access
+        apu.write_register(0xFF1A, 0x00);
+        assert_eq!(apu.read_register(0xFF30), 0x01);
+        assert_eq!(apu.read_register(0xFF3F), 0xEF);
+    }
+
+    #[test]
+    fn wave_trigger_keeps_sample_buffer() {
+        let mut apu = apu_with_wave();
+        apu.ch3.sample_buffer = 0xF0;
+        // period 0x700: the channel advances every 512 t-cycles
+        apu.write_register(0xFF1D, 0x00);
+        apu.write_register(0xFF1E, 0x87);
+        assert_eq!(apu.ch3.output(), 0xF);
+        apu.step(255);
+        apu.step(255);
+        apu.step(7);
+        assert_eq!(apu.ch3.position, 0, "advanced before the trigger delay");
+        assert_eq!(apu.ch3.output(), 0xF);
+        apu.step(1);
+        assert_eq!(apu.ch3.position, 1);
+        assert_eq!(apu.ch3.output(), 0x1);
+    }
+
+    #[test]
+    fn wave_retrigger_corrupts_wave_ram() {
+        let mut apu = apu_with_wave();
+        apu.write_register(0xFF1D, 0x00);
+        apu.write_register(0xFF1E, 0x87);
+        // advance to sample 10, then stop right before the read of sample 11 (in byte 5)
+        for _ in 0..10 {
+            apu.ch3.step(apu.ch3.period_timer);
+        }
+        assert_eq!(apu.ch3.position, 10);
+        apu.ch3.step(apu.ch3.period_timer - 1);
+        apu.write_register(0xFF1E, 0x87);
+        assert_eq!(apu.ch3.wave_ram[..4], [0x89, 0xAB, 0xCD, 0xEF]);
+        assert_eq!(apu.ch3.wave_ram[4..8], [0x89, 0xAB, 0xCD, 0xEF]);
+        assert_eq!(apu.ch3.wave_ram[8], 0x01);
+
+        // the CGB doesn't have the bug
+        let mut apu = apu_with_wave();
+        apu.cgb = true;
+        apu.write_register(0xFF1D, 0x00);
+        apu.write_register(0xFF1E, 0x87);
+        for _ in 0..10 {
+            apu.ch3.step(apu.ch3.period_timer);
+        }
+        apu.ch3.step(apu.ch3.period_timer - 1);
+        apu.write_register(0xFF1E, 0x87);
+        assert_eq!(apu.ch3.wave_ram[..4], [0x01, 0x23, 0x45, 0x67]);
+    }
+
+    #[test]
+    fn power_off_clears_registers() {
+        let mut apu = apu_with_wave();
+        apu.write_register(0xFF12, 0xF3);
+        apu.write_register(0xFF24, 0x77);
+        apu.write_register(0xFF14, 0x80);
+        assert_eq!(apu.read_register(0xFF26), 0xF1);
+        apu.write_register(0xFF26, 0x00);
+        assert_eq!(apu.read_register(0xFF26), 0x70);
+        assert_eq!(apu.read_register(0xFF12), 0x00);
+        assert_eq!(apu.read_register(0xFF24), 0x00);
+        apu.write_register(0xFF12, 0xF3);
+        assert_eq!(apu.read_register(0xFF12), 0x00);
+        // wave RAM is kept
+        assert_eq!(apu.read_register(0xFF30), 0x01);
+    }
+
+    #[test]
+    fn save_state_round_trip() {
+        let mut apu = apu_with_wave();
+        apu.write_register(0xFF12, 0xF3);
+        apu.write_register(0xFF13, 0x42);
+        apu.write_register(0xFF14, 0x87);
+        apu.write_register(0xFF21, 0xA1);
+        apu.write_register(0xFF22, 0x12);
+        apu.write_register(0xFF23, 0x80);
+        apu.write_register(0xFF1E, 0x87);
+        for _ in 0..1000 {
+            apu.step(4);
+        }
+        let bytes = rmp_serde::to_vec(&apu).unwrap();
+        let mut restored: Apu = rmp_serde::from_slice(&bytes).unwrap();
+        for addr in 0xFF10..=0xFF3F {
+            assert_eq!(
+                restored.read_register(addr),
+                apu.read_register(addr),
+                "{addr:04X}"
+            );
+        }
+        for _ in 0..1000 {
+            apu.step(4);
+            restored.step(4);
+        }
+        assert_eq!(
+            restored.samples,
+            apu.samples[apu.samples.len() - restored.samples.len()..]
+        );
+    }
+
+    #[test]
+    fn muted_channels_are_left_out_of_the_mix() {
+        let mut apu = apu_with_wave();
+        apu.write_register(0xFF1E, 0x87);
+        apu.step(8);
+        assert_ne!(apu.mix(), [0.0; 2]);
+        apu.muted_channels.insert(Channel::Wave);
+        assert_eq!(apu.mix(), [0.0; 2]);
+        // the channel keeps playing while muted
+        assert!(apu.ch3.enabled);
+    }
+
+    #[test]
+    fn panning_and_master_volume() {
+        let mut apu = apu_with_wave();
+        apu.write_register(0xFF1E, 0x87);
+        apu.step(8);
+        // wave channel to the left output only
+        apu.write_register(0xFF25, 0x40);
+        let [left, right] = apu.mix();
+        assert_ne!(left, 0.0);
+        assert_eq!(right, 0.0);
+
+        // the left output at the lowest volume is an eighth of the highest volume
+        apu.write_register(0xFF24, 0x07);
+        let [quiet_left, _] = apu.mix();
+        assert_eq!(quiet_left, left / 8.0);
+    }
+
+    #[test]
+    #[cfg_attr(
+        miri,
+        ignore = "steps through a second of audio, which is too slow to interpret"
+    )]
+    fn generates_samples_at_the_configured_rate() {
+        for sample_rate in [DEFAULT_SAMPLE_RATE, 48000] {
+            let mut apu = apu_with_wave();
+            apu.set_sample_rate(sample_rate);
+            apu.write_register(0xFF1E, 0x87);
+            // half a second
+            for _ in 0..T_CYCLES_PER_SECOND / 8 {
+                apu.step(4);
+            }
+            assert_eq!(
+                apu.samples.len(),
+                sample_rate as usize / 2 * CHANNELS as usize
+            );
+        }
+    }
+}
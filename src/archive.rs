@@ -0,0 +1,103 @@
+//! ROMs are often downloaded in zip or gzip archives, so these are unpacked when loading them.
+use std::borrow::Cow;
+use std::io::{Cursor, Read};
+use std::path::{Path, PathBuf};
+
+use flate2::read::GzDecoder;
+use zip::ZipArchive;
+
+use crate::RomError;
+
+/// The largest cartridge ROM (MBC5). Archives are unpacked up to one byte more, so that larger ROMs fail validation
+/// without unpacking all of them.
+const MAX_ROM_LEN: u64 = 8 * 1024 * 1024;
+
+/// The ROM in `data` if it's a zip or gzip archive, or else `data` itself.
+///
+/// Zip archives can contain other files, like a readme, so the first `.gb` or `.gbc` file is picked.
+pub(crate) fn extract_rom(data: &[u8]) -> Result<Cow<'_, [u8]>, RomError> {
+    if data.starts_with(b"PK\x03\x04") {
+        from_zip(data).map(Cow::Owned)
+    } else if data.starts_with(&[0x1F, 0x8B]) {
+        let mut rom = Vec::new();
+        GzDecoder::new(data)
+            .take(MAX_ROM_LEN + 1)
+            .read_to_end(&mut rom)
+            .map_err(|e| RomError::Archive(e.to_string()))?;
+        Ok(Cow::Owned(rom))
+    } else {
+        Ok(Cow::Borrowed(data))
+    }
+}
+
+/// The path of the ROM in a gzip archive at `path`, e.g. `game.gb` for `game.gb.gz`, for naming save files after.
+pub(crate) fn unpacked_path(path: &Path) -> PathBuf {
+    if path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("gz"))
+    {
+        path.with_extension("")
+    } else {
+        path.to_path_buf()
+    }
+}
+
+fn from_zip(data: &[u8]) -> Result<Vec<u8>, RomError> {
+    let archive_error = |e: zip::result::ZipError| RomError::Archive(e.to_string());
+    let mut archive = ZipArchive::new(Cursor::new(data)).map_err(archive_error)?;
+    for idx in 0..archive.len() {
+        let file = archive.by_index(idx).map_err(archive_error)?;
+        let name = file.name().to_ascii_lowercase();
+        if !file.is_file() || !(name.ends_with(".gb") || name.ends_with(".gbc")) {
+            continue;
+        }
+        let mut rom = Vec::new();
+        file.take(MAX_ROM_LEN + 1)
+            .read_to_end(&mut rom)
+            .map_err(|e| RomError::Archive(e.to_string()))?;
+        return Ok(rom);
+    }
+    Err(RomError::NoRomInArchive)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+
+    use flate2::write::GzEncoder;
+    use zip::write::SimpleFileOptions;
+    use zip::ZipWriter;
+
+    use super::extract_rom;
+    use crate::RomError;
+
+    #[test]
+    fn extracts_first_rom_from_archives() {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        zip.start_file("README.txt", SimpleFileOptions::default())
+            .unwrap();
+        zip.write_all(b"not a ROM").unwrap();
+        zip.start_file("Game.GBC", SimpleFileOptions::default())
+            .unwrap();
+        zip.write_all(&[1, 2, 3]).unwrap();
+        zip.start_file("other.gb", SimpleFileOptions::default())
+            .unwrap();
+        zip.write_all(&[4, 5, 6]).unwrap();
+        let zip = zip.finish().unwrap().into_inner();
+        assert_eq!(extract_rom(&zip).unwrap().as_ref(), [1, 2, 3]);
+
+        let mut gzip = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzip.write_all(&[7, 8, 9]).unwrap();
+        let gzip = gzip.finish().unwrap();
+        assert_eq!(extract_rom(&gzip).unwrap().as_ref(), [7, 8, 9]);
+
+        // anything else is the ROM itself
+        assert_eq!(extract_rom(&[0x00, 0xC3]).unwrap().as_ref(), [0x00, 0xC3]);
+
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        zip.start_file("README.txt", SimpleFileOptions::default())
+            .unwrap();
+        let zip = zip.finish().unwrap().into_inner();
+        assert_eq!(extract_rom(&zip), Err(RomError::NoRomInArchive));
+    }
+}
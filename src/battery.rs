@@ -0,0 +1,163 @@
+//! Persistence of battery backed cartridge RAM in `.sav` files.
+//!
+//! The file holds the raw RAM, followed by the real-time clock for MBC3 cartridges with one, in the format other
+//! emulators use, so in-game saves can be moved between emulators. Unlike save states, these files only contain what
+//! the cartridge itself would keep when the Game Boy is turned off.
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use twox_hash::xxh3;
+
+use crate::Emulator;
+
+/// Write the save file at most this often while running, in case the emulator doesn't shut down cleanly
+const FLUSH_INTERVAL_FRAMES: u64 = 60 * 10;
+
+pub(crate) struct BatteryFile {
+    path: PathBuf,
+    /// The hash of the save that the file holds, to skip writing it when nothing changed
+    saved_hash: Option<u64>,
+}
+
+impl BatteryFile {
+    /// The save file for the ROM at `rom_path`, e.g. `tetris.sav` for `tetris.gb`.
+    pub(crate) fn for_rom(rom_path: &Path) -> Self {
+        BatteryFile {
+            path: rom_path.with_extension("sav"),
+            saved_hash: None,
+        }
+    }
+}
+
+impl Emulator {
+    /// Start keeping the battery backed RAM in `file`, loading the save that's already there.
+    pub(crate) fn attach_battery_file(
+        &mut self,
+        mut file: BatteryFile,
+    ) -> Result<(), Box<dyn Error>> {
+        if self.battery_save().is_none() {
+            return Ok(());
+        }
+        if file.path.exists() {
+            let save = std::fs::read(&file.path)
+                .context(format!("Unable to read save file: {:?}", file.path))?;
+            self.load_battery_save(&save)?;
+            file.saved_hash = Some(xxh3::hash64(&save));
+        }
+        self.battery_file = Some(file);
+        Ok(())
+    }
+
+    /// Write the battery backed RAM to `file` from now on, without loading the save that's there, e.g. after restoring
+    /// a save state, whose RAM is the one the game continues with.
+    pub(crate) fn keep_battery_file(&mut self, file: BatteryFile) {
+        if self.battery_save().is_some() {
+            self.battery_file = Some(file);
+        }
+    }
+
+    /// The file that battery backed RAM is written to, if the cartridge has a battery.
+    pub fn battery_file_path(&self) -> Option<&Path> {
+        self.battery_file.as_ref().map(|file| file.path.as_path())
+    }
+
+    /// Write the battery backed RAM to its save file, if it changed since the last write.
+    ///
+    /// This happens on [`Emulator::shutdown`], and every few seconds while running.
+    pub fn flush_battery_save(&mut self) -> Result<(), Box<dyn Error>> {
+        let Some(save) = self.battery_save() else {
+            return Ok(());
+        };
+        let Some(file) = &mut self.battery_file else {
+            return Ok(());
+        };
+        let hash = xxh3::hash64(&save);
+        if file.saved_hash == Some(hash) {
+            return Ok(());
+        }
+        std::fs::write(&file.path, &save)
+            .context(format!("Unable to write save file: {:?}", file.path))?;
+        file.saved_hash = Some(hash);
+        Ok(())
+    }
+
+    /// Called at the end of every frame.
+    pub(crate) fn flush_battery_save_periodically(&mut self) {
+        if self.battery_file.is_none() || !self.frame_count.is_multiple_of(FLUSH_INTERVAL_FRAMES) {
+            return;
+        }
+        if let Err(e) = self.flush_battery_save() {
+            eprintln!("Failed to write the battery save: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::cartridge::header_checksum;
+    use crate::mmu::Memory;
+    use crate::util::with_large_stack;
+    use crate::EmulatorBuilder;
+
+    #[test]
+    fn battery_save_survives_restart() {
+        let dir = std::env::temp_dir().join(format!("gbrs-battery-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let rom_path = dir.join("battery.gb");
+        let mut rom = vec![0; 0x8000];
+        // ROM+RAM+BATTERY
+        rom[0x0147] = 0x09;
+        rom[0x014D] = header_checksum(&rom);
+
+        let mut emu = EmulatorBuilder::new().for_rom(&rom, &rom_path).unwrap();
+        assert_eq!(
+            emu.battery_file_path(),
+            Some(dir.join("battery.sav").as_path())
+        );
+        emu.cpu.mmu.write_byte(0xA123, 0x42);
+        emu.shutdown().unwrap();
+        assert_eq!(std::fs::read(dir.join("battery.sav")).unwrap()[0x123], 0x42);
+
+        let emu = EmulatorBuilder::new().for_rom(&rom, &rom_path).unwrap();
+        assert_eq!(emu.cpu.mmu.read_byte(0xA123), 0x42);
+        // cartridges without a battery don't get a save file
+        rom[0x0147] = 0x08;
+        rom[0x014D] = header_checksum(&rom);
+        let emu = EmulatorBuilder::new()
+            .for_rom(&rom, Path::new("no-battery.gb"))
+            .unwrap();
+        assert_eq!(emu.battery_file_path(), None);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "save states are compressed with zstd, a C library")]
+    fn save_state_keeps_writing_the_battery_save() {
+        with_large_stack(|| {
+            let dir =
+                std::env::temp_dir().join(format!("gbrs-state-battery-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            let rom_path = dir.join("battery.gb");
+            let mut rom = vec![0; 0x8000];
+            // ROM+RAM+BATTERY
+            rom[0x0147] = 0x09;
+            rom[0x014D] = header_checksum(&rom);
+            let mut emu = EmulatorBuilder::new().for_rom(&rom, &rom_path).unwrap();
+            emu.cpu.mmu.write_byte(0xA123, 0x42);
+            let state = emu.save_state().unwrap();
+            std::fs::write(dir.join("battery.sav"), vec![0x13; 0x2000]).unwrap();
+
+            let mut emu = EmulatorBuilder::new()
+                .load_save_state(&rom, &rom_path, &dir.join("battery.sav.zst"), &state)
+                .unwrap();
+            // the RAM of the state wins over the older save file
+            assert_eq!(emu.cpu.mmu.read_byte(0xA123), 0x42);
+            emu.shutdown().unwrap();
+            assert_eq!(std::fs::read(dir.join("battery.sav")).unwrap()[0x123], 0x42);
+            std::fs::remove_dir_all(dir).unwrap();
+        });
+    }
+}
@@ -0,0 +1,160 @@
+//! Blending each frame with the ones before it. The DMG's LCD is slow to change, so games that flicker objects on
+//! and off every other frame look transparent on hardware, but flash without blending.
+use std::str::FromStr;
+
+use crate::{Emulator, FRAME_RGBA_LEN};
+
+/// How [`Emulator::frame_rgba`] blends each frame with the ones before it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FrameBlending {
+    /// Show each frame as it was drawn
+    #[default]
+    Off,
+    /// Show the average of each frame and the one before it
+    Mix,
+    /// Move each pixel halfway from the color it showed to the color of the new frame, so that changes fade in over a
+    /// few frames like on the DMG's LCD
+    LcdResponse,
+}
+
+impl FromStr for FrameBlending {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "off" => Ok(FrameBlending::Off),
+            "mix" => Ok(FrameBlending::Mix),
+            "lcd" | "lcd-response" => Ok(FrameBlending::LcdResponse),
+            _ => Err(format!(
+                "unknown frame blending {s:?}, expected off, mix, or lcd"
+            )),
+        }
+    }
+}
+
+pub(crate) struct Blender {
+    mode: FrameBlending,
+    /// The last frame as it was drawn
+    previous: Vec<u8>,
+    current: Vec<u8>,
+    /// What [`Emulator::frame_rgba`] shows
+    output: Vec<u8>,
+    /// Whether `previous` and `output` hold a frame yet
+    primed: bool,
+}
+
+impl Blender {
+    fn new(mode: FrameBlending) -> Self {
+        Blender {
+            mode,
+            previous: vec![0; FRAME_RGBA_LEN],
+            current: vec![0; FRAME_RGBA_LEN],
+            output: vec![0; FRAME_RGBA_LEN],
+            primed: false,
+        }
+    }
+
+    /// Blend in `current`, the frame that was just drawn
+    fn blend(&mut self) {
+        if !self.primed {
+            self.output.copy_from_slice(&self.current);
+            self.primed = true;
+        } else {
+            let pixels = self
+                .output
+                .iter_mut()
+                .zip(&self.current)
+                .zip(&self.previous);
+            for ((output, &new), &previous) in pixels {
+                let old = match self.mode {
+                    FrameBlending::Off | FrameBlending::Mix => previous,
+                    FrameBlending::LcdResponse => *output,
+                };
+                *output = (new as u16 + old as u16).div_ceil(2) as u8;
+            }
+        }
+        std::mem::swap(&mut self.previous, &mut self.current);
+    }
+
+    /// Forget the frames before, e.g. after loading a save state
+    pub(crate) fn reset(&mut self) {
+        self.primed = false;
+    }
+}
+
+impl Emulator {
+    /// Blend each frame that [`Emulator::frame_rgba`] shows with the ones before it. Blending starts from the next
+    /// frame.
+    pub fn set_frame_blending(&mut self, mode: FrameBlending) {
+        self.blender = match mode {
+            FrameBlending::Off => None,
+            mode => Some(Box::new(Blender::new(mode))),
+        };
+    }
+
+    pub fn frame_blending(&self) -> FrameBlending {
+        self.blender
+            .as_ref()
+            .map_or(FrameBlending::Off, |blender| blender.mode)
+    }
+
+    /// Blend in the frame that was just completed
+    pub(crate) fn blend_frame(&mut self) {
+        let Some(mut blender) = self.blender.take() else {
+            return;
+        };
+        self.draw_frame_rgba(&mut blender.current);
+        blender.blend();
+        self.blender = Some(blender);
+    }
+
+    /// The blended frame, unless blending is off or the LCD is off, which shows a blank screen right away
+    pub(crate) fn blended_frame(&self) -> Option<&[u8]> {
+        self.blender
+            .as_ref()
+            .filter(|blender| blender.primed && self.cpu.mmu.ppu.lcd_enabled)
+            .map(|blender| blender.output.as_slice())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blender_with_frames(mode: FrameBlending, frames: &[u8]) -> Blender {
+        let mut blender = Blender::new(mode);
+        for &value in frames {
+            blender.current.fill(value);
+            blender.blend();
+        }
+        blender
+    }
+
+    #[test]
+    fn blend_modes() {
+        assert_eq!(
+            blender_with_frames(FrameBlending::Mix, &[0]).output[0],
+            0,
+            "the first frame is shown as it is"
+        );
+        // flickering between black and white looks gray
+        assert_eq!(
+            blender_with_frames(FrameBlending::Mix, &[0, 0, 200]).output[0],
+            100
+        );
+        assert_eq!(
+            blender_with_frames(FrameBlending::Mix, &[200, 0, 200]).output[0],
+            100
+        );
+        // the LCD fades towards a new color over several frames
+        assert_eq!(
+            blender_with_frames(FrameBlending::LcdResponse, &[0, 200, 200]).output[0],
+            150
+        );
+        let mut blender = blender_with_frames(FrameBlending::LcdResponse, &[0, 200]);
+        blender.reset();
+        blender.current.fill(40);
+        blender.blend();
+        assert_eq!(blender.output[0], 40);
+    }
+}
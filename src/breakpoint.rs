@@ -0,0 +1,211 @@
+//! Breakpoints on the address of the next instruction, for debugger frontends.
+//!
+//! [`Emulator::step`] checks for a breakpoint before executing an instruction, and returns
+//! [`Stopped::Breakpoint`] instead of executing it. The next step executes the instruction, so that running again
+//! continues past the breakpoint.
+use crate::cpu::Registers;
+use crate::debugger::Condition;
+use crate::mmu::Mmu;
+use crate::{Emulator, Stopped};
+
+/// An address to stop at, in any ROM bank or only while a specific bank is mapped at 0x4000-0x7FFF, and optionally
+/// only when a condition holds.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Breakpoint {
+    pub addr: u16,
+    /// Only stop while this bank is mapped at 0x4000-0x7FFF, or `None` for any bank. Ignored for addresses outside
+    /// of the switchable ROM bank.
+    pub rom_bank: Option<usize>,
+    /// Only stop when this holds before executing the instruction
+    pub condition: Option<Condition>,
+}
+
+impl Breakpoint {
+    pub fn in_rom_bank(rom_bank: usize, addr: u16) -> Self {
+        Breakpoint {
+            addr,
+            rom_bank: Some(rom_bank),
+            condition: None,
+        }
+    }
+
+    /// Only stop when `condition` holds, e.g. `Breakpoint::from(0x0150).when("A == 0x3E".parse()?)`
+    pub fn when(self, condition: Condition) -> Self {
+        Breakpoint {
+            condition: Some(condition),
+            ..self
+        }
+    }
+
+    /// Whether `self` and `other` stop at the same address in the same bank, regardless of their conditions.
+    fn same_location(&self, other: &Breakpoint) -> bool {
+        self.addr == other.addr && self.rom_bank == other.rom_bank
+    }
+
+    fn matches(&self, regs: &Registers, mmu: &Mmu) -> bool {
+        regs.pc == self.addr
+            && match (regs.pc, self.rom_bank) {
+                (0x4000..=0x7FFF, Some(rom_bank)) => rom_bank == mmu.rom_bank(),
+                _ => true,
+            }
+            && self
+                .condition
+                .as_ref()
+                .is_none_or(|condition| condition.evaluate(regs, mmu))
+    }
+}
+
+/// A breakpoint at `addr` in any bank
+impl From<u16> for Breakpoint {
+    fn from(addr: u16) -> Self {
+        Breakpoint {
+            addr,
+            rom_bank: None,
+            condition: None,
+        }
+    }
+}
+
+impl Emulator {
+    /// Stop before executing the instruction at a breakpoint, e.g. `emu.add_breakpoint(0x0150)` or
+    /// `emu.add_breakpoint(Breakpoint::in_rom_bank(2, 0x4000))`.
+    ///
+    /// Replaces the breakpoint at the same location, if there is one, so that its condition can be changed.
+    pub fn add_breakpoint(&mut self, breakpoint: impl Into<Breakpoint>) {
+        let breakpoint = breakpoint.into();
+        self.remove_breakpoint(Breakpoint {
+            condition: None,
+            ..breakpoint.clone()
+        });
+        self.cpu.breakpoints.push(breakpoint);
+    }
+
+    /// Remove the breakpoint at the location of `breakpoint`, whatever its condition.
+    pub fn remove_breakpoint(&mut self, breakpoint: impl Into<Breakpoint>) {
+        let breakpoint = breakpoint.into();
+        self.cpu
+            .breakpoints
+            .retain(|other| !other.same_location(&breakpoint));
+    }
+
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        &self.cpu.breakpoints
+    }
+
+    /// The breakpoint at the next instruction, unless execution already stopped there.
+    ///
+    /// A halted or stopped CPU isn't about to execute the instruction at PC, so it doesn't hit breakpoints until it
+    /// wakes up.
+    pub(crate) fn check_breakpoints(&mut self) -> Result<(), Stopped> {
+        let cpu = &self.cpu;
+        if std::mem::take(&mut self.resuming_from_breakpoint)
+            || cpu.breakpoints.is_empty()
+            || cpu.is_halted
+            || cpu.is_stopped
+            || cpu.locked_up.is_some()
+        {
+            return Ok(());
+        }
+        match cpu
+            .breakpoints
+            .iter()
+            .find(|breakpoint| breakpoint.matches(&cpu.regs, &cpu.mmu))
+        {
+            Some(breakpoint) => {
+                let breakpoint = breakpoint.clone();
+                self.resuming_from_breakpoint = true;
+                Err(Stopped::Breakpoint(breakpoint))
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cartridge::header_checksum;
+    use crate::util::{emulator_for, emulator_running};
+    use crate::{Breakpoint, Stopped};
+
+    #[test]
+    fn stops_before_the_instruction_once() {
+        let mut rom = vec![0; 0x8000];
+        let program = [
+            0x3C, // INC A
+            0x18, 0xFD, // JR -3
+        ];
+        rom[..program.len()].copy_from_slice(&program);
+        // MBC1, so that the bank at 0x4000-0x7FFF can be switched
+        rom[0x0147] = 0x01;
+        rom[0x014D] = header_checksum(&rom);
+        let mut emu = emulator_for(&rom);
+        emu.cpu.regs.a = 0;
+        emu.add_breakpoint(0x0001);
+        emu.add_breakpoint(Breakpoint::in_rom_bank(2, 0x0001));
+
+        assert_eq!(emu.step(), Ok(4));
+        assert_eq!(emu.run_frame(), Err(Stopped::Breakpoint(0x0001.into())));
+        assert_eq!((emu.cpu.regs.pc, emu.cpu.regs.a), (0x0001, 1));
+        // continuing executes the instruction at the breakpoint, and stops when it's reached again
+        assert_eq!(emu.run_frame(), Err(Stopped::Breakpoint(0x0001.into())));
+        assert_eq!((emu.cpu.regs.pc, emu.cpu.regs.a), (0x0001, 2));
+
+        emu.remove_breakpoint(0x0001);
+        emu.remove_breakpoint(Breakpoint::in_rom_bank(2, 0x0001));
+        assert!(emu.breakpoints().is_empty());
+        emu.run_frame().unwrap();
+    }
+
+    #[test]
+    fn rom_bank_breakpoints() {
+        let mut rom = vec![0; 0x10000];
+        let program = [
+            0x3E, 0x02, // LD A,2
+            0xEA, 0x00, 0x20, // LD [0x2000],A   (map bank 2)
+            0xC3, 0x00, 0x40, // JP 0x4000
+        ];
+        rom[..program.len()].copy_from_slice(&program);
+        for bank in 1..4 {
+            // JR -2
+            rom[bank * 0x4000..bank * 0x4000 + 2].copy_from_slice(&[0x18, 0xFE]);
+        }
+        rom[0x0147] = 0x01;
+        rom[0x0148] = 0x01;
+        rom[0x014D] = header_checksum(&rom);
+        let mut emu = emulator_for(&rom);
+        emu.add_breakpoint(Breakpoint::in_rom_bank(1, 0x4000));
+        emu.run_frame().unwrap();
+
+        emu.cpu.regs.pc = 0x0000;
+        emu.add_breakpoint(Breakpoint::in_rom_bank(2, 0x4000));
+        assert_eq!(
+            emu.run_frame(),
+            Err(Stopped::Breakpoint(Breakpoint::in_rom_bank(2, 0x4000)))
+        );
+        assert_eq!(emu.cpu.mmu.rom_bank(), 2);
+    }
+
+    #[test]
+    fn conditional_breakpoints() {
+        let mut emu = emulator_running(&[
+            0x3C, // INC A
+            0x18, 0xFD, // JR -3
+        ]);
+        emu.cpu.regs.a = 0;
+        let breakpoint = Breakpoint::from(0x0001).when("A >= 3 && A != 4".parse().unwrap());
+        emu.add_breakpoint(breakpoint.clone());
+        assert_eq!(
+            emu.run_frame(),
+            Err(Stopped::Breakpoint(breakpoint.clone()))
+        );
+        assert_eq!(emu.cpu.regs.a, 3);
+        assert_eq!(emu.run_frame(), Err(Stopped::Breakpoint(breakpoint)));
+        assert_eq!(emu.cpu.regs.a, 5);
+
+        // adding a breakpoint at the same location replaces the condition
+        emu.add_breakpoint(Breakpoint::from(0x0001).when("A == 0x10".parse().unwrap()));
+        assert_eq!(emu.breakpoints().len(), 1);
+        assert!(emu.run_frame().is_err());
+        assert_eq!(emu.cpu.regs.a, 0x10);
+    }
+}
@@ -0,0 +1,326 @@
+//! Read-only subscriptions to memory accesses, for RAM watches, heatmaps, and achievement checks.
+//!
+//! Accesses are collected while the frame runs and delivered to each subscriber in one batch when the frame ends.
+//! Hooks are called as the accesses happen instead, for tools that react to a single access, like auto-splitters.
+//! When nothing is subscribed or hooked, the MMU doesn't check addresses at all.
+use std::cell::RefCell;
+use std::ops::RangeInclusive;
+use std::sync::mpsc::{self, Receiver, Sender};
+
+use enumset::{EnumSet, EnumSetType};
+
+use crate::Emulator;
+
+#[derive(Debug, EnumSetType)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BusAccess {
+    pub addr: u16,
+    pub kind: AccessKind,
+    /// The byte that was read or written
+    pub value: u8,
+}
+
+/// The accesses made during a frame, in the order they happened.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BusBatch {
+    /// The frame count at the end of the frame, see [`Emulator::frame_count`]
+    pub frame: u64,
+    pub accesses: Vec<BusAccess>,
+}
+
+/// Identifies a hook added with [`Emulator::add_memory_hook`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct HookId(u64);
+
+/// The accesses that a subscription or a hook is interested in
+struct Filter {
+    ranges: Vec<RangeInclusive<u16>>,
+    kinds: EnumSet<AccessKind>,
+}
+
+impl Filter {
+    fn matches(&self, addr: u16, kind: AccessKind) -> bool {
+        self.kinds.contains(kind) && self.ranges.iter().any(|range| range.contains(&addr))
+    }
+}
+
+struct Subscription {
+    filter: Filter,
+    pending: Vec<BusAccess>,
+    sender: Sender<BusBatch>,
+}
+
+struct Hook {
+    id: HookId,
+    filter: Filter,
+    callback: Box<dyn FnMut(BusAccess) + Send>,
+}
+
+pub(crate) struct BusSpy {
+    /// One bit per address, set if any subscription or hook covers the address, so that uninteresting accesses are
+    /// cheap
+    watched: Box<[u64; 1024]>,
+    /// Behind a `RefCell` because reads only borrow the MMU immutably
+    subscriptions: RefCell<Vec<Subscription>>,
+    hooks: RefCell<Vec<Hook>>,
+    next_hook_id: u64,
+}
+
+impl BusSpy {
+    fn new() -> Self {
+        BusSpy {
+            watched: Box::new([0; 1024]),
+            subscriptions: RefCell::new(Vec::new()),
+            hooks: RefCell::new(Vec::new()),
+            next_hook_id: 0,
+        }
+    }
+
+    fn add(&mut self, subscription: Subscription) {
+        self.watch(&subscription.filter.ranges);
+        self.subscriptions.get_mut().push(subscription);
+    }
+
+    fn add_hook(&mut self, filter: Filter, callback: Box<dyn FnMut(BusAccess) + Send>) -> HookId {
+        let id = HookId(self.next_hook_id);
+        self.next_hook_id += 1;
+        self.watch(&filter.ranges);
+        self.hooks.get_mut().push(Hook {
+            id,
+            filter,
+            callback,
+        });
+        id
+    }
+
+    /// Returns whether the hook was found.
+    fn remove_hook(&mut self, id: HookId) -> bool {
+        let hooks = self.hooks.get_mut();
+        let Some(idx) = hooks.iter().position(|hook| hook.id == id) else {
+            return false;
+        };
+        hooks.remove(idx);
+        self.rewatch();
+        true
+    }
+
+    fn is_empty(&mut self) -> bool {
+        self.subscriptions.get_mut().is_empty() && self.hooks.get_mut().is_empty()
+    }
+
+    fn watch(&mut self, ranges: &[RangeInclusive<u16>]) {
+        for addr in ranges.iter().cloned().flatten() {
+            self.watched[addr as usize / 64] |= 1 << (addr % 64);
+        }
+    }
+
+    /// Recompute the watched addresses after a subscription or a hook was removed.
+    fn rewatch(&mut self) {
+        self.watched.fill(0);
+        let subscriptions = std::mem::take(self.subscriptions.get_mut());
+        let hooks = std::mem::take(self.hooks.get_mut());
+        for filter in subscriptions
+            .iter()
+            .map(|subscription| &subscription.filter)
+            .chain(hooks.iter().map(|hook| &hook.filter))
+        {
+            self.watch(&filter.ranges);
+        }
+        *self.subscriptions.get_mut() = subscriptions;
+        *self.hooks.get_mut() = hooks;
+    }
+
+    /// Called for every access while anything is subscribed.
+    #[inline]
+    pub(crate) fn record(&self, addr: u16, kind: AccessKind, value: u8) {
+        if self.watched[addr as usize / 64] & (1 << (addr % 64)) == 0 {
+            return;
+        }
+        let access = BusAccess { addr, kind, value };
+        for subscription in self.subscriptions.borrow_mut().iter_mut() {
+            if subscription.filter.matches(addr, kind) {
+                subscription.pending.push(access);
+            }
+        }
+        for hook in self.hooks.borrow_mut().iter_mut() {
+            if hook.filter.matches(addr, kind) {
+                (hook.callback)(access);
+            }
+        }
+    }
+
+    /// Deliver each subscription's batch, and drop the subscriptions whose receiver was dropped.
+    ///
+    /// Returns false once nothing is subscribed or hooked anymore.
+    fn end_frame(&mut self, frame: u64) -> bool {
+        let subscriptions = self.subscriptions.get_mut();
+        let subscribed = subscriptions.len();
+        subscriptions.retain_mut(|subscription| {
+            let batch = BusBatch {
+                frame,
+                accesses: std::mem::take(&mut subscription.pending),
+            };
+            subscription.sender.send(batch).is_ok()
+        });
+        if subscriptions.len() != subscribed {
+            self.rewatch();
+        }
+        !self.is_empty()
+    }
+}
+
+impl Emulator {
+    /// Receive the accesses of `kinds` to the addresses in `ranges`, in one batch at the end of every frame.
+    ///
+    /// Accesses are recorded at the MMU, so they include DMA transfers and reads by debug views like
+    /// [`Emulator::describe_instruction`]. Dropping the receiver ends the subscription.
+    pub fn subscribe_bus(
+        &mut self,
+        ranges: impl IntoIterator<Item = RangeInclusive<u16>>,
+        kinds: EnumSet<AccessKind>,
+    ) -> Receiver<BusBatch> {
+        let (sender, receiver) = mpsc::channel();
+        self.cpu
+            .mmu
+            .bus_spy
+            .get_or_insert_with(|| Box::new(BusSpy::new()))
+            .add(Subscription {
+                filter: Filter {
+                    ranges: ranges.into_iter().collect(),
+                    kinds,
+                },
+                pending: Vec::new(),
+                sender,
+            });
+        receiver
+    }
+
+    /// Call `hook` with every access of `kinds` to the addresses in `ranges`, as the access happens.
+    ///
+    /// Like [`Emulator::subscribe_bus`], this sees DMA transfers and reads by debug views. The hook can't access the
+    /// emulator, so it should only record what it needs, e.g. in a channel or an atomic.
+    pub fn add_memory_hook(
+        &mut self,
+        ranges: impl IntoIterator<Item = RangeInclusive<u16>>,
+        kinds: EnumSet<AccessKind>,
+        hook: impl FnMut(BusAccess) + Send + 'static,
+    ) -> HookId {
+        self.cpu
+            .mmu
+            .bus_spy
+            .get_or_insert_with(|| Box::new(BusSpy::new()))
+            .add_hook(
+                Filter {
+                    ranges: ranges.into_iter().collect(),
+                    kinds,
+                },
+                Box::new(hook),
+            )
+    }
+
+    /// Returns whether the hook was still there.
+    pub fn remove_memory_hook(&mut self, id: HookId) -> bool {
+        let Some(spy) = &mut self.cpu.mmu.bus_spy else {
+            return false;
+        };
+        let removed = spy.remove_hook(id);
+        if spy.is_empty() {
+            self.cpu.mmu.bus_spy = None;
+        }
+        removed
+    }
+
+    /// Called at the end of every frame.
+    pub(crate) fn deliver_bus_batches(&mut self) {
+        if let Some(spy) = &mut self.cpu.mmu.bus_spy {
+            if !spy.end_frame(self.frame_count) {
+                self.cpu.mmu.bus_spy = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::{AccessKind, BusAccess};
+    use crate::util::emulator_running;
+
+    #[test]
+    fn batches_accesses_per_frame() {
+        let mut emu = emulator_running(&[
+            0x3E, 0x80, // LD A,0x80
+            0xE0, 0x40, // LDH [0x40],A   (turn on the LCD)
+            0x04, // 0x0004: INC B
+            0x78, // LD A,B
+            0xEA, 0x23, 0xC1, // LD [0xC123],A
+            0x76, // HALT
+        ]);
+        let writes = emu.subscribe_bus([0xC100..=0xC1FF], AccessKind::Write.into());
+        let io = emu.subscribe_bus([0xFF40..=0xFF40], AccessKind::Read | AccessKind::Write);
+        emu.run_frame().unwrap();
+        let batch = writes.try_recv().unwrap();
+        assert_eq!(batch.frame, emu.frame_count());
+        assert_eq!(
+            batch.accesses,
+            [BusAccess {
+                addr: 0xC123,
+                kind: AccessKind::Write,
+                value: 0x01
+            }]
+        );
+        assert_eq!(io.try_recv().unwrap().accesses.len(), 1);
+
+        // dropping every receiver unsubscribes
+        drop((writes, io));
+        emu.run_frame().unwrap();
+        assert!(emu.cpu.mmu.bus_spy.is_none());
+    }
+
+    #[test]
+    fn hooks_see_accesses_as_they_happen() {
+        let mut emu = emulator_running(&[
+            0x3E, 0x2A, // LD A,0x2A
+            0xEA, 0x00, 0xC0, // LD [0xC000],A
+            0xFA, 0x00, 0xC0, // LD A,[0xC000]
+            0x18, 0xF6, // JR -10
+        ]);
+        let accesses = Arc::new(Mutex::new(Vec::new()));
+        let recorded = accesses.clone();
+        let id = emu.add_memory_hook(
+            [0xC000..=0xC000],
+            AccessKind::Read | AccessKind::Write,
+            move |access| recorded.lock().unwrap().push(access),
+        );
+        emu.step().unwrap();
+        emu.step().unwrap();
+        assert_eq!(
+            *accesses.lock().unwrap(),
+            [BusAccess {
+                addr: 0xC000,
+                kind: AccessKind::Write,
+                value: 0x2A
+            }]
+        );
+        emu.step().unwrap();
+        assert_eq!(accesses.lock().unwrap().len(), 2);
+        // debugger reads aren't accesses
+        assert_eq!(emu.read_memory(0xC000), 0x2A);
+        emu.describe_instruction(0x0005);
+        assert_eq!(accesses.lock().unwrap().len(), 2);
+
+        assert!(emu.remove_memory_hook(id));
+        assert!(!emu.remove_memory_hook(id));
+        assert!(emu.cpu.mmu.bus_spy.is_none());
+        for _ in 0..4 {
+            emu.step().unwrap();
+        }
+        assert_eq!(accesses.lock().unwrap().len(), 2);
+    }
+}
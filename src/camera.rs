@@ -0,0 +1,82 @@
+//! The image sensor of the Game Boy Camera, fed by the frontend.
+//!
+//! https://gbdev.io/pandocs/Gameboy_Camera.html
+
+/// The width of the images the camera captures
+pub const WIDTH: usize = 128;
+/// The height of the images the camera captures
+pub const HEIGHT: usize = 112;
+
+/// A grayscale image, from 0 for black to 255 for white
+pub type Frame = [[u8; WIDTH]; HEIGHT];
+
+/// What the camera sees, e.g. a static image or frames from a webcam.
+pub trait CameraSource: Send {
+    /// Called whenever the game takes a picture.
+    fn capture(&mut self) -> Frame;
+}
+
+/// Shows the same image for every picture.
+pub struct StaticImage(pub Box<Frame>);
+
+impl StaticImage {
+    /// Scale a grayscale image of `width` x `height` pixels, in rows, to the camera's resolution. The image is scaled
+    /// to cover the whole frame, and the sides that don't fit are cropped.
+    pub fn from_grayscale(pixels: &[u8], width: usize, height: usize) -> StaticImage {
+        assert_eq!(
+            pixels.len(),
+            width * height,
+            "expected {width}x{height} pixels"
+        );
+        let scale = f64::min(width as f64 / WIDTH as f64, height as f64 / HEIGHT as f64);
+        let (left, top) = (
+            (width as f64 - WIDTH as f64 * scale) / 2.0,
+            (height as f64 - HEIGHT as f64 * scale) / 2.0,
+        );
+        let mut frame = Box::new([[0; WIDTH]; HEIGHT]);
+        for (y, row) in frame.iter_mut().enumerate() {
+            for (x, pixel) in row.iter_mut().enumerate() {
+                let source_x = ((left + x as f64 * scale) as usize).min(width - 1);
+                let source_y = ((top + y as f64 * scale) as usize).min(height - 1);
+                *pixel = pixels[source_y * width + source_x];
+            }
+        }
+        StaticImage(frame)
+    }
+}
+
+impl CameraSource for StaticImage {
+    fn capture(&mut self) -> Frame {
+        *self.0
+    }
+}
+
+/// What the camera sees without a source: the same noise for every picture, like a sensor with the lens covered.
+pub(crate) fn noise() -> Frame {
+    let mut state: u32 = 0x2545_F491;
+    let mut frame = [[0; WIDTH]; HEIGHT];
+    for pixel in frame.iter_mut().flatten() {
+        // xorshift
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        *pixel = (state % 64) as u8;
+    }
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CameraSource, StaticImage};
+
+    #[test]
+    fn static_image_is_cropped_to_cover_the_frame() {
+        // 256x112: twice as wide as the frame, so only the middle 128 columns are kept
+        let pixels: Vec<u8> = (0..112)
+            .flat_map(|_| (0..=255).collect::<Vec<u8>>())
+            .collect();
+        let frame = StaticImage::from_grayscale(&pixels, 256, 112).capture();
+        assert_eq!(frame[0][0], 64);
+        assert_eq!(frame[111][127], 191);
+    }
+}
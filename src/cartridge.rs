@@ -1,14 +1,290 @@
-use std::time::{Duration, SystemTime};
+use std::fmt::Display;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde_big_array::BigArray;
 
+use crate::apu::T_CYCLES_PER_SECOND;
+use crate::camera::{self, CameraSource};
+use crate::patch::PatchError;
+
 #[typetag::serde(tag = "cartridge")]
-pub trait Cartridge {
+pub trait Cartridge: Send {
     fn read(&self, addr: u16) -> u8;
     fn write(&mut self, addr: u16, byte: u8);
     /// When loading the cartridge state from a save file, use this to set the rom data in the cartridge
     fn set_rom(&mut self, rom: &[u8]);
+    /// The ROM bank mapped at 0x4000-0x7FFF
+    fn rom_bank(&self) -> usize;
+    /// Called with every step of the CPU, in normal speed T-cycles.
+    fn step(&mut self, _t_cycles: u8) {}
+    /// Does nothing for cartridges without a real-time clock.
+    fn set_rtc_clock_source(&mut self, _source: RtcClockSource) {}
+    /// How strongly the rumble motor ran since the last call, from 0 for off to 1 for on the whole time. Games vary
+    /// the strength by turning the motor on and off quickly. `None` for cartridges without a motor.
+    fn take_rumble_state(&mut self) -> Option<f32> {
+        None
+    }
+    /// Use `source` for the cartridge's image sensor. Does nothing for cartridges without a camera.
+    fn connect_camera(&mut self, _source: Box<dyn CameraSource>) {}
+    fn disconnect_camera(&mut self) -> Option<Box<dyn CameraSource>> {
+        None
+    }
+    /// Tilt the cartridge by `x` and `y` g. Does nothing for cartridges without an accelerometer.
+    fn set_accelerometer(&mut self, _x: f32, _y: f32) {}
+    /// The battery backed RAM, followed by the real-time clock if there is one. `None` if there's no battery.
+    fn battery_save(&self) -> Option<Vec<u8>> {
+        None
+    }
+    /// Restore the RAM and clock from [`Cartridge::battery_save`], or from a `.sav` file of another emulator.
+    fn load_battery_save(&mut self, _save: &[u8]) -> Result<(), String> {
+        Err("The cartridge has no battery backed RAM".into())
+    }
+}
+
+/// Why a ROM can't be loaded, see [`validate_rom`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RomError {
+    /// The file ends before the end of the cartridge header at 0x14F
+    TooShort {
+        len: usize,
+    },
+    /// The checksum at 0x14D doesn't match the header, so the boot ROM would lock up
+    HeaderChecksum {
+        expected: u8,
+        actual: u8,
+    },
+    /// The ROM size in the header at 0x148 doesn't match the size of the file
+    SizeMismatch {
+        declared: usize,
+        actual: usize,
+    },
+    /// The cartridge type at 0x147
+    UnsupportedMapper(u8),
+    UnsupportedRomSize {
+        mapper: Mapper,
+        rom_size: u8,
+    },
+    UnsupportedRamSize {
+        mapper: Mapper,
+        ram_size: u8,
+    },
+    /// The zip or gzip archive couldn't be unpacked
+    Archive(String),
+    /// The zip archive doesn't contain a `.gb` or `.gbc` file
+    NoRomInArchive,
+    Patch(PatchError),
+    /// The boot ROM is neither a DMG boot ROM (256 bytes) nor a CGB boot ROM (2304 bytes)
+    BootRomSize {
+        len: usize,
+    },
+}
+
+impl std::fmt::Display for RomError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RomError::TooShort { len } => {
+                write!(f, "The ROM has {len} bytes, which is too short for a cartridge header")
+            }
+            RomError::HeaderChecksum { expected, actual } => write!(
+                f,
+                "The header checksum is {actual:02X}, but should be {expected:02X}. The ROM is corrupted"
+            ),
+            RomError::SizeMismatch { declared, actual } => write!(
+                f,
+                "The header declares {declared} bytes of ROM, but the file has {actual} bytes. The ROM may be truncated"
+            ),
+            RomError::UnsupportedMapper(cartridge_type) => {
+                writeln!(f, "Unsupported cartridge type: {cartridge_type:02X}. The supported mappers are:")?;
+                for mapper in Mapper::ALL {
+                    writeln!(f, "  {mapper} (cartridge type {})", mapper.header_types())?;
+                }
+                write!(f, "A mapper can also be forced, regardless of the cartridge type")
+            }
+            RomError::UnsupportedRomSize { mapper, rom_size } => {
+                write!(f, "Unsupported ROM size {rom_size:02X} for the {mapper} mapper")
+            }
+            RomError::UnsupportedRamSize { mapper, ram_size } => {
+                write!(f, "Unsupported RAM size {ram_size:02X} for the {mapper} mapper")
+            }
+            RomError::Archive(e) => write!(f, "Unable to unpack the archive: {e}"),
+            RomError::NoRomInArchive => write!(f, "The archive doesn't contain a .gb or .gbc file"),
+            RomError::Patch(e) => write!(f, "Unable to apply the patch: {e}"),
+            RomError::BootRomSize { len } => write!(
+                f,
+                "The boot ROM has {len} bytes, but should have 256 for a DMG or 2304 for a CGB"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RomError {}
+
+impl From<PatchError> for RomError {
+    fn from(e: PatchError) -> Self {
+        RomError::Patch(e)
+    }
+}
+
+/// The checksum of the header bytes 0x134-0x14C, which belongs at 0x14D.
+pub(crate) fn header_checksum(rom: &[u8]) -> u8 {
+    rom[0x0134..=0x014C].iter().fold(0u8, |checksum, &byte| {
+        checksum.wrapping_sub(byte).wrapping_sub(1)
+    })
+}
+
+/// The cartridge hardware that maps ROM and RAM banks into memory. It's picked from the cartridge type in the header,
+/// unless it's forced with [`crate::EmulatorBuilder::mapper`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mapper {
+    NoMbc,
+    Mbc1,
+    Mbc3,
+    Mbc5,
+    Mbc7,
+    PocketCamera,
+    WisdomTree,
+}
+
+impl Mapper {
+    pub const ALL: [Mapper; 7] = [
+        Mapper::NoMbc,
+        Mapper::Mbc1,
+        Mapper::Mbc3,
+        Mapper::Mbc5,
+        Mapper::Mbc7,
+        Mapper::PocketCamera,
+        Mapper::WisdomTree,
+    ];
+
+    /// The mapper for the cartridge type in the header of `rom`, or `None` if it isn't supported.
+    pub fn for_header(rom: &[u8]) -> Option<Mapper> {
+        if WisdomTree::detect(rom) {
+            return Some(Mapper::WisdomTree);
+        }
+        match rom[0x0147] {
+            0x00 | 0x08 | 0x09 => Some(Mapper::NoMbc),
+            0x01..=0x03 => Some(Mapper::Mbc1),
+            0x0F..=0x13 => Some(Mapper::Mbc3),
+            0x19..=0x1E => Some(Mapper::Mbc5),
+            0x22 => Some(Mapper::Mbc7),
+            0xFC => Some(Mapper::PocketCamera),
+            _ => None,
+        }
+    }
+
+    /// The cartridge types in the header that select this mapper
+    fn header_types(self) -> &'static str {
+        match self {
+            Mapper::NoMbc => "00, 08, 09",
+            Mapper::Mbc1 => "01-03",
+            Mapper::Mbc3 => "0F-13",
+            Mapper::Mbc5 => "19-1E",
+            Mapper::Mbc7 => "22",
+            Mapper::PocketCamera => "FC",
+            Mapper::WisdomTree => "00 or C0 with more than 32 KiB of ROM",
+        }
+    }
+
+    /// The largest ROM and RAM size bytes in the header that the mapper supports
+    fn max_sizes(self) -> (u8, u8) {
+        match self {
+            Mapper::NoMbc => (0x00, 0x02),
+            Mapper::Mbc1 | Mapper::Mbc3 => (0x06, 0x03),
+            Mapper::Mbc5 => (0x08, 0x05),
+            // the EEPROM doesn't depend on the RAM size
+            Mapper::Mbc7 => (0x08, 0xFF),
+            Mapper::PocketCamera => (0x08, 0x04),
+            // Wisdom Tree games ignore their header's size bytes
+            Mapper::WisdomTree => (0xFF, 0xFF),
+        }
+    }
+
+    /// Panics if the ROM isn't valid for the mapper, see [`validate_rom`].
+    pub(crate) fn create(self, rom: &[u8]) -> Box<dyn Cartridge> {
+        match self {
+            Mapper::NoMbc => Box::new(NoMbc::from_game_rom(rom)),
+            Mapper::Mbc1 => Box::new(Mbc1::from_game_rom(rom)),
+            Mapper::Mbc3 => Box::new(Mbc3::from_game_rom(rom)),
+            Mapper::Mbc5 => Box::new(Mbc5::from_game_rom(rom)),
+            Mapper::Mbc7 => Box::new(Mbc7::from_game_rom(rom)),
+            Mapper::PocketCamera => Box::new(PocketCamera::from_game_rom(rom)),
+            Mapper::WisdomTree => Box::new(WisdomTree::from_game_rom(rom)),
+        }
+    }
+}
+
+impl FromStr for Mapper {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Mapper::ALL
+            .into_iter()
+            .find(|mapper| mapper.to_string().eq_ignore_ascii_case(s))
+            .ok_or_else(|| {
+                let names: Vec<String> = Mapper::ALL.iter().map(Mapper::to_string).collect();
+                format!(
+                    "unknown mapper {s:?}, expected one of: {}",
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+impl Display for Mapper {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Mapper::NoMbc => write!(f, "none"),
+            Mapper::Mbc1 => write!(f, "mbc1"),
+            Mapper::Mbc3 => write!(f, "mbc3"),
+            Mapper::Mbc5 => write!(f, "mbc5"),
+            Mapper::Mbc7 => write!(f, "mbc7"),
+            Mapper::PocketCamera => write!(f, "camera"),
+            Mapper::WisdomTree => write!(f, "wisdom-tree"),
+        }
+    }
+}
+
+/// Check that `rom` can be loaded with `mapper`, or with the mapper for its header if `None`, so that a bad or
+/// truncated file is reported instead of panicking. Returns the mapper to load it with.
+///
+/// https://gbdev.io/pandocs/The_Cartridge_Header.html
+pub fn validate_rom(rom: &[u8], mapper: Option<Mapper>) -> Result<Mapper, RomError> {
+    if rom.len() < 0x0150 {
+        return Err(RomError::TooShort { len: rom.len() });
+    }
+    let expected = header_checksum(rom);
+    if rom[0x014D] != expected {
+        return Err(RomError::HeaderChecksum {
+            expected,
+            actual: rom[0x014D],
+        });
+    }
+    let mapper = match mapper.or_else(|| Mapper::for_header(rom)) {
+        Some(mapper) => mapper,
+        None => return Err(RomError::UnsupportedMapper(rom[0x0147])),
+    };
+    if mapper == Mapper::WisdomTree {
+        return Ok(mapper);
+    }
+    let (max_rom_size, max_ram_size) = mapper.max_sizes();
+    let rom_size = rom[0x0148];
+    if rom_size > max_rom_size {
+        return Err(RomError::UnsupportedRomSize { mapper, rom_size });
+    }
+    let declared = 0x8000 << rom_size;
+    if rom.len() != declared {
+        return Err(RomError::SizeMismatch {
+            declared,
+            actual: rom.len(),
+        });
+    }
+    let ram_size = rom[0x0149];
+    if ram_size > max_ram_size {
+        return Err(RomError::UnsupportedRamSize { mapper, ram_size });
+    }
+    Ok(mapper)
 }
 
 /// Small games of not more than 32 KiB ROM do not require a MBC chip for ROM banking.
@@ -23,6 +299,8 @@ pub struct NoMbc {
     rom: [u8; 0x8000],
     #[serde(with = "BigArray")]
     ext_ram: [u8; 0x2000],
+    /// Cartridge type 0x09 has a battery to keep the RAM
+    has_battery: bool,
 }
 
 fn skip_serializing_rom<S>(_: &[u8; 0x8000], s: S) -> Result<S::Ok, S::Error>
@@ -32,10 +310,12 @@ where
     s.serialize_none()
 }
 
-fn create_default_rom<'de, D>(_: D) -> Result<[u8; 0x8000], D::Error>
+fn create_default_rom<'de, D>(d: D) -> Result<[u8; 0x8000], D::Error>
 where
     D: Deserializer<'de>,
 {
+    // consume the placeholder written by `skip_serializing_rom`
+    Option::<()>::deserialize(d)?;
     Ok([0; 0x8000])
 }
 impl NoMbc {
@@ -49,6 +329,7 @@ impl NoMbc {
         NoMbc {
             rom: cart_rom,
             ext_ram: [0; 0x2000],
+            has_battery: rom[0x0147] == 0x09,
         }
     }
 }
@@ -81,18 +362,50 @@ impl Cartridge for NoMbc {
         );
         self.rom.copy_from_slice(rom);
     }
+
+    fn rom_bank(&self) -> usize {
+        1
+    }
+
+    fn battery_save(&self) -> Option<Vec<u8>> {
+        self.has_battery.then(|| self.ext_ram.to_vec())
+    }
+
+    fn load_battery_save(&mut self, save: &[u8]) -> Result<(), String> {
+        if !self.has_battery {
+            return Err("The cartridge has no battery backed RAM".into());
+        }
+        check_ram_save_len(save, self.ext_ram.len())?;
+        self.ext_ram.copy_from_slice(save);
+        Ok(())
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct Mbc1 {
     #[serde(skip)]
     rom_banks: Vec<RomBank>,
-    rom_bank_idx: usize,
     ram_banks: Vec<RamBank>,
-    ram_bank_idx: usize,
+    /// The 5 bit register at 0x2000-0x3FFF. Writing 0 selects 1
+    bank_low: u8,
+    /// The 2 bit register at 0x4000-0x5FFF. Selects the upper bits of the ROM bank, or the RAM bank
+    bank_high: u8,
+    /// Set with 0x6000-0x7FFF. In this mode, `bank_high` also applies to 0x0000-0x3FFF and to RAM
+    advanced_banking: bool,
     ram_enable: bool,
+    /// Cartridge type 0x03 has a battery to keep the RAM
+    has_battery: bool,
+    /// Multicarts (MBC1M) wire `bank_high` to bit 4 of the ROM bank instead of bit 5, for 4 games of 16 banks each
+    multicart: bool,
 }
 
+/// The logo in the header of every licensed cartridge, and of every game in a multicart
+pub(crate) const NINTENDO_LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+    0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+    0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+
 fn parse_banks(rom: &[u8]) -> Vec<RomBank> {
     let rom_size_byte = rom[0x0148];
     assert!((0x00..=0x08).contains(&rom_size_byte));
@@ -116,8 +429,8 @@ impl Mbc1 {
     pub fn from_game_rom(rom: &[u8]) -> Self {
         let rom_banks = parse_banks(rom);
         assert!(
-            rom_banks.len() <= 32,
-            "Only support 5 bits for ROM bank selection"
+            rom_banks.len() <= 128,
+            "Only support 7 bits for ROM bank selection"
         );
         let ram_size_byte = rom[0x0149];
         let ram_banks = match ram_size_byte {
@@ -137,9 +450,49 @@ impl Mbc1 {
         Mbc1 {
             rom_banks,
             ram_banks,
-            rom_bank_idx: 1,
-            ram_bank_idx: 0,
+            bank_low: 1,
+            bank_high: 0,
+            advanced_banking: false,
             ram_enable: false,
+            has_battery: rom[0x0147] == 0x03,
+            multicart: Mbc1::is_multicart(rom),
+        }
+    }
+
+    /// Multicarts are 1 MiB, and have the header of another game at bank 0x10.
+    fn is_multicart(rom: &[u8]) -> bool {
+        rom.len() == 0x10_0000 && rom[0x4_0104..0x4_0134] == NINTENDO_LOGO
+    }
+
+    fn bank_high_shift(&self) -> u32 {
+        if self.multicart {
+            4
+        } else {
+            5
+        }
+    }
+
+    /// The ROM bank mapped at 0x0000-0x3FFF
+    fn low_rom_bank(&self) -> usize {
+        if self.advanced_banking {
+            ((self.bank_high as usize) << self.bank_high_shift()) % self.rom_banks.len()
+        } else {
+            0
+        }
+    }
+
+    fn high_rom_bank(&self) -> usize {
+        let low_mask = (1 << self.bank_high_shift()) - 1;
+        let bank = ((self.bank_high as usize) << self.bank_high_shift())
+            | (self.bank_low & low_mask) as usize;
+        bank % self.rom_banks.len()
+    }
+
+    fn ram_bank(&self) -> usize {
+        if self.advanced_banking && !self.ram_banks.is_empty() {
+            self.bank_high as usize % self.ram_banks.len()
+        } else {
+            0
         }
     }
 }
@@ -148,17 +501,14 @@ impl Mbc1 {
 impl Cartridge for Mbc1 {
     fn read(&self, addr: u16) -> u8 {
         match addr {
-            0x0000..=0x3FFF => self.rom_banks[0].as_slice()[addr as usize],
+            0x0000..=0x3FFF => self.rom_banks[self.low_rom_bank()].as_slice()[addr as usize],
             0x4000..=0x7FFF => {
-                self.rom_banks[self.rom_bank_idx].as_slice()[(addr - 0x4000) as usize]
-            }
-            0xA000..=0xBFFF => {
-                if self.ram_enable {
-                    self.ram_banks[self.ram_bank_idx].as_slice()[addr as usize - 0xA000]
-                } else {
-                    0xFF
-                }
+                self.rom_banks[self.high_rom_bank()].as_slice()[(addr - 0x4000) as usize]
             }
+            0xA000..=0xBFFF => match self.ram_banks.get(self.ram_bank()) {
+                Some(bank) if self.ram_enable => bank.as_slice()[addr as usize - 0xA000],
+                _ => 0xFF,
+            },
 
             _ => panic!("invalid cartridge read: {}", addr),
         }
@@ -170,24 +520,24 @@ impl Cartridge for Mbc1 {
                 self.ram_enable = byte & 0xF == 0xA;
             }
             0x2000..=0x3FFF => {
-                // TODO: maybe mask this further if idx out of bounds error
-                let idx = byte & 0b0001_1111;
-                self.rom_bank_idx = match idx {
+                // the check for 0 sees all 5 bits, even on multicarts where only 4 are used
+                self.bank_low = match byte & 0b0001_1111 {
                     0 => 1,
-                    _ => idx as usize,
+                    bank => bank,
                 };
             }
             0x4000..=0x5FFF => {
-                let idx = byte & 0b0011;
-                self.ram_bank_idx = idx as usize;
+                self.bank_high = byte & 0b0011;
             }
             0x6000..=0x7FFF => {
-                // TODO: bank mode select
-                panic!("Have not implemented bank mode select for MBC1")
+                self.advanced_banking = byte & 1 == 1;
             }
             0xA000..=0xBFFF => {
-                if self.ram_enable {
-                    self.ram_banks[self.ram_bank_idx].as_mut_slice()[addr as usize - 0xA000] = byte;
+                let ram_bank = self.ram_bank();
+                if let Some(bank) = self.ram_banks.get_mut(ram_bank) {
+                    if self.ram_enable {
+                        bank.as_mut_slice()[addr as usize - 0xA000] = byte;
+                    }
                 }
             }
             _ => panic!("Illegal write to cartridge: {} <- {}", addr, byte),
@@ -198,19 +548,77 @@ impl Cartridge for Mbc1 {
         let banks = parse_banks(rom);
         self.rom_banks = banks;
     }
+
+    fn rom_bank(&self) -> usize {
+        self.high_rom_bank()
+    }
+
+    fn battery_save(&self) -> Option<Vec<u8>> {
+        self.has_battery.then(|| ram_banks_to_vec(&self.ram_banks))
+    }
+
+    fn load_battery_save(&mut self, save: &[u8]) -> Result<(), String> {
+        if !self.has_battery {
+            return Err("The cartridge has no battery backed RAM".into());
+        }
+        check_ram_save_len(save, self.ram_banks.len() * 0x2000)?;
+        load_ram_banks(&mut self.ram_banks, save);
+        Ok(())
+    }
+}
+
+fn ram_banks_to_vec(ram_banks: &[RamBank]) -> Vec<u8> {
+    ram_banks
+        .iter()
+        .flat_map(|bank| bank.as_slice())
+        .copied()
+        .collect()
+}
+
+/// `ram` must be as long as all banks together
+fn load_ram_banks(ram_banks: &mut [RamBank], ram: &[u8]) {
+    for (bank, data) in ram_banks.iter_mut().zip(ram.chunks(0x2000)) {
+        bank.as_mut_slice().copy_from_slice(data);
+    }
+}
+
+fn check_ram_save_len(save: &[u8], ram_len: usize) -> Result<(), String> {
+    if save.len() != ram_len {
+        return Err(format!(
+            "The save has {} bytes, but the cartridge has {ram_len} bytes of RAM",
+            save.len()
+        ));
+    }
+    Ok(())
 }
 
 /// Either RAM/clock is disabled, or we have mapped in a ram bank, or we have mapped a clock register.
 #[derive(Serialize, Deserialize)]
 enum RamBankOrRtcSelect {
     Ram { idx: u8 },
+    Rtc(RtcRegister),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+enum RtcRegister {
     Seconds,
     Minutes,
     Hours,
     DayCounterLoBits,
+    /// Bit 0 is bit 8 of the day counter, bit 6 halts the clock, and bit 7 is the day counter carry
     DayCounterHiBits,
 }
 
+impl RtcRegister {
+    const ALL: [RtcRegister; 5] = [
+        RtcRegister::Seconds,
+        RtcRegister::Minutes,
+        RtcRegister::Hours,
+        RtcRegister::DayCounterLoBits,
+        RtcRegister::DayCounterHiBits,
+    ];
+}
+
 /// Controls when the clock data is latched to the clock registers
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 enum LatchState {
@@ -218,49 +626,249 @@ enum LatchState {
     Staged,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// What drives the MBC3 real-time clock.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RtcClockSource {
+    /// The clock follows the host's wall clock, so it keeps running while the emulator is closed, like the battery
+    /// powered clock in a real cartridge.
+    #[default]
+    HostTime,
+    /// The clock advances with emulated cycles, and only while the game runs. Runs are reproducible, and the clock
+    /// speeds up in fast-forward.
+    EmulatedTime,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
 struct RealTimeClockRegisters {
-    seconds: u8,  // 0-59
-    minutes: u8,  // 0-59
-    hours: u8,    // 0-23
-    days_low: u8, // Lower 8 bits of day counter
-    days_hi_bit: bool,
+    /// 6 bits. Counts to 59, but the game can write values up to 63, which then overflow to 0 without a carry
+    seconds: u8,
+    /// 6 bits, like `seconds`
+    minutes: u8,
+    /// 5 bits, counts to 23
+    hours: u8,
+    /// 9 bits
+    days: u16,
+    halted: bool,
+    /// Set when the day counter overflows, and only cleared by the game
     day_counter_carry: bool,
-    // We use system time instead of Instant because Instant is opaque and not serializable.
-    last_update_time: SystemTime,
 }
+
 impl RealTimeClockRegisters {
-    fn update(&mut self) {
+    fn read(&self, register: RtcRegister) -> u8 {
+        match register {
+            RtcRegister::Seconds => self.seconds,
+            RtcRegister::Minutes => self.minutes,
+            RtcRegister::Hours => self.hours,
+            RtcRegister::DayCounterLoBits => self.days as u8,
+            RtcRegister::DayCounterHiBits => {
+                (self.days >> 8) as u8
+                    | if self.halted { 0x40 } else { 0 }
+                    | if self.day_counter_carry { 0x80 } else { 0 }
+            }
+        }
+    }
+
+    fn write(&mut self, register: RtcRegister, byte: u8) {
+        match register {
+            RtcRegister::Seconds => self.seconds = byte & 0x3F,
+            RtcRegister::Minutes => self.minutes = byte & 0x3F,
+            RtcRegister::Hours => self.hours = byte & 0x1F,
+            RtcRegister::DayCounterLoBits => self.days = (self.days & 0x100) | byte as u16,
+            RtcRegister::DayCounterHiBits => {
+                self.days = (self.days & 0xFF) | ((byte as u16 & 0x01) << 8);
+                self.halted = byte & 0x40 != 0;
+                self.day_counter_carry = byte & 0x80 != 0;
+            }
+        }
+    }
+
+    /// Advance the clock by `seconds`.
+    fn advance(&mut self, mut seconds: u64) {
+        // out of range values, which the game can write, count up to their overflow one second at a time
+        while seconds > 0 && (self.seconds >= 60 || self.minutes >= 60 || self.hours >= 24) {
+            self.tick();
+            seconds -= 1;
+        }
+        let total =
+            self.seconds as u64 + self.minutes as u64 * 60 + self.hours as u64 * 3600 + seconds;
+        self.seconds = (total % 60) as u8;
+        self.minutes = (total / 60 % 60) as u8;
+        self.hours = (total / 3600 % 24) as u8;
+        let days = self.days as u64 + total / (24 * 3600);
+        if days >= 512 {
+            self.day_counter_carry = true;
+        }
+        self.days = (days % 512) as u16;
+    }
+
+    /// Advance the clock by one second.
+    fn tick(&mut self) {
+        self.seconds = (self.seconds + 1) & 0x3F;
+        if self.seconds != 60 {
+            return;
+        }
+        self.seconds = 0;
+        self.minutes = (self.minutes + 1) & 0x3F;
+        if self.minutes != 60 {
+            return;
+        }
+        self.minutes = 0;
+        self.hours = (self.hours + 1) & 0x1F;
+        if self.hours != 24 {
+            return;
+        }
+        self.hours = 0;
+        self.days += 1;
+        if self.days == 512 {
+            self.days = 0;
+            self.day_counter_carry = true;
+        }
+    }
+}
+
+/// The MBC3 real-time clock. The clock counts in its own registers, and the game reads a copy of them that's only
+/// updated when the game latches the clock.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RealTimeClock {
+    live: RealTimeClockRegisters,
+    latched: RealTimeClockRegisters,
+    latch_state: LatchState,
+    source: RtcClockSource,
+    /// The T-cycles since the last tick, when counting emulated time
+    sub_second_t_cycles: u32,
+    /// The time of the last tick, when counting host time. Not part of save states, so that they only depend on what
+    /// the game did; a restored clock counts on from its registers.
+    #[serde(skip, default = "SystemTime::now")]
+    last_update_time: SystemTime,
+}
+
+impl RealTimeClock {
+    fn new() -> Self {
+        RealTimeClock {
+            live: RealTimeClockRegisters::default(),
+            latched: RealTimeClockRegisters::default(),
+            latch_state: LatchState::Latched,
+            source: RtcClockSource::default(),
+            sub_second_t_cycles: 0,
+            last_update_time: SystemTime::now(),
+        }
+    }
+
+    /// Count the whole seconds of host time that passed since the last tick.
+    fn catch_up(&mut self) {
+        if self.source != RtcClockSource::HostTime {
+            return;
+        }
         let now = SystemTime::now();
+        if self.live.halted {
+            self.last_update_time = now;
+            return;
+        }
         let elapsed = now
             .duration_since(self.last_update_time)
             .unwrap_or(Duration::ZERO)
             .as_secs();
-        if elapsed == 0 {
+        // keep the fraction of a second that hasn't been counted yet
+        self.last_update_time += Duration::from_secs(elapsed);
+        self.live.advance(elapsed);
+    }
+
+    fn step(&mut self, t_cycles: u8) {
+        if self.source != RtcClockSource::EmulatedTime || self.live.halted {
             return;
         }
-        self.last_update_time = now;
+        self.sub_second_t_cycles += t_cycles as u32;
+        if self.sub_second_t_cycles >= T_CYCLES_PER_SECOND {
+            self.sub_second_t_cycles -= T_CYCLES_PER_SECOND;
+            self.live.tick();
+        }
+    }
 
-        // Update seconds, minutes, hours, and days
-        let total_seconds = self.seconds as u64 + elapsed;
-        self.seconds = (total_seconds % 60) as u8;
+    fn set_source(&mut self, source: RtcClockSource) {
+        if source == self.source {
+            return;
+        }
+        self.catch_up();
+        self.source = source;
+        self.sub_second_t_cycles = 0;
+        self.last_update_time = SystemTime::now();
+    }
 
-        let total_minutes = self.minutes as u64 + (total_seconds / 60);
-        self.minutes = (total_minutes % 60) as u8;
+    /// Writing 0 and then 1 copies the clock to the registers the game reads.
+    fn write_latch(&mut self, byte: u8) {
+        match byte {
+            0x0 => self.latch_state = LatchState::Staged,
+            0x1 if self.latch_state == LatchState::Staged => {
+                self.catch_up();
+                self.latched = self.live;
+                self.latch_state = LatchState::Latched
+            }
+            _ => {}
+        }
+    }
 
-        let total_hours = self.hours as u64 + (total_minutes / 60);
-        self.hours = (total_hours % 24) as u8;
+    fn read(&self, register: RtcRegister) -> u8 {
+        self.latched.read(register)
+    }
 
-        let total_days = (if self.days_hi_bit { 256 } else { 0 } + self.days_low as u16) as u64
-            + (total_hours / 24);
+    /// The clock in the footer that BGB and VBA-M append to `.sav` files: the live and the latched registers as
+    /// little endian u32s, and the unix time at which they were saved as a u64.
+    fn to_sav_footer(&self) -> Vec<u8> {
+        let mut clock = self.clone();
+        clock.catch_up();
+        let saved_at = match clock.source {
+            RtcClockSource::HostTime => clock.last_update_time,
+            RtcClockSource::EmulatedTime => SystemTime::now(),
+        };
+        let mut footer = Vec::with_capacity(48);
+        for registers in [clock.live, clock.latched] {
+            for register in RtcRegister::ALL {
+                footer.extend((registers.read(register) as u32).to_le_bytes());
+            }
+        }
+        let saved_at = saved_at.duration_since(UNIX_EPOCH).unwrap_or_default();
+        footer.extend(saved_at.as_secs().to_le_bytes());
+        footer
+    }
 
-        // Check for day counter overflow (> 511 days)
-        if total_days > 511 {
-            self.day_counter_carry = true;
+    /// Restore the clock from a footer written by [`RealTimeClock::to_sav_footer`]. Some emulators write the time as a
+    /// u32, for a 44 byte footer. With host time, the clock catches up on the time since the footer was saved.
+    fn load_sav_footer(&mut self, footer: &[u8]) -> Result<(), String> {
+        let saved_at = match footer.len() {
+            44 => u32::from_le_bytes(footer[40..44].try_into().unwrap()) as u64,
+            48 => u64::from_le_bytes(footer[40..48].try_into().unwrap()),
+            len => {
+                return Err(format!(
+                    "The RTC footer should be 44 or 48 bytes, not {len}"
+                ))
+            }
+        };
+        let mut words = footer[..40]
+            .chunks(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()) as u8);
+        for registers in [&mut self.live, &mut self.latched] {
+            for register in RtcRegister::ALL {
+                registers.write(register, words.next().unwrap());
+            }
         }
+        self.sub_second_t_cycles = 0;
+        self.last_update_time = match self.source {
+            RtcClockSource::HostTime => UNIX_EPOCH + Duration::from_secs(saved_at),
+            RtcClockSource::EmulatedTime => SystemTime::now(),
+        };
+        Ok(())
+    }
 
-        self.days_low = (total_days % 256) as u8;
-        self.days_hi_bit = (total_days % 512) >= 256;
+    fn write(&mut self, register: RtcRegister, byte: u8) {
+        // count the time that passed under the old values, e.g. before the clock is halted
+        self.catch_up();
+        self.live.write(register, byte);
+        self.latched.write(register, byte);
+        if register == RtcRegister::Seconds {
+            // writing the seconds restarts the current second
+            self.sub_second_t_cycles = 0;
+            self.last_update_time = SystemTime::now();
+        }
     }
 }
 
@@ -272,8 +880,10 @@ pub struct Mbc3 {
     ram_banks: Vec<RamBank>,
     enable_ram_and_rtc: bool,
     ram_bank_or_rtc_select: RamBankOrRtcSelect,
-    clock_registers: RealTimeClockRegisters,
-    latch_state: LatchState,
+    rtc: RealTimeClock,
+    /// Cartridge types 0x0F and 0x10 have a clock, and only those and 0x13 have a battery to keep the RAM and clock
+    has_rtc: bool,
+    has_battery: bool,
 }
 
 impl Mbc3 {
@@ -318,21 +928,21 @@ impl Mbc3 {
             rom_bank_idx: 1,
             ram_banks,
             ram_bank_or_rtc_select: RamBankOrRtcSelect::Ram { idx: 0 },
-            clock_registers: RealTimeClockRegisters {
-                seconds: 0,
-                minutes: 0,
-                hours: 0,
-                days_low: 0,
-                days_hi_bit: false,
-                day_counter_carry: false,
-                last_update_time: SystemTime::now(),
-            },
+            rtc: RealTimeClock::new(),
+            has_rtc: matches!(rom[0x0147], 0x0F | 0x10),
+            has_battery: matches!(rom[0x0147], 0x0F | 0x10 | 0x13),
             enable_ram_and_rtc: false,
-            latch_state: LatchState::Latched,
         }
     }
 }
 
+impl Mbc3 {
+    /// The RAM bank that selecting bank `idx` maps, with the unused bits ignored. `None` on carts without RAM.
+    fn ram_bank(&self, idx: u8) -> Option<usize> {
+        (!self.ram_banks.is_empty()).then(|| idx as usize % self.ram_banks.len())
+    }
+}
+
 #[typetag::serde]
 impl Cartridge for Mbc3 {
     fn read(&self, addr: u16) -> u8 {
@@ -342,31 +952,17 @@ impl Cartridge for Mbc3 {
             0xA000..=0xBFFF => {
                 if self.enable_ram_and_rtc {
                     match self.ram_bank_or_rtc_select {
-                        RamBankOrRtcSelect::Ram { idx } => {
-                            self.ram_banks[idx as usize].as_slice()[addr as usize - 0xA000]
-                        }
-                        RamBankOrRtcSelect::Seconds => self.clock_registers.seconds,
-                        RamBankOrRtcSelect::Minutes => self.clock_registers.minutes,
-                        RamBankOrRtcSelect::Hours => self.clock_registers.hours,
-                        RamBankOrRtcSelect::DayCounterLoBits => self.clock_registers.days_low,
-                        RamBankOrRtcSelect::DayCounterHiBits => {
-                            let mut value = 0;
-                            if self.clock_registers.days_hi_bit {
-                                value |= 0x01;
-                            }
-                            if self.clock_registers.day_counter_carry {
-                                value |= 0x80;
-                            }
-                            value
-                        }
+                        RamBankOrRtcSelect::Ram { idx } => match self.ram_bank(idx) {
+                            Some(bank) => self.ram_banks[bank].as_slice()[addr as usize - 0xA000],
+                            None => 0xFF,
+                        },
+                        RamBankOrRtcSelect::Rtc(register) => self.rtc.read(register),
                     }
                 } else {
                     0xFF
                 }
             }
-            _ => {
-                todo!("BUG: Invalid read from mbc3 cartridge")
-            }
+            _ => panic!("Invalid cartridge memory access: {:0X}", addr),
         }
     }
 
@@ -379,58 +975,33 @@ impl Cartridge for Mbc3 {
             },
             0x2000..=0x3FFF => {
                 let rom_bank_number = byte & 0x07F;
-                self.rom_bank_idx = if rom_bank_number == 0 {
-                    1
-                } else {
-                    rom_bank_number as usize
-                };
+                // bank 0 maps bank 1
+                self.rom_bank_idx = rom_bank_number.max(1) as usize % self.rom_banks.len();
             }
             0x4000..=0x5FFF => {
                 self.ram_bank_or_rtc_select = match byte {
                     0x0..=0x3 => RamBankOrRtcSelect::Ram { idx: byte },
-                    0x8 => RamBankOrRtcSelect::Seconds,
-                    0x9 => RamBankOrRtcSelect::Minutes,
-                    0xA => RamBankOrRtcSelect::Hours,
-                    0xB => RamBankOrRtcSelect::DayCounterLoBits,
-                    0xC => RamBankOrRtcSelect::DayCounterHiBits,
+                    0x8 => RamBankOrRtcSelect::Rtc(RtcRegister::Seconds),
+                    0x9 => RamBankOrRtcSelect::Rtc(RtcRegister::Minutes),
+                    0xA => RamBankOrRtcSelect::Rtc(RtcRegister::Hours),
+                    0xB => RamBankOrRtcSelect::Rtc(RtcRegister::DayCounterLoBits),
+                    0xC => RamBankOrRtcSelect::Rtc(RtcRegister::DayCounterHiBits),
                     _ => {
                         // ignore other writes
                         return;
                     }
                 };
             }
-            0x6000..=0x7FFF => match byte {
-                0x0 => self.latch_state = LatchState::Staged,
-                0x1 if self.latch_state == LatchState::Staged => {
-                    self.clock_registers.update();
-                    self.latch_state = LatchState::Latched
-                }
-                _ => {}
-            },
+            0x6000..=0x7FFF => self.rtc.write_latch(byte),
             0xA000..=0xBFFF => {
                 if self.enable_ram_and_rtc {
                     match self.ram_bank_or_rtc_select {
                         RamBankOrRtcSelect::Ram { idx } => {
-                            self.ram_banks[idx as usize].as_mut_slice()[addr as usize - 0xA000] =
-                                byte;
-                        }
-                        // TODO: implement writes to clock register
-                        RamBankOrRtcSelect::Seconds => {
-                            self.clock_registers.seconds = byte % 60;
-                        }
-                        RamBankOrRtcSelect::Minutes => {
-                            self.clock_registers.minutes = byte % 60;
-                        }
-                        RamBankOrRtcSelect::Hours => {
-                            self.clock_registers.hours = byte % 24;
-                        }
-                        RamBankOrRtcSelect::DayCounterLoBits => {
-                            self.clock_registers.days_low = byte;
-                        }
-                        RamBankOrRtcSelect::DayCounterHiBits => {
-                            self.clock_registers.days_hi_bit = (byte & 0x01) != 0;
-                            self.clock_registers.day_counter_carry = (byte & 0x80) != 0;
+                            if let Some(bank) = self.ram_bank(idx) {
+                                self.ram_banks[bank].as_mut_slice()[addr as usize - 0xA000] = byte;
+                            }
                         }
+                        RamBankOrRtcSelect::Rtc(register) => self.rtc.write(register, byte),
                     }
                 }
             }
@@ -442,6 +1013,682 @@ impl Cartridge for Mbc3 {
         let banks = parse_banks(rom);
         self.rom_banks = banks;
     }
+
+    fn rom_bank(&self) -> usize {
+        self.rom_bank_idx
+    }
+
+    fn step(&mut self, t_cycles: u8) {
+        self.rtc.step(t_cycles);
+    }
+
+    fn set_rtc_clock_source(&mut self, source: RtcClockSource) {
+        self.rtc.set_source(source);
+    }
+
+    fn battery_save(&self) -> Option<Vec<u8>> {
+        if !self.has_battery {
+            return None;
+        }
+        let mut save = ram_banks_to_vec(&self.ram_banks);
+        if self.has_rtc {
+            save.extend(self.rtc.to_sav_footer());
+        }
+        Some(save)
+    }
+
+    fn load_battery_save(&mut self, save: &[u8]) -> Result<(), String> {
+        if !self.has_battery {
+            return Err("The cartridge has no battery backed RAM".into());
+        }
+        let ram_len = self.ram_banks.len() * 0x2000;
+        let (ram, footer) = save.split_at(save.len().min(ram_len));
+        check_ram_save_len(ram, ram_len)?;
+        match footer.len() {
+            0 => {}
+            _ if self.has_rtc => self.rtc.load_sav_footer(footer)?,
+            len => return Err(format!("Unexpected {len} bytes after the cartridge RAM")),
+        }
+        load_ram_banks(&mut self.ram_banks, ram);
+        Ok(())
+    }
+}
+
+/// The mapper of unlicensed Wisdom Tree games. It has no RAM, and maps a 32 KiB bank over the whole ROM area.
+///
+/// Any write to 0x0000-0x3FFF selects the bank in the lower byte of the address. The written value is ignored.
+#[derive(Serialize, Deserialize)]
+pub struct WisdomTree {
+    #[serde(skip)]
+    rom: Vec<u8>,
+    bank_idx: usize,
+}
+
+impl WisdomTree {
+    /// Wisdom Tree games claim to be ROM-only (or use the otherwise unused type 0xC0) in the header, but are larger
+    /// than 32 KiB. Most also have "WISDOM TREE" in their first bank.
+    pub fn detect(rom: &[u8]) -> bool {
+        let signed = rom[..rom.len().min(0x8000)]
+            .windows(11)
+            .any(|window| window == b"WISDOM TREE" || window == b"WISDOM\0TREE");
+        rom.len() > 0x8000 && (matches!(rom[0x0147], 0x00 | 0xC0) || signed)
+    }
+
+    pub fn from_game_rom(rom: &[u8]) -> Self {
+        WisdomTree {
+            rom: rom.to_vec(),
+            bank_idx: 0,
+        }
+    }
+}
+
+#[typetag::serde]
+impl Cartridge for WisdomTree {
+    fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x7FFF => {
+                let offset = self.bank_idx * 0x8000 + addr as usize;
+                self.rom
+                    .get(offset % self.rom.len())
+                    .copied()
+                    .unwrap_or(0xFF)
+            }
+            // no cartridge RAM
+            0xA000..=0xBFFF => 0xFF,
+            _ => panic!("Invalid cartridge memory access: {:0X}", addr),
+        }
+    }
+
+    fn write(&mut self, addr: u16, _byte: u8) {
+        match addr {
+            0x0000..=0x3FFF => self.bank_idx = (addr & 0xFF) as usize,
+            0x4000..=0x7FFF | 0xA000..=0xBFFF => {}
+            _ => panic!("Illegal write to cartridge: {addr:0X}"),
+        }
+    }
+
+    fn set_rom(&mut self, rom: &[u8]) {
+        self.rom = rom.to_vec();
+    }
+
+    /// The second half of the mapped 32 KiB bank, in 16 KiB banks
+    fn rom_bank(&self) -> usize {
+        self.bank_idx * 2 + 1
+    }
+}
+
+/// The mapper of Kirby Tilt 'n' Tumble and Command Master, with a 2-axis accelerometer and a 93LC56 serial EEPROM
+/// instead of RAM.
+///
+/// https://gbdev.io/pandocs/MBC7.html
+#[derive(Serialize, Deserialize)]
+pub struct Mbc7 {
+    #[serde(skip)]
+    rom_banks: Vec<RomBank>,
+    rom_bank_idx: usize,
+    /// Both 0x0000-0x1FFF and 0x4000-0x5FFF need to be written to enable the registers
+    ram_enable_1: bool,
+    ram_enable_2: bool,
+    /// The tilt set by the frontend, in g
+    #[serde(skip)]
+    accelerometer: (f32, f32),
+    /// The latched X and Y readings, centered at 0x81D0
+    latched: (u16, u16),
+    latch_erased: bool,
+    eeprom: Eeprom,
+}
+
+/// The accelerometer reading when level
+const ACCELEROMETER_CENTER: f32 = 0x81D0 as f32;
+/// The change of the accelerometer reading per g
+const ACCELEROMETER_PER_G: f32 = 0x70 as f32;
+
+impl Mbc7 {
+    pub fn from_game_rom(rom: &[u8]) -> Self {
+        Mbc7 {
+            rom_banks: parse_banks(rom),
+            rom_bank_idx: 1,
+            ram_enable_1: false,
+            ram_enable_2: false,
+            accelerometer: (0.0, 0.0),
+            latched: (0x8000, 0x8000),
+            latch_erased: false,
+            eeprom: Eeprom::new(),
+        }
+    }
+}
+
+#[typetag::serde]
+impl Cartridge for Mbc7 {
+    fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x3FFF => self.rom_banks[0].as_slice()[addr as usize],
+            0x4000..=0x7FFF => self.rom_banks[self.rom_bank_idx].as_slice()[addr as usize - 0x4000],
+            0xA000..=0xAFFF if self.ram_enable_1 && self.ram_enable_2 => match (addr >> 4) & 0xF {
+                0x2 => self.latched.0 as u8,
+                0x3 => (self.latched.0 >> 8) as u8,
+                0x4 => self.latched.1 as u8,
+                0x5 => (self.latched.1 >> 8) as u8,
+                0x6 => 0x00,
+                0x8 => self.eeprom.read(),
+                _ => 0xFF,
+            },
+            0xA000..=0xBFFF => 0xFF,
+            _ => panic!("Invalid cartridge memory access: {:0X}", addr),
+        }
+    }
+
+    fn write(&mut self, addr: u16, byte: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ram_enable_1 = byte & 0xF == 0xA,
+            0x2000..=0x3FFF => self.rom_bank_idx = (byte & 0x7F) as usize % self.rom_banks.len(),
+            0x4000..=0x5FFF => self.ram_enable_2 = byte == 0x40,
+            0x6000..=0x7FFF => {}
+            0xA000..=0xAFFF if self.ram_enable_1 && self.ram_enable_2 => match (addr >> 4) & 0xF {
+                0x0 if byte == 0x55 => {
+                    self.latched = (0x8000, 0x8000);
+                    self.latch_erased = true;
+                }
+                0x1 if byte == 0xAA && self.latch_erased => {
+                    let reading = |g: f32| (ACCELEROMETER_CENTER + g * ACCELEROMETER_PER_G) as u16;
+                    self.latched = (reading(self.accelerometer.0), reading(self.accelerometer.1));
+                    self.latch_erased = false;
+                }
+                0x8 => self.eeprom.write(byte),
+                _ => {}
+            },
+            0xA000..=0xBFFF => {}
+            _ => panic!("Illegal write to cartridge: {} <- {}", addr, byte),
+        }
+    }
+
+    fn set_rom(&mut self, rom: &[u8]) {
+        self.rom_banks = parse_banks(rom);
+    }
+
+    fn rom_bank(&self) -> usize {
+        self.rom_bank_idx
+    }
+
+    fn set_accelerometer(&mut self, x: f32, y: f32) {
+        self.accelerometer = (x, y);
+    }
+
+    fn battery_save(&self) -> Option<Vec<u8>> {
+        Some(
+            self.eeprom
+                .words
+                .iter()
+                .flat_map(|word| word.to_le_bytes())
+                .collect(),
+        )
+    }
+
+    fn load_battery_save(&mut self, save: &[u8]) -> Result<(), String> {
+        check_ram_save_len(save, self.eeprom.words.len() * 2)?;
+        for (word, bytes) in self.eeprom.words.iter_mut().zip(save.chunks(2)) {
+            *word = u16::from_le_bytes([bytes[0], bytes[1]]);
+        }
+        Ok(())
+    }
+}
+
+/// A 93LC56 EEPROM of 128 16-bit words, accessed through a serial interface.
+///
+/// The game drives chip select, clock, and data in, and commands are clocked in one bit per rising clock edge: a start
+/// bit, a 2 bit opcode, and an 8 bit address (of which the upper bit is ignored), followed by 16 data bits for writes.
+#[derive(Serialize, Deserialize)]
+struct Eeprom {
+    words: Vec<u16>,
+    chip_select: bool,
+    clock: bool,
+    data_in: bool,
+    data_out: bool,
+    write_enabled: bool,
+    /// The bits of the command received so far, after the start bit, which stays in the top as a marker
+    command: u16,
+    state: EepromState,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+enum EepromState {
+    Command,
+    /// Shifting out a word, MSB first
+    Reading {
+        word: u16,
+        bits_left: u8,
+    },
+    /// Receiving a word to write to `addr`, or to every word if `addr` is `None`
+    Writing {
+        addr: Option<u8>,
+        word: u16,
+        bits_left: u8,
+    },
+}
+
+impl Eeprom {
+    fn new() -> Self {
+        Eeprom {
+            words: vec![0xFFFF; 128],
+            chip_select: false,
+            clock: false,
+            data_in: false,
+            data_out: true,
+            write_enabled: false,
+            command: 0,
+            state: EepromState::Command,
+        }
+    }
+
+    fn read(&self) -> u8 {
+        (self.chip_select as u8) << 7
+            | (self.clock as u8) << 6
+            | (self.data_in as u8) << 1
+            | self.data_out as u8
+    }
+
+    fn write(&mut self, byte: u8) {
+        let (chip_select, clock, data_in) = (byte & 0x80 != 0, byte & 0x40 != 0, byte & 0x02 != 0);
+        if !chip_select {
+            // deselecting aborts the current command
+            self.command = 0;
+            self.state = EepromState::Command;
+            self.data_out = true;
+        } else if !self.clock && clock {
+            self.clock_in(data_in);
+        }
+        (self.chip_select, self.clock, self.data_in) = (chip_select, clock, data_in);
+    }
+
+    fn clock_in(&mut self, bit: bool) {
+        match &mut self.state {
+            EepromState::Command => {
+                if self.command == 0 && !bit {
+                    // waiting for the start bit
+                    return;
+                }
+                self.command = self.command << 1 | bit as u16;
+                // the start bit and 10 command bits
+                if self.command & 0x400 != 0 {
+                    self.execute(self.command as u8, (self.command >> 8) & 0b11);
+                    self.command = 0;
+                }
+            }
+            EepromState::Reading { word, bits_left } => {
+                self.data_out = *word & 0x8000 != 0;
+                *word <<= 1;
+                *bits_left -= 1;
+                if *bits_left == 0 {
+                    self.state = EepromState::Command;
+                }
+            }
+            EepromState::Writing {
+                addr,
+                word,
+                bits_left,
+            } => {
+                *word = *word << 1 | bit as u16;
+                *bits_left -= 1;
+                if *bits_left == 0 {
+                    let (addr, word) = (*addr, *word);
+                    self.store(addr, word);
+                    self.state = EepromState::Command;
+                }
+            }
+        }
+    }
+
+    /// `addr` is the 8 bit address field. The upper bit isn't part of the word address.
+    fn execute(&mut self, addr: u8, opcode: u16) {
+        let word_addr = addr & 0x7F;
+        // the extended commands are told apart by the top 2 address bits
+        match (opcode, addr >> 6) {
+            (0b10, _) => {
+                // a dummy 0 comes before the data
+                self.data_out = false;
+                self.state = EepromState::Reading {
+                    word: self.words[word_addr as usize],
+                    bits_left: 16,
+                };
+            }
+            (0b01, _) => {
+                self.state = EepromState::Writing {
+                    addr: Some(word_addr),
+                    word: 0,
+                    bits_left: 16,
+                }
+            }
+            (0b11, _) => self.store(Some(word_addr), 0xFFFF),
+            (0b00, 0b11) => self.write_enabled = true,
+            (0b00, 0b00) => self.write_enabled = false,
+            (0b00, 0b10) => self.store(None, 0xFFFF),
+            (0b00, _) => {
+                self.state = EepromState::Writing {
+                    addr: None,
+                    word: 0,
+                    bits_left: 16,
+                }
+            }
+            _ => unreachable!("the opcode has 2 bits"),
+        }
+    }
+
+    /// Writes finish instantly, so the chip always reports that it's ready
+    fn store(&mut self, addr: Option<u8>, word: u16) {
+        if self.write_enabled {
+            match addr {
+                Some(addr) => self.words[addr as usize] = word,
+                None => self.words.fill(word),
+            }
+        }
+        self.data_out = true;
+    }
+}
+
+/// MBC5, with up to 8 MiB of ROM and 128 KiB of RAM, and a rumble motor on some cartridges.
+///
+/// https://gbdev.io/pandocs/MBC5.html
+#[derive(Serialize, Deserialize)]
+pub struct Mbc5 {
+    #[serde(skip)]
+    rom_banks: Vec<RomBank>,
+    /// 9 bits, unlike other MBCs bank 0 can be mapped to 0x4000-0x7FFF
+    rom_bank_idx: usize,
+    ram_banks: Vec<RamBank>,
+    ram_bank_idx: usize,
+    ram_enable: bool,
+    /// Cartridge types 0x1C-0x1E have a rumble motor, wired to bit 3 of the RAM bank number
+    has_rumble: bool,
+    has_battery: bool,
+    rumble_on: bool,
+    /// T-cycles since the rumble state was last taken, and how many of them the motor was on for
+    rumble_cycles: u32,
+    rumble_on_cycles: u32,
+}
+
+impl Mbc5 {
+    pub fn from_game_rom(rom: &[u8]) -> Self {
+        let ram_size_byte = rom[0x0149];
+        let ram_banks = match ram_size_byte {
+            0x00 | 0x01 => 0,
+            0x02 => 1,
+            0x03 => 4,
+            0x04 => 16,
+            0x05 => 8,
+            _ => panic!("Unexpected RAM size for MBC 5: {:X}", ram_size_byte),
+        };
+        Mbc5 {
+            rom_banks: parse_banks(rom),
+            rom_bank_idx: 1,
+            ram_banks: vec![RamBank([0; 0x2000]); ram_banks],
+            ram_bank_idx: 0,
+            ram_enable: false,
+            has_rumble: matches!(rom[0x0147], 0x1C..=0x1E),
+            has_battery: matches!(rom[0x0147], 0x1B | 0x1E),
+            rumble_on: false,
+            rumble_cycles: 0,
+            rumble_on_cycles: 0,
+        }
+    }
+}
+
+#[typetag::serde]
+impl Cartridge for Mbc5 {
+    fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x3FFF => self.rom_banks[0].as_slice()[addr as usize],
+            0x4000..=0x7FFF => self.rom_banks[self.rom_bank_idx].as_slice()[addr as usize - 0x4000],
+            0xA000..=0xBFFF => match self.ram_banks.get(self.ram_bank_idx) {
+                Some(bank) if self.ram_enable => bank.as_slice()[addr as usize - 0xA000],
+                _ => 0xFF,
+            },
+            _ => panic!("Invalid cartridge memory access: {:0X}", addr),
+        }
+    }
+
+    fn write(&mut self, addr: u16, byte: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ram_enable = byte & 0xF == 0xA,
+            0x2000..=0x2FFF => {
+                self.rom_bank_idx =
+                    (self.rom_bank_idx & 0x100 | byte as usize) % self.rom_banks.len();
+            }
+            0x3000..=0x3FFF => {
+                self.rom_bank_idx =
+                    (self.rom_bank_idx & 0xFF | (byte as usize & 1) << 8) % self.rom_banks.len();
+            }
+            0x4000..=0x5FFF => {
+                if self.has_rumble {
+                    self.rumble_on = byte & 0x08 != 0;
+                    self.ram_bank_idx = (byte & 0x07) as usize;
+                } else {
+                    self.ram_bank_idx = (byte & 0x0F) as usize;
+                }
+            }
+            0x6000..=0x7FFF => {}
+            0xA000..=0xBFFF => {
+                if let Some(bank) = self.ram_banks.get_mut(self.ram_bank_idx) {
+                    if self.ram_enable {
+                        bank.as_mut_slice()[addr as usize - 0xA000] = byte;
+                    }
+                }
+            }
+            _ => panic!("Illegal write to cartridge: {} <- {}", addr, byte),
+        }
+    }
+
+    fn set_rom(&mut self, rom: &[u8]) {
+        self.rom_banks = parse_banks(rom);
+    }
+
+    fn rom_bank(&self) -> usize {
+        self.rom_bank_idx
+    }
+
+    fn step(&mut self, t_cycles: u8) {
+        if self.has_rumble {
+            self.rumble_cycles = self.rumble_cycles.saturating_add(t_cycles as u32);
+            if self.rumble_on {
+                self.rumble_on_cycles = self.rumble_on_cycles.saturating_add(t_cycles as u32);
+            }
+        }
+    }
+
+    fn take_rumble_state(&mut self) -> Option<f32> {
+        if !self.has_rumble {
+            return None;
+        }
+        let strength = match self.rumble_cycles {
+            0 if self.rumble_on => 1.0,
+            0 => 0.0,
+            cycles => self.rumble_on_cycles as f32 / cycles as f32,
+        };
+        self.rumble_cycles = 0;
+        self.rumble_on_cycles = 0;
+        Some(strength)
+    }
+
+    fn battery_save(&self) -> Option<Vec<u8>> {
+        self.has_battery.then(|| ram_banks_to_vec(&self.ram_banks))
+    }
+
+    fn load_battery_save(&mut self, save: &[u8]) -> Result<(), String> {
+        if !self.has_battery {
+            return Err("The cartridge has no battery backed RAM".into());
+        }
+        check_ram_save_len(save, self.ram_banks.len() * 0x2000)?;
+        load_ram_banks(&mut self.ram_banks, save);
+        Ok(())
+    }
+}
+
+/// The Game Boy Camera (Pocket Camera): 1 MiB of ROM, 128 KiB of battery backed RAM, and an image sensor.
+///
+/// The sensor's registers are mapped over RAM when bit 4 of the RAM bank is set. Pictures are written as tiles to RAM
+/// bank 0. The sensor's edge enhancement isn't emulated.
+#[derive(Serialize, Deserialize)]
+pub struct PocketCamera {
+    #[serde(skip)]
+    rom_banks: Vec<RomBank>,
+    rom_bank_idx: usize,
+    ram_banks: Vec<RamBank>,
+    ram_bank_idx: usize,
+    /// Only writes to RAM need enabling
+    ram_write_enable: bool,
+    registers_mapped: bool,
+    #[serde(with = "BigArray")]
+    registers: [u8; 0x36],
+    /// The T-cycles until the picture that's being taken is done
+    capture_cycles_left: u32,
+    #[serde(skip)]
+    source: Option<Box<dyn CameraSource>>,
+}
+
+impl PocketCamera {
+    pub fn from_game_rom(rom: &[u8]) -> Self {
+        PocketCamera {
+            rom_banks: parse_banks(rom),
+            rom_bank_idx: 1,
+            ram_banks: vec![RamBank([0; 0x2000]); 16],
+            ram_bank_idx: 0,
+            ram_write_enable: false,
+            registers_mapped: false,
+            registers: [0; 0x36],
+            capture_cycles_left: 0,
+            source: None,
+        }
+    }
+
+    fn exposure(&self) -> u32 {
+        u16::from_be_bytes([self.registers[2], self.registers[3]]) as u32
+    }
+
+    fn start_capture(&mut self) {
+        // in M-cycles, plus 512 without the N bit of register 1
+        let n_bit_cycles = if self.registers[1] & 0x80 == 0 {
+            512
+        } else {
+            0
+        };
+        self.capture_cycles_left = 4 * (32446 + n_bit_cycles + 16 * self.exposure());
+    }
+
+    /// Process the source's picture through the exposure and the dithering matrix in registers 6-0x35, and write it
+    /// to RAM bank 0 at 0x100 as 16x14 tiles.
+    fn finish_capture(&mut self) {
+        self.registers[0] &= !1;
+        let frame = match &mut self.source {
+            Some(source) => source.capture(),
+            None => camera::noise(),
+        };
+        let exposure = self.exposure();
+        let ram = self.ram_banks[0].as_mut_slice();
+        for (y, row) in frame.iter().enumerate() {
+            for (x, &pixel) in row.iter().enumerate() {
+                // 0x800 passes the source through
+                let value = (pixel as u32 * exposure / 0x800).min(255) as u8;
+                let thresholds_idx = 6 + ((y % 4) * 4 + x % 4) * 3;
+                let thresholds = &self.registers[thresholds_idx..thresholds_idx + 3];
+                let color = match thresholds.iter().position(|&threshold| value < threshold) {
+                    Some(0) => 3,
+                    Some(1) => 2,
+                    Some(_) => 1,
+                    None => 0,
+                };
+                let tile = (y / 8) * (camera::WIDTH / 8) + x / 8;
+                let offset = 0x100 + tile * 16 + (y % 8) * 2;
+                let bit = 7 - x % 8;
+                ram[offset] = ram[offset] & !(1 << bit) | (color & 1) << bit;
+                ram[offset + 1] = ram[offset + 1] & !(1 << bit) | (color >> 1) << bit;
+            }
+        }
+    }
+}
+
+#[typetag::serde]
+impl Cartridge for PocketCamera {
+    fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x3FFF => self.rom_banks[0].as_slice()[addr as usize],
+            0x4000..=0x7FFF => self.rom_banks[self.rom_bank_idx].as_slice()[addr as usize - 0x4000],
+            // only the first register can be read, and it's busy while a picture is being taken
+            0xA000..=0xBFFF if self.registers_mapped => match (addr - 0xA000) & 0x7F {
+                0 => self.registers[0],
+                _ => 0x00,
+            },
+            0xA000..=0xBFFF => self.ram_banks[self.ram_bank_idx].as_slice()[addr as usize - 0xA000],
+            _ => panic!("Invalid cartridge memory access: {:0X}", addr),
+        }
+    }
+
+    fn write(&mut self, addr: u16, byte: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ram_write_enable = byte & 0xF == 0xA,
+            0x2000..=0x3FFF => self.rom_bank_idx = (byte & 0x3F) as usize % self.rom_banks.len(),
+            0x4000..=0x5FFF => {
+                self.registers_mapped = byte & 0x10 != 0;
+                self.ram_bank_idx = (byte & 0x0F) as usize;
+            }
+            0x6000..=0x7FFF => {}
+            0xA000..=0xBFFF if self.registers_mapped => {
+                let register = ((addr - 0xA000) & 0x7F) as usize;
+                match register {
+                    0 => {
+                        let busy = self.registers[0] & 1 != 0;
+                        self.registers[0] = byte & 0x07 | self.registers[0] & 1;
+                        if byte & 1 != 0 && !busy {
+                            self.registers[0] |= 1;
+                            self.start_capture();
+                        }
+                    }
+                    1..0x36 => self.registers[register] = byte,
+                    _ => {}
+                }
+            }
+            0xA000..=0xBFFF => {
+                if self.ram_write_enable {
+                    self.ram_banks[self.ram_bank_idx].as_mut_slice()[addr as usize - 0xA000] = byte;
+                }
+            }
+            _ => panic!("Illegal write to cartridge: {} <- {}", addr, byte),
+        }
+    }
+
+    fn set_rom(&mut self, rom: &[u8]) {
+        self.rom_banks = parse_banks(rom);
+    }
+
+    fn rom_bank(&self) -> usize {
+        self.rom_bank_idx
+    }
+
+    fn step(&mut self, t_cycles: u8) {
+        if self.capture_cycles_left == 0 {
+            return;
+        }
+        self.capture_cycles_left = self.capture_cycles_left.saturating_sub(t_cycles as u32);
+        if self.capture_cycles_left == 0 {
+            self.finish_capture();
+        }
+    }
+
+    fn connect_camera(&mut self, source: Box<dyn CameraSource>) {
+        self.source = Some(source);
+    }
+
+    fn disconnect_camera(&mut self) -> Option<Box<dyn CameraSource>> {
+        self.source.take()
+    }
+
+    fn battery_save(&self) -> Option<Vec<u8>> {
+        Some(ram_banks_to_vec(&self.ram_banks))
+    }
+
+    fn load_battery_save(&mut self, save: &[u8]) -> Result<(), String> {
+        check_ram_save_len(save, self.ram_banks.len() * 0x2000)?;
+        load_ram_banks(&mut self.ram_banks, save);
+        Ok(())
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -1,12 +1,19 @@
 use opcode::{RstVec, CC};
-use register_file::{Registers, R16, R8};
+pub use register_file::Registers;
+use register_file::{R16, R8};
 use serde::{Deserialize, Serialize};
 
+use crate::breakpoint::Breakpoint;
 use crate::mmu::{InterruptKind, Memory};
+use crate::opcode_stats::{Opcode, OpcodeStats};
+use crate::trace::{DoctorLog, ExecutionTrace, TraceEntry};
 
+pub mod instruction;
 mod opcode;
 mod register_file;
 
+pub use instruction::{decode, Instruction};
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ImeState {
     Enabled,
@@ -14,6 +21,51 @@ pub enum ImeState {
     PendingEnable,
 }
 
+/// An opcode that doesn't exist on the Game Boy, like 0xD3. Executing one hangs the CPU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IllegalOpcode {
+    pub opcode: u8,
+    /// The address that the opcode was fetched from
+    pub addr: u16,
+}
+
+impl std::fmt::Display for IllegalOpcode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Illegal opcode {:02X} at {:04X} locked up the CPU",
+            self.opcode, self.addr
+        )
+    }
+}
+
+impl std::error::Error for IllegalOpcode {}
+
+/// The deepest call stack that's tracked. Games that leave routines without returning, e.g. by resetting SP, would
+/// otherwise grow it forever, so the outermost calls are dropped beyond this.
+const MAX_CALL_DEPTH: usize = 256;
+
+/// How a [`CallFrame`] was entered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallKind {
+    Call,
+    Rst,
+    Interrupt(InterruptKind),
+}
+
+/// A routine that execution is in, see [`crate::Emulator::call_stack`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CallFrame {
+    pub kind: CallKind,
+    /// The address of the routine or interrupt handler
+    pub target: u16,
+    /// Where execution continues after returning: the instruction after the CALL or RST, or the instruction that the
+    /// interrupt interrupted
+    pub return_addr: u16,
+    /// Where the return address was pushed to
+    sp: u16,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct Cpu<Mem: Memory> {
     pub regs: Registers,
@@ -23,27 +75,76 @@ pub struct Cpu<Mem: Memory> {
     /// `IME` is the main switch to enable/disable all interrupts. `IE` is more granular, and enables/disables interrupts individually depending on which bits are set.
     pub ime: ImeState,
     pub is_halted: bool,
-    print_cpu_logs: bool,
+    /// In low power mode after STOP. Unlike HALT, the system clock stops, so the timer, PPU, and APU don't run either.
+    pub is_stopped: bool,
+    /// Set when the CPU executed an illegal opcode. It no longer fetches instructions or services interrupts, while
+    /// the rest of the system keeps running.
+    pub locked_up: Option<IllegalOpcode>,
+    /// Set by HALT when it triggers the halt bug, so the next opcode fetch doesn't increment PC
+    halt_bug: bool,
+    /// The T-cycles that the MMU has been advanced by during the current instruction
+    #[serde(skip)]
+    instruction_t_cycles: u8,
+    /// Where executed instructions are recorded, see [`crate::Emulator::enable_trace`]
+    #[serde(skip)]
+    pub(crate) trace: Option<ExecutionTrace>,
+    /// Where the state before every instruction is written, see [`crate::Emulator::start_doctor_log`]
+    #[serde(skip)]
+    pub(crate) doctor_log: Option<DoctorLog>,
+    /// See [`crate::Emulator::add_breakpoint`]
+    #[serde(skip)]
+    pub(crate) breakpoints: Vec<Breakpoint>,
+    /// See [`crate::Emulator::call_stack`]
+    #[serde(skip)]
+    pub(crate) call_stack: Vec<CallFrame>,
+    /// See [`crate::Emulator::enable_opcode_stats`]
+    #[serde(skip)]
+    pub(crate) opcode_stats: Option<Box<OpcodeStats>>,
 }
 
 impl<Mem: Memory> Cpu<Mem> {
-    pub fn new(mmu: Mem, print_cpu_logs: bool) -> Self {
-        let mut cpu = Cpu {
+    pub fn new(mmu: Mem) -> Self {
+        Cpu {
             regs: Registers::create(),
             mmu,
             ime: ImeState::Disabled,
             is_halted: false,
-            print_cpu_logs,
-        };
-        cpu.log_state();
-        cpu
+            is_stopped: false,
+            locked_up: None,
+            halt_bug: false,
+            instruction_t_cycles: 0,
+            trace: None,
+            doctor_log: None,
+            breakpoints: Vec::new(),
+            call_stack: Vec::new(),
+            opcode_stats: None,
+        }
+    }
+
+    /// Push a frame for the routine that was just jumped to, after pushing `return_addr`.
+    fn enter_call(&mut self, kind: CallKind, return_addr: u16) {
+        if self.call_stack.len() == MAX_CALL_DEPTH {
+            self.call_stack.remove(0);
+        }
+        self.call_stack.push(CallFrame {
+            kind,
+            target: self.regs.pc,
+            return_addr,
+            sp: self.regs.sp,
+        });
     }
 
-    fn log_state(&mut self) {
-        if self.print_cpu_logs {
-            println!(
-                "IME: {:?} HALTED: {:?}, IE: {:?}, IF: {:?}\nA:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
-                self.ime, self.is_halted, self.mmu.interrupts_enabled(), self.mmu.interrupts_requested(), self.regs.a, self.regs.f, self.regs.b, self.regs.c, self.regs.d, self.regs.e, self.regs.h, self.regs.l, self.regs.sp, self.regs.pc, self.mmu.read_byte(self.regs.pc), self.mmu.read_byte(self.regs.pc+1), self.mmu.read_byte(self.regs.pc+2), self.mmu.read_byte(self.regs.pc+3));
+    /// Pop the frames that a return is about to leave, before popping the return address.
+    ///
+    /// That's the frame whose return address is at SP, and any frames deeper than it that were left without
+    /// returning, e.g. by popping their return address.
+    fn leave_call(&mut self) {
+        while self
+            .call_stack
+            .last()
+            .is_some_and(|frame| frame.sp <= self.regs.sp)
+        {
+            self.call_stack.pop();
         }
     }
 
@@ -52,7 +153,20 @@ impl<Mem: Memory> Cpu<Mem> {
     /// Returns the number of master clock cycles (at 4 MiHz) that the instruction takes.
     /// E.g. executing the `NOP` instruction will return 4
     pub fn step(&mut self) -> u8 {
+        if self.locked_up.is_some() {
+            self.mmu.step(4);
+            return 4;
+        }
+        if self.is_stopped {
+            // any button press wakes the CPU, even with the joypad interrupt disabled
+            if self.mmu.pressed_buttons().is_empty() {
+                return 4;
+            }
+            self.is_stopped = false;
+        }
         let mut handled_interrupt = false;
+        // EI takes effect after the instruction that follows it
+        let enable_ime = self.ime == ImeState::PendingEnable;
         if self.ime == ImeState::Enabled {
             use InterruptKind::*;
             for interrupt_kind in [Vblank, LcdStat, Serial, Timer, Joypad] {
@@ -62,7 +176,16 @@ impl<Mem: Memory> Cpu<Mem> {
                     self.ime = ImeState::Disabled;
                     self.is_halted = false;
                     self.mmu.clear_requested_interrupt(interrupt_kind);
-                    self.push_u16(self.regs.pc);
+                    // 2 wait states, pushing PC, and jumping to the handler
+                    self.internal_cycle();
+                    // after EI; HALT triggers the halt bug, the handler returns to the HALT
+                    let return_addr = if self.halt_bug {
+                        self.halt_bug = false;
+                        self.regs.pc.wrapping_sub(1)
+                    } else {
+                        self.regs.pc
+                    };
+                    self.push_u16(return_addr);
                     self.regs.pc = match interrupt_kind {
                         Joypad => 0x60,
                         Serial => 0x58,
@@ -70,7 +193,8 @@ impl<Mem: Memory> Cpu<Mem> {
                         LcdStat => 0x48,
                         Vblank => 0x40,
                     };
-                    self.mmu.step(20);
+                    self.enter_call(CallKind::Interrupt(interrupt_kind), return_addr);
+                    self.internal_cycle();
                     handled_interrupt = true;
                     break;
                 }
@@ -83,25 +207,62 @@ impl<Mem: Memory> Cpu<Mem> {
             }
         }
 
-        // update ime state
-        if self.ime == ImeState::PendingEnable {
-            self.ime = ImeState::Enabled;
-        }
-
-        if self.is_halted {
+        let t_cycles = if self.is_halted {
             self.mmu.step(4);
             4
         } else {
             // execute opcode
-            let opcode = self.mmu.read_byte(self.regs.pc);
-            self.regs.pc = self.regs.pc.wrapping_add(1);
+            self.instruction_t_cycles = 0;
+            if let Some(doctor_log) = &mut self.doctor_log {
+                doctor_log.log(&self.regs, &self.mmu);
+            }
+            let opcode = self.read_cycle(self.regs.pc);
+            if let Some(trace) = &mut self.trace {
+                trace.record(TraceEntry {
+                    opcode,
+                    regs: self.regs,
+                });
+            }
+            if let Some(opcode_stats) = &mut self.opcode_stats {
+                opcode_stats.record(Opcode::Base(opcode));
+            }
+            if self.halt_bug {
+                self.halt_bug = false;
+            } else {
+                self.regs.pc = self.regs.pc.wrapping_add(1);
+            }
             let t_cycles = self.execute(opcode);
             assert!(t_cycles % 4 == 0 && t_cycles <= 24, "Unexpected number of t-cycles during execution of opcode {opcode:x} execution: {t_cycles}");
-            self.log_state();
-            self.mmu.step(t_cycles);
+            // the M-cycles without memory accesses, which aren't modeled individually
+            self.mmu.step(t_cycles - self.instruction_t_cycles);
 
             t_cycles + if handled_interrupt { 20 } else { 0 }
+        };
+        // unless the instruction was DI
+        if enable_ime && self.ime == ImeState::PendingEnable {
+            self.ime = ImeState::Enabled;
         }
+        t_cycles
+    }
+
+    /// Advance the MMU by one M-cycle in which the CPU doesn't access memory.
+    fn internal_cycle(&mut self) {
+        self.mmu.step(4);
+        self.instruction_t_cycles += 4;
+    }
+
+    /// Read `addr` in the next M-cycle.
+    ///
+    /// The access happens at the end of the M-cycle, so the read sees the hardware after it has been advanced.
+    fn read_cycle(&mut self, addr: u16) -> u8 {
+        self.internal_cycle();
+        self.mmu.read_byte(addr)
+    }
+
+    /// Write `byte` to `addr` in the next M-cycle, at the end of the M-cycle like [`Cpu::read_cycle`].
+    fn write_cycle(&mut self, addr: u16, byte: u8) {
+        self.internal_cycle();
+        self.mmu.write_byte(addr, byte);
     }
 
     /// Execute a single instruction and return the number of system clock cycles (T-cycles) the instruction takes.
@@ -123,8 +284,11 @@ impl<Mem: Memory> Cpu<Mem> {
             0xF3 => self.di(),
             0xFB => self.ei(),
             0xCB => {
-                let opcode = self.mmu.read_byte(self.regs.pc);
+                let opcode = self.read_cycle(self.regs.pc);
                 self.regs.pc = self.regs.pc.wrapping_add(1);
+                if let Some(opcode_stats) = &mut self.opcode_stats {
+                    opcode_stats.record(Opcode::Cb(opcode));
+                }
                 match opcode {
                     // rlc
                     0x00 => self.rlc_r8(R8::B),
@@ -684,7 +848,7 @@ impl<Mem: Memory> Cpu<Mem> {
             0x1F => self.rra(),
 
             0xD3 | 0xDB | 0xDD | 0xE3 | 0xE4 | 0xEB | 0xEC | 0xED | 0xF4 | 0xFC | 0xFD => {
-                panic!("Instruction {opcode:X} is not supported on the game boy")
+                self.lock_up(opcode)
             }
         }
     }
@@ -707,7 +871,7 @@ mod test {
     #[test]
     fn run_boot_rom() {
         let boot_rom = include_bytes!("../roms/dmg_boot.bin");
-        let mut cpu = Cpu::new(Mmu::new(boot_rom), false);
+        let mut cpu = Cpu::new(Mmu::new(boot_rom));
         while cpu.regs.pc != 0x100 {
             cpu.step();
         }
@@ -800,6 +964,7 @@ mod test {
     }
 
     #[test]
+    #[cfg_attr(miri, ignore = "reads the test vectors from disk")]
     fn sm83_per_instruction_test() {
         let test_dir = path::Path::new("tests/sm83/v1");
         let ignored_tests = [
@@ -842,7 +1007,7 @@ mod test {
 
     impl Cpu<ByteArrayMmu> {
         fn from_state(state: &Sm83State) -> Self {
-            let mut cpu = Cpu::new(ByteArrayMmu::new(), false);
+            let mut cpu = Cpu::new(ByteArrayMmu::new());
             cpu.mmu = ByteArrayMmu {
                 memory: [0; 0x10000],
             };
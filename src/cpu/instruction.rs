@@ -1,5 +1,6 @@
-use crate::cpu::register_file::R16;
-use crate::cpu::register_file::R8;
+//! Decoding of opcodes into [`Instruction`]s, separate from executing them, for tools that analyze code.
+pub use super::opcode::{RstVec, CC};
+pub use super::register_file::{R16, R8};
 
 /// A decoded instruction.
 ///
@@ -20,8 +21,11 @@ pub enum Instruction {
     XOR_A(Operand),
 
     // --- 16-bit arithmetic instructions ---
+    /// ADD HL,r16, where r16 is BC, DE, HL, or SP
     ADD_HL(R16),
+    /// DEC r16, where r16 is BC, DE, HL, or SP
     DEC16(R16),
+    /// INC r16, where r16 is BC, DE, HL, or SP
     INC16(R16),
 
     // --- bit ops instructions ---
@@ -31,8 +35,7 @@ pub enum Instruction {
     SWAP(HlOrReg8),
 
     // --- bit shift instructions ---
-    // TODO: take care in implementation of RLA, RLCA, RRA, and RRCA since flag behavior can vary across implementations
-    // TODO: should RLA, RLCA, etc be separate instructions?
+    // RLA, RLCA, RRA, and RRCA are separate from RL, RLC, RR, and RRC on A, because they always clear the Z flag
     RL(HlOrReg8),
     RLA,
     RLC(HlOrReg8),
@@ -50,51 +53,55 @@ pub enum Instruction {
     LD_R8(R8, Operand),
     /// LD [HL],*
     LD_HL(ImmOrR8),
-    /// LD r16,n16
+    /// LD r16,n16, where r16 is BC, DE, HL, or SP
     LD_R16_N16(R16, u16),
-    /// LD [r16],A
+    /// LD [r16],A, where r16 is BC or DE
     LD_ADDR_R16(R16),
     /// LD [n16],A
     LD_ADDR_N16(u16),
-    /// LDH [n16],A
-    LDH_N16_A,
+    /// LDH [n16],A, where n16 is between 0xFF00 and 0xFFFF
+    LDH_N16_A(u16),
     /// LDH [C],A
     LDH_C_A,
-    /// LD A,[r16]
+    /// LD A,[r16], where r16 is BC or DE
+    LD_A_ADDR_R16(R16),
     /// LD A,[n16]
-    LD_A(COrN16),
-    LDH_A(COrN16),
+    LD_A_ADDR_N16(u16),
+    /// LDH A,[n16], where n16 is between 0xFF00 and 0xFFFF
+    LDH_A_N16(u16),
+    /// LDH A,[C]
+    LDH_A_C,
+    /// LD [HLI],A or LD [HLD],A
     LD_HL_A(HLIncOrDec),
+    /// LD A,[HLI] or LD A,[HLD]
     LD_A_HL(HLIncOrDec),
 
     // --- jumps and subroutines ---
     CALL(u16),
-    CALL_CC(ConditionCode, u16),
+    CALL_CC(CC, u16),
     JP_HL,
     JP_N16(u16),
-    // TODO: when decoding the JR instructions, make sure to calculate the address N16, properly given a relative jump of type i8 from the current address
-    JP_CC_N16(ConditionCode, u16),
-    JR(u16),
-    JR_CC(ConditionCode, u16),
-    RET_CC(ConditionCode),
+    JP_CC_N16(CC, u16),
+    /// JR e8, where the offset is relative to the address after the instruction
+    JR(i8),
+    /// JR cc,e8, where the offset is relative to the address after the instruction
+    JR_CC(CC, i8),
+    RET_CC(CC),
     RET,
     RETI,
     RST(RstVec),
 
     // --- stack operation instructions
-    ADD_HL_SP,
+    /// ADD SP,e8
     ADD_SP(i8),
-    DEC_SP,
-    INC_SP,
-    LD_SP_N16(u16),
     /// LD [n16],SP
     LD_ADDR_N16_SP(u16),
     /// LD HL,SP+e8
     LD_HL_SP_E8(i8),
     LD_SP_HL,
-    POP_AF,
+    /// POP r16, where r16 is BC, DE, HL, or AF
     POP_R16(R16),
-    PUSH_AF,
+    /// PUSH r16, where r16 is BC, DE, HL, or AF
     PUSH_R16(R16),
 
     // --- miscellaneous instructions
@@ -107,6 +114,8 @@ pub enum Instruction {
     NOP,
     SCF,
     STOP,
+    /// An opcode that doesn't exist on the Game Boy, like 0xD3. Executing it locks up the CPU.
+    ILLEGAL(u8),
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
@@ -117,15 +126,6 @@ pub enum HLIncOrDec {
     HLD,
 }
 
-#[allow(non_camel_case_types)]
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
-pub enum COrN16 {
-    /// The value at the byte address 0xFF00 + C.
-    FF_C,
-    /// A 16 bit immediate value
-    N16,
-}
-
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum ImmOrR8 {
     /// An 8-bit register.
@@ -134,17 +134,6 @@ pub enum ImmOrR8 {
     N8(u8),
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
-pub enum AOrHLOrR8 {
-    /// The accumualtor register
-    A,
-    /// The byte pointed to be HL.
-    /// Also encoded as [HL].
-    HL,
-    /// An 8-bit register.
-    Reg(R8),
-}
-
 /// For instructions that operate on either [HL] or an 8-bit register.
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum HlOrReg8 {
@@ -176,106 +165,292 @@ impl U3 {
         debug_assert!(value <= 7, "U3 can only represent values 0-7.");
         Self(value)
     }
+
+    pub fn value(self) -> u8 {
+        self.0
+    }
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
-pub enum RstVec {
-    X00 = 0x00,
-    X08 = 0x08,
-    X10 = 0x10,
-    X18 = 0x18,
-    X20 = 0x20,
-    X28 = 0x28,
-    X30 = 0x30,
-    X38 = 0x38,
+/// The 8-bit operands in the order they're encoded in opcodes, with 6 for [HL]
+fn hl_or_reg8(idx: u8) -> HlOrReg8 {
+    use R8::*;
+    match idx {
+        6 => HlOrReg8::HL,
+        _ => HlOrReg8::Reg([B, C, D, E, H, L, A, A][idx as usize]),
+    }
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
-pub enum ConditionCode {
-    /// Execute if Z is set
-    Z,
-    /// Execute if Z is not set
-    NZ,
-    /// Execute if C is set
-    C,
-    /// Execute if C is not set
-    NC,
+fn operand(idx: u8) -> Operand {
+    match hl_or_reg8(idx) {
+        HlOrReg8::HL => Operand::HL,
+        HlOrReg8::Reg(r) => Operand::Reg(r),
+    }
+}
+
+fn condition(idx: u8) -> CC {
+    [CC::NZ, CC::Z, CC::NC, CC::C][idx as usize]
+}
+
+/// Decode the instruction with `opcode`, followed by the bytes `imm`. Instructions that take fewer than 2 immediate
+/// bytes ignore the rest, and for 0xCB, the CB-prefixed opcode is `imm[0]`.
+pub fn decode(opcode: u8, imm: [u8; 2]) -> Instruction {
+    use Instruction::*;
+    let n8 = imm[0];
+    let n16 = u16::from_le_bytes(imm);
+    let e8 = n8 as i8;
+    let (x, y, z) = (opcode >> 6, (opcode >> 3) & 0b111, opcode & 0b111);
+    let (p, q) = (y >> 1, y & 1);
+    let r16 = [R16::BC, R16::DE, R16::HL, R16::SP][p as usize];
+    let alu = |operand| match y {
+        0 => ADD_A(operand),
+        1 => ADC_A(operand),
+        2 => SUB_A(operand),
+        3 => SBC_A(operand),
+        4 => AND_A(operand),
+        5 => XOR_A(operand),
+        6 => OR_A(operand),
+        _ => CP_A(operand),
+    };
+    match (x, z) {
+        (0, 0) => match y {
+            0 => NOP,
+            1 => LD_ADDR_N16_SP(n16),
+            2 => STOP,
+            3 => JR(e8),
+            _ => JR_CC(condition(y - 4), e8),
+        },
+        (0, 1) if q == 0 => LD_R16_N16(r16, n16),
+        (0, 1) => ADD_HL(r16),
+        (0, 2) => match (p, q) {
+            (0 | 1, 0) => LD_ADDR_R16(r16),
+            (0 | 1, _) => LD_A_ADDR_R16(r16),
+            (2, 0) => LD_HL_A(HLIncOrDec::HLI),
+            (2, _) => LD_A_HL(HLIncOrDec::HLI),
+            (_, 0) => LD_HL_A(HLIncOrDec::HLD),
+            (_, _) => LD_A_HL(HLIncOrDec::HLD),
+        },
+        (0, 3) if q == 0 => INC16(r16),
+        (0, 3) => DEC16(r16),
+        (0, 4) => INC(hl_or_reg8(y)),
+        (0, 5) => DEC(hl_or_reg8(y)),
+        (0, 6) => match hl_or_reg8(y) {
+            HlOrReg8::HL => LD_HL(ImmOrR8::N8(n8)),
+            HlOrReg8::Reg(r) => LD_R8(r, Operand::Imm(n8)),
+        },
+        (0, _) => [RLCA, RRCA, RLA, RRA, DAA, CPL, SCF, CCF][y as usize],
+        (1, 6) if y == 6 => HALT,
+        (1, _) => match (hl_or_reg8(y), hl_or_reg8(z)) {
+            (HlOrReg8::HL, HlOrReg8::Reg(src)) => LD_HL(ImmOrR8::Reg(src)),
+            (HlOrReg8::Reg(dst), _) => LD_R8(dst, operand(z)),
+            (HlOrReg8::HL, HlOrReg8::HL) => unreachable!("0x76 is HALT"),
+        },
+        (2, _) => alu(operand(z)),
+        (3, 0) => match y {
+            0..=3 => RET_CC(condition(y)),
+            4 => LDH_N16_A(0xFF00 | n8 as u16),
+            5 => ADD_SP(e8),
+            6 => LDH_A_N16(0xFF00 | n8 as u16),
+            _ => LD_HL_SP_E8(e8),
+        },
+        (3, 1) if q == 0 => POP_R16([R16::BC, R16::DE, R16::HL, R16::AF][p as usize]),
+        (3, 1) => [RET, RETI, JP_HL, LD_SP_HL][p as usize],
+        (3, 2) => match y {
+            0..=3 => JP_CC_N16(condition(y), n16),
+            4 => LDH_C_A,
+            5 => LD_ADDR_N16(n16),
+            6 => LDH_A_C,
+            _ => LD_A_ADDR_N16(n16),
+        },
+        (3, 3) => match y {
+            0 => JP_N16(n16),
+            1 => decode_cb(n8),
+            6 => DI,
+            7 => EI,
+            _ => ILLEGAL(opcode),
+        },
+        (3, 4) if y < 4 => CALL_CC(condition(y), n16),
+        (3, 5) if q == 0 => PUSH_R16([R16::BC, R16::DE, R16::HL, R16::AF][p as usize]),
+        (3, 5) if p == 0 => CALL(n16),
+        (3, 6) => alu(Operand::Imm(n8)),
+        (3, 7) => {
+            use RstVec::*;
+            RST([X00, X08, X10, X18, X20, X28, X30, X38][y as usize])
+        }
+        _ => ILLEGAL(opcode),
+    }
+}
+
+/// Decode the opcode that follows the 0xCB prefix.
+fn decode_cb(opcode: u8) -> Instruction {
+    use Instruction::*;
+    let (x, y, z) = (opcode >> 6, (opcode >> 3) & 0b111, opcode & 0b111);
+    let target = hl_or_reg8(z);
+    match x {
+        0 => [RLC, RRC, RL, RR, SLA, SRA, SWAP, SRL][y as usize](target),
+        1 => BIT(U3::new(y), target),
+        2 => RES(U3::new(y), target),
+        _ => SET(U3::new(y), target),
+    }
 }
 
 impl Instruction {
-    fn cycles(self) -> u8 {
+    /// The number of bytes of the instruction, including the opcode.
+    pub fn size(self) -> u8 {
+        use Instruction::*;
+        match self {
+            ADC_A(Operand::Imm(_))
+            | ADD_A(Operand::Imm(_))
+            | AND_A(Operand::Imm(_))
+            | CP_A(Operand::Imm(_))
+            | OR_A(Operand::Imm(_))
+            | SBC_A(Operand::Imm(_))
+            | SUB_A(Operand::Imm(_))
+            | XOR_A(Operand::Imm(_))
+            | LD_R8(_, Operand::Imm(_))
+            | LD_HL(ImmOrR8::N8(_))
+            | LDH_N16_A(_)
+            | LDH_A_N16(_)
+            | JR(_)
+            | JR_CC(..)
+            | ADD_SP(_)
+            | LD_HL_SP_E8(_)
+            | STOP => 2,
+            BIT(..) | RES(..) | SET(..) | SWAP(_) | RL(_) | RLC(_) | RR(_) | RRC(_) | SLA(_)
+            | SRA(_) | SRL(_) => 2,
+            LD_R16_N16(..) | LD_ADDR_N16(_) | LD_A_ADDR_N16(_) | CALL(_) | CALL_CC(..)
+            | JP_N16(_) | JP_CC_N16(..) | LD_ADDR_N16_SP(_) => 3,
+            _ => 1,
+        }
+    }
+
+    /// The number of T-cycles the instruction takes. Conditional jumps, calls, and returns take longer when
+    /// `branch_taken`, which other instructions ignore.
+    pub fn cycles(self, branch_taken: bool) -> u8 {
+        use Instruction::*;
+        let by_operand = |operand: Operand| match operand {
+            Operand::Reg(_) => 4,
+            Operand::Imm(_) | Operand::HL => 8,
+        };
+        let by_target = |target: HlOrReg8, reg: u8, hl: u8| match target {
+            HlOrReg8::Reg(_) => reg,
+            HlOrReg8::HL => hl,
+        };
+        let by_branch = |taken: u8, not_taken: u8| if branch_taken { taken } else { not_taken };
         match self {
-            Instruction::ADC_A(operand) => todo!(),
-            Instruction::ADD_A(operand) => todo!(),
-            Instruction::AND_A(operand) => todo!(),
-            Instruction::CP_A(operand) => todo!(),
-            Instruction::DEC(hl_or_reg8) => todo!(),
-            Instruction::INC(hl_or_reg8) => todo!(),
-            Instruction::OR_A(operand) => todo!(),
-            Instruction::SBC_A(operand) => todo!(),
-            Instruction::SUB_A(operand) => todo!(),
-            Instruction::XOR_A(operand) => todo!(),
-            Instruction::ADD_HL(r16) => todo!(),
-            Instruction::DEC16(r16) => todo!(),
-            Instruction::INC16(r16) => todo!(),
-            Instruction::BIT(u3, hl_or_reg8) => todo!(),
-            Instruction::RES(u3, hl_or_reg8) => todo!(),
-            Instruction::SET(u3, hl_or_reg8) => todo!(),
-            Instruction::SWAP(hl_or_reg8) => todo!(),
-            Instruction::RL(hl_or_reg8) => todo!(),
-            Instruction::RLA => todo!(),
-            Instruction::RLC(hl_or_reg8) => todo!(),
-            Instruction::RLCA => todo!(),
-            Instruction::RR(hl_or_reg8) => todo!(),
-            Instruction::RRA => todo!(),
-            Instruction::RRC(hl_or_reg8) => todo!(),
-            Instruction::RRCA => todo!(),
-            Instruction::SLA(hl_or_reg8) => todo!(),
-            Instruction::SRA(hl_or_reg8) => todo!(),
-            Instruction::SRL(hl_or_reg8) => todo!(),
-            Instruction::LD_R8(r8, operand) => todo!(),
-            Instruction::LD_HL(imm_or_r8) => todo!(),
-            Instruction::LD_R16_N16(r16, _) => todo!(),
-            Instruction::LD_ADDR_R16(r16) => todo!(),
-            Instruction::LD_ADDR_N16(_) => todo!(),
-            Instruction::LDH_N16_A => todo!(),
-            Instruction::LDH_C_A => todo!(),
-            Instruction::LD_A(cor_n16) => todo!(),
-            Instruction::LDH_A(cor_n16) => todo!(),
-            Instruction::LD_HL_A(hlinc_or_dec) => todo!(),
-            Instruction::LD_A_HL(hlinc_or_dec) => todo!(),
-            Instruction::CALL(_) => todo!(),
-            Instruction::CALL_CC(condition_code, _) => todo!(),
-            Instruction::JP_HL => todo!(),
-            Instruction::JP_N16(_) => todo!(),
-            Instruction::JP_CC_N16(condition_code, _) => todo!(),
-            Instruction::JR(_) => todo!(),
-            Instruction::JR_CC(condition_code, _) => todo!(),
-            Instruction::RET_CC(condition_code) => todo!(),
-            Instruction::RET => todo!(),
-            Instruction::RETI => todo!(),
-            Instruction::RST(rst_vec) => todo!(),
-            Instruction::ADD_HL_SP => todo!(),
-            Instruction::ADD_SP(_) => todo!(),
-            Instruction::DEC_SP => todo!(),
-            Instruction::INC_SP => todo!(),
-            Instruction::LD_SP_N16(_) => todo!(),
-            Instruction::LD_ADDR_N16_SP(_) => todo!(),
-            Instruction::LD_HL_SP_E8(_) => todo!(),
-            Instruction::LD_SP_HL => todo!(),
-            Instruction::POP_AF => todo!(),
-            Instruction::POP_R16(r16) => todo!(),
-            Instruction::PUSH_AF => todo!(),
-            Instruction::PUSH_R16(r16) => todo!(),
-            Instruction::CCF => todo!(),
-            Instruction::CPL => todo!(),
-            Instruction::DAA => todo!(),
-            Instruction::DI => todo!(),
-            Instruction::EI => todo!(),
-            Instruction::HALT => todo!(),
-            Instruction::NOP => todo!(),
-            Instruction::SCF => todo!(),
-            Instruction::STOP => todo!(),
+            ADC_A(operand)
+            | ADD_A(operand)
+            | AND_A(operand)
+            | CP_A(operand)
+            | OR_A(operand)
+            | SBC_A(operand)
+            | SUB_A(operand)
+            | XOR_A(operand)
+            | LD_R8(_, operand) => by_operand(operand),
+            DEC(target) | INC(target) => by_target(target, 4, 12),
+            BIT(_, target) => by_target(target, 8, 12),
+            RES(_, target)
+            | SET(_, target)
+            | SWAP(target)
+            | RL(target)
+            | RLC(target)
+            | RR(target)
+            | RRC(target)
+            | SLA(target)
+            | SRA(target)
+            | SRL(target) => by_target(target, 8, 16),
+            LD_HL(ImmOrR8::Reg(_)) => 8,
+            LD_HL(ImmOrR8::N8(_)) => 12,
+            ADD_HL(_) | DEC16(_) | INC16(_) | LD_SP_HL => 8,
+            LD_ADDR_R16(_) | LD_A_ADDR_R16(_) | LDH_C_A | LDH_A_C | LD_HL_A(_) | LD_A_HL(_) => 8,
+            LD_R16_N16(..) | LDH_N16_A(_) | LDH_A_N16(_) | LD_HL_SP_E8(_) | POP_R16(_) => 12,
+            LD_ADDR_N16(_) | LD_A_ADDR_N16(_) | JP_N16(_) | RET | RETI | RST(_) | ADD_SP(_)
+            | PUSH_R16(_) => 16,
+            LD_ADDR_N16_SP(_) => 20,
+            CALL(_) => 24,
+            CALL_CC(..) => by_branch(24, 12),
+            JP_CC_N16(..) => by_branch(16, 12),
+            JR(_) => 12,
+            JR_CC(..) => by_branch(12, 8),
+            RET_CC(_) => by_branch(20, 8),
+            JP_HL | RLA | RLCA | RRA | RRCA | CCF | CPL | DAA | DI | EI | HALT | NOP | SCF
+            | STOP | ILLEGAL(_) => 4,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, HlOrReg8, Instruction, Operand, CC, R8};
+    use crate::cpu::Cpu;
+    use crate::mmu::{Memory, Mmu};
+
+    #[test]
+    fn decodes_operands() {
+        assert_eq!(
+            decode(0x3E, [0x42, 0]),
+            Instruction::LD_R8(R8::A, Operand::Imm(0x42))
+        );
+        assert_eq!(decode(0x20, [0xFE, 0]), Instruction::JR_CC(CC::NZ, -2));
+        assert_eq!(decode(0xEA, [0x34, 0x12]), Instruction::LD_ADDR_N16(0x1234));
+        assert_eq!(decode(0xE0, [0x40, 0]), Instruction::LDH_N16_A(0xFF40));
+        assert_eq!(
+            decode(0xCB, [0x7E, 0]),
+            Instruction::BIT(super::U3::new(7), HlOrReg8::HL)
+        );
+        assert_eq!(decode(0xDD, [0, 0]), Instruction::ILLEGAL(0xDD));
+    }
+
+    /// Whether the instruction jumps when the flags are `f`
+    fn jumps(instruction: Instruction, f: u8) -> bool {
+        use Instruction::*;
+        let holds = |cc: CC| match cc {
+            CC::Z => f & 0x80 != 0,
+            CC::NZ => f & 0x80 == 0,
+            CC::C => f & 0x10 != 0,
+            CC::NC => f & 0x10 == 0,
+        };
+        match instruction {
+            CALL(_) | JP_HL | JP_N16(_) | JR(_) | RET | RETI | RST(_) => true,
+            CALL_CC(cc, _) | JP_CC_N16(cc, _) | JR_CC(cc, _) | RET_CC(cc) => holds(cc),
+            _ => false,
+        }
+    }
+
+    #[test]
+    /// The size and timing of every decoded instruction match executing it
+    fn size_and_cycles_match_execution() {
+        let imm = [0x00, 0xC0];
+        let cb_opcodes = (0..=0xFF).map(|cb| (0xCB, [cb, 0]));
+        let opcodes = (0..=0xFF)
+            .filter(|&opcode| opcode != 0xCB)
+            .map(|opcode| (opcode, imm));
+        for (opcode, imm) in opcodes.chain(cb_opcodes) {
+            let instruction = decode(opcode, imm);
+            for f in [0x00, 0xF0] {
+                let mut rom = [0x00; 0x8000];
+                rom[..3].copy_from_slice(&[opcode, imm[0], imm[1]]);
+                let mut cpu = Cpu::new(Mmu::new(&rom));
+                cpu.mmu.set_not_in_boot_rom();
+                cpu.regs.pc = 0;
+                cpu.regs.f = f;
+                (cpu.regs.b, cpu.regs.c) = (0xC0, 0x80);
+                (cpu.regs.d, cpu.regs.e) = (0xC0, 0x00);
+                (cpu.regs.h, cpu.regs.l) = (0xC0, 0x00);
+                cpu.regs.sp = 0xD000;
+                let jumped = jumps(instruction, f);
+                assert_eq!(
+                    cpu.step(),
+                    instruction.cycles(jumped),
+                    "cycles of {instruction:?} with F={f:02X}"
+                );
+                if !jumped {
+                    assert_eq!(
+                        cpu.regs.pc,
+                        instruction.size() as u16,
+                        "size of {instruction:?}"
+                    );
+                }
+            }
         }
     }
 }
@@ -2,7 +2,7 @@ use crate::mmu::Memory;
 
 use super::{
     register_file::{Flag, R16, R8},
-    Cpu, ImeState,
+    CallKind, Cpu, IllegalOpcode, ImeState,
 };
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
@@ -17,6 +17,7 @@ pub enum RstVec {
     X38 = 0x38,
 }
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum CC {
     /// Execute if Z is set
     Z,
@@ -31,36 +32,41 @@ pub enum CC {
 /// Implementation of the unique types of cpu instructions.
 ///
 /// Each function simulates the execution of an instruction and returns the number of T-cycles it takes. e.g. [Cpu::nop] returns 4.
+/// Memory accesses go through [Cpu::read_cycle] and [Cpu::write_cycle], which advance the MMU by an M-cycle as they
+/// happen. The rest of the T-cycles are stepped after the instruction.
 impl<M: Memory> Cpu<M> {
     // --- utility functions ---
     /// Fetch the 8-bit immediate that follows the opcode, and advance PC.
     fn fetch_imm8(&mut self) -> u8 {
-        let res = self.mmu.read_byte(self.regs.pc);
-        self.regs.pc += 1;
+        let res = self.read_cycle(self.regs.pc);
+        self.regs.pc = self.regs.pc.wrapping_add(1);
         res
     }
 
     /// Fetch the 16-bit immediate that follows the opcode, and advance PC.
     fn fetch_imm16(&mut self) -> u16 {
-        let res = self.mmu.read_word(self.regs.pc);
-        self.regs.pc += 2;
-        res
+        let lo = self.fetch_imm8();
+        let hi = self.fetch_imm8();
+        u16::from_le_bytes([lo, hi])
     }
 
     /// Pushes the word on to the stack in little-endian order (the lower-order byte is at the lower address).
+    ///
+    /// Takes 3 M-cycles: decrementing SP, then writing the high and low bytes.
     pub fn push_u16(&mut self, word: u16) {
         // println!("PUSH {:#04X} at addr {:#04X}", word, self.regs.sp);
         let [lo, hi] = word.to_le_bytes();
+        self.internal_cycle();
         self.regs.sp = self.regs.sp.wrapping_sub(1);
-        self.mmu.write_byte(self.regs.sp, hi);
+        self.write_cycle(self.regs.sp, hi);
         self.regs.sp = self.regs.sp.wrapping_sub(1);
-        self.mmu.write_byte(self.regs.sp, lo);
+        self.write_cycle(self.regs.sp, lo);
     }
 
     fn pop_u16(&mut self) -> u16 {
-        let lo = self.mmu.read_byte(self.regs.sp);
+        let lo = self.read_cycle(self.regs.sp);
         self.regs.sp = self.regs.sp.wrapping_add(1);
-        let hi = self.mmu.read_byte(self.regs.sp);
+        let hi = self.read_cycle(self.regs.sp);
         self.regs.sp = self.regs.sp.wrapping_add(1);
         // println!(
         //     "POP {:04X} at addr {:#04X}",
@@ -111,7 +117,8 @@ impl<M: Memory> Cpu<M> {
 
     /// ADC A,\[HL\]
     pub fn adc_a_ref_hl(&mut self) -> u8 {
-        self.alu_adc(self.mmu.read_byte(self.regs.hl()));
+        let val = self.read_cycle(self.regs.hl());
+        self.alu_adc(val);
         8
     }
 
@@ -130,7 +137,8 @@ impl<M: Memory> Cpu<M> {
 
     /// ADD A,\[HL\]
     pub fn add_a_ref_hl(&mut self) -> u8 {
-        self.alu_add(self.mmu.read_byte(self.regs.hl()), false);
+        let val = self.read_cycle(self.regs.hl());
+        self.alu_add(val, false);
         8
     }
 
@@ -159,7 +167,8 @@ impl<M: Memory> Cpu<M> {
 
     /// AND A,\[HL\]
     pub fn and_a_ref_hl(&mut self) -> u8 {
-        self.alu_and(self.mmu.read_byte(self.regs.hl()));
+        let val = self.read_cycle(self.regs.hl());
+        self.alu_and(val);
         8
     }
 
@@ -198,7 +207,8 @@ impl<M: Memory> Cpu<M> {
 
     /// CP A,\[HL\]
     pub fn cp_a_ref_hl(&mut self) -> u8 {
-        self.alu_cp(self.mmu.read_byte(self.regs.hl()));
+        let val = self.read_cycle(self.regs.hl());
+        self.alu_cp(val);
         8
     }
 
@@ -228,8 +238,9 @@ impl<M: Memory> Cpu<M> {
 
     /// DEC \[HL\]
     pub fn dec_ref_hl(&mut self) -> u8 {
-        let result = self.alu_dec(self.mmu.read_byte(self.regs.hl()));
-        self.mmu.write_byte(self.regs.hl(), result);
+        let val = self.read_cycle(self.regs.hl());
+        let result = self.alu_dec(val);
+        self.write_cycle(self.regs.hl(), result);
         12
     }
 
@@ -252,8 +263,9 @@ impl<M: Memory> Cpu<M> {
 
     /// INC \[HL\]
     pub fn inc_ref_hl(&mut self) -> u8 {
-        let result = self.alu_inc(self.mmu.read_byte(self.regs.hl()));
-        self.mmu.write_byte(self.regs.hl(), result);
+        let val = self.read_cycle(self.regs.hl());
+        let result = self.alu_inc(val);
+        self.write_cycle(self.regs.hl(), result);
         12
     }
 
@@ -275,7 +287,8 @@ impl<M: Memory> Cpu<M> {
 
     /// OR A,\[HL\]
     pub fn or_a_ref_hl(&mut self) -> u8 {
-        self.alu_or(self.mmu.read_byte(self.regs.hl()));
+        let val = self.read_cycle(self.regs.hl());
+        self.alu_or(val);
         8
     }
 
@@ -294,7 +307,8 @@ impl<M: Memory> Cpu<M> {
 
     /// SBC A,\[HL\]
     pub fn sbc_a_ref_hl(&mut self) -> u8 {
-        self.alu_sub(self.mmu.read_byte(self.regs.hl()), self.regs.flag(Flag::C));
+        let val = self.read_cycle(self.regs.hl());
+        self.alu_sub(val, self.regs.flag(Flag::C));
         8
     }
 
@@ -313,7 +327,8 @@ impl<M: Memory> Cpu<M> {
 
     /// SUB A,\[HL\]
     pub fn sub_a_ref_hl(&mut self) -> u8 {
-        self.alu_sub(self.mmu.read_byte(self.regs.hl()), false);
+        let val = self.read_cycle(self.regs.hl());
+        self.alu_sub(val, false);
         8
     }
 
@@ -342,7 +357,8 @@ impl<M: Memory> Cpu<M> {
 
     /// XOR A,\[HL\]
     pub fn xor_a_ref_hl(&mut self) -> u8 {
-        self.alu_xor(self.mmu.read_byte(self.regs.hl()));
+        let val = self.read_cycle(self.regs.hl());
+        self.alu_xor(val);
         8
     }
 
@@ -403,7 +419,8 @@ impl<M: Memory> Cpu<M> {
 
     /// BIT u3,\[HL\]
     pub fn bit_u3_ref_hl(&mut self, u3: u8) -> u8 {
-        self.test_bit_u3(u3, self.mmu.read_byte(self.regs.hl()));
+        let val = self.read_cycle(self.regs.hl());
+        self.test_bit_u3(u3, val);
         12
     }
 
@@ -421,8 +438,8 @@ impl<M: Memory> Cpu<M> {
     /// Set bit u3 in the byte pointed by HL to 0. Bit 0 is the rightmost one, bit 7 the leftmost one.
     pub fn res_u3_ref_hl(&mut self, u3: u8) -> u8 {
         let mask = !(1 << u3);
-        let val = self.mmu.read_byte(self.regs.hl()) & mask;
-        self.mmu.write_byte(self.regs.hl(), val);
+        let val = self.read_cycle(self.regs.hl()) & mask;
+        self.write_cycle(self.regs.hl(), val);
         16
     }
 
@@ -440,8 +457,8 @@ impl<M: Memory> Cpu<M> {
     /// Set bit u3 in the byte pointed by HL to 1. Bit 0 is the rightmost one, bit 7 the leftmost one.
     pub fn set_u3_ref_hl(&mut self, u3: u8) -> u8 {
         let mask = 1 << u3;
-        let val = self.mmu.read_byte(self.regs.hl()) | mask;
-        self.mmu.write_byte(self.regs.hl(), val);
+        let val = self.read_cycle(self.regs.hl()) | mask;
+        self.write_cycle(self.regs.hl(), val);
         16
     }
 
@@ -467,9 +484,9 @@ impl<M: Memory> Cpu<M> {
 
     /// SWAP \[HL\]
     pub fn swap_ref_hl(&mut self) -> u8 {
-        let val = self.mmu.read_byte(self.regs.hl());
+        let val = self.read_cycle(self.regs.hl());
         let swapped = self.swap_byte(val);
-        self.mmu.write_byte(self.regs.hl(), swapped);
+        self.write_cycle(self.regs.hl(), swapped);
         16
     }
 
@@ -502,9 +519,9 @@ impl<M: Memory> Cpu<M> {
 
     /// RL \[HL\]
     pub fn rl_ref_hl(&mut self) -> u8 {
-        let val = self.mmu.read_byte(self.regs.hl());
+        let val = self.read_cycle(self.regs.hl());
         let rotated = self.alu_rl(val);
-        self.mmu.write_byte(self.regs.hl(), rotated);
+        self.write_cycle(self.regs.hl(), rotated);
         16
     }
 
@@ -542,9 +559,9 @@ impl<M: Memory> Cpu<M> {
 
     /// RLC \[HL\]
     pub fn rlc_ref_hl(&mut self) -> u8 {
-        let val = self.mmu.read_byte(self.regs.hl());
+        let val = self.read_cycle(self.regs.hl());
         let rotated = self.alu_rlc(val);
-        self.mmu.write_byte(self.regs.hl(), rotated);
+        self.write_cycle(self.regs.hl(), rotated);
         16
     }
 
@@ -582,9 +599,9 @@ impl<M: Memory> Cpu<M> {
 
     /// RR \[HL\]
     pub fn rr_ref_hl(&mut self) -> u8 {
-        let val = self.mmu.read_byte(self.regs.hl());
+        let val = self.read_cycle(self.regs.hl());
         let rotated = self.alu_rr(val);
-        self.mmu.write_byte(self.regs.hl(), rotated);
+        self.write_cycle(self.regs.hl(), rotated);
         16
     }
 
@@ -622,9 +639,9 @@ impl<M: Memory> Cpu<M> {
 
     /// RRC \[HL\]
     pub fn rrc_ref_hl(&mut self) -> u8 {
-        let val = self.mmu.read_byte(self.regs.hl());
+        let val = self.read_cycle(self.regs.hl());
         let rotated = self.alu_rrc(val);
-        self.mmu.write_byte(self.regs.hl(), rotated);
+        self.write_cycle(self.regs.hl(), rotated);
         16
     }
 
@@ -661,9 +678,9 @@ impl<M: Memory> Cpu<M> {
 
     /// SLA \[HL\]
     pub fn sla_ref_hl(&mut self) -> u8 {
-        let val = self.mmu.read_byte(self.regs.hl());
+        let val = self.read_cycle(self.regs.hl());
         let rotated = self.alu_sla(val);
-        self.mmu.write_byte(self.regs.hl(), rotated);
+        self.write_cycle(self.regs.hl(), rotated);
         16
     }
 
@@ -695,9 +712,9 @@ impl<M: Memory> Cpu<M> {
 
     /// SRA \[HL\]
     pub fn sra_ref_hl(&mut self) -> u8 {
-        let val = self.mmu.read_byte(self.regs.hl());
+        let val = self.read_cycle(self.regs.hl());
         let rotated = self.alu_sra(val);
-        self.mmu.write_byte(self.regs.hl(), rotated);
+        self.write_cycle(self.regs.hl(), rotated);
         16
     }
 
@@ -727,9 +744,9 @@ impl<M: Memory> Cpu<M> {
 
     /// SRL \[HL\]
     pub fn srl_ref_hl(&mut self) -> u8 {
-        let val = self.mmu.read_byte(self.regs.hl());
+        let val = self.read_cycle(self.regs.hl());
         let rotated = self.alu_srl(val);
-        self.mmu.write_byte(self.regs.hl(), rotated);
+        self.write_cycle(self.regs.hl(), rotated);
         16
     }
 
@@ -757,34 +774,34 @@ impl<M: Memory> Cpu<M> {
 
     /// LD \[HL\],r8
     pub fn ld_ref_hl_r8(&mut self, reg: R8) -> u8 {
-        self.mmu.write_byte(self.regs.hl(), self.regs.r8(reg));
+        self.write_cycle(self.regs.hl(), self.regs.r8(reg));
         8
     }
 
     /// LD \[HL\],n8
     pub fn ld_ref_hl_n8(&mut self) -> u8 {
         let imm = self.fetch_imm8();
-        self.mmu.write_byte(self.regs.hl(), imm);
+        self.write_cycle(self.regs.hl(), imm);
         12
     }
 
     /// LD r8,\[HL\]
     pub fn ld_r8_ref_hl(&mut self, reg: R8) -> u8 {
-        let val = self.mmu.read_byte(self.regs.hl());
+        let val = self.read_cycle(self.regs.hl());
         self.regs.set_r8(reg, val);
         8
     }
 
     /// LD \[r16\],A
     pub fn ld_ref_r16_a(&mut self, reg: R16) -> u8 {
-        self.mmu.write_byte(self.regs.r16(reg), self.regs.a);
+        self.write_cycle(self.regs.r16(reg), self.regs.a);
         8
     }
 
     /// LD \[n16\],A
     pub fn ld_ref_n16_a(&mut self) -> u8 {
         let addr = self.fetch_imm16();
-        self.mmu.write_byte(addr, self.regs.a);
+        self.write_cycle(addr, self.regs.a);
         16
     }
 
@@ -794,7 +811,7 @@ impl<M: Memory> Cpu<M> {
     pub fn ldh_ref_a8_a(&mut self) -> u8 {
         let offset = self.fetch_imm8();
         let addr = 0xFF00 + offset as u16;
-        self.mmu.write_byte(addr, self.regs.a);
+        self.write_cycle(addr, self.regs.a);
         12
     }
 
@@ -803,20 +820,20 @@ impl<M: Memory> Cpu<M> {
     /// Also encoded as LD \[$FF00+C\], A
     pub fn ldh_ref_c_a(&mut self) -> u8 {
         let addr = 0xFF00 + (self.regs.c as u16);
-        self.mmu.write_byte(addr, self.regs.a);
+        self.write_cycle(addr, self.regs.a);
         8
     }
 
     /// LD A,\[r16\]
     pub fn ld_a_ref_r16(&mut self, reg: R16) -> u8 {
-        self.regs.a = self.mmu.read_byte(self.regs.r16(reg));
+        self.regs.a = self.read_cycle(self.regs.r16(reg));
         8
     }
 
     /// LD A,\[n16\]
     pub fn ld_a_ref_n16(&mut self) -> u8 {
         let addr = self.fetch_imm16();
-        self.regs.a = self.mmu.read_byte(addr);
+        self.regs.a = self.read_cycle(addr);
         16
     }
 
@@ -826,7 +843,7 @@ impl<M: Memory> Cpu<M> {
     pub fn ldh_a_ref_a8(&mut self) -> u8 {
         let offset = self.fetch_imm8();
         let addr = 0xFF00 + offset as u16;
-        self.regs.a = self.mmu.read_byte(addr);
+        self.regs.a = self.read_cycle(addr);
         12
     }
 
@@ -835,7 +852,7 @@ impl<M: Memory> Cpu<M> {
     /// Also expressed as LD A,[$FF00+$C]
     pub fn ldh_a_ref_c(&mut self) -> u8 {
         let addr = 0xFF00 + self.regs.c as u16;
-        self.regs.a = self.mmu.read_byte(addr);
+        self.regs.a = self.read_cycle(addr);
         8
     }
 
@@ -872,8 +889,10 @@ impl<M: Memory> Cpu<M> {
     /// CALL n16
     pub fn call_n16(&mut self) -> u8 {
         let jump_addr = self.fetch_imm16();
-        self.push_u16(self.regs.pc);
+        let return_addr = self.regs.pc;
+        self.push_u16(return_addr);
         self.regs.pc = jump_addr;
+        self.enter_call(CallKind::Call, return_addr);
         24
     }
 
@@ -881,8 +900,10 @@ impl<M: Memory> Cpu<M> {
     pub fn call_cc_n16(&mut self, cc: CC) -> u8 {
         let jump_addr = self.fetch_imm16();
         if self.check_cond(cc) {
-            self.push_u16(self.regs.pc);
+            let return_addr = self.regs.pc;
+            self.push_u16(return_addr);
             self.regs.pc = jump_addr;
+            self.enter_call(CallKind::Call, return_addr);
             24
         } else {
             12
@@ -920,7 +941,7 @@ impl<M: Memory> Cpu<M> {
     /// The address is encoded as a signed 8-bit offset from the address immediately following the JR instruction, so the target address n16 must be between -128 and 127 bytes away.
     pub fn jr_e8(&mut self) -> u8 {
         let offset = self.fetch_imm8() as i8;
-        self.regs.pc = (self.regs.pc as i16).wrapping_add(offset as i16) as u16;
+        self.regs.pc = self.regs.pc.wrapping_add_signed(offset as i16);
         12
     }
 
@@ -928,7 +949,7 @@ impl<M: Memory> Cpu<M> {
     pub fn jr_cc_e8(&mut self, cc: CC) -> u8 {
         let offset = self.fetch_imm8() as i8;
         if self.check_cond(cc) {
-            self.regs.pc = (self.regs.pc as i16 + offset as i16) as u16;
+            self.regs.pc = self.regs.pc.wrapping_add_signed(offset as i16);
             12
         } else {
             8
@@ -937,13 +958,16 @@ impl<M: Memory> Cpu<M> {
 
     /// RET
     pub fn ret(&mut self) -> u8 {
+        self.leave_call();
         self.regs.pc = self.pop_u16();
         16
     }
 
     /// RET cc
     pub fn ret_cc(&mut self, cc: CC) -> u8 {
+        self.internal_cycle();
         if self.check_cond(cc) {
+            self.leave_call();
             self.regs.pc = self.pop_u16();
             20
         } else {
@@ -953,6 +977,7 @@ impl<M: Memory> Cpu<M> {
 
     /// RETI
     pub fn reti(&mut self) -> u8 {
+        self.leave_call();
         self.regs.pc = self.pop_u16();
         self.ime = ImeState::Enabled;
         16
@@ -960,8 +985,10 @@ impl<M: Memory> Cpu<M> {
 
     /// RST vec
     pub fn rst_vec(&mut self, vec: RstVec) -> u8 {
-        self.push_u16(self.regs.pc);
+        let return_addr = self.regs.pc;
+        self.push_u16(return_addr);
         self.regs.pc = vec as u16;
+        self.enter_call(CallKind::Rst, return_addr);
         16
     }
 
@@ -994,8 +1021,8 @@ impl<M: Memory> Cpu<M> {
     pub fn ld_n16_sp(&mut self) -> u8 {
         let addr = self.fetch_imm16();
         let [lo, hi] = self.regs.sp.to_le_bytes();
-        self.mmu.write_byte(addr, lo);
-        self.mmu.write_byte(addr + 1, hi);
+        self.write_cycle(addr, lo);
+        self.write_cycle(addr.wrapping_add(1), hi);
         20
     }
 
@@ -1088,9 +1115,27 @@ impl<M: Memory> Cpu<M> {
         4
     }
 
+    /// Executing an illegal opcode hangs the CPU, see [`Cpu::locked_up`].
+    pub fn lock_up(&mut self, opcode: u8) -> u8 {
+        self.locked_up = Some(IllegalOpcode {
+            opcode,
+            addr: self.regs.pc.wrapping_sub(1),
+        });
+        4
+    }
+
+    /// With IME off and an interrupt already pending, HALT doesn't halt. Instead, the CPU fails to increment PC after
+    /// reading the next opcode, so the byte after HALT is read twice. Right after EI, IME is still off, so the interrupt
+    /// is serviced next, and its handler returns to the HALT.
+    ///
+    /// https://gbdev.io/pandocs/halt.html#halt-bug
     pub fn halt(&mut self) -> u8 {
-        // understand and implement the halt bug
-        self.is_halted = true;
+        let pending_interrupts = self.mmu.interrupts_requested() & self.mmu.interrupts_enabled();
+        if self.ime != ImeState::Enabled && !pending_interrupts.is_empty() {
+            self.halt_bug = true;
+        } else {
+            self.is_halted = true;
+        }
         4
     }
 
@@ -1107,11 +1152,21 @@ impl<M: Memory> Cpu<M> {
         4
     }
 
+    /// STOP
+    ///
+    /// Resets DIV and enters low power mode, where the system clock stops until a button is pressed. On CGB, this is
+    /// also how the CPU switches between normal and double speed after the switch is requested through KEY1, in which
+    /// case the CPU keeps running.
+    ///
+    /// https://gbdev.io/pandocs/Reducing_Power_Consumption.html#using-the-stop-instruction
     pub fn stop(&mut self) -> u8 {
-        // Stop must be followed by an additional byte that is ignored by the CPU
-        self.fetch_imm8();
-        panic!("STOP");
-        // 4
+        // Stop must be followed by an additional byte that is ignored by the CPU, and isn't read in an extra M-cycle
+        self.regs.pc = self.regs.pc.wrapping_add(1);
+        self.mmu.reset_divider();
+        if !self.mmu.try_speed_switch() {
+            self.is_stopped = true;
+        }
+        4
     }
 }
 
@@ -1123,9 +1178,10 @@ mod tests {
     use crate::{
         cpu::{
             register_file::{Flag, R8},
-            Cpu, ImeState,
+            CallKind, Cpu, ImeState,
         },
-        mmu::{Memory, Mmu},
+        joypad::Button,
+        mmu::{InterruptKind, Memory, Mmu},
     };
 
     #[test]
@@ -1142,7 +1198,7 @@ mod tests {
         // NOP
         // NOP
         // ...
-        let mut cpu = Cpu::new(Mmu::new(&program), false);
+        let mut cpu = Cpu::new(Mmu::new(&program));
         cpu.mmu.set_not_in_boot_rom();
         assert_eq!(cpu.ime, Disabled);
         cpu.step();
@@ -1166,7 +1222,7 @@ mod tests {
         // EI
         // DI
         // NOP
-        let mut cpu = Cpu::new(Mmu::new(&program), false);
+        let mut cpu = Cpu::new(Mmu::new(&program));
         cpu.mmu.set_not_in_boot_rom();
         assert_eq!(cpu.ime, Disabled);
         cpu.step();
@@ -1178,11 +1234,244 @@ mod tests {
         assert_eq!(cpu.ime, Disabled);
     }
 
+    /// Enables the timer interrupt and requests it, then runs `program`
+    fn cpu_with_pending_timer_interrupt(program: &[u8]) -> Cpu<Mmu> {
+        let mut rom = [0x00; 0x8000];
+        rom[..program.len()].copy_from_slice(program);
+        let mut cpu = Cpu::new(Mmu::new(&rom));
+        cpu.mmu.set_not_in_boot_rom();
+        cpu.mmu.write_byte(0xFFFF, 0x04);
+        cpu.mmu.write_byte(0xFF0F, 0x04);
+        cpu
+    }
+
+    #[test]
+    /// Like Mooneye's halt_ime0_nointr_timing: with IME off and an interrupt pending, HALT falls through and the next
+    /// byte is executed twice
+    fn halt_bug_executes_next_byte_twice() {
+        // HALT
+        // INC A
+        // INC B
+        let mut cpu = cpu_with_pending_timer_interrupt(&[0x76, 0x3C, 0x04]);
+        cpu.step();
+        assert!(!cpu.is_halted);
+        assert_eq!(cpu.regs.pc, 0x0001);
+        cpu.step();
+        assert_eq!(cpu.regs.pc, 0x0001);
+        cpu.step();
+        cpu.step();
+        assert_eq!((cpu.regs.a, cpu.regs.b), (2, 1));
+        assert_eq!(cpu.regs.pc, 0x0003);
+        // the interrupt isn't serviced with IME off
+        assert_eq!(cpu.mmu.read_byte(0xFF0F) & 0x04, 0x04);
+    }
+
+    #[test]
+    /// The byte after HALT is read twice even when it's the opcode of a longer instruction, so its operand is
+    /// fetched from the opcode's address: LD A,n loads the LD A,n opcode itself
+    fn halt_bug_repeats_opcode_fetch_of_multi_byte_instruction() {
+        // HALT
+        // LD A,0x14
+        let mut cpu = cpu_with_pending_timer_interrupt(&[0x76, 0x3E, 0x14]);
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.regs.a, 0x3E);
+        assert_eq!(cpu.regs.pc, 0x0002);
+    }
+
+    #[test]
+    /// HALT right after EI triggers the halt bug, so the pending interrupt is serviced and returns to the HALT, which
+    /// then halts
+    fn halt_after_ei_services_pending_interrupt() {
+        // EI
+        // HALT
+        // INC A
+        let mut cpu = cpu_with_pending_timer_interrupt(&[0xFB, 0x76, 0x3C]);
+        cpu.step();
+        cpu.step();
+        assert!(!cpu.is_halted);
+        cpu.step();
+        assert_eq!(cpu.regs.pc, 0x0051);
+        // the handler returns to the HALT
+        assert_eq!(cpu.pop_u16(), 0x0001);
+        assert_eq!(cpu.regs.a, 0);
+    }
+
+    #[test]
+    /// CALL, RST, and interrupts push call frames, and returns pop them
+    fn call_stack() {
+        // EI
+        // CALL 0x0010
+        // ...
+        // 0x0010: RST 0x28
+        //         RET
+        // 0x0028: RET
+        // 0x0050: NOP
+        //         RETI
+        let mut program = [0x00; 0x60];
+        program[..4].copy_from_slice(&[0xFB, 0xCD, 0x10, 0x00]);
+        program[0x10..0x12].copy_from_slice(&[0xEF, 0xC9]);
+        program[0x28] = 0xC9;
+        program[0x50..0x52].copy_from_slice(&[0x00, 0xD9]);
+        let mut cpu = cpu_with_pending_timer_interrupt(&program);
+        cpu.mmu.write_byte(0xFF0F, 0x00);
+        let frames = |cpu: &Cpu<Mmu>| -> Vec<(CallKind, u16, u16)> {
+            cpu.call_stack
+                .iter()
+                .map(|frame| (frame.kind, frame.target, frame.return_addr))
+                .collect()
+        };
+        cpu.step();
+        cpu.step();
+        assert_eq!(frames(&cpu), [(CallKind::Call, 0x0010, 0x0004)]);
+        cpu.step();
+        assert_eq!(
+            frames(&cpu),
+            [
+                (CallKind::Call, 0x0010, 0x0004),
+                (CallKind::Rst, 0x0028, 0x0011)
+            ]
+        );
+        // the interrupt is dispatched before the RET at 0x0028, and the NOP at the handler is executed
+        cpu.mmu.write_byte(0xFF0F, 0x04);
+        cpu.step();
+        assert_eq!(
+            frames(&cpu)[2],
+            (CallKind::Interrupt(InterruptKind::Timer), 0x0050, 0x0028)
+        );
+        cpu.step();
+        assert_eq!(frames(&cpu).len(), 2);
+        cpu.step();
+        cpu.step();
+        assert_eq!(frames(&cpu), []);
+        assert_eq!(cpu.regs.pc, 0x0004);
+    }
+
+    #[test]
+    /// Memory accesses happen in their own M-cycle, after the hardware has been advanced by the earlier M-cycles of
+    /// the instruction. Like Mooneye's mem_timing tests, this reads DIV just as it ticks over.
+    fn memory_access_timing() {
+        for (nops, div) in [(61, 0), (62, 1)] {
+            // LD HL,0xFF04
+            // LD [HL],A: resets DIV at the end of the instruction
+            // NOP x nops
+            // LD A,[HL]: reads DIV 8 T-cycles into the instruction
+            let mut program = [0x00; 0x8000];
+            program[..4].copy_from_slice(&[0x21, 0x04, 0xFF, 0x77]);
+            program[4 + nops] = 0x7E;
+            let mut cpu = Cpu::new(Mmu::new(&program));
+            cpu.mmu.set_not_in_boot_rom();
+            cpu.regs.a = 0xFF;
+            for _ in 0..(3 + nops) {
+                cpu.step();
+            }
+            assert_eq!(cpu.regs.a, div, "after {nops} NOPs");
+        }
+        // BIT 0,[HL] reads 12 T-cycles into the instruction, after fetching the prefixed opcode
+        for (nops, div) in [(60, 0), (61, 1)] {
+            let mut program = [0x00; 0x8000];
+            program[..4].copy_from_slice(&[0x21, 0x04, 0xFF, 0x77]);
+            program[4 + nops..6 + nops].copy_from_slice(&[0xCB, 0x46]);
+            let mut cpu = Cpu::new(Mmu::new(&program));
+            cpu.mmu.set_not_in_boot_rom();
+            for _ in 0..(3 + nops) {
+                cpu.step();
+            }
+            assert_eq!(!cpu.regs.flag(Flag::Z) as u8, div, "after {nops} NOPs");
+        }
+    }
+
+    #[test]
+    /// Dispatching an interrupt takes 5 M-cycles, which the hardware is advanced by along with the cycles reported
+    fn interrupt_dispatch_timing() {
+        // EI
+        // NOP
+        let mut cpu = cpu_with_pending_timer_interrupt(&[0xFB, 0x00]);
+        cpu.step();
+        cpu.step();
+        let before = cpu.mmu.divider.internal_counter();
+        // dispatching, then the NOP at the handler
+        assert_eq!(cpu.step(), 24);
+        assert_eq!(cpu.regs.pc, 0x0051);
+        assert_eq!(cpu.mmu.divider.internal_counter().wrapping_sub(before), 24);
+    }
+
+    #[test]
+    /// STOP resets DIV and stops the clock until a button is pressed
+    fn stop_waits_for_button_press() {
+        // NOP x 0x40
+        // STOP
+        // INC A
+        let mut program = [0x00; 0x8000];
+        program[0x40..0x43].copy_from_slice(&[0x10, 0x00, 0x3C]);
+        let mut cpu = Cpu::new(Mmu::new(&program));
+        cpu.mmu.set_not_in_boot_rom();
+        while cpu.regs.pc != 0x40 {
+            cpu.step();
+        }
+        assert_ne!(cpu.mmu.read_byte(0xFF04), 0);
+        cpu.step();
+        assert!(cpu.is_stopped);
+        assert_eq!(cpu.regs.pc, 0x42);
+        assert_eq!(cpu.mmu.read_byte(0xFF04), 0);
+        let divider = cpu.mmu.divider.internal_counter();
+        for _ in 0..1000 {
+            cpu.step();
+        }
+        assert_eq!(cpu.mmu.divider.internal_counter(), divider);
+        assert_eq!(cpu.regs.pc, 0x42);
+
+        cpu.mmu.set_pressed_buttons(Button::Start.into());
+        cpu.step();
+        assert!(!cpu.is_stopped);
+        assert_eq!(cpu.regs.a, 1);
+    }
+
+    #[test]
+    /// Relative jumps can cross from the top of the cartridge ROM into VRAM
+    fn jr_crosses_0x8000() {
+        let mut program = [0x00; 0x8000];
+        // JR NZ,+2
+        program[0x7FFD] = 0x20;
+        program[0x7FFE] = 0x02;
+        let mut cpu = Cpu::new(Mmu::new(&program));
+        cpu.mmu.set_not_in_boot_rom();
+        cpu.regs.pc = 0x7FFD;
+        cpu.regs.set_flag(Flag::Z, false);
+        cpu.step();
+        assert_eq!(cpu.regs.pc, 0x8001);
+    }
+
+    #[test]
+    /// 16-bit memory accesses at 0xFFFF wrap around to 0x0000
+    fn word_accesses_at_0xffff() {
+        let mut program = [0x00; 0x8000];
+        // LD [0xFFFF],SP
+        program[0] = 0x08;
+        program[1] = 0xFF;
+        program[2] = 0xFF;
+        // POP BC
+        program[3] = 0xC1;
+        let mut cpu = Cpu::new(Mmu::new(&program));
+        cpu.mmu.set_not_in_boot_rom();
+        cpu.regs.sp = 0x1203;
+        cpu.step();
+        // the low byte is written to IE, and the high byte to the cartridge, which ignores it
+        assert_eq!(cpu.mmu.read_byte(0xFFFF), 0x03);
+        assert_eq!(cpu.mmu.read_byte(0x0000), 0x08);
+
+        cpu.regs.sp = 0xFFFF;
+        cpu.step();
+        assert_eq!(cpu.regs.bc(), 0x0803);
+        assert_eq!(cpu.regs.sp, 0x0001);
+    }
+
     proptest! {
+        #![proptest_config(crate::util::proptest_config())]
         #[test]
         fn sub_a_a(a: u8, init_flags: bool) {
             use Flag::*;
-            let mut cpu= Cpu::new(Mmu::new(&FAKE_ROM), false);
+            let mut cpu= Cpu::new(Mmu::new(&FAKE_ROM));
             for flag in [Z, N, H, C] {
                 cpu.regs.set_flag(flag, init_flags);
             }
@@ -1198,7 +1487,7 @@ mod tests {
         #[test]
         fn xor_a_a(a: u8, init_flags: bool) {
             use Flag::*;
-            let mut cpu= Cpu::new(Mmu::new(&FAKE_ROM),false);
+            let mut cpu= Cpu::new(Mmu::new(&FAKE_ROM));
             for flag in [Z, N, H, C] {
                 cpu.regs.set_flag(flag, init_flags);
             }
@@ -1214,7 +1503,7 @@ mod tests {
         #[test]
         fn or_a_a(a: u8, init_flags: bool) {
             use Flag::*;
-            let mut cpu= Cpu::new(Mmu::new(&FAKE_ROM), false);
+            let mut cpu= Cpu::new(Mmu::new(&FAKE_ROM));
             for flag in [Z, N, H, C] {
                 cpu.regs.set_flag(flag, init_flags);
             }
@@ -1230,7 +1519,7 @@ mod tests {
         #[test]
         fn and_a_a(a: u8, init_flags: bool) {
             use Flag::*;
-            let mut cpu= Cpu::new(Mmu::new(&FAKE_ROM), false);
+            let mut cpu= Cpu::new(Mmu::new(&FAKE_ROM));
             for flag in [Z, N, H, C] {
                 cpu.regs.set_flag(flag, init_flags);
             }
@@ -1246,7 +1535,7 @@ mod tests {
         #[test]
         fn cp_a_a(a: u8, init_flags: bool) {
             use Flag::*;
-            let mut cpu= Cpu::new(Mmu::new(&FAKE_ROM),false);
+            let mut cpu= Cpu::new(Mmu::new(&FAKE_ROM));
             for flag in [Z, N, H, C] {
                 cpu.regs.set_flag(flag, init_flags);
             }
@@ -9,7 +9,7 @@ use serde::{Deserialize, Serialize};
 /// The A register is the accumulator register.
 /// The F register is the flags register and is not directly accessible.
 /// Instead, the upper 4 bits are used to store flags from the results of math operations.
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Registers {
     pub a: u8,
     pub f: u8,
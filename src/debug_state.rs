@@ -0,0 +1,221 @@
+//! A snapshot of the CPU and of the hardware registers that debuggers show, so that frontends don't depend on the
+//! emulator's internals.
+use std::path::Path;
+
+use enumset::EnumSet;
+use serde::Serialize;
+
+use crate::cpu::{ImeState, Registers};
+use crate::mmu::{InterruptKind, Memory};
+use crate::ppu::Mode;
+use crate::Emulator;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct DebugState {
+    pub regs: Registers,
+    pub ime: ImeState,
+    pub halted: bool,
+    /// In low power mode after STOP
+    pub stopped: bool,
+    /// IE
+    pub interrupts_enabled: EnumSet<InterruptKind>,
+    /// IF
+    pub interrupts_requested: EnumSet<InterruptKind>,
+    pub ppu: PpuState,
+    pub timer: TimerState,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct PpuState {
+    pub mode: Mode,
+    pub ly: u8,
+    pub lcdc: u8,
+    pub stat: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct TimerState {
+    pub div: u8,
+    pub tima: u8,
+    pub tma: u8,
+    pub tac: u8,
+}
+
+/// The raw values of the IO registers that debuggers show in a register panel, as the CPU would read them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct IoRegisters {
+    /// P1/JOYP: the selected button group in bits 4-5, and the pressed buttons in it
+    pub joyp: u8,
+    pub div: u8,
+    pub tima: u8,
+    pub tma: u8,
+    pub tac: u8,
+    /// IF
+    pub interrupts_requested: u8,
+    /// IE
+    pub interrupts_enabled: u8,
+    pub lcdc: u8,
+    pub stat: u8,
+    pub scy: u8,
+    pub scx: u8,
+    pub ly: u8,
+    pub lyc: u8,
+    pub wy: u8,
+    pub wx: u8,
+    pub bgp: u8,
+    pub obp0: u8,
+    pub obp1: u8,
+}
+
+impl Emulator {
+    pub fn debug_state(&self) -> DebugState {
+        let cpu = &self.cpu;
+        let mmu = &cpu.mmu;
+        let read = |addr| mmu.read_byte_unobserved(addr);
+        DebugState {
+            regs: cpu.regs,
+            ime: cpu.ime,
+            halted: cpu.is_halted,
+            stopped: cpu.is_stopped,
+            interrupts_enabled: mmu.interrupts_enabled(),
+            interrupts_requested: mmu.interrupts_requested(),
+            ppu: PpuState {
+                mode: mmu.ppu.mode,
+                ly: mmu.ppu.ly(),
+                lcdc: read(0xFF40),
+                stat: read(0xFF41),
+            },
+            timer: TimerState {
+                div: read(0xFF04),
+                tima: read(0xFF05),
+                tma: read(0xFF06),
+                tac: read(0xFF07),
+            },
+        }
+    }
+
+    /// A snapshot of the IO registers. Like [`Emulator::read_memory`], this isn't seen by memory hooks or bus
+    /// subscriptions.
+    pub fn io_registers(&self) -> IoRegisters {
+        let read = |addr| self.cpu.mmu.read_byte_unobserved(addr);
+        IoRegisters {
+            joyp: read(0xFF00),
+            div: read(0xFF04),
+            tima: read(0xFF05),
+            tma: read(0xFF06),
+            tac: read(0xFF07),
+            interrupts_requested: read(0xFF0F),
+            interrupts_enabled: read(0xFFFF),
+            lcdc: read(0xFF40),
+            stat: read(0xFF41),
+            scy: read(0xFF42),
+            scx: read(0xFF43),
+            ly: read(0xFF44),
+            lyc: read(0xFF45),
+            wy: read(0xFF4A),
+            wx: read(0xFF4B),
+            bgp: read(0xFF47),
+            obp0: read(0xFF48),
+            obp1: read(0xFF49),
+        }
+    }
+
+    /// Overwrite the CPU registers, e.g. from a debugger. The low nibble of F is always 0, so it's ignored.
+    pub fn set_registers(&mut self, regs: Registers) {
+        self.cpu.regs = Registers {
+            f: regs.f & 0xF0,
+            ..regs
+        };
+    }
+
+    /// Read `addr` for a debugger, without advancing the hardware or being seen by memory hooks or bus
+    /// subscriptions. Unlike the CPU, this can read VRAM and OAM while the PPU or an OAM DMA transfer is using them.
+    pub fn read_memory(&self, addr: u16) -> u8 {
+        self.cpu.mmu.read_byte_unobserved(addr)
+    }
+
+    /// Read `len` bytes from `addr` on like [`Emulator::read_memory`], wrapping around at the end of the address space.
+    pub fn read_range(&self, addr: u16, len: usize) -> Vec<u8> {
+        (0..len)
+            .map(|offset| self.read_memory(addr.wrapping_add(offset as u16)))
+            .collect()
+    }
+
+    /// Write the 64 KiB address space as [`Emulator::read_memory`] sees it to `path`, for post-mortem analysis and
+    /// diffing. It's followed by each VRAM bank (one on a DMG, two on a CGB) regardless of the bank mapped, and by the battery backed cartridge RAM, if any, in the format of [`Emulator::battery_save`].
+    pub fn dump_memory(&self, path: &Path) -> std::io::Result<()> {
+        let mut dump = self.read_range(0x0000, 0x10000);
+        let vram_banks = if self.model().is_cgb() { 2 } else { 1 };
+        for bank in 0..vram_banks {
+            dump.extend(
+                (0x8000..=0x9FFF).map(|addr| self.cpu.mmu.ppu.read_vram_bank_byte(bank, addr)),
+            );
+        }
+        dump.extend(self.battery_save().unwrap_or_default());
+        std::fs::write(path, dump)
+    }
+
+    /// Write `byte` to `addr` like the CPU would, including the side effects of writing to IO registers.
+    pub fn write_memory(&mut self, addr: u16, byte: u8) {
+        self.cpu.mmu.write_byte(addr, byte);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::ImeState;
+    use crate::mmu::InterruptKind;
+    use crate::util::{emulator_for, emulator_running, rom_with_program};
+    use crate::Mode;
+
+    #[test]
+    fn snapshot_of_the_cpu_and_io_registers() {
+        let mut emu = emulator_running(&[
+            0x3E, 0x91, // LD A,0x91
+            0xE0, 0x40, // LDH [0x40],A   (turn on the LCD)
+            0x18, 0xFE, // JR -2
+        ]);
+        emu.write_memory(0xFF06, 0xAB);
+        emu.write_memory(0xFFFF, 0x01);
+        for _ in 0..3 {
+            emu.step().unwrap();
+        }
+
+        let state = emu.debug_state();
+        assert_eq!((state.regs.a, state.regs.pc), (0x91, 0x0004));
+        assert_eq!(state.ime, ImeState::Disabled);
+        assert!(!state.halted);
+        assert_eq!(state.interrupts_enabled, InterruptKind::Vblank);
+        assert_eq!(state.ppu.lcdc, 0x91);
+        assert_eq!(state.ppu.mode, Mode::ScanlineOAM);
+        assert_eq!(state.timer.tma, 0xAB);
+        assert_eq!(emu.read_memory(0xFF06), 0xAB);
+        let io = emu.io_registers();
+        assert_eq!((io.lcdc, io.tma, io.interrupts_enabled), (0x91, 0xAB, 0x01));
+        assert_eq!(io.stat & 0x03, 2);
+
+        let mut regs = state.regs;
+        regs.f = 0xFF;
+        emu.set_registers(regs);
+        assert_eq!(emu.debug_state().regs.f, 0xF0);
+        serde_json::to_string(&state).unwrap();
+    }
+
+    #[test]
+    fn read_range_and_dump_memory() {
+        let rom = rom_with_program(&[]);
+        let mut emu = emulator_for(&rom);
+        emu.write_memory(0xFFFF, 0x1F);
+        emu.write_memory(0xC000, 0xAB);
+        assert_eq!(emu.read_range(0xFFFF, 2), [0x1F, 0x00]);
+        assert_eq!(emu.read_range(0xC000, 1), [0xAB]);
+
+        let path = std::env::temp_dir().join(format!("gbrs-dump-{}.bin", std::process::id()));
+        emu.dump_memory(&path).unwrap();
+        let dump = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(dump.len(), 0x10000 + 0x2000);
+        assert_eq!(dump[0xC000], 0xAB);
+        assert_eq!(dump[..0x8000], rom[..]);
+    }
+}
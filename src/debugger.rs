@@ -0,0 +1,290 @@
+//! Conditions for breakpoints, written in a small expression language, e.g. `A == 0x3E && (HL) > 0xC0`.
+//!
+//! Operands are registers (`A`, `F`, `BC`, `HL`, `SP`, `PC`, ...), numbers in hex (`0x3E` or `$3E`) or decimal, and
+//! the byte at an address in parentheses or brackets (`(HL)`, `[0xC000]`). Operands are compared with `==`, `!=`,
+//! `<`, `<=`, `>`, and `>=`, and comparisons are combined with `&&` and `||`, grouped with parentheses.
+//! Like in Rust, `&&` binds tighter than `||`.
+use std::fmt::Display;
+use std::str::FromStr;
+
+use crate::cpu::instruction::{R16, R8};
+use crate::cpu::Registers;
+use crate::mmu::Memory;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Operand {
+    R8(R8),
+    F,
+    R16(R16),
+    PC,
+    Number(u16),
+    /// The byte at the address
+    Memory(Box<Operand>),
+}
+
+impl Operand {
+    fn value(&self, regs: &Registers, memory: &impl Memory) -> u16 {
+        match self {
+            Operand::R8(reg) => regs.r8(*reg) as u16,
+            Operand::F => regs.f as u16,
+            Operand::R16(reg) => regs.r16(*reg),
+            Operand::PC => regs.pc,
+            Operand::Number(value) => *value,
+            Operand::Memory(addr) => memory.read_byte_unobserved(addr.value(regs, memory)) as u16,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Condition {
+    Compare(Operand, Comparison, Operand),
+    And(Box<Condition>, Box<Condition>),
+    Or(Box<Condition>, Box<Condition>),
+}
+
+impl Condition {
+    pub fn evaluate(&self, regs: &Registers, memory: &impl Memory) -> bool {
+        match self {
+            Condition::Compare(lhs, comparison, rhs) => {
+                let (lhs, rhs) = (lhs.value(regs, memory), rhs.value(regs, memory));
+                match comparison {
+                    Comparison::Eq => lhs == rhs,
+                    Comparison::Ne => lhs != rhs,
+                    Comparison::Lt => lhs < rhs,
+                    Comparison::Le => lhs <= rhs,
+                    Comparison::Gt => lhs > rhs,
+                    Comparison::Ge => lhs >= rhs,
+                }
+            }
+            Condition::And(lhs, rhs) => lhs.evaluate(regs, memory) && rhs.evaluate(regs, memory),
+            Condition::Or(lhs, rhs) => lhs.evaluate(regs, memory) || rhs.evaluate(regs, memory),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseConditionError {
+    UnexpectedChar(char),
+    UnexpectedToken(String),
+    UnexpectedEnd,
+    /// Not a register, or a number that fits in 16 bits
+    InvalidOperand(String),
+}
+
+impl Display for ParseConditionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseConditionError::UnexpectedChar(c) => write!(f, "Unexpected character {c:?}"),
+            ParseConditionError::UnexpectedToken(token) => write!(f, "Unexpected {token:?}"),
+            ParseConditionError::UnexpectedEnd => write!(f, "The condition ends too early"),
+            ParseConditionError::InvalidOperand(operand) => {
+                write!(f, "{operand:?} is neither a register nor a 16 bit number")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseConditionError {}
+
+impl FromStr for Condition {
+    type Err = ParseConditionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser {
+            tokens: tokenize(s)?,
+            pos: 0,
+        };
+        let condition = parser.or()?;
+        match parser.next() {
+            None => Ok(condition),
+            Some(token) => Err(ParseConditionError::UnexpectedToken(token.to_string())),
+        }
+    }
+}
+
+const SYMBOLS: [&str; 12] = [
+    "&&", "||", "==", "!=", "<=", ">=", "<", ">", "(", ")", "[", "]",
+];
+
+/// Split `s` into symbols and words, like `HL` or `0x3E`
+fn tokenize(s: &str) -> Result<Vec<&str>, ParseConditionError> {
+    let mut tokens = Vec::new();
+    let mut rest = s.trim_start();
+    while let Some(c) = rest.chars().next() {
+        let len = match SYMBOLS.iter().find(|symbol| rest.starts_with(*symbol)) {
+            Some(symbol) => symbol.len(),
+            None if c.is_ascii_alphanumeric() || c == '$' => rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '$'))
+                .unwrap_or(rest.len()),
+            None => return Err(ParseConditionError::UnexpectedChar(c)),
+        };
+        let (token, after) = rest.split_at(len);
+        tokens.push(token);
+        rest = after.trim_start();
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: Vec<&'a str>,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self, offset: usize) -> Option<&'a str> {
+        self.tokens.get(self.pos + offset).copied()
+    }
+
+    fn next(&mut self) -> Option<&'a str> {
+        let token = self.peek(0);
+        self.pos += 1;
+        token
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), ParseConditionError> {
+        match self.next() {
+            Some(token) if token == expected => Ok(()),
+            Some(token) => Err(ParseConditionError::UnexpectedToken(token.to_string())),
+            None => Err(ParseConditionError::UnexpectedEnd),
+        }
+    }
+
+    fn or(&mut self) -> Result<Condition, ParseConditionError> {
+        let mut condition = self.and()?;
+        while self.peek(0) == Some("||") {
+            self.pos += 1;
+            condition = Condition::Or(Box::new(condition), Box::new(self.and()?));
+        }
+        Ok(condition)
+    }
+
+    fn and(&mut self) -> Result<Condition, ParseConditionError> {
+        let mut condition = self.comparison()?;
+        while self.peek(0) == Some("&&") {
+            self.pos += 1;
+            condition = Condition::And(Box::new(condition), Box::new(self.comparison()?));
+        }
+        Ok(condition)
+    }
+
+    /// A comparison, or a condition in parentheses. `(HL)` is a memory operand rather than a group, since a group
+    /// contains at least a comparison.
+    fn comparison(&mut self) -> Result<Condition, ParseConditionError> {
+        if self.peek(0) == Some("(") && self.peek(2) != Some(")") {
+            self.pos += 1;
+            let condition = self.or()?;
+            self.expect(")")?;
+            return Ok(condition);
+        }
+        let lhs = self.operand()?;
+        let comparison = match self.next() {
+            Some("==") => Comparison::Eq,
+            Some("!=") => Comparison::Ne,
+            Some("<") => Comparison::Lt,
+            Some("<=") => Comparison::Le,
+            Some(">") => Comparison::Gt,
+            Some(">=") => Comparison::Ge,
+            Some(token) => return Err(ParseConditionError::UnexpectedToken(token.to_string())),
+            None => return Err(ParseConditionError::UnexpectedEnd),
+        };
+        Ok(Condition::Compare(lhs, comparison, self.operand()?))
+    }
+
+    fn operand(&mut self) -> Result<Operand, ParseConditionError> {
+        match self.next() {
+            Some(open @ ("(" | "[")) => {
+                let addr = self.operand()?;
+                self.expect(if open == "(" { ")" } else { "]" })?;
+                Ok(Operand::Memory(Box::new(addr)))
+            }
+            Some(word) => parse_word(word),
+            None => Err(ParseConditionError::UnexpectedEnd),
+        }
+    }
+}
+
+fn parse_word(word: &str) -> Result<Operand, ParseConditionError> {
+    let operand = match word.to_ascii_uppercase().as_str() {
+        "A" => Operand::R8(R8::A),
+        "F" => Operand::F,
+        "B" => Operand::R8(R8::B),
+        "C" => Operand::R8(R8::C),
+        "D" => Operand::R8(R8::D),
+        "E" => Operand::R8(R8::E),
+        "H" => Operand::R8(R8::H),
+        "L" => Operand::R8(R8::L),
+        "AF" => Operand::R16(R16::AF),
+        "BC" => Operand::R16(R16::BC),
+        "DE" => Operand::R16(R16::DE),
+        "HL" => Operand::R16(R16::HL),
+        "SP" => Operand::R16(R16::SP),
+        "PC" => Operand::PC,
+        upper => {
+            let number = if let Some(hex) = upper.strip_prefix("0X").or(upper.strip_prefix('$')) {
+                u16::from_str_radix(hex, 16)
+            } else {
+                upper.parse()
+            };
+            Operand::Number(
+                number.map_err(|_| ParseConditionError::InvalidOperand(word.to_string()))?,
+            )
+        }
+    };
+    Ok(operand)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Condition, ParseConditionError};
+    use crate::mmu::Memory;
+    use crate::util::emulator_running;
+
+    #[test]
+    fn evaluates_registers_and_memory() {
+        let mut emu = emulator_running(&[]);
+        emu.cpu.regs.a = 0x3E;
+        emu.cpu.regs.b = 0x01;
+        emu.cpu.regs.set_hl(0xC010);
+        emu.cpu.mmu.write_byte(0xC010, 0x80);
+        let eval = |condition: &str| {
+            condition
+                .parse::<Condition>()
+                .unwrap()
+                .evaluate(&emu.cpu.regs, &emu.cpu.mmu)
+        };
+        assert!(eval("A == 0x3E && (HL) > 0x7F"));
+        assert!(eval("a == $3e && [hl] >= 128"));
+        assert!(!eval("A == 0x3E && (HL) < 0x80"));
+        // && binds tighter than ||
+        assert!(eval("A == 0 && B == 0 || HL == 0xC010"));
+        assert!(!eval("A == 0 && (B == 0 || HL == 0xC010)"));
+        assert!(eval("([0xC010] == 0x80)"));
+
+        assert_eq!(
+            "A == 0x10000".parse::<Condition>(),
+            Err(ParseConditionError::InvalidOperand("0x10000".to_string()))
+        );
+        assert_eq!(
+            "A ==".parse::<Condition>(),
+            Err(ParseConditionError::UnexpectedEnd)
+        );
+        assert_eq!(
+            "A == 1 B".parse::<Condition>(),
+            Err(ParseConditionError::UnexpectedToken("B".to_string()))
+        );
+        assert_eq!(
+            "A = 1".parse::<Condition>(),
+            Err(ParseConditionError::UnexpectedChar('='))
+        );
+    }
+}
@@ -0,0 +1,378 @@
+//! Decoding of instructions in memory into RGBDS syntax, for debuggers and traces.
+use std::fmt::Display;
+
+use crate::cpu::instruction::{decode, HLIncOrDec, HlOrReg8, ImmOrR8, Instruction, Operand, R16};
+use crate::cpu::Registers;
+use crate::mmu::Memory;
+use crate::symbols::SymbolTable;
+use crate::Emulator;
+
+/// An instruction in memory, with its operands resolved. See [`Emulator::describe_instruction`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstructionDescription {
+    pub addr: u16,
+    /// The opcode and its immediate operands
+    pub bytes: Vec<u8>,
+    /// The instruction in RGBDS syntax, with immediates and relative jumps resolved, e.g. `JR NZ,$0150`
+    pub text: String,
+    /// Where a jump, call, return, or RST goes. Conditional instructions report where they go if the condition holds.
+    pub target: Option<u16>,
+    /// The symbol at `target`, if symbols were loaded with [`Emulator::load_symbols`]
+    pub target_symbol: Option<String>,
+    /// The address accessed through a memory operand, e.g. the value of HL for `LD A,[HL]`, and the byte currently at
+    /// that address. The byte isn't read from IO registers, where reads can be unsupported.
+    pub memory_operand: Option<(u16, Option<u8>)>,
+}
+
+impl Display for InstructionDescription {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.text)?;
+        if let Some(symbol) = &self.target_symbol {
+            write!(f, " <{symbol}>")?;
+        }
+        match self.memory_operand {
+            Some((addr, Some(byte))) => write!(f, "  ; [${addr:04X}]=${byte:02X}"),
+            Some((addr, None)) => write!(f, "  ; [${addr:04X}]"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// How an instruction accesses memory, before it's resolved against the registers
+enum MemoryOperand {
+    Hl,
+    Bc,
+    De,
+    Sp,
+    /// `[$FF00+C]`
+    HighC,
+    Absolute(u16),
+}
+
+/// An instruction's text, and the parts that depend on the registers
+struct Decoded {
+    text: String,
+    target: Option<Target>,
+    memory: Option<MemoryOperand>,
+}
+
+enum Target {
+    Absolute(u16),
+    Hl,
+    /// The return address on top of the stack
+    Stack,
+}
+
+fn target_text(target: HlOrReg8) -> String {
+    match target {
+        HlOrReg8::HL => "[HL]".into(),
+        HlOrReg8::Reg(r) => format!("{r:?}"),
+    }
+}
+
+fn target_memory(target: HlOrReg8) -> Option<MemoryOperand> {
+    (target == HlOrReg8::HL).then_some(MemoryOperand::Hl)
+}
+
+fn operand_text(operand: Operand) -> String {
+    match operand {
+        Operand::Reg(r) => format!("{r:?}"),
+        Operand::Imm(n8) => format!("${n8:02X}"),
+        Operand::HL => "[HL]".into(),
+    }
+}
+
+fn operand_memory(operand: Operand) -> Option<MemoryOperand> {
+    (operand == Operand::HL).then_some(MemoryOperand::Hl)
+}
+
+/// Format `instruction`, located at `addr`, in RGBDS syntax.
+fn format_instruction(addr: u16, instruction: Instruction) -> Decoded {
+    use Instruction::*;
+    let simple = |text: String| Decoded {
+        text,
+        target: None,
+        memory: None,
+    };
+    let with_memory = |text: String, memory: Option<MemoryOperand>| Decoded {
+        text,
+        target: None,
+        memory,
+    };
+    let jump = |text: String, target: Target| Decoded {
+        text,
+        target: Some(target),
+        memory: None,
+    };
+    let relative = |e8: i8| addr.wrapping_add(2).wrapping_add_signed(e8 as i16);
+    let r16_memory = |r16: R16| match r16 {
+        R16::BC => MemoryOperand::Bc,
+        _ => MemoryOperand::De,
+    };
+    let hl_inc_or_dec = |hl: HLIncOrDec| match hl {
+        HLIncOrDec::HLI => "[HL+]",
+        HLIncOrDec::HLD => "[HL-]",
+    };
+    match instruction {
+        ADC_A(operand) | ADD_A(operand) | AND_A(operand) | CP_A(operand) | OR_A(operand)
+        | SBC_A(operand) | SUB_A(operand) | XOR_A(operand) => {
+            let name = match instruction {
+                ADC_A(_) => "ADC",
+                ADD_A(_) => "ADD",
+                AND_A(_) => "AND",
+                CP_A(_) => "CP",
+                OR_A(_) => "OR",
+                SBC_A(_) => "SBC",
+                SUB_A(_) => "SUB",
+                _ => "XOR",
+            };
+            with_memory(
+                format!("{name} A,{}", operand_text(operand)),
+                operand_memory(operand),
+            )
+        }
+        DEC(target) | INC(target) | SWAP(target) | RL(target) | RLC(target) | RR(target)
+        | RRC(target) | SLA(target) | SRA(target) | SRL(target) => {
+            let name = match instruction {
+                DEC(_) => "DEC",
+                INC(_) => "INC",
+                SWAP(_) => "SWAP",
+                RL(_) => "RL",
+                RLC(_) => "RLC",
+                RR(_) => "RR",
+                RRC(_) => "RRC",
+                SLA(_) => "SLA",
+                SRA(_) => "SRA",
+                _ => "SRL",
+            };
+            with_memory(
+                format!("{name} {}", target_text(target)),
+                target_memory(target),
+            )
+        }
+        BIT(bit, target) | RES(bit, target) | SET(bit, target) => {
+            let name = match instruction {
+                BIT(..) => "BIT",
+                RES(..) => "RES",
+                _ => "SET",
+            };
+            with_memory(
+                format!("{name} {},{}", bit.value(), target_text(target)),
+                target_memory(target),
+            )
+        }
+        ADD_HL(r16) => simple(format!("ADD HL,{r16:?}")),
+        DEC16(r16) => simple(format!("DEC {r16:?}")),
+        INC16(r16) => simple(format!("INC {r16:?}")),
+        LD_R8(r8, operand) => with_memory(
+            format!("LD {r8:?},{}", operand_text(operand)),
+            operand_memory(operand),
+        ),
+        LD_HL(ImmOrR8::Reg(r8)) => with_memory(format!("LD [HL],{r8:?}"), Some(MemoryOperand::Hl)),
+        LD_HL(ImmOrR8::N8(n8)) => {
+            with_memory(format!("LD [HL],${n8:02X}"), Some(MemoryOperand::Hl))
+        }
+        LD_R16_N16(r16, n16) => simple(format!("LD {r16:?},${n16:04X}")),
+        LD_ADDR_R16(r16) => with_memory(format!("LD [{r16:?}],A"), Some(r16_memory(r16))),
+        LD_A_ADDR_R16(r16) => with_memory(format!("LD A,[{r16:?}]"), Some(r16_memory(r16))),
+        LD_ADDR_N16(n16) => with_memory(
+            format!("LD [${n16:04X}],A"),
+            Some(MemoryOperand::Absolute(n16)),
+        ),
+        LD_A_ADDR_N16(n16) => with_memory(
+            format!("LD A,[${n16:04X}]"),
+            Some(MemoryOperand::Absolute(n16)),
+        ),
+        LDH_N16_A(n16) => with_memory(
+            format!("LDH [${n16:04X}],A"),
+            Some(MemoryOperand::Absolute(n16)),
+        ),
+        LDH_A_N16(n16) => with_memory(
+            format!("LDH A,[${n16:04X}]"),
+            Some(MemoryOperand::Absolute(n16)),
+        ),
+        LDH_C_A => with_memory("LDH [C],A".into(), Some(MemoryOperand::HighC)),
+        LDH_A_C => with_memory("LDH A,[C]".into(), Some(MemoryOperand::HighC)),
+        LD_HL_A(hl) => with_memory(
+            format!("LD {},A", hl_inc_or_dec(hl)),
+            Some(MemoryOperand::Hl),
+        ),
+        LD_A_HL(hl) => with_memory(
+            format!("LD A,{}", hl_inc_or_dec(hl)),
+            Some(MemoryOperand::Hl),
+        ),
+        CALL(n16) => jump(format!("CALL ${n16:04X}"), Target::Absolute(n16)),
+        CALL_CC(cc, n16) => jump(format!("CALL {cc:?},${n16:04X}"), Target::Absolute(n16)),
+        JP_HL => jump("JP HL".into(), Target::Hl),
+        JP_N16(n16) => jump(format!("JP ${n16:04X}"), Target::Absolute(n16)),
+        JP_CC_N16(cc, n16) => jump(format!("JP {cc:?},${n16:04X}"), Target::Absolute(n16)),
+        JR(e8) => jump(
+            format!("JR ${:04X}", relative(e8)),
+            Target::Absolute(relative(e8)),
+        ),
+        JR_CC(cc, e8) => jump(
+            format!("JR {cc:?},${:04X}", relative(e8)),
+            Target::Absolute(relative(e8)),
+        ),
+        RET_CC(cc) => jump(format!("RET {cc:?}"), Target::Stack),
+        RET => jump("RET".into(), Target::Stack),
+        RETI => jump("RETI".into(), Target::Stack),
+        RST(vector) => {
+            let vector = vector as u16;
+            jump(format!("RST ${vector:02X}"), Target::Absolute(vector))
+        }
+        ADD_SP(e8) => simple(format!("ADD SP,{e8}")),
+        LD_ADDR_N16_SP(n16) => with_memory(
+            format!("LD [${n16:04X}],SP"),
+            Some(MemoryOperand::Absolute(n16)),
+        ),
+        LD_HL_SP_E8(e8) => simple(format!("LD HL,SP{e8:+}")),
+        LD_SP_HL => simple("LD SP,HL".into()),
+        POP_R16(r16) => with_memory(format!("POP {r16:?}"), Some(MemoryOperand::Sp)),
+        PUSH_R16(r16) => with_memory(format!("PUSH {r16:?}"), Some(MemoryOperand::Sp)),
+        ILLEGAL(opcode) => simple(format!("DB ${opcode:02X}")),
+        RLA | RLCA | RRA | RRCA | CCF | CPL | DAA | DI | EI | HALT | NOP | SCF | STOP => {
+            simple(format!("{instruction:?}"))
+        }
+    }
+}
+
+impl Emulator {
+    /// Use `symbols` to name the targets of jumps and calls in [`Emulator::describe_instruction`].
+    pub fn load_symbols(&mut self, symbols: SymbolTable) {
+        self.symbols = Some(symbols);
+    }
+
+    pub fn symbols(&self) -> Option<&SymbolTable> {
+        self.symbols.as_ref()
+    }
+
+    /// Decode the instruction at `addr`.
+    ///
+    /// Operands that depend on registers, like `[HL]`, `JP HL`, and the return address of `RET`, are resolved with the
+    /// current registers, so they're only meaningful for the instruction at PC.
+    pub fn describe_instruction(&self, addr: u16) -> InstructionDescription {
+        self.describe_instruction_with(addr, &self.cpu.regs)
+    }
+
+    /// Like [`Emulator::describe_instruction`], but resolve the operands with `regs`, e.g. the registers of a trace
+    /// entry.
+    pub(crate) fn describe_instruction_with(
+        &self,
+        addr: u16,
+        regs: &Registers,
+    ) -> InstructionDescription {
+        let mut bytes = [0; 3];
+        for (offset, byte) in (0..).zip(&mut bytes) {
+            *byte = self.peek(addr.wrapping_add(offset)).unwrap_or(0xFF);
+        }
+        let instruction = decode(bytes[0], [bytes[1], bytes[2]]);
+        let decoded = format_instruction(addr, instruction);
+        let target = decoded.target.map(|target| match target {
+            Target::Absolute(target) => target,
+            Target::Hl => regs.hl(),
+            Target::Stack => u16::from_le_bytes([
+                self.peek(regs.sp).unwrap_or(0xFF),
+                self.peek(regs.sp.wrapping_add(1)).unwrap_or(0xFF),
+            ]),
+        });
+        let memory_addr = decoded.memory.map(|memory| match memory {
+            MemoryOperand::Hl => regs.hl(),
+            MemoryOperand::Bc => u16::from_be_bytes([regs.b, regs.c]),
+            MemoryOperand::De => u16::from_be_bytes([regs.d, regs.e]),
+            MemoryOperand::Sp => regs.sp,
+            MemoryOperand::HighC => 0xFF00 | regs.c as u16,
+            MemoryOperand::Absolute(addr) => addr,
+        });
+        InstructionDescription {
+            addr,
+            bytes: bytes[..instruction.size() as usize].to_vec(),
+            text: decoded.text,
+            target,
+            target_symbol: target.and_then(|target| {
+                let symbols = self.symbols.as_ref()?;
+                let label = symbols.lookup(target, self.cpu.mmu.rom_bank())?;
+                Some(label.to_string())
+            }),
+            memory_operand: memory_addr.map(|addr| (addr, self.peek(addr))),
+        }
+    }
+
+    /// Decode `count` consecutive instructions, starting at `addr`.
+    pub fn describe_instructions(&self, addr: u16, count: usize) -> Vec<InstructionDescription> {
+        let mut addr = addr;
+        (0..count)
+            .map(|_| {
+                let description = self.describe_instruction(addr);
+                addr = addr.wrapping_add(description.bytes.len() as u16);
+                description
+            })
+            .collect()
+    }
+
+    /// Read a byte for display, skipping the unusable area and the IO registers, which never hold instructions.
+    fn peek(&self, addr: u16) -> Option<u8> {
+        (!(0xFEA0..=0xFF7F).contains(&addr)).then(|| self.cpu.mmu.read_byte_unobserved(addr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::format_instruction;
+    use crate::mmu::Memory;
+    use crate::util::emulator_running;
+
+    fn decode(addr: u16, bytes: [u8; 3]) -> String {
+        let instruction = crate::cpu::decode(bytes[0], [bytes[1], bytes[2]]);
+        format_instruction(addr, instruction).text
+    }
+
+    #[test]
+    fn decode_every_opcode() {
+        let texts: Vec<String> = (0..=0xFF)
+            .map(|opcode| decode(0x0150, [opcode, 0xFE, 0x12]))
+            .collect();
+        assert_eq!(texts[0x00], "NOP");
+        assert_eq!(texts[0x08], "LD [$12FE],SP");
+        assert_eq!(texts[0x18], "JR $0150");
+        assert_eq!(texts[0x20], "JR NZ,$0150");
+        assert_eq!(texts[0x22], "LD [HL+],A");
+        assert_eq!(texts[0x3A], "LD A,[HL-]");
+        assert_eq!(texts[0x36], "LD [HL],$FE");
+        assert_eq!(texts[0x76], "HALT");
+        assert_eq!(texts[0x7E], "LD A,[HL]");
+        assert_eq!(texts[0x9F], "SBC A,A");
+        assert_eq!(texts[0xC4], "CALL NZ,$12FE");
+        assert_eq!(texts[0xE0], "LDH [$FFFE],A");
+        assert_eq!(texts[0xE2], "LDH [C],A");
+        assert_eq!(texts[0xE8], "ADD SP,-2");
+        assert_eq!(texts[0xF8], "LD HL,SP-2");
+        assert_eq!(texts[0xD3], "DB $D3");
+        assert_eq!(texts[0xFF], "RST $38");
+        assert_eq!(decode(0, [0xCB, 0x7E, 0]), "BIT 7,[HL]");
+        assert_eq!(decode(0, [0xCB, 0x37, 0]), "SWAP A");
+    }
+
+    #[test]
+    fn describe_resolves_operands() {
+        let mut emu = emulator_running(&[
+            0x21, 0x23, 0xC1, // LD HL,$C123
+            0x7E, // 0x0003: LD A,[HL]
+            0xCD, 0x50, 0x01, // CALL $0150
+            0xE9, // JP HL
+        ]);
+        emu.load_symbols("00:0150 Main\n".parse().unwrap());
+        emu.cpu.mmu.write_byte(0xC123, 0x05);
+        emu.step().unwrap();
+
+        let [load, call, jump] = emu.describe_instructions(0x0003, 3).try_into().unwrap();
+        assert_eq!(load.memory_operand, Some((0xC123, Some(0x05))));
+        assert_eq!(load.to_string(), "LD A,[HL]  ; [$C123]=$05");
+        assert_eq!(call.addr, 0x0004);
+        assert_eq!(call.bytes, [0xCD, 0x50, 0x01]);
+        assert_eq!(call.target, Some(0x0150));
+        assert_eq!(call.to_string(), "CALL $0150 <Main>");
+        assert_eq!(jump.target, Some(0xC123));
+        assert_eq!(jump.target_symbol, None);
+    }
+}
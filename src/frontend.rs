@@ -0,0 +1,239 @@
+use std::fmt::Write;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use clap::ValueEnum;
+
+use gbrs::model::{DmgRevision, HardwareModel};
+use gbrs::palette::ShadePalette;
+use gbrs::watchdog::Lockup;
+use gbrs::Color;
+
+#[cfg(feature = "gdb")]
+pub mod gdb;
+pub mod headless;
+#[cfg(feature = "sdl")]
+pub mod input;
+pub mod link;
+pub mod lockstep;
+#[cfg(feature = "sdl")]
+pub mod sdl;
+pub mod snapshot;
+
+/// The hardware models that can be chosen with `--model`
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Model {
+    /// The original Game Boy, of the revision chosen with `--dmg-revision`
+    Dmg,
+    /// Game Boy Color. DMG-only ROMs are colorized like on real hardware
+    Cgb,
+}
+
+/// A builder for the model, mapper, and boot options chosen on the command line. Those that are `None` are picked
+/// from the cartridge header.
+pub fn emulator_builder(
+    model: Option<Model>,
+    dmg_revision: DmgRevision,
+    mapper: Option<gbrs::Mapper>,
+    skip_boot: bool,
+) -> gbrs::EmulatorBuilder {
+    let builder = gbrs::EmulatorBuilder::new()
+        .dmg_revision(dmg_revision)
+        .skip_boot_rom(skip_boot);
+    let builder = match model {
+        Some(Model::Dmg) => builder.model(HardwareModel::Dmg(dmg_revision)),
+        Some(Model::Cgb) => builder.model(HardwareModel::Cgb),
+        None => builder,
+    };
+    match mapper {
+        Some(mapper) => builder.mapper(mapper),
+        None => builder,
+    }
+}
+
+/// The renderer chosen with `--scanline-renderer`
+pub fn renderer(scanline: bool) -> gbrs::ppu::Renderer {
+    if scanline {
+        gbrs::ppu::Renderer::Scanline
+    } else {
+        gbrs::ppu::Renderer::PixelFifo
+    }
+}
+
+/// Plug the link cable into another instance of gbrs over TCP, when `--link-listen` or `--link-connect` is given.
+pub fn connect_link(
+    emu: &mut gbrs::Emulator,
+    listen: Option<u16>,
+    connect: Option<&str>,
+) -> std::io::Result<()> {
+    let link = match (listen, connect) {
+        (Some(port), _) => link::TcpLink::listen(port)?,
+        (None, Some(addr)) => link::TcpLink::connect(addr)?,
+        (None, None) => return Ok(()),
+    };
+    emu.connect_serial(Box::new(link));
+    Ok(())
+}
+
+/// Apply the IPS or BPS patch at `patch_path`, if there is one, when the ROM is loaded.
+pub fn with_patch(
+    builder: gbrs::EmulatorBuilder,
+    patch_path: Option<&Path>,
+) -> Result<gbrs::EmulatorBuilder, Box<dyn std::error::Error>> {
+    let Some(patch_path) = patch_path else {
+        return Ok(builder);
+    };
+    let patch =
+        std::fs::read(patch_path).context(format!("Unable to read patch: {:?}", patch_path))?;
+    Ok(builder.patch(patch))
+}
+
+/// Read the boot ROM at `boot_rom_path`, if one was given, for `builder` to run before the cartridge.
+pub fn with_boot_rom(
+    builder: gbrs::EmulatorBuilder,
+    boot_rom_path: Option<&Path>,
+) -> Result<gbrs::EmulatorBuilder, Box<dyn std::error::Error>> {
+    let Some(boot_rom_path) = boot_rom_path else {
+        return Ok(builder);
+    };
+    let boot_rom = std::fs::read(boot_rom_path)
+        .context(format!("Unable to read boot ROM: {:?}", boot_rom_path))?;
+    Ok(builder.boot_rom(boot_rom))
+}
+
+/// Read a movie in the text format of [`gbrs::Movie`].
+pub fn read_movie(path: &Path) -> Result<gbrs::Movie, Box<dyn std::error::Error>> {
+    let movie =
+        std::fs::read_to_string(path).context(format!("Unable to read movie: {:?}", path))?;
+    Ok(movie.parse()?)
+}
+
+/// Write `movie` to `path` in the text format that [`read_movie`] reads.
+#[cfg(feature = "sdl")]
+pub fn write_movie(path: &Path, movie: &gbrs::Movie) -> Result<(), Box<dyn std::error::Error>> {
+    std::fs::write(path, movie.to_string())
+        .context(format!("Unable to write movie: {:?}", path))?;
+    Ok(())
+}
+
+/// Load the ROM at `rom_path` with the configuration in `builder`, optionally restoring the save state at `save_path`.
+pub fn load_emulator(
+    builder: gbrs::EmulatorBuilder,
+    rom_path: &Path,
+    save_path: Option<&Path>,
+) -> Result<gbrs::Emulator, Box<dyn std::error::Error>> {
+    let rom = std::fs::read(rom_path).context(format!("Unable to read ROM: {:?}", rom_path))?;
+    let emu = match save_path {
+        Some(sav_path) => {
+            let sav = std::fs::read(sav_path)
+                .context(format!("Unable to read sav file: {:?}", sav_path))?;
+            builder.load_save_state(&rom, rom_path, sav_path, &sav)?
+        }
+        None => builder
+            .for_rom(&rom, rom_path)
+            .context(format!("Unable to load ROM: {:?}", rom_path))?,
+    };
+    Ok(emu)
+}
+
+/// Write a save state, a debug snapshot, a memory dump, and a report of the CPU state to a new directory in `dir`, for
+/// attaching to bug reports. Returns the path of the new directory.
+pub fn write_diagnostics_bundle(
+    emu: &gbrs::Emulator,
+    dir: &Path,
+    lockup: Option<Lockup>,
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let bundle_dir = dir.join(format!("diagnostics-frame-{}", emu.frame_count()));
+    std::fs::create_dir_all(&bundle_dir).context(format!(
+        "Unable to create diagnostics directory: {:?}",
+        bundle_dir
+    ))?;
+    emu.write_save_state(&bundle_dir.join("state.sav.zst"))?;
+    let image = snapshot::compose(emu);
+    let image: Vec<&[Color]> = image.iter().map(|row| row.as_slice()).collect();
+    write_png(
+        &bundle_dir.join("debug-snapshot.png"),
+        &image,
+        emu.palette(),
+    )?;
+    emu.dump_memory(&bundle_dir.join("memory.bin"))?;
+
+    let mut report = String::new();
+    if let Some(lockup) = lockup {
+        writeln!(
+            report,
+            "Locked up in {:04X}-{:04X} since cycle {}",
+            lockup.lowest_pc, lockup.highest_pc, lockup.since_cycle
+        )?;
+    }
+    let state = emu.debug_state();
+    let regs = &state.regs;
+    writeln!(
+        report,
+        "Model: {:?}\nFrame: {}\nCycle: {}",
+        emu.model(),
+        emu.frame_count(),
+        emu.cycle_count()
+    )?;
+    writeln!(
+        report,
+        "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X}",
+        regs.a, regs.f, regs.b, regs.c, regs.d, regs.e, regs.h, regs.l, regs.sp, regs.pc
+    )?;
+    writeln!(
+        report,
+        "IME: {:?} HALTED: {} IE: {:?} IF: {:?}",
+        state.ime, state.halted, state.interrupts_enabled, state.interrupts_requested
+    )?;
+    let code: Vec<String> = emu
+        .read_range(regs.pc, 16)
+        .iter()
+        .map(|byte| format!("{byte:02X}"))
+        .collect();
+    writeln!(report, "Memory at PC: {}", code.join(" "))?;
+    std::fs::write(bundle_dir.join("report.txt"), report)?;
+    Ok(bundle_dir)
+}
+
+/// Write `image` to a PNG file at `path`, with the shades in the colors of `palette`.
+pub fn write_png(
+    path: &Path,
+    image: &[&[Color]],
+    palette: ShadePalette,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let height = image.len();
+    let width = image.first().map_or(0, |row| row.len());
+    let data: Vec<u8> = image
+        .iter()
+        .flat_map(|row| row.iter().flat_map(|&color| palette[color as usize]))
+        .collect();
+    encode_png(path, width, height, png::ColorType::Rgb, &data)
+}
+
+/// Write the last full frame of `emu` to a PNG file at `path`, in the colors it is displayed in.
+pub fn write_frame_png(
+    path: &Path,
+    emu: &gbrs::Emulator,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut frame = vec![0; gbrs::FRAME_RGBA_LEN];
+    emu.frame_rgba(&mut frame);
+    encode_png(path, 160, 144, png::ColorType::Rgba, &frame)
+}
+
+fn encode_png(
+    path: &Path,
+    width: usize,
+    height: usize,
+    color_type: png::ColorType,
+    data: &[u8],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let file = File::create(path).context(format!("Unable to create PNG file: {:?}", path))?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), width as u32, height as u32);
+    encoder.set_color(color_type);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(data)?;
+    Ok(())
+}
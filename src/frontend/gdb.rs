@@ -0,0 +1,298 @@
+//! A GDB remote protocol server, so that gdb, lldb, and other tools that speak the protocol can debug a game: read
+//! and write registers and memory, set breakpoints, single-step, and continue.
+//!
+//! GDB doesn't know the Game Boy's CPU, so the registers are described in the target description instead: A, F, B,
+//! C, D, E, H, and L as 8 bit registers, followed by SP and PC as 16 bit registers.
+use std::net::TcpListener;
+
+use gdbstub::common::Signal;
+use gdbstub::conn::ConnectionExt;
+use gdbstub::stub::{run_blocking, DisconnectReason, GdbStub, SingleThreadStopReason};
+use gdbstub::target::ext::base::singlethread::{
+    SingleThreadBase, SingleThreadResume, SingleThreadResumeOps, SingleThreadSingleStep,
+    SingleThreadSingleStepOps,
+};
+use gdbstub::target::ext::base::BaseOps;
+use gdbstub::target::ext::breakpoints::{
+    Breakpoints, BreakpointsOps, SwBreakpoint, SwBreakpointOps,
+};
+use gdbstub::target::{Target, TargetResult};
+
+use gbrs::cpu::Registers;
+use gbrs::{Emulator, Stopped};
+
+/// The number of instructions to run between checks for a message from the debugger, e.g. to interrupt execution
+const INSTRUCTIONS_PER_POLL: u32 = 1024;
+
+/// The Game Boy's Sharp SM83 CPU
+enum Sm83 {}
+
+impl gdbstub::arch::Arch for Sm83 {
+    type Usize = u16;
+    type Registers = Sm83Registers;
+    type BreakpointKind = usize;
+    type RegId = ();
+
+    fn target_description_xml() -> Option<&'static str> {
+        Some(
+            r#"<target version="1.0"><feature name="org.gbrs.sm83.core">
+<reg name="a" bitsize="8" regnum="0"/>
+<reg name="f" bitsize="8"/>
+<reg name="b" bitsize="8"/>
+<reg name="c" bitsize="8"/>
+<reg name="d" bitsize="8"/>
+<reg name="e" bitsize="8"/>
+<reg name="h" bitsize="8"/>
+<reg name="l" bitsize="8"/>
+<reg name="sp" bitsize="16" type="data_ptr"/>
+<reg name="pc" bitsize="16" type="code_ptr"/>
+</feature></target>"#,
+        )
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+struct Sm83Registers {
+    /// A, F, B, C, D, E, H, and L
+    r8: [u8; 8],
+    sp: u16,
+    pc: u16,
+}
+
+impl gdbstub::arch::Registers for Sm83Registers {
+    type ProgramCounter = u16;
+
+    fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    fn gdb_serialize(&self, mut write_byte: impl FnMut(Option<u8>)) {
+        let words = self
+            .sp
+            .to_le_bytes()
+            .into_iter()
+            .chain(self.pc.to_le_bytes());
+        for byte in self.r8.into_iter().chain(words) {
+            write_byte(Some(byte));
+        }
+    }
+
+    fn gdb_deserialize(&mut self, bytes: &[u8]) -> Result<(), ()> {
+        let bytes: &[u8; 12] = bytes.try_into().map_err(|_| ())?;
+        self.r8.copy_from_slice(&bytes[..8]);
+        self.sp = u16::from_le_bytes([bytes[8], bytes[9]]);
+        self.pc = u16::from_le_bytes([bytes[10], bytes[11]]);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ExecMode {
+    Step,
+    Continue,
+}
+
+struct GdbTarget {
+    emu: Emulator,
+    exec_mode: ExecMode,
+}
+
+impl GdbTarget {
+    /// Run until the emulator stops, after a single instruction when stepping, or until `incoming_data` returns true.
+    fn run(
+        &mut self,
+        mut incoming_data: impl FnMut() -> bool,
+    ) -> Option<SingleThreadStopReason<u16>> {
+        if self.exec_mode == ExecMode::Step {
+            return Some(self.step_once().unwrap_or(SingleThreadStopReason::DoneStep));
+        }
+        loop {
+            for _ in 0..INSTRUCTIONS_PER_POLL {
+                if let Some(stop_reason) = self.step_once() {
+                    return Some(stop_reason);
+                }
+            }
+            if incoming_data() {
+                return None;
+            }
+        }
+    }
+
+    fn step_once(&mut self) -> Option<SingleThreadStopReason<u16>> {
+        match self.emu.step() {
+            Ok(_) => None,
+            Err(Stopped::Breakpoint(_)) => Some(SingleThreadStopReason::SwBreak(())),
+            Err(Stopped::IllegalOpcode(_)) => Some(SingleThreadStopReason::Signal(Signal::SIGILL)),
+        }
+    }
+}
+
+impl Target for GdbTarget {
+    type Arch = Sm83;
+    type Error = &'static str;
+
+    #[inline(always)]
+    fn base_ops(&mut self) -> BaseOps<'_, Sm83, Self::Error> {
+        BaseOps::SingleThread(self)
+    }
+
+    #[inline(always)]
+    fn support_breakpoints(&mut self) -> Option<BreakpointsOps<'_, Self>> {
+        Some(self)
+    }
+}
+
+impl SingleThreadBase for GdbTarget {
+    fn read_registers(&mut self, regs: &mut Sm83Registers) -> TargetResult<(), Self> {
+        let cpu_regs = self.emu.debug_state().regs;
+        regs.r8 = [
+            cpu_regs.a, cpu_regs.f, cpu_regs.b, cpu_regs.c, cpu_regs.d, cpu_regs.e, cpu_regs.h,
+            cpu_regs.l,
+        ];
+        regs.sp = cpu_regs.sp;
+        regs.pc = cpu_regs.pc;
+        Ok(())
+    }
+
+    fn write_registers(&mut self, regs: &Sm83Registers) -> TargetResult<(), Self> {
+        let [a, f, b, c, d, e, h, l] = regs.r8;
+        self.emu.set_registers(Registers {
+            a,
+            f,
+            b,
+            c,
+            d,
+            e,
+            h,
+            l,
+            sp: regs.sp,
+            pc: regs.pc,
+        });
+        Ok(())
+    }
+
+    fn read_addrs(&mut self, start_addr: u16, data: &mut [u8]) -> TargetResult<usize, Self> {
+        let mut len = 0;
+        for (addr, byte) in (start_addr..=0xFFFF).zip(data.iter_mut()) {
+            *byte = self.emu.read_memory(addr);
+            len += 1;
+        }
+        Ok(len)
+    }
+
+    fn write_addrs(&mut self, start_addr: u16, data: &[u8]) -> TargetResult<(), Self> {
+        for (addr, &byte) in (start_addr..=0xFFFF).zip(data) {
+            self.emu.write_memory(addr, byte);
+        }
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn support_resume(&mut self) -> Option<SingleThreadResumeOps<'_, Self>> {
+        Some(self)
+    }
+}
+
+impl SingleThreadResume for GdbTarget {
+    fn resume(&mut self, signal: Option<Signal>) -> Result<(), Self::Error> {
+        if signal.is_some() {
+            return Err("The Game Boy can't be resumed with a signal");
+        }
+        self.exec_mode = ExecMode::Continue;
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn support_single_step(&mut self) -> Option<SingleThreadSingleStepOps<'_, Self>> {
+        Some(self)
+    }
+}
+
+impl SingleThreadSingleStep for GdbTarget {
+    fn step(&mut self, signal: Option<Signal>) -> Result<(), Self::Error> {
+        if signal.is_some() {
+            return Err("The Game Boy can't be stepped with a signal");
+        }
+        self.exec_mode = ExecMode::Step;
+        Ok(())
+    }
+}
+
+impl Breakpoints for GdbTarget {
+    #[inline(always)]
+    fn support_sw_breakpoint(&mut self) -> Option<SwBreakpointOps<'_, Self>> {
+        Some(self)
+    }
+}
+
+impl SwBreakpoint for GdbTarget {
+    fn add_sw_breakpoint(&mut self, addr: u16, _kind: usize) -> TargetResult<bool, Self> {
+        self.emu.add_breakpoint(addr);
+        Ok(true)
+    }
+
+    fn remove_sw_breakpoint(&mut self, addr: u16, _kind: usize) -> TargetResult<bool, Self> {
+        let breakpoint = gbrs::Breakpoint::from(addr);
+        if !self.emu.breakpoints().contains(&breakpoint) {
+            return Ok(false);
+        }
+        self.emu.remove_breakpoint(breakpoint);
+        Ok(true)
+    }
+}
+
+enum GdbEventLoop {}
+
+impl run_blocking::BlockingEventLoop for GdbEventLoop {
+    type Target = GdbTarget;
+    type Connection = Box<dyn ConnectionExt<Error = std::io::Error>>;
+    type StopReason = SingleThreadStopReason<u16>;
+
+    #[allow(clippy::type_complexity)]
+    fn wait_for_stop_reason(
+        target: &mut GdbTarget,
+        conn: &mut Self::Connection,
+    ) -> Result<
+        run_blocking::Event<SingleThreadStopReason<u16>>,
+        run_blocking::WaitForStopReasonError<&'static str, std::io::Error>,
+    > {
+        // a connection error also stops execution, and is reported when reading from it
+        let incoming_data = || conn.peek().map(|byte| byte.is_some()).unwrap_or(true);
+        match target.run(incoming_data) {
+            Some(stop_reason) => Ok(run_blocking::Event::TargetStopped(stop_reason)),
+            None => {
+                let byte = conn
+                    .read()
+                    .map_err(run_blocking::WaitForStopReasonError::Connection)?;
+                Ok(run_blocking::Event::IncomingData(byte))
+            }
+        }
+    }
+
+    fn on_interrupt(
+        _target: &mut GdbTarget,
+    ) -> Result<Option<SingleThreadStopReason<u16>>, &'static str> {
+        // the emulator only runs inside of wait_for_stop_reason, so it's already paused
+        Ok(Some(SingleThreadStopReason::Signal(Signal::SIGINT)))
+    }
+}
+
+/// Wait for a debugger to connect on `port`, and run `emu` under its control until it disconnects.
+pub fn serve(emu: Emulator, port: u16) -> Result<Emulator, Box<dyn std::error::Error>> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    eprintln!("Waiting for a debugger to connect to 127.0.0.1:{port}");
+    let (stream, addr) = listener.accept()?;
+    eprintln!("Debugger connected from {addr}");
+    let connection: Box<dyn ConnectionExt<Error = std::io::Error>> = Box::new(stream);
+    let mut target = GdbTarget {
+        emu,
+        exec_mode: ExecMode::Continue,
+    };
+    match GdbStub::new(connection).run_blocking::<GdbEventLoop>(&mut target) {
+        Ok(DisconnectReason::Disconnect) => eprintln!("The debugger disconnected"),
+        Ok(DisconnectReason::Kill) => eprintln!("The debugger killed the game"),
+        Ok(reason) => eprintln!("The debugging session ended: {reason:?}"),
+        Err(e) => return Err(format!("The debugging session failed: {e}").into()),
+    }
+    Ok(target.emu)
+}
@@ -0,0 +1,683 @@
+//! Subcommands that run the emulator without a display or an audio device.
+use std::any::Any;
+use std::fs::File;
+use std::io::BufWriter;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use clap::{Args, ValueEnum};
+
+use gbrs::blending::FrameBlending;
+use gbrs::model::DmgRevision;
+use gbrs::palette::Preset;
+
+/// CPU frequency from pandocs: https://gbdev.io/pandocs/Specifications.html#dmg_clk
+const T_CYCLES_PER_SECOND: f64 = 4194304.0;
+/// The largest cartridge ROM (MBC5). Apart from the ROM, an emulator's memory use is fixed, so rejecting larger files
+/// bounds the memory used by each compat-run worker.
+const MAX_ROM_LEN: usize = 8 * 1024 * 1024;
+
+#[derive(Args, Debug)]
+pub struct RunArgs {
+    /// Path to the ROM file, which can be in a .zip or .gz archive
+    rom_path: PathBuf,
+
+    /// Optional path to save state
+    #[arg(long)]
+    save: Option<PathBuf>,
+
+    /// The number of frames to run
+    #[arg(long, default_value = "600")]
+    frames: u32,
+
+    /// Capture when this many frames have completed since power on. Can be repeated
+    #[arg(long)]
+    capture_frame: Vec<u64>,
+
+    /// Capture the first time execution reaches this address. Can be repeated
+    #[arg(long, value_parser = parse_addr)]
+    capture_at_pc: Vec<u16>,
+
+    /// Capture after the first write to this address. Can be repeated
+    #[arg(long, value_parser = parse_addr)]
+    capture_on_write: Vec<u16>,
+
+    /// What to write for each capture
+    #[arg(long, value_enum, default_value = "both")]
+    capture_format: CaptureFormat,
+
+    /// The directory to write captures to
+    #[arg(long, default_value = ".")]
+    capture_dir: PathBuf,
+
+    /// The DMG revision to emulate for ROMs that don't enable CGB features: dmg0 or dmg-b
+    #[arg(long, default_value = "dmg-b")]
+    dmg_revision: DmgRevision,
+
+    /// Emulate this model instead of picking it from the cartridge header
+    #[arg(long, value_enum)]
+    model: Option<super::Model>,
+
+    /// Use this mapper regardless of the cartridge type in the header: none, mbc1, mbc3, mbc5, mbc7, camera, or
+    /// wisdom-tree
+    #[arg(long)]
+    force_mbc: Option<gbrs::Mapper>,
+
+    /// A dump of the DMG or CGB boot ROM to run before the cartridge. Without one, the emulator starts at the
+    /// cartridge entry point with the state that the boot ROM leaves behind
+    #[arg(long)]
+    boot_rom: Option<PathBuf>,
+
+    /// Start at the cartridge entry point with the state that the boot ROM leaves behind, even with --boot-rom
+    #[arg(long, default_value = "false")]
+    skip_boot: bool,
+
+    /// An IPS or BPS patch, e.g. a translation, to apply to the ROM when it's loaded
+    #[arg(long)]
+    patch: Option<PathBuf>,
+
+    /// Stop and write a diagnostics bundle to the capture directory if the game spends this many seconds of emulated
+    /// time in a tight loop with interrupts disabled and no IO activity
+    #[arg(long)]
+    lockup_watchdog: Option<f64>,
+
+    /// Record the last N instructions, and print them if the CPU hits an illegal opcode or the emulator panics
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    trace: Option<u32>,
+
+    /// Write the CPU state before every instruction to this file, in the log format of Gameboy Doctor. Logging
+    /// starts at the cartridge entry point, like Gameboy Doctor's logs
+    #[arg(long)]
+    doctor_log: Option<PathBuf>,
+
+    /// Press the buttons of a movie recorded with `--record-movie`
+    #[arg(long)]
+    movie: Option<PathBuf>,
+
+    /// Draw each scanline at once instead of dot by dot. Faster, but effects that change PPU registers in the middle
+    /// of a scanline apply to the whole line
+    #[arg(long, default_value = "false")]
+    scanline_renderer: bool,
+
+    /// The colors to write captured frames in: green, pocket, or grayscale
+    #[arg(long, default_value = "green")]
+    palette: Preset,
+
+    /// Blend captured frames with the ones before them: off, mix (with the previous frame), or lcd (fade like the
+    /// DMG's LCD)
+    #[arg(long, default_value = "off")]
+    frame_blending: FrameBlending,
+
+    /// Count the T-cycles spent at each instruction, and print the N hottest as bank:addr after the run
+    #[arg(long, value_name = "N")]
+    hotspots: Option<usize>,
+
+    /// Wait for another gbrs to connect its link cable to this port
+    #[arg(long, value_name = "PORT", conflicts_with = "link_connect")]
+    link_listen: Option<u16>,
+
+    /// Connect the link cable to another gbrs that was started with --link-listen, e.g. 192.168.0.2:5555
+    #[arg(long, value_name = "HOST:PORT")]
+    link_connect: Option<String>,
+
+    /// Wait for gdb, lldb, or another GDB remote protocol client to connect on this port, and run under its control
+    /// instead of for a number of frames
+    #[cfg(feature = "gdb")]
+    #[arg(long, value_name = "PORT")]
+    gdb: Option<u16>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum CaptureFormat {
+    /// A PNG screenshot of the LCD
+    Png,
+    /// A save state
+    State,
+    Both,
+}
+
+#[derive(Args, Debug)]
+pub struct DebugSnapshotArgs {
+    /// Path to the ROM file, which can be in a .zip or .gz archive
+    rom_path: PathBuf,
+
+    /// Optional path to save state
+    #[arg(long)]
+    save: Option<PathBuf>,
+
+    /// The number of frames to run before taking the snapshot
+    #[arg(long, default_value = "0")]
+    frames: u32,
+
+    /// Where to write the PNG
+    #[arg(long, short, default_value = "debug-snapshot.png")]
+    output: PathBuf,
+
+    /// The colors to draw the shades in: green, pocket, or grayscale
+    #[arg(long, default_value = "green")]
+    palette: Preset,
+}
+
+#[derive(Args, Debug)]
+pub struct BenchArgs {
+    /// Path to the ROM file, which can be in a .zip or .gz archive
+    rom_path: PathBuf,
+
+    /// The number of frames to run
+    #[arg(long, default_value = "3600")]
+    frames: u32,
+}
+
+#[derive(Args, Debug)]
+pub struct VerifyMovieArgs {
+    /// Path to the ROM file, which can be in a .zip or .gz archive
+    rom_path: PathBuf,
+
+    /// The movie to play, recorded with `--record-movie`
+    movie: PathBuf,
+
+    /// Optional path to the save state that the movie was recorded from
+    #[arg(long)]
+    save: Option<PathBuf>,
+
+    /// The DMG revision to emulate for ROMs that don't enable CGB features: dmg0 or dmg-b
+    #[arg(long, default_value = "dmg-b")]
+    dmg_revision: DmgRevision,
+
+    /// Emulate this model instead of picking it from the cartridge header
+    #[arg(long, value_enum)]
+    model: Option<super::Model>,
+
+    /// A dump of the DMG or CGB boot ROM to run before the cartridge
+    #[arg(long)]
+    boot_rom: Option<PathBuf>,
+
+    /// Start at the cartridge entry point with the state that the boot ROM leaves behind, even with --boot-rom
+    #[arg(long, default_value = "false")]
+    skip_boot: bool,
+
+    /// An IPS or BPS patch to apply to the ROM when it's loaded
+    #[arg(long)]
+    patch: Option<PathBuf>,
+
+    /// Fail unless the last frame of the movie has this hash, in hex. See `gbrs::Emulator::frame_hash`
+    #[arg(long, value_parser = parse_hash)]
+    expect_frame_hash: Option<u64>,
+
+    /// Fail unless the emulator ends up in the state with this hash, in hex. See `gbrs::Emulator::state_hash`
+    #[arg(long, value_parser = parse_hash)]
+    expect_state_hash: Option<u64>,
+}
+
+#[derive(Args, Debug)]
+pub struct CompatRunArgs {
+    /// Test ROMs, or directories containing .gb/.gbc test ROMs or .zip/.gz archives of them
+    #[arg(required = true)]
+    roms: Vec<PathBuf>,
+
+    /// Give up on a ROM if it hasn't reported a result after this many frames
+    #[arg(long, default_value = "3600")]
+    frames: u32,
+
+    /// Give up on a ROM if it hasn't reported a result after this many seconds
+    #[arg(long, default_value = "30")]
+    timeout: u64,
+
+    /// The number of ROMs to run in parallel. Defaults to the number of cores
+    #[arg(long)]
+    jobs: Option<usize>,
+}
+
+pub fn run(args: &RunArgs) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    let builder = super::emulator_builder(
+        args.model,
+        args.dmg_revision,
+        args.force_mbc,
+        args.skip_boot,
+    );
+    let builder = super::with_patch(builder, args.patch.as_deref())?;
+    let builder = super::with_boot_rom(builder, args.boot_rom.as_deref())?;
+    let builder = builder.renderer(super::renderer(args.scanline_renderer));
+    let mut emu = super::load_emulator(builder, &args.rom_path, args.save.as_deref())?;
+    emu.set_palette(args.palette.colors());
+    emu.set_frame_blending(args.frame_blending);
+    let mut triggers = CaptureTriggers::new(args);
+    for &addr in &args.capture_on_write {
+        emu.add_write_watch(addr);
+    }
+    if let Some(seconds) = args.lockup_watchdog {
+        emu.enable_lockup_watchdog(Duration::from_secs_f64(seconds));
+    }
+    if let Some(len) = args.trace {
+        emu.enable_trace(len as usize);
+    }
+    if let Some(path) = &args.doctor_log {
+        while emu.in_boot_rom() {
+            emu.step()?;
+        }
+        emu.start_doctor_log(BufWriter::new(File::create(path)?));
+    }
+    if args.hotspots.is_some() {
+        emu.enable_cycle_profile();
+    }
+    if let Some(path) = &args.movie {
+        emu.play_movie(super::read_movie(path)?)?;
+    }
+    super::connect_link(&mut emu, args.link_listen, args.link_connect.as_deref())?;
+    #[cfg(feature = "gdb")]
+    if let Some(port) = args.gdb {
+        let mut emu = super::gdb::serve(emu, port)?;
+        emu.shutdown()?;
+        return Ok(ExitCode::SUCCESS);
+    }
+    for _ in 0..args.frames {
+        if triggers.is_empty() {
+            emu.run_frame()?;
+        } else {
+            let frame = emu.frame_count();
+            let mut capture_result = Ok(());
+            emu.run_until(gbrs::T_CYCLES_PER_FRAME, |emu| {
+                capture_result = triggers.check(emu);
+                capture_result.is_err() || emu.frame_count() != frame
+            })?;
+            capture_result?;
+            triggers.seen_events = 0;
+        }
+        for event in emu.take_events() {
+            if let gbrs::Event::LockedUp(lockup) = event {
+                let bundle =
+                    super::write_diagnostics_bundle(&emu, &args.capture_dir, Some(lockup))?;
+                eprintln!(
+                    "The game locked up in {:04X}-{:04X} at frame {}. Wrote diagnostics to {:?}",
+                    lockup.lowest_pc,
+                    lockup.highest_pc,
+                    emu.frame_count(),
+                    bundle
+                );
+                emu.shutdown()?;
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+    }
+    println!(
+        "Ran {} frames, PC: {:04X}",
+        args.frames,
+        emu.debug_state().regs.pc
+    );
+    if let Some(count) = args.hotspots {
+        emu.write_hotspot_report(&mut std::io::stdout().lock(), count)?;
+    }
+    emu.shutdown()?;
+    Ok(ExitCode::SUCCESS)
+}
+
+/// The captures requested on the command line that haven't happened yet
+struct CaptureTriggers {
+    frames: Vec<u64>,
+    pcs: Vec<u16>,
+    writes: Vec<u16>,
+    format: CaptureFormat,
+    dir: PathBuf,
+    rom_name: String,
+    /// The number of pending emulator events that have already been checked for watched writes
+    seen_events: usize,
+}
+
+impl CaptureTriggers {
+    fn new(args: &RunArgs) -> Self {
+        CaptureTriggers {
+            frames: args.capture_frame.clone(),
+            pcs: args.capture_at_pc.clone(),
+            writes: args.capture_on_write.clone(),
+            format: args.capture_format,
+            dir: args.capture_dir.clone(),
+            rom_name: args
+                .rom_path
+                .file_stem()
+                .map_or("rom".into(), |stem| stem.to_string_lossy().into_owned()),
+            seen_events: 0,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.frames.is_empty() && self.pcs.is_empty() && self.writes.is_empty()
+    }
+
+    /// Capture for every trigger that fired since the last check. Called after every instruction.
+    fn check(&mut self, emu: &gbrs::Emulator) -> Result<(), Box<dyn std::error::Error>> {
+        let mut fired = vec![];
+        self.frames.retain(|&frame| {
+            let hit = emu.frame_count() >= frame;
+            if hit {
+                fired.push(format!("frame-{frame}"));
+            }
+            !hit
+        });
+        let current_pc = emu.debug_state().regs.pc;
+        self.pcs.retain(|&pc| {
+            let hit = current_pc == pc;
+            if hit {
+                fired.push(format!("pc-{pc:04X}"));
+            }
+            !hit
+        });
+        for event in &emu.pending_events()[self.seen_events..] {
+            if let gbrs::Event::WatchedWrite(addr) = *event {
+                if let Some(idx) = self.writes.iter().position(|&watched| watched == addr) {
+                    self.writes.remove(idx);
+                    fired.push(format!("write-{addr:04X}"));
+                }
+            }
+        }
+        self.seen_events = emu.pending_events().len();
+        for trigger in fired {
+            self.capture(emu, &trigger)?;
+        }
+        Ok(())
+    }
+
+    fn capture(
+        &self,
+        emu: &gbrs::Emulator,
+        trigger: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        std::fs::create_dir_all(&self.dir)?;
+        let path = self.dir.join(format!("{}-{trigger}", self.rom_name));
+        if self.format != CaptureFormat::State {
+            super::write_frame_png(&path.with_extension("png"), emu)?;
+        }
+        if self.format != CaptureFormat::Png {
+            emu.write_save_state(&path.with_extension("sav.zst"))?;
+        }
+        eprintln!(
+            "Captured {trigger} at frame {}, PC: {:04X}",
+            emu.frame_count(),
+            emu.debug_state().regs.pc
+        );
+        Ok(())
+    }
+}
+
+/// Parse an address in hex (with a `0x` prefix) or decimal
+fn parse_hash(s: &str) -> Result<u64, String> {
+    let hex = s
+        .strip_prefix("0x")
+        .or_else(|| s.strip_prefix("0X"))
+        .unwrap_or(s);
+    u64::from_str_radix(hex, 16).map_err(|e| format!("invalid hash {s:?}: {e}"))
+}
+
+fn parse_addr(s: &str) -> Result<u16, String> {
+    let parsed = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    parsed.map_err(|e| format!("invalid address {s:?}: {e}"))
+}
+
+pub fn debug_snapshot(args: &DebugSnapshotArgs) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    let builder = gbrs::EmulatorBuilder::new();
+    let mut emu = super::load_emulator(builder, &args.rom_path, args.save.as_deref())?;
+    emu.set_palette(args.palette.colors());
+    for _ in 0..args.frames {
+        emu.run_frame()?;
+    }
+    let image = super::snapshot::compose(&emu);
+    let image: Vec<&[gbrs::Color]> = image.iter().map(|row| row.as_slice()).collect();
+    super::write_png(&args.output, &image, emu.palette())?;
+    println!("Wrote {:?} at frame {}", args.output, emu.frame_count());
+    Ok(ExitCode::SUCCESS)
+}
+
+pub fn bench(args: &BenchArgs) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    let mut emu = super::load_emulator(gbrs::EmulatorBuilder::new(), &args.rom_path, None)?;
+    let start = Instant::now();
+    for _ in 0..args.frames {
+        emu.run_frame()?;
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+    let emulated_seconds = emu.cycle_count() as f64 / T_CYCLES_PER_SECOND;
+    println!(
+        "Ran {} frames in {elapsed:.2}s: {:.1} fps, {:.1}x real time",
+        args.frames,
+        args.frames as f64 / elapsed,
+        emulated_seconds / elapsed
+    );
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Play a movie to its end, and check that it ends with the expected frame and state.
+pub fn verify_movie(args: &VerifyMovieArgs) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    let builder = super::emulator_builder(args.model, args.dmg_revision, None, args.skip_boot);
+    let builder = super::with_patch(builder, args.patch.as_deref())?;
+    let builder = super::with_boot_rom(builder, args.boot_rom.as_deref())?;
+    let mut emu = super::load_emulator(builder, &args.rom_path, args.save.as_deref())?;
+    let movie = super::read_movie(&args.movie)?;
+    let frames = movie.frames.len();
+    emu.play_movie(movie)?;
+    while emu.playing_movie() {
+        emu.run_frame()?;
+    }
+    let (frame_hash, state_hash) = (emu.frame_hash(), emu.state_hash());
+    println!(
+        "Played {frames} frames, frame hash: {frame_hash:016X}, state hash: {state_hash:016X}"
+    );
+    let mut matches = true;
+    if let Some(expected) = args.expect_frame_hash.filter(|&hash| hash != frame_hash) {
+        println!("The frame hash should be {expected:016X}");
+        matches = false;
+    }
+    if let Some(expected) = args.expect_state_hash.filter(|&hash| hash != state_hash) {
+        println!("The state hash should be {expected:016X}");
+        matches = false;
+    }
+    if matches {
+        Ok(ExitCode::SUCCESS)
+    } else {
+        Ok(ExitCode::FAILURE)
+    }
+}
+
+#[derive(Debug)]
+enum Outcome {
+    Passed,
+    Failed,
+    Crashed(String),
+    /// Didn't report a result before the timeout
+    TimedOut,
+    /// Ran every frame without reporting a result
+    NoResult,
+}
+
+pub fn compat_run(args: &CompatRunArgs) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    let roms = collect_roms(&args.roms)?;
+    let jobs = args
+        .jobs
+        .unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()))
+        .clamp(1, roms.len().max(1));
+    let timeout = Duration::from_secs(args.timeout);
+    // Crashes are reported in the summary, so silence the default panic output
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+    // Each worker takes the next ROM that hasn't been started yet
+    let next_rom = AtomicUsize::new(0);
+    let mut results: Vec<(usize, Outcome)> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..jobs)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut results = vec![];
+                    loop {
+                        let idx = next_rom.fetch_add(1, Ordering::Relaxed);
+                        let Some(rom) = roms.get(idx) else {
+                            break;
+                        };
+                        results.push((idx, run_test_rom(rom, args.frames, timeout)));
+                    }
+                    results
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().expect("BUG: compat-run worker panicked"))
+            .collect()
+    });
+    panic::set_hook(default_hook);
+    results.sort_by_key(|&(idx, _)| idx);
+
+    let (mut passed, mut failed, mut crashed, mut timed_out, mut no_result) = (0, 0, 0, 0, 0);
+    for (idx, outcome) in &results {
+        let rom = &roms[*idx];
+        match outcome {
+            Outcome::Passed => {
+                passed += 1;
+                println!("PASS    {}", rom.display());
+            }
+            Outcome::Failed => {
+                failed += 1;
+                println!("FAIL    {}", rom.display());
+            }
+            Outcome::Crashed(message) => {
+                crashed += 1;
+                println!("CRASH   {}: {message}", rom.display());
+            }
+            Outcome::TimedOut => {
+                timed_out += 1;
+                println!("TIMEOUT {}", rom.display());
+            }
+            Outcome::NoResult => {
+                no_result += 1;
+                println!("NORESULT {}", rom.display());
+            }
+        }
+    }
+    println!(
+        "{passed} passed, {failed} failed, {crashed} crashed, {timed_out} timed out, {no_result} without a result"
+    );
+    if failed > 0 || crashed > 0 || timed_out > 0 || no_result > 0 {
+        Ok(ExitCode::FAILURE)
+    } else {
+        Ok(ExitCode::SUCCESS)
+    }
+}
+
+fn collect_roms(paths: &[PathBuf]) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let mut roms = vec![];
+    for path in paths {
+        if path.is_dir() {
+            let mut entries = std::fs::read_dir(path)?
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<Result<Vec<_>, _>>()?;
+            entries.retain(|entry| {
+                matches!(
+                    entry.extension().and_then(|ext| ext.to_str()),
+                    Some("gb" | "gbc" | "zip" | "gz")
+                )
+            });
+            entries.sort();
+            roms.extend(entries);
+        } else {
+            roms.push(path.clone());
+        }
+    }
+    Ok(roms)
+}
+
+fn run_test_rom(path: &Path, frames: u32, timeout: Duration) -> Outcome {
+    let rom = match std::fs::read(path) {
+        Ok(rom) => rom,
+        Err(e) => return Outcome::Crashed(format!("Unable to read ROM: {e}")),
+    };
+    if rom.len() > MAX_ROM_LEN {
+        return Outcome::Crashed(format!(
+            "ROM is larger than the largest cartridge ({MAX_ROM_LEN} bytes)"
+        ));
+    }
+    let cancel_token = gbrs::CancelToken::new();
+    let (done_tx, done_rx) = mpsc::channel::<()>();
+    thread::scope(|scope| {
+        // Watchdog that stops the emulator if it doesn't finish in time
+        let watchdog_token = cancel_token.clone();
+        scope.spawn(move || {
+            if done_rx.recv_timeout(timeout) == Err(RecvTimeoutError::Timeout) {
+                watchdog_token.cancel();
+            }
+        });
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let builder = gbrs::EmulatorBuilder::new()
+                .illegal_opcode_policy(gbrs::IllegalOpcodePolicy::Error);
+            let mut emu = match builder.for_rom(&rom, path) {
+                Ok(emu) => emu,
+                Err(e) => return Outcome::Crashed(e.to_string()),
+            };
+            emu.set_cancel_token(cancel_token.clone());
+            let mut verdict = None;
+            let serial = gbrs::serial::Capture::new();
+            emu.connect_serial(Box::new(serial.clone()));
+            let mut serial_output = Vec::new();
+            for _ in 0..frames {
+                let result = emu.run_until(gbrs::T_CYCLES_PER_FRAME, |emu| {
+                    verdict = mooneye_verdict(emu);
+                    verdict.is_some()
+                });
+                if let Err(e) = result {
+                    return Outcome::Crashed(e.to_string());
+                }
+                if let Some(verdict) = verdict {
+                    return verdict;
+                }
+                serial_output.extend(serial.take());
+                if let Some(verdict) = blargg_verdict(&serial_output) {
+                    return verdict;
+                }
+                if emu.is_cancelled() {
+                    return Outcome::TimedOut;
+                }
+            }
+            Outcome::NoResult
+        }));
+        drop(done_tx);
+        result.unwrap_or_else(|payload| Outcome::Crashed(panic_message(payload)))
+    })
+}
+
+/// Mooneye test ROMs execute `LD B,B` when they finish, with the Fibonacci numbers in the registers if the test
+/// passed, or 0x42 in every register if it failed.
+fn mooneye_verdict(emu: &gbrs::Emulator) -> Option<Outcome> {
+    let regs = emu.debug_state().regs;
+    if emu.read_memory(regs.pc) != 0x40 {
+        return None;
+    }
+    match [regs.b, regs.c, regs.d, regs.e, regs.h, regs.l] {
+        [3, 5, 8, 13, 21, 34] => Some(Outcome::Passed),
+        [0x42, 0x42, 0x42, 0x42, 0x42, 0x42] => Some(Outcome::Failed),
+        _ => None,
+    }
+}
+
+/// Blargg's test ROMs print their results over the serial port, ending with "Passed" or "Failed".
+fn blargg_verdict(serial_output: &[u8]) -> Option<Outcome> {
+    let output = String::from_utf8_lossy(serial_output);
+    if output.contains("Passed") {
+        Some(Outcome::Passed)
+    } else if output.contains("Failed") {
+        Some(Outcome::Failed)
+    } else {
+        None
+    }
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
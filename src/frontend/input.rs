@@ -0,0 +1,245 @@
+//! Bindings of keyboard keys and controller buttons to joypad buttons and emulator hotkeys.
+//!
+//! Bindings are written as `INPUT = ACTION`, one per line in a bindings file or one per `--bind` option. An input is
+//! an SDL key name like `X`, `Return`, or `Left Shift`, or a controller button prefixed with `pad:`, like `pad:a` or
+//! `pad:dpup`. An action is a joypad button (`a`, `b`, `start`, `select`, `up`, `down`, `left`, `right`), a turbo
+//! button that presses and releases a joypad button while held (`turbo-a`, `turbo-b`, ...), a hotkey (`fast-forward`,
+//! `pause`, `frame-advance`, `save-state`, `quick-save`, `quick-load`, `record-movie`, `print-logs`, `diagnostics`), or
+//! `none` to unbind the input.
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use enumset::EnumSet;
+use sdl2::controller::Button as ControllerButton;
+use sdl2::event::Event;
+use sdl2::keyboard::Keycode;
+
+use gbrs::joypad::Button;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Input {
+    Key(Keycode),
+    Controller(ControllerButton),
+}
+
+impl Input {
+    /// The input that `event` presses (`true`) or releases (`false`), if any
+    pub fn from_event(event: &Event) -> Option<(Input, bool)> {
+        match *event {
+            Event::KeyDown {
+                keycode: Some(key), ..
+            } => Some((Input::Key(key), true)),
+            Event::KeyUp {
+                keycode: Some(key), ..
+            } => Some((Input::Key(key), false)),
+            Event::ControllerButtonDown { button, .. } => Some((Input::Controller(button), true)),
+            Event::ControllerButtonUp { button, .. } => Some((Input::Controller(button), false)),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Input {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("pad:") {
+            Some(button) => ControllerButton::from_string(button)
+                .map(Input::Controller)
+                .ok_or_else(|| format!("unknown controller button: {button:?}")),
+            None => Keycode::from_name(s)
+                .map(Input::Key)
+                .ok_or_else(|| format!("unknown key: {s:?}")),
+        }
+    }
+}
+
+/// What an input does while it's held, or when it's pressed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Joypad(Button),
+    /// Alternately press and release the button while held
+    Turbo(Button),
+    /// Emulate several frames per displayed frame while held
+    FastForward,
+    /// Toggle pause
+    Pause,
+    /// Pause, or run a single frame if already paused
+    FrameAdvance,
+    SaveState,
+    /// Save the state in memory, for re-recording movies
+    QuickSave,
+    /// Restore the state saved with [`Action::QuickSave`]
+    QuickLoad,
+    /// Start recording a movie, or continue recording the movie that is playing from the current frame
+    RecordMovie,
+    /// Print the CPU and PPU state before every instruction while held
+    PrintLogs,
+    /// Write a diagnostics bundle for bug reports
+    Diagnostics,
+}
+
+impl FromStr for Action {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(button) = parse_button(s) {
+            return Ok(Action::Joypad(button));
+        }
+        if let Some(button) = s.strip_prefix("turbo-").and_then(parse_button) {
+            return Ok(Action::Turbo(button));
+        }
+        let action = match s {
+            "fast-forward" => Action::FastForward,
+            "pause" => Action::Pause,
+            "frame-advance" => Action::FrameAdvance,
+            "save-state" => Action::SaveState,
+            "quick-save" => Action::QuickSave,
+            "quick-load" => Action::QuickLoad,
+            "record-movie" => Action::RecordMovie,
+            "print-logs" => Action::PrintLogs,
+            "diagnostics" => Action::Diagnostics,
+            _ => return Err(format!("unknown action: {s:?}")),
+        };
+        Ok(action)
+    }
+}
+
+fn parse_button(s: &str) -> Option<Button> {
+    let button = match s {
+        "a" => Button::A,
+        "b" => Button::B,
+        "start" => Button::Start,
+        "select" => Button::Select,
+        "up" => Button::Up,
+        "down" => Button::Down,
+        "left" => Button::Left,
+        "right" => Button::Right,
+        _ => return None,
+    };
+    Some(button)
+}
+
+/// The buttons that turbo inputs are holding, which are pressed for `period` frames and then released for `period`
+/// frames.
+#[derive(Debug, Clone)]
+pub struct Turbo {
+    held: EnumSet<Button>,
+    period: u32,
+}
+
+impl Turbo {
+    /// Panics if `period` is 0.
+    pub fn new(period: u32) -> Self {
+        assert!(period > 0, "The turbo period must be at least one frame");
+        Turbo {
+            held: EnumSet::empty(),
+            period,
+        }
+    }
+
+    pub fn set_held(&mut self, button: Button, held: bool) {
+        if held {
+            self.held.insert(button);
+        } else {
+            self.held.remove(button);
+        }
+    }
+
+    /// The buttons that are pressed during emulated frame `frame`
+    pub fn pressed_buttons(&self, frame: u64) -> EnumSet<Button> {
+        let period = self.period as u64;
+        if frame % (2 * period) < period {
+            self.held
+        } else {
+            EnumSet::empty()
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Bindings {
+    actions: HashMap<Input, Action>,
+}
+
+impl Default for Bindings {
+    fn default() -> Self {
+        let keys = [
+            (Keycode::X, Action::Joypad(Button::A)),
+            (Keycode::Z, Action::Joypad(Button::B)),
+            (Keycode::Return, Action::Joypad(Button::Start)),
+            (Keycode::RShift, Action::Joypad(Button::Select)),
+            (Keycode::Up, Action::Joypad(Button::Up)),
+            (Keycode::Down, Action::Joypad(Button::Down)),
+            (Keycode::Left, Action::Joypad(Button::Left)),
+            (Keycode::Right, Action::Joypad(Button::Right)),
+            (Keycode::LShift, Action::FastForward),
+            (Keycode::P, Action::Pause),
+            (Keycode::N, Action::FrameAdvance),
+            (Keycode::S, Action::SaveState),
+            (Keycode::F5, Action::QuickSave),
+            (Keycode::F9, Action::QuickLoad),
+            (Keycode::R, Action::RecordMovie),
+            (Keycode::D, Action::PrintLogs),
+            (Keycode::F12, Action::Diagnostics),
+        ];
+        // the Game Boy's A and B are on the right and bottom, like on a Nintendo controller
+        let controller_buttons = [
+            (ControllerButton::B, Action::Joypad(Button::A)),
+            (ControllerButton::A, Action::Joypad(Button::B)),
+            (ControllerButton::Start, Action::Joypad(Button::Start)),
+            (ControllerButton::Back, Action::Joypad(Button::Select)),
+            (ControllerButton::DPadUp, Action::Joypad(Button::Up)),
+            (ControllerButton::DPadDown, Action::Joypad(Button::Down)),
+            (ControllerButton::DPadLeft, Action::Joypad(Button::Left)),
+            (ControllerButton::DPadRight, Action::Joypad(Button::Right)),
+            (ControllerButton::RightShoulder, Action::FastForward),
+        ];
+        let actions = keys
+            .into_iter()
+            .map(|(key, action)| (Input::Key(key), action))
+            .chain(
+                controller_buttons
+                    .into_iter()
+                    .map(|(button, action)| (Input::Controller(button), action)),
+            )
+            .collect();
+        Bindings { actions }
+    }
+}
+
+impl Bindings {
+    pub fn action(&self, input: Input) -> Option<Action> {
+        self.actions.get(&input).copied()
+    }
+
+    /// Apply a single `INPUT = ACTION` binding, replacing what the input was bound to before.
+    pub fn bind(&mut self, binding: &str) -> Result<(), String> {
+        let (input, action) = binding
+            .rsplit_once('=')
+            .ok_or_else(|| format!("expected INPUT = ACTION, got {binding:?}"))?;
+        let input = input.trim().parse()?;
+        match action.trim() {
+            "none" => {
+                self.actions.remove(&input);
+            }
+            action => {
+                self.actions.insert(input, action.parse()?);
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply the bindings in a bindings file, one per line. Everything after a `#` is a comment.
+    pub fn bind_all(&mut self, bindings: &str) -> Result<(), String> {
+        for (line_idx, line) in bindings.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            self.bind(line)
+                .map_err(|e| format!("invalid binding on line {}: {e}", line_idx + 1))?;
+        }
+        Ok(())
+    }
+}
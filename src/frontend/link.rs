@@ -0,0 +1,126 @@
+//! A link cable between two instances of gbrs over TCP, e.g. on two computers.
+//!
+//! Each transfer with the internal clock sends a message with the byte and a sequence number, and waits for the
+//! other end to answer with its own byte once its game is ready to receive. When both ends use the internal clock at
+//! the same time, their messages cross, and each receives the other's byte without an answer, like on hardware.
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use gbrs::serial::{Clock, SerialDevice};
+
+/// How long a transfer with the internal clock waits for the other end before receiving 0xFF like an unplugged cable
+const ANSWER_TIMEOUT: Duration = Duration::from_millis(500);
+
+const TRANSFER: u8 = 0;
+const ANSWER: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Message {
+    /// A byte sent with the internal clock
+    Transfer { seq: u8, byte: u8 },
+    /// The byte that the other end shifted out during the transfer with sequence number `seq`
+    Answer { seq: u8, byte: u8 },
+}
+
+impl Message {
+    fn to_bytes(self) -> [u8; 3] {
+        match self {
+            Message::Transfer { seq, byte } => [TRANSFER, seq, byte],
+            Message::Answer { seq, byte } => [ANSWER, seq, byte],
+        }
+    }
+}
+
+pub struct TcpLink {
+    stream: TcpStream,
+    messages: Receiver<Message>,
+    seq: u8,
+}
+
+impl TcpLink {
+    /// Wait for the other end to connect on `port`.
+    pub fn listen(port: u16) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(("0.0.0.0", port))?;
+        eprintln!("Waiting for the other end of the link cable to connect to port {port}");
+        let (stream, addr) = listener.accept()?;
+        eprintln!("Link cable connected to {addr}");
+        TcpLink::new(stream)
+    }
+
+    pub fn connect(addr: impl ToSocketAddrs) -> std::io::Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        eprintln!("Link cable connected to {}", stream.peer_addr()?);
+        TcpLink::new(stream)
+    }
+
+    fn new(stream: TcpStream) -> std::io::Result<Self> {
+        stream.set_nodelay(true)?;
+        let mut reader = stream.try_clone()?;
+        let (sender, messages) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buf = [0; 3];
+            while reader.read_exact(&mut buf).is_ok() {
+                let message = match buf {
+                    [TRANSFER, seq, byte] => Message::Transfer { seq, byte },
+                    [ANSWER, seq, byte] => Message::Answer { seq, byte },
+                    _ => break,
+                };
+                if sender.send(message).is_err() {
+                    break;
+                }
+            }
+            eprintln!("The link cable was disconnected");
+        });
+        Ok(TcpLink {
+            stream,
+            messages,
+            seq: 0,
+        })
+    }
+
+    fn send(&mut self, message: Message) {
+        // a broken connection shows up as the other end never answering
+        let _ = self.stream.write_all(&message.to_bytes());
+    }
+}
+
+impl SerialDevice for TcpLink {
+    fn exchange(&mut self, sent: u8, clock: Clock) -> Option<u8> {
+        match clock {
+            Clock::Internal => {
+                self.seq = self.seq.wrapping_add(1);
+                let seq = self.seq;
+                self.send(Message::Transfer { seq, byte: sent });
+                let deadline = Instant::now() + ANSWER_TIMEOUT;
+                loop {
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+                    match self.messages.recv_timeout(timeout) {
+                        Ok(Message::Answer {
+                            seq: answered,
+                            byte,
+                        }) if answered == seq => return Some(byte),
+                        // both ends drive the clock, so they shift each other's byte in
+                        Ok(Message::Transfer { byte, .. }) => return Some(byte),
+                        // the answer to a transfer that timed out
+                        Ok(Message::Answer { .. }) => {}
+                        Err(RecvTimeoutError::Timeout | RecvTimeoutError::Disconnected) => {
+                            return None
+                        }
+                    }
+                }
+            }
+            Clock::External => loop {
+                match self.messages.try_recv().ok()? {
+                    Message::Transfer { seq, byte } => {
+                        self.send(Message::Answer { seq, byte: sent });
+                        return Some(byte);
+                    }
+                    Message::Answer { .. } => {}
+                }
+            },
+        }
+    }
+}
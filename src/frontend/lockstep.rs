@@ -0,0 +1,202 @@
+//! Lockstep comparison against a reference emulator, to find the first instruction where gbrs diverges from it.
+//!
+//! The reference runs as a child process, and speaks a line based protocol over its stdin and stdout:
+//! - It's started with the ROM path as its last argument, and prints the state at the cartridge entry point.
+//! - For every `step` line on its stdin, it executes one instruction and prints the state after it.
+//!
+//! A state is a line of `NAME:HEX` fields, like the lines of Gameboy Doctor logs, e.g.
+//! `A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02`. Only the fields that the reference
+//! prints are compared. Besides the Gameboy Doctor fields, `LY`, `DIV`, `IF`, and `IE` are supported.
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, ChildStdout, Command, ExitCode, Stdio};
+
+use anyhow::Context;
+use clap::Args;
+
+use gbrs::model::DmgRevision;
+
+#[derive(Args, Debug)]
+pub struct LockstepArgs {
+    /// Path to the ROM file, which can be in a .zip or .gz archive
+    rom_path: PathBuf,
+
+    /// Stop after this many instructions without a divergence
+    #[arg(long, default_value = "10000000")]
+    max_instructions: u64,
+
+    /// The number of instructions before the divergence to print
+    #[arg(long, default_value = "10")]
+    context: usize,
+
+    /// The DMG revision to emulate for ROMs that don't enable CGB features: dmg0 or dmg-b
+    #[arg(long, default_value = "dmg-b")]
+    dmg_revision: DmgRevision,
+
+    /// Emulate this model instead of picking it from the cartridge header
+    #[arg(long, value_enum)]
+    model: Option<super::Model>,
+
+    /// Use this mapper regardless of the cartridge type in the header: none, mbc1, mbc3, mbc5, mbc7, camera, or
+    /// wisdom-tree
+    #[arg(long)]
+    force_mbc: Option<gbrs::Mapper>,
+
+    /// The reference emulator's command line, after `--`. The ROM path is appended to it
+    #[arg(last = true, required = true)]
+    reference: Vec<String>,
+}
+
+struct Reference {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+}
+
+impl Reference {
+    fn spawn(command: &[String], rom_path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut child = Command::new(&command[0])
+            .args(&command[1..])
+            .arg(rom_path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .context(format!(
+                "Unable to start the reference emulator: {:?}",
+                command
+            ))?;
+        let stdin = child.stdin.take().expect("stdin is piped");
+        let stdout = BufReader::new(child.stdout.take().expect("stdout is piped"));
+        Ok(Reference {
+            child,
+            stdin,
+            stdout,
+        })
+    }
+
+    fn step(&mut self) -> std::io::Result<()> {
+        writeln!(self.stdin, "step")?;
+        self.stdin.flush()
+    }
+
+    /// The next state line, or `None` once the reference exited
+    fn read_state(&mut self) -> std::io::Result<Option<String>> {
+        let mut line = String::new();
+        if self.stdout.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        Ok(Some(line.trim().to_string()))
+    }
+}
+
+impl Drop for Reference {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+pub fn lockstep(args: &LockstepArgs) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    let builder = super::emulator_builder(args.model, args.dmg_revision, args.force_mbc, false);
+    let mut emu = super::load_emulator(builder, &args.rom_path, None)?;
+    let mut reference = Reference::spawn(&args.reference, &args.rom_path)?;
+    // the reference starts at the cartridge entry point
+    while emu.in_boot_rom() {
+        emu.step()?;
+    }
+    let mut history = VecDeque::with_capacity(args.context);
+    let mut instructions = 0;
+    loop {
+        let Some(expected) = reference.read_state()? else {
+            println!("The reference exited after {instructions} instructions without a divergence");
+            return Ok(ExitCode::SUCCESS);
+        };
+        let actual = state(&emu);
+        let mismatches = compare(&expected, &actual)?;
+        if !mismatches.is_empty() {
+            println!("Diverged after {instructions} instructions:");
+            for line in &history {
+                println!("  {line}");
+            }
+            println!("> gbrs:      {}", format_state(&actual));
+            println!("> reference: {expected}");
+            for (name, actual, expected) in mismatches {
+                println!("  {name} is {actual}, but should be {expected}");
+            }
+            return Ok(ExitCode::FAILURE);
+        }
+        if instructions == args.max_instructions {
+            println!("No divergence in {instructions} instructions");
+            return Ok(ExitCode::SUCCESS);
+        }
+        if args.context > 0 {
+            if history.len() == args.context {
+                history.pop_front();
+            }
+            history.push_back(format!(
+                "{}  {}",
+                format_state(&actual),
+                emu.describe_instruction(emu.debug_state().regs.pc)
+            ));
+        }
+        emu.step()?;
+        reference.step()?;
+        instructions += 1;
+    }
+}
+
+/// Every supported field of the emulator's state
+fn state(emu: &gbrs::Emulator) -> Vec<(&'static str, String)> {
+    let state = emu.debug_state();
+    let regs = &state.regs;
+    let pc_mem: Vec<String> = (0..4)
+        .map(|offset| format!("{:02X}", emu.read_memory(regs.pc.wrapping_add(offset))))
+        .collect();
+    vec![
+        ("A", format!("{:02X}", regs.a)),
+        ("F", format!("{:02X}", regs.f)),
+        ("B", format!("{:02X}", regs.b)),
+        ("C", format!("{:02X}", regs.c)),
+        ("D", format!("{:02X}", regs.d)),
+        ("E", format!("{:02X}", regs.e)),
+        ("H", format!("{:02X}", regs.h)),
+        ("L", format!("{:02X}", regs.l)),
+        ("SP", format!("{:04X}", regs.sp)),
+        ("PC", format!("{:04X}", regs.pc)),
+        ("PCMEM", pc_mem.join(",")),
+        ("LY", format!("{:02X}", state.ppu.ly)),
+        ("DIV", format!("{:02X}", state.timer.div)),
+        ("IF", format!("{:02X}", state.interrupts_requested.as_u8())),
+        ("IE", format!("{:02X}", state.interrupts_enabled.as_u8())),
+    ]
+}
+
+fn format_state(state: &[(&str, String)]) -> String {
+    state
+        .iter()
+        .map(|(name, value)| format!("{name}:{value}"))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// The fields of the reference's state line that differ, as (name, gbrs value, reference value).
+fn compare<'a>(
+    expected: &'a str,
+    actual: &[(&'static str, String)],
+) -> Result<Vec<(&'a str, String, String)>, String> {
+    let mut mismatches = Vec::new();
+    for field in expected.split_whitespace() {
+        let (name, expected_value) = field
+            .split_once(':')
+            .ok_or_else(|| format!("invalid field {field:?} in reference state {expected:?}"))?;
+        let (_, actual_value) = actual
+            .iter()
+            .find(|(actual_name, _)| actual_name.eq_ignore_ascii_case(name))
+            .ok_or_else(|| format!("unsupported field {name:?} in reference state {expected:?}"))?;
+        if !actual_value.eq_ignore_ascii_case(expected_value) {
+            mismatches.push((name, actual_value.clone(), expected_value.to_uppercase()));
+        }
+    }
+    Ok(mismatches)
+}
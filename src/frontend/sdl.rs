@@ -0,0 +1,674 @@
+//! The interactive SDL frontend.
+use std::fs::File;
+use std::path::Path;
+use std::thread;
+use std::time::{self, Instant};
+
+use anyhow::Context;
+use enumset::EnumSet;
+use sdl2::controller::{Axis, GameController};
+use sdl2::event::{Event, WindowEvent};
+use sdl2::pixels::PixelFormatEnum;
+
+use gbrs::camera::StaticImage;
+use gbrs::joypad;
+use gbrs::pacing::{FramePacer, PacingStats, RefreshMode, FRAME_DURATION};
+use gbrs::palette::ShadePalette;
+use gbrs::ppu::TilePalette;
+use gbrs::profiler::{Profiler, Section};
+use gbrs::Color;
+
+use super::input::{Action, Bindings, Input, Turbo};
+use crate::PlayArgs;
+
+/// With --no-sleep, only render one out of this many frames
+const UNTHROTTLED_FRAMES_PER_HOST_FRAME: u32 = 10;
+
+pub fn run(args: &PlayArgs) -> Result<(), Box<dyn std::error::Error>> {
+    if args.scale == 0 {
+        return Err("scale value must be > 0".into());
+    }
+    if args.fast_forward_speed == 0 {
+        return Err("fast forward speed must be > 0".into());
+    }
+    if args.turbo_period == 0 {
+        return Err("turbo period must be > 0".into());
+    }
+    let builder = super::emulator_builder(
+        args.model,
+        args.dmg_revision,
+        args.force_mbc,
+        args.skip_boot,
+    );
+    let builder = super::with_patch(builder, args.patch.as_deref())?;
+    let builder = super::with_boot_rom(builder, args.boot_rom.as_deref())?;
+    let builder = builder.renderer(super::renderer(args.scanline_renderer));
+    let mut emu = super::load_emulator(builder, &args.rom_path, args.save.as_deref())?;
+    emu.set_palette(args.palette.colors());
+    emu.set_frame_blending(args.frame_blending);
+    if let Some(path) = &args.record_audio {
+        emu.start_audio_capture(path)?;
+    }
+    if let Some(path) = &args.symbols {
+        let symbols = std::fs::read_to_string(path)
+            .context(format!("Unable to read symbol file: {:?}", path))?;
+        emu.load_symbols(symbols.parse()?);
+    }
+    if let Some(path) = &args.camera_image {
+        emu.connect_camera(Box::new(read_camera_image(path)?));
+    }
+    if let Some(seconds) = args.lockup_watchdog {
+        emu.enable_lockup_watchdog(time::Duration::from_secs_f64(seconds));
+    }
+    if let Some(len) = args.trace {
+        emu.enable_trace(len as usize);
+    }
+    super::connect_link(&mut emu, args.link_listen, args.link_connect.as_deref())?;
+    if let Some(path) = &args.play_movie {
+        emu.play_movie(super::read_movie(path)?)?;
+    }
+    let mut bindings = Bindings::default();
+    if let Some(path) = &args.bindings {
+        let text = std::fs::read_to_string(path)
+            .context(format!("Unable to read bindings file: {:?}", path))?;
+        bindings.bind_all(&text)?;
+    }
+    for binding in &args.bind {
+        bindings.bind(binding)?;
+    }
+    let sdl_context = sdl2::init()?;
+    let video_subsystem = sdl_context.video()?;
+    // bg layer
+    let bg_canvas_and_texture = if args.show_bg {
+        let window = video_subsystem
+            .window(
+                "Background Debug View",
+                256 * args.scale as u32,
+                256 * args.scale as u32,
+            )
+            .position(0, 0)
+            .build()?;
+        let mut canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
+        canvas.set_scale(args.scale as f32, args.scale as f32)?;
+        let texture_creator = Box::new(canvas.texture_creator());
+        let texture_creator = Box::leak(texture_creator);
+        let texture = texture_creator.create_texture_streaming(
+            sdl2::pixels::PixelFormatEnum::RGB24,
+            256,
+            256,
+        )?;
+        Some((canvas, texture))
+    } else {
+        None
+    };
+
+    // window layer
+    let window_canvas_and_texture = if args.show_window {
+        let window = video_subsystem
+            .window(
+                "Window Debug View",
+                256 * args.scale as u32,
+                256 * args.scale as u32,
+            )
+            .position(512, 0)
+            .build()?;
+        let mut canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
+        canvas.set_scale(args.scale as f32, args.scale as f32)?;
+        let texture_creator = Box::new(canvas.texture_creator());
+        let texture_creator = Box::leak(texture_creator);
+        let texture = texture_creator
+            .create_texture_streaming(sdl2::pixels::PixelFormatEnum::RGB24, 256, 256)
+            .map_err(|e| e.to_string())?;
+        Some((canvas, texture))
+    } else {
+        None
+    };
+
+    // object tiles layer
+    let obj_canvas_and_texture = if args.show_obj_layer {
+        let window = video_subsystem
+            .window(
+                "OAM Debug View",
+                176 * args.scale as u32,
+                176 * args.scale as u32,
+            )
+            .position(512, 100)
+            .build()?;
+        let mut canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
+        canvas.set_scale(args.scale as f32, args.scale as f32)?;
+        let texture_creator = Box::new(canvas.texture_creator());
+        let texture_creator = Box::leak(texture_creator);
+        let texture = texture_creator.create_texture_streaming(
+            sdl2::pixels::PixelFormatEnum::RGB24,
+            176,
+            176,
+        )?;
+        Some((canvas, texture))
+    } else {
+        None
+    };
+
+    // tile atlas
+    let tiles_canvas_and_texture = if args.show_tiles {
+        let window = video_subsystem
+            .window(
+                "Tiles Debug View",
+                128 * args.scale as u32,
+                192 * args.scale as u32,
+            )
+            .position(768, 0)
+            .build()?;
+        let mut canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
+        canvas.set_scale(args.scale as f32, args.scale as f32)?;
+        let texture_creator = Box::new(canvas.texture_creator());
+        let texture_creator = Box::leak(texture_creator);
+        let texture = texture_creator.create_texture_streaming(
+            sdl2::pixels::PixelFormatEnum::RGB24,
+            128,
+            192,
+        )?;
+        Some((canvas, texture))
+    } else {
+        None
+    };
+
+    let refresh_mode = if args.match_host_refresh {
+        match video_subsystem.current_display_mode(0)?.refresh_rate {
+            0 => {
+                eprintln!("The display's refresh rate is unknown, running at the Game Boy's refresh rate instead");
+                RefreshMode::Exact
+            }
+            refresh_rate => RefreshMode::MatchHost(refresh_rate as f64),
+        }
+    } else {
+        RefreshMode::Exact
+    };
+    let window = video_subsystem
+        .window(
+            "GB Emulator",
+            160 * args.scale as u32,
+            144 * args.scale as u32,
+        )
+        .position_centered()
+        .build()
+        .map_err(|e| e.to_string())?;
+    let canvas = window.into_canvas();
+    let canvas = match refresh_mode {
+        RefreshMode::MatchHost(_) => canvas.present_vsync(),
+        RefreshMode::Exact => canvas,
+    };
+    let mut canvas = canvas.build().map_err(|e| e.to_string())?;
+    canvas.set_scale(args.scale as f32, args.scale as f32)?;
+    // tilt sensing cartridges can be tilted with the first controller's left stick, or with the mouse, and rumble
+    // cartridges shake the controller
+    let game_controller_subsystem = sdl_context.game_controller()?;
+    let controller: Option<GameController> = (0..game_controller_subsystem.num_joysticks()?)
+        .find(|&idx| game_controller_subsystem.is_game_controller(idx))
+        .and_then(|idx| game_controller_subsystem.open(idx).ok());
+    let event_pump = sdl_context.event_pump()?;
+    let texture_creator = canvas.texture_creator();
+    let texture = texture_creator.create_texture_streaming(PixelFormatEnum::RGBA32, 160, 144)?;
+
+    execute_rom(
+        emu,
+        event_pump,
+        controller,
+        canvas,
+        texture,
+        bg_canvas_and_texture,
+        window_canvas_and_texture,
+        obj_canvas_and_texture,
+        tiles_canvas_and_texture.map(|(canvas, texture)| (canvas, texture, args.tile_palette)),
+        &bindings,
+        Turbo::new(args.turbo_period),
+        args.record_movie.as_deref(),
+        (!args.no_sleep).then_some(refresh_mode),
+        args.fast_forward_speed,
+        args.profile,
+        args.break_at_entry,
+        args.pause_in_background,
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+fn execute_rom(
+    mut emu: gbrs::Emulator,
+    mut event_pump: sdl2::EventPump,
+    mut controller: Option<GameController>,
+    mut lcd_canvas: sdl2::render::Canvas<sdl2::video::Window>,
+    mut lcd_texture: sdl2::render::Texture,
+    mut background_canvas_and_texture: Option<(
+        sdl2::render::Canvas<sdl2::video::Window>,
+        sdl2::render::Texture,
+    )>,
+    mut window_canvas_and_texture: Option<(
+        sdl2::render::Canvas<sdl2::video::Window>,
+        sdl2::render::Texture,
+    )>,
+    mut obj_canvas_and_texture: Option<(
+        sdl2::render::Canvas<sdl2::video::Window>,
+        sdl2::render::Texture,
+    )>,
+    mut tiles_canvas_and_texture: Option<(
+        sdl2::render::Canvas<sdl2::video::Window>,
+        sdl2::render::Texture,
+        TilePalette,
+    )>,
+    bindings: &Bindings,
+    mut turbo: Turbo,
+    record_movie: Option<&Path>,
+    refresh_mode: Option<RefreshMode>,
+    fast_forward_speed: u32,
+    profile: bool,
+    break_at_entry: bool,
+    pause_in_background: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut profiler = profile.then(Profiler::new);
+    emu.set_profiling(profile);
+    let sleep_enabled = refresh_mode.is_some();
+    let mut pacer = FramePacer::for_refresh_mode(refresh_mode.unwrap_or(RefreshMode::Exact));
+    let mut pressed_buttons = EnumSet::<joypad::Button>::empty();
+    let mut lcd = vec![0; gbrs::FRAME_RGBA_LEN];
+    let mut print_logs: bool = false;
+    let stdout = std::io::stdout();
+    let mut lock = stdout.lock();
+    let mut fast_mode = false;
+    // without a boot ROM to run, the emulator starts at the cartridge entry point
+    let mut paused = break_at_entry && emu.cycle_count() == 0 && !emu.in_boot_rom();
+    if paused {
+        eprintln!("Paused at the cartridge entry point, press P to resume");
+    }
+    // Focus moves between the LCD and the debug views, so track which window has it
+    let mut focused_window = Some(lcd_canvas.window().id());
+    let mut minimized = false;
+    let mut tilt = (0.0, 0.0);
+    let mut lockup = None;
+    let mut frame_advance = false;
+    let mut quick_save = None;
+    // without a path for the recording, it goes into the save directory
+    let movie_path = match record_movie {
+        Some(path) => path.to_path_buf(),
+        None => emu.save_dir().join("recording.movie"),
+    };
+    if record_movie.is_some() && !emu.playing_movie() {
+        emu.start_movie_recording();
+    }
+    loop {
+        // Handle events
+        for event in event_pump.poll_iter() {
+            if let Some((input, pressed)) = Input::from_event(&event) {
+                match (bindings.action(input), pressed) {
+                    (Some(Action::Joypad(button)), true) => {
+                        pressed_buttons.insert(button);
+                    }
+                    (Some(Action::Joypad(button)), false) => {
+                        pressed_buttons.remove(button);
+                    }
+                    (Some(Action::Turbo(button)), held) => turbo.set_held(button, held),
+                    (Some(Action::FastForward), pressed) => fast_mode = pressed,
+                    (Some(Action::PrintLogs), pressed) => print_logs = pressed,
+                    (Some(Action::Pause), true) => paused = !paused,
+                    (Some(Action::FrameAdvance), true) => {
+                        frame_advance = paused;
+                        paused = true;
+                    }
+                    (Some(Action::QuickSave), true) => match emu.save_state() {
+                        Ok(state) => quick_save = Some(state),
+                        Err(e) => eprintln!("Failed to create save state: {e}"),
+                    },
+                    (Some(Action::QuickLoad), true) => {
+                        if let Some(state) = &quick_save {
+                            if let Err(e) = emu.load_state(state) {
+                                eprintln!("Failed to load save state: {e}");
+                            }
+                        }
+                    }
+                    (Some(Action::RecordMovie), true) => {
+                        if emu.playing_movie() {
+                            emu.resume_movie_recording();
+                            eprintln!("Recording from frame {}", emu.frame_count());
+                        } else if emu.movie().is_none() {
+                            emu.start_movie_recording();
+                            eprintln!("Recording a movie to {movie_path:?}");
+                        }
+                    }
+                    (Some(Action::SaveState), true) => match emu.dump_save_state() {
+                        Ok(_) => {}
+                        Err(e) => eprintln!("Failed to create save state: {e}"),
+                    },
+                    (Some(Action::Diagnostics), true) => {
+                        match super::write_diagnostics_bundle(&emu, emu.save_dir(), lockup) {
+                            Ok(path) => eprintln!("Wrote diagnostics to {path:?}"),
+                            Err(e) => eprintln!("Failed to write diagnostics: {e}"),
+                        };
+                    }
+                    _ => {}
+                }
+                continue;
+            }
+            match event {
+                Event::Quit { .. } => {
+                    if let Some(movie) = emu.finish_movie_recording() {
+                        super::write_movie(&movie_path, &movie)?;
+                        eprintln!(
+                            "Wrote a movie of {} frames to {movie_path:?}",
+                            movie.frames.len()
+                        );
+                    }
+                    return emu.shutdown();
+                }
+                Event::Window {
+                    window_id,
+                    win_event,
+                    ..
+                } => match win_event {
+                    WindowEvent::FocusGained => focused_window = Some(window_id),
+                    WindowEvent::FocusLost if focused_window == Some(window_id) => {
+                        focused_window = None
+                    }
+                    WindowEvent::Minimized if window_id == lcd_canvas.window().id() => {
+                        minimized = true
+                    }
+                    WindowEvent::Restored | WindowEvent::Maximized | WindowEvent::Shown
+                        if window_id == lcd_canvas.window().id() =>
+                    {
+                        minimized = false
+                    }
+                    _ => {}
+                },
+                Event::MouseMotion {
+                    window_id, x, y, ..
+                } if window_id == lcd_canvas.window().id() => {
+                    let (width, height) = lcd_canvas.window().size();
+                    tilt = (
+                        x as f32 / width as f32 * 2.0 - 1.0,
+                        y as f32 / height as f32 * 2.0 - 1.0,
+                    );
+                }
+                Event::ControllerAxisMotion {
+                    axis: Axis::LeftX,
+                    value,
+                    ..
+                } => tilt.0 = value as f32 / i16::MAX as f32,
+                Event::ControllerAxisMotion {
+                    axis: Axis::LeftY,
+                    value,
+                    ..
+                } => tilt.1 = value as f32 / i16::MAX as f32,
+                _ => {}
+            };
+        }
+        emu.set_pressed_buttons(pressed_buttons | turbo.pressed_buttons(emu.frame_count()));
+        emu.set_accelerometer(tilt.0, tilt.1);
+        let in_background = pause_in_background && (focused_window.is_none() || minimized);
+
+        // When fast-forwarding, run several frames per host frame and only render the last one
+        let emulated_frames = if frame_advance {
+            frame_advance = false;
+            1
+        } else if paused || in_background {
+            0
+        } else if fast_mode {
+            fast_forward_speed
+        } else if !sleep_enabled {
+            UNTHROTTLED_FRAMES_PER_HOST_FRAME
+        } else {
+            1
+        };
+        let emulation_start = std::time::Instant::now();
+        for _ in 0..emulated_frames {
+            if print_logs {
+                let frame = emu.frame_count();
+                let mut log_result = Ok(());
+                emu.run_until(gbrs::T_CYCLES_PER_FRAME, |emu| {
+                    log_result = print_emulator_state(&mut lock, emu);
+                    log_result.is_err()
+                        || emu.frame_count() != frame
+                        || hit_break(emu, break_at_entry)
+                })?;
+                log_result?;
+            } else if break_at_entry && emu.in_boot_rom() {
+                let frame = emu.frame_count();
+                emu.run_until(gbrs::T_CYCLES_PER_FRAME, |emu| {
+                    emu.frame_count() != frame || hit_break(emu, break_at_entry)
+                })?;
+            } else {
+                emu.run_frame()?;
+            }
+            if hit_break(&emu, break_at_entry) {
+                break;
+            }
+        }
+        // the motor stops while paused
+        let rumble = if emulated_frames > 0 {
+            emu.take_rumble_state()
+        } else {
+            Some(0.0)
+        };
+        if let (Some(controller), Some(strength)) = (&mut controller, rumble) {
+            let strength = (strength * u16::MAX as f32) as u16;
+            // renewed every frame, so it stops soon if the emulator does
+            let _ = controller.set_rumble(strength, strength, 100);
+        }
+        for event in emu.take_events() {
+            match event {
+                gbrs::Event::BootRomExited if break_at_entry => {
+                    paused = true;
+                    eprintln!("Paused at the cartridge entry point, press P to resume");
+                    print_emulator_state(&mut lock, &emu)?;
+                }
+                gbrs::Event::LockedUp(locked_up) => {
+                    eprintln!(
+                        "The game seems to have locked up: it has been looping in {:04X}-{:04X} with interrupts disabled. Press F12 to write a diagnostics bundle",
+                        locked_up.lowest_pc, locked_up.highest_pc
+                    );
+                    lockup = Some(locked_up);
+                }
+                gbrs::Event::MovieEnded => {
+                    eprintln!("The movie ended at frame {}", emu.frame_count())
+                }
+                gbrs::Event::BootRomExited | gbrs::Event::WatchedWrite(_) => {}
+            }
+        }
+        if let Some(profiler) = &mut profiler {
+            // scanlines are drawn while stepping, so split that time out of the step loop
+            let render_time = emu.take_ppu_render_time();
+            profiler.add(Section::CpuStep, emulation_start.elapsed());
+            profiler.subtract(Section::CpuStep, render_time);
+            profiler.add(Section::PpuRender, render_time);
+        }
+
+        let debug_views_start = std::time::Instant::now();
+        // Update background texture
+        if let Some((ref mut canvas, ref mut texture)) = background_canvas_and_texture {
+            let background = emu.dbg_resolve_background();
+            texture.with_lock(None, |buffer: &mut [u8], _pitch: usize| {
+                for (y, row) in background.iter().enumerate() {
+                    for (x, &color) in row.iter().enumerate() {
+                        let offset = (y * background[0].len() + x) * 3;
+                        let sdl_color = emu.shade_rgb(color);
+                        buffer[offset..offset + 3].copy_from_slice(&sdl_color);
+                    }
+                }
+            })?;
+            canvas.clear();
+            canvas.copy(texture, None, None)?;
+            canvas.present();
+        }
+
+        // Update OAM texture
+        if let Some((ref mut canvas, ref mut texture)) = obj_canvas_and_texture {
+            let oam_data = emu.dbg_resolve_obj_layer();
+            texture.with_lock(None, |buffer: &mut [u8], _pitch: usize| {
+                for (y, row) in oam_data.iter().enumerate() {
+                    for (x, &color) in row.iter().enumerate() {
+                        let offset = (y * oam_data[0].len() + x) * 3;
+                        let sdl_color = emu.shade_rgb(color);
+                        buffer[offset..offset + 3].copy_from_slice(&sdl_color);
+                    }
+                }
+            })?;
+            canvas.clear();
+            canvas.copy(texture, None, None)?;
+            canvas.present();
+        }
+
+        // update window texture
+        if let Some((ref mut canvas, ref mut texture)) = window_canvas_and_texture {
+            let window = emu.dbg_resolve_window();
+            let window = window
+                .iter()
+                .map(|line| line.as_slice())
+                .collect::<Vec<_>>();
+            update_canvas(canvas, texture, &window, emu.palette())?;
+            canvas.present();
+        }
+
+        // update tiles texture
+        if let Some((ref mut canvas, ref mut texture, palette)) = tiles_canvas_and_texture {
+            let tiles = emu.dbg_resolve_tiles(palette);
+            let tiles = tiles.iter().map(|line| line.as_slice()).collect::<Vec<_>>();
+            update_canvas(canvas, texture, &tiles, emu.palette())?;
+            canvas.present();
+        }
+        if let Some(profiler) = &mut profiler {
+            profiler.add(Section::DebugViews, debug_views_start.elapsed());
+        }
+
+        // update main display
+        let upload_start = std::time::Instant::now();
+        emu.frame_rgba(&mut lcd);
+        lcd_texture.update(None, &lcd, 160 * 4)?;
+        lcd_canvas.clear();
+        lcd_canvas.copy(&lcd_texture, None, None)?;
+        if let Some(profiler) = &mut profiler {
+            profiler.add(Section::TextureUpload, upload_start.elapsed());
+            draw_profiler_overlay(&mut lcd_canvas, profiler)?;
+        }
+        lcd_canvas.present();
+        if let Some(profiler) = &mut profiler {
+            profiler.end_frame();
+        }
+
+        // Sleep to maintain frame rate, if requested
+        if sleep_enabled {
+            thread::sleep(pacer.end_host_frame(emulated_frames, Instant::now()));
+        } else {
+            pacer.end_unthrottled_host_frame(emulated_frames, Instant::now());
+        }
+        if let Some(stats) = pacer.take_updated_stats() {
+            lcd_canvas
+                .window_mut()
+                .set_title(&window_title(stats, profiler.as_ref()))?;
+        }
+    }
+
+    /// Whether the emulator should pause because it just reached a point requested on the command line
+    fn hit_break(emu: &gbrs::Emulator, break_at_entry: bool) -> bool {
+        break_at_entry && emu.pending_events().contains(&gbrs::Event::BootRomExited)
+    }
+
+    fn print_emulator_state(
+        out: &mut impl std::io::Write,
+        emu: &gbrs::Emulator,
+    ) -> std::io::Result<()> {
+        let state = emu.debug_state();
+        let regs = &state.regs;
+        let pc_mem = |offset| emu.read_memory(regs.pc.wrapping_add(offset));
+        writeln!(out, "CPU State:")?;
+        writeln!(out, "Boot ROM mapped: {}", emu.in_boot_rom())?;
+        writeln!(out,
+        "IME: {:?} HALTED: {:?}, IE: {:?}, IF: {:?}\nA:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+        state.ime, state.halted, state.interrupts_enabled, state.interrupts_requested, regs.a, regs.f, regs.b, regs.c, regs.d, regs.e, regs.h, regs.l, regs.sp, regs.pc, pc_mem(0), pc_mem(1), pc_mem(2), pc_mem(3))?;
+        writeln!(out, "Next: {}", emu.describe_instruction(regs.pc))?;
+        let ppu = &state.ppu;
+        writeln!(out, "PPU State:")?;
+        writeln!(out, "  Mode: {:?}", ppu.mode)?;
+        writeln!(out, "  Line: {}", ppu.ly)?;
+        writeln!(out, "  LCD Enabled: {}", ppu.lcdc & 0x80 != 0)?;
+        writeln!(out, "  Window Enabled: {}", ppu.lcdc & 0x20 != 0)?;
+        writeln!(out, "----------------------------------------")?;
+        Ok(())
+    }
+
+    fn update_canvas(
+        canvas: &mut sdl2::render::Canvas<sdl2::video::Window>,
+        texture: &mut sdl2::render::Texture,
+        image: &[&[Color]],
+        palette: ShadePalette,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        texture.with_lock(None, |buffer: &mut [u8], _pitch: usize| {
+            for (y, row) in image.iter().enumerate() {
+                for (x, &color) in row.iter().enumerate() {
+                    let offset = (y * image[0].len() + x) * 3;
+                    let sdl_color = palette[color as usize];
+                    buffer[offset..offset + 3].copy_from_slice(&sdl_color);
+                }
+            }
+        })?;
+        canvas.clear();
+        canvas.copy(texture, None, None)?;
+        Ok(())
+    }
+
+    /// Draw one bar per profiler section across the top of the display.
+    ///
+    /// A bar spanning the full width of the display represents a full frame on real hardware.
+    fn draw_profiler_overlay(
+        canvas: &mut sdl2::render::Canvas<sdl2::video::Window>,
+        profiler: &Profiler,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        for (idx, section) in Section::ALL.into_iter().enumerate() {
+            let fraction_of_frame =
+                profiler.last_frame(section).as_secs_f64() / FRAME_DURATION.as_secs_f64();
+            let width = (fraction_of_frame * 160.0).clamp(1.0, 160.0) as u32;
+            let color = match section {
+                Section::CpuStep => sdl2::pixels::Color::RGB(220, 50, 50),
+                Section::PpuRender => sdl2::pixels::Color::RGB(50, 200, 50),
+                Section::TextureUpload => sdl2::pixels::Color::RGB(50, 100, 230),
+                Section::DebugViews => sdl2::pixels::Color::RGB(230, 200, 40),
+            };
+            canvas.set_draw_color(color);
+            canvas.fill_rect(sdl2::rect::Rect::new(1, 1 + 3 * idx as i32, width, 2))?;
+        }
+        Ok(())
+    }
+
+    fn window_title(stats: PacingStats, profiler: Option<&Profiler>) -> String {
+        let mut title = format!(
+            "GB Emulator | {:.1} fps ({:.1}x)",
+            stats.emulated_fps, stats.speed
+        );
+        if let Some(profiler) = profiler {
+            for section in Section::ALL {
+                let millis = profiler.last_frame(section).as_secs_f64() * 1000.0;
+                title.push_str(&format!(" {} {millis:.2}ms", section.name()));
+            }
+        }
+        title
+    }
+}
+
+/// Read a PNG file for the Game Boy Camera to see.
+fn read_camera_image(path: &Path) -> Result<StaticImage, Box<dyn std::error::Error>> {
+    let file = File::open(path).context(format!("Unable to open PNG file: {:?}", path))?;
+    let mut decoder = png::Decoder::new(file);
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info()?;
+    let mut data = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut data)?;
+    let channels = info.color_type.samples();
+    let pixels: Vec<u8> = data[..info.buffer_size()]
+        .chunks_exact(channels)
+        .map(|pixel| match pixel {
+            [r, g, b, ..] => ((*r as u32 * 299 + *g as u32 * 587 + *b as u32 * 114) / 1000) as u8,
+            [luma, ..] => *luma,
+            [] => unreachable!("PNG pixels have at least one sample"),
+        })
+        .collect();
+    Ok(StaticImage::from_grayscale(
+        &pixels,
+        info.width as usize,
+        info.height as usize,
+    ))
+}
@@ -0,0 +1,89 @@
+//! A single image with the LCD and every PPU debug view, for attaching to rendering bug reports.
+use gbrs::Color;
+
+/// The space between panels, and around the edges of the image
+const MARGIN: usize = 8;
+/// Labels are drawn in a 3x5 pixel font at this scale
+const LABEL_SCALE: usize = 2;
+const LABEL_HEIGHT: usize = 5 * LABEL_SCALE;
+
+/// Lay out the LCD, the background map with its viewport box, the window map, the tile atlas, and the OAM grid side by
+/// side, each with a label above it.
+pub fn compose(emu: &gbrs::Emulator) -> Vec<Vec<Color>> {
+    let panels: [(&str, Vec<Vec<Color>>); 5] = [
+        ("LCD", to_rows(&emu.resolve_display())),
+        ("BACKGROUND", to_rows(&emu.dbg_resolve_background())),
+        ("WINDOW", to_rows(&emu.dbg_resolve_window())),
+        (
+            "TILES",
+            to_rows(&emu.dbg_resolve_tiles(gbrs::ppu::TilePalette::Bg)),
+        ),
+        ("OAM", to_rows(&emu.dbg_resolve_oam())),
+    ];
+    let panel_top = MARGIN + LABEL_HEIGHT + MARGIN / 2;
+    let height = panel_top + panels.iter().map(|(_, p)| p.len()).max().unwrap_or(0) + MARGIN;
+    let width = MARGIN
+        + panels
+            .iter()
+            .map(|(_, p)| p[0].len() + MARGIN)
+            .sum::<usize>();
+    let mut image = vec![vec![Color::White; width]; height];
+    let mut left = MARGIN;
+    for (label, panel) in panels {
+        draw_label(&mut image, label, MARGIN, left);
+        for (y, row) in panel.iter().enumerate() {
+            image[panel_top + y][left..left + row.len()].copy_from_slice(row);
+        }
+        left += panel[0].len() + MARGIN;
+    }
+    image
+}
+
+fn to_rows<const W: usize, const H: usize>(grid: &[[Color; W]; H]) -> Vec<Vec<Color>> {
+    grid.iter().map(|row| row.to_vec()).collect()
+}
+
+fn draw_label(image: &mut [Vec<Color>], label: &str, top: usize, left: usize) {
+    for (char_idx, c) in label.chars().enumerate() {
+        let char_left = left + char_idx * 4 * LABEL_SCALE;
+        for (y, row) in glyph(c).into_iter().enumerate() {
+            for x in 0..3 {
+                if row & (0b100 >> x) == 0 {
+                    continue;
+                }
+                for dy in 0..LABEL_SCALE {
+                    for dx in 0..LABEL_SCALE {
+                        image[top + y * LABEL_SCALE + dy][char_left + x * LABEL_SCALE + dx] =
+                            Color::Black;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The rows of a 3x5 glyph, top to bottom. Bit 2 is the left-most pixel.
+///
+/// Only the letters used in labels are defined. Anything else is blank.
+fn glyph(c: char) -> [u8; 5] {
+    match c {
+        'A' => [0b010, 0b101, 0b111, 0b101, 0b101],
+        'B' => [0b110, 0b101, 0b110, 0b101, 0b110],
+        'C' => [0b011, 0b100, 0b100, 0b100, 0b011],
+        'D' => [0b110, 0b101, 0b101, 0b101, 0b110],
+        'E' => [0b111, 0b100, 0b110, 0b100, 0b111],
+        'G' => [0b011, 0b100, 0b101, 0b101, 0b011],
+        'I' => [0b111, 0b010, 0b010, 0b010, 0b111],
+        'K' => [0b101, 0b101, 0b110, 0b101, 0b101],
+        'L' => [0b100, 0b100, 0b100, 0b100, 0b111],
+        'M' => [0b101, 0b111, 0b111, 0b101, 0b101],
+        'N' => [0b110, 0b101, 0b101, 0b101, 0b101],
+        'O' => [0b010, 0b101, 0b101, 0b101, 0b010],
+        'R' => [0b110, 0b101, 0b110, 0b101, 0b101],
+        'S' => [0b011, 0b100, 0b010, 0b001, 0b110],
+        'T' => [0b111, 0b010, 0b010, 0b010, 0b010],
+        'U' => [0b101, 0b101, 0b101, 0b101, 0b111],
+        'W' => [0b101, 0b101, 0b111, 0b111, 0b101],
+        _ => [0; 5],
+    }
+}
@@ -0,0 +1,136 @@
+//! An opt-in profile of the T-cycles spent at each instruction, for finding the hot loops of a game.
+//!
+//! Addresses are qualified by their ROM bank like in symbol files, so that code in different banks at the same
+//! address is counted separately.
+use std::collections::HashMap;
+use std::io::Write;
+
+use crate::Emulator;
+
+/// The T-cycles spent executing the instruction at `bank:addr`, including the time spent halted at it and
+/// dispatching interrupts before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hotspot {
+    /// The ROM bank mapped at 0x4000-0x7FFF for addresses in it, and 0 for any other address
+    pub bank: usize,
+    pub addr: u16,
+    pub t_cycles: u64,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct CycleProfile {
+    t_cycles: HashMap<(usize, u16), u64>,
+    total_t_cycles: u64,
+}
+
+impl CycleProfile {
+    pub(crate) fn record(&mut self, addr: u16, rom_bank: usize, t_cycles: u8) {
+        let bank = match addr {
+            0x4000..=0x7FFF => rom_bank,
+            _ => 0,
+        };
+        *self.t_cycles.entry((bank, addr)).or_default() += t_cycles as u64;
+        self.total_t_cycles += t_cycles as u64;
+    }
+}
+
+impl Emulator {
+    /// Start counting the T-cycles spent at each instruction, see [`Emulator::hotspots`]. Restarts the count if it
+    /// was already enabled.
+    pub fn enable_cycle_profile(&mut self) {
+        self.cycle_profile = Some(CycleProfile::default());
+    }
+
+    pub fn disable_cycle_profile(&mut self) {
+        self.cycle_profile = None;
+    }
+
+    /// The `count` instructions that the most T-cycles were spent at, most first. Empty unless profiling was enabled
+    /// with [`Emulator::enable_cycle_profile`].
+    pub fn hotspots(&self, count: usize) -> Vec<Hotspot> {
+        let Some(profile) = &self.cycle_profile else {
+            return Vec::new();
+        };
+        let mut hotspots: Vec<Hotspot> = profile
+            .t_cycles
+            .iter()
+            .map(|(&(bank, addr), &t_cycles)| Hotspot {
+                bank,
+                addr,
+                t_cycles,
+            })
+            .collect();
+        // ties are broken by address, so that the order is stable
+        hotspots.sort_unstable_by_key(|hotspot| {
+            (
+                std::cmp::Reverse(hotspot.t_cycles),
+                hotspot.bank,
+                hotspot.addr,
+            )
+        });
+        hotspots.truncate(count);
+        hotspots
+    }
+
+    /// Write the `count` hottest instructions as a table of their share of the profiled T-cycles, their address as
+    /// `bank:addr`, and their T-cycles.
+    pub fn write_hotspot_report(&self, out: &mut impl Write, count: usize) -> std::io::Result<()> {
+        let total = self
+            .cycle_profile
+            .as_ref()
+            .map_or(0, |profile| profile.total_t_cycles);
+        writeln!(out, "{total} T-cycles profiled")?;
+        for hotspot in self.hotspots(count) {
+            writeln!(
+                out,
+                "{:6.2}%  {:02X}:{:04X}  {}",
+                hotspot.t_cycles as f64 * 100.0 / total as f64,
+                hotspot.bank,
+                hotspot.addr,
+                hotspot.t_cycles
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cartridge::header_checksum;
+    use crate::util::emulator_for;
+
+    #[test]
+    fn busy_loop_is_the_hottest() {
+        let mut rom = vec![0; 0x10000];
+        let program = [
+            0x3E, 0x02, // LD A,2
+            0xEA, 0x00, 0x20, // LD [0x2000],A   (map bank 2)
+            0xC3, 0x00, 0x40, // JP 0x4000
+        ];
+        rom[..program.len()].copy_from_slice(&program);
+        // 0x4000 in bank 2: NOP; JR -3
+        rom[0x8000..0x8003].copy_from_slice(&[0x00, 0x18, 0xFD]);
+        // MBC1 with 4 banks
+        rom[0x0147] = 0x01;
+        rom[0x0148] = 0x01;
+        rom[0x014D] = header_checksum(&rom);
+        let mut emu = emulator_for(&rom);
+        assert!(emu.hotspots(1).is_empty());
+        emu.enable_cycle_profile();
+        emu.run_frame().unwrap();
+
+        let hotspots = emu.hotspots(3);
+        let locations: Vec<(usize, u16)> = hotspots
+            .iter()
+            .map(|hotspot| (hotspot.bank, hotspot.addr))
+            .collect();
+        assert_eq!(locations, [(2, 0x4001), (2, 0x4000), (0, 0x0002)]);
+        // JR takes 12 T-cycles to NOP's 4, and the frame can end between them
+        assert!(hotspots[0].t_cycles.abs_diff(hotspots[1].t_cycles * 3) <= 12);
+
+        let mut report = Vec::new();
+        emu.write_hotspot_report(&mut report, 1).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        assert!(report.lines().nth(1).unwrap().contains("02:4001"));
+    }
+}
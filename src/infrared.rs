@@ -0,0 +1,116 @@
+//! The CGB infrared port, RP (0xFF56).
+//!
+//! https://gbdev.io/pandocs/CGB_Registers.html#ff56--rp-cgb-mode-only-infrared-communications-port
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::util::U8Ext;
+
+/// The other side of the infrared port: whatever the LED shines at, and whatever shines at the sensor.
+pub trait InfraredTransceiver: Send {
+    /// Called when the game turns the LED on or off.
+    fn set_led(&mut self, on: bool);
+    /// Whether the sensor currently receives light.
+    fn receiving(&self) -> bool;
+}
+
+/// One end of an infrared link between two emulators. Each end receives light while the other end's LED is on.
+pub struct InfraredLink {
+    led: Arc<AtomicBool>,
+    other_led: Arc<AtomicBool>,
+}
+
+impl InfraredLink {
+    /// Create the two ends of a link, e.g. for emulators running on two threads.
+    pub fn pair() -> (InfraredLink, InfraredLink) {
+        let (a, b) = (
+            Arc::new(AtomicBool::new(false)),
+            Arc::new(AtomicBool::new(false)),
+        );
+        (
+            InfraredLink {
+                led: a.clone(),
+                other_led: b.clone(),
+            },
+            InfraredLink {
+                led: b,
+                other_led: a,
+            },
+        )
+    }
+}
+
+impl InfraredTransceiver for InfraredLink {
+    fn set_led(&mut self, on: bool) {
+        self.led.store(on, Ordering::Relaxed);
+    }
+
+    fn receiving(&self) -> bool {
+        self.other_led.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub(crate) struct InfraredPort {
+    led: bool,
+    /// Bits 6-7 are both set. The sensor can only be read while enabled.
+    read_enabled: bool,
+    /// Without a transceiver, the sensor never receives light
+    #[serde(skip)]
+    pub(crate) transceiver: Option<Box<dyn InfraredTransceiver>>,
+}
+
+impl InfraredPort {
+    pub(crate) fn read(&self) -> u8 {
+        let receiving = self.read_enabled
+            && self
+                .transceiver
+                .as_ref()
+                .is_some_and(|transceiver| transceiver.receiving());
+        // bit 1 is 0 while receiving, and bits 2-5 are unused
+        (if self.read_enabled { 0xC0 } else { 0x00 })
+            | 0x3C
+            | ((!receiving as u8) << 1)
+            | self.led as u8
+    }
+
+    pub(crate) fn write(&mut self, byte: u8) {
+        self.read_enabled = byte & 0xC0 == 0xC0;
+        let led = byte.bit(0);
+        if led != self.led {
+            self.led = led;
+            if let Some(transceiver) = &mut self.transceiver {
+                transceiver.set_led(led);
+            }
+        }
+    }
+
+    pub(crate) fn connect(&mut self, mut transceiver: Box<dyn InfraredTransceiver>) {
+        transceiver.set_led(self.led);
+        self.transceiver = Some(transceiver);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{InfraredLink, InfraredPort};
+
+    #[test]
+    fn linked_ports_see_each_others_led() {
+        let (a, b) = InfraredLink::pair();
+        let mut port_a = InfraredPort::default();
+        let mut port_b = InfraredPort::default();
+        port_a.connect(Box::new(a));
+        port_b.connect(Box::new(b));
+        // reading is disabled
+        port_a.write(0x01);
+        assert_eq!(port_a.read(), 0x3F);
+        assert_eq!(port_b.read(), 0x3E);
+        port_b.write(0xC0);
+        assert_eq!(port_b.read(), 0xFC);
+        port_a.write(0x00);
+        assert_eq!(port_b.read(), 0xFE);
+    }
+}
@@ -1,7 +1,7 @@
 use enumset::EnumSetType;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, EnumSetType, Serialize)]
+#[derive(Debug, EnumSetType, Serialize, Deserialize)]
 #[enumset(repr = "u8")]
 pub enum Button {
     A,
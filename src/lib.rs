@@ -1,38 +1,208 @@
 #![allow(incomplete_features)]
 #![feature(assert_matches)]
 #![feature(generic_const_exprs)]
+// The core is unsafe-free, and its tests run under miri (`cargo miri-core`). Any future unsafe, e.g. for SIMD, should be
+// isolated behind a feature.
+#![forbid(unsafe_code)]
+pub mod apu;
+mod archive;
+mod battery;
+pub mod blending;
+pub mod breakpoint;
+pub mod bus_spy;
+pub mod camera;
 mod cartridge;
 pub mod cpu;
+pub mod debug_state;
+pub mod debugger;
+pub mod disassembler;
+pub mod hotspots;
+pub mod infrared;
 pub mod joypad;
+pub mod link;
 pub mod mmu;
+pub mod model;
+pub mod movie;
+pub mod opcode_stats;
+pub mod pacing;
+pub mod palette;
+pub mod patch;
 pub mod ppu;
+pub mod profiler;
+pub mod rewind;
+pub mod serial;
+pub mod symbols;
 mod timer;
+pub mod trace;
 mod util;
+pub mod watchdog;
+mod wav;
 use anyhow::Context;
 use std::{
+    borrow::Cow,
     error::Error,
     path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
 };
 use twox_hash::xxh3;
 
+pub use breakpoint::Breakpoint;
+pub use cartridge::{validate_rom, Mapper, RomError, RtcClockSource};
+pub use cpu::{CallFrame, CallKind, IllegalOpcode};
+pub use debug_state::{DebugState, IoRegisters};
 use enumset::EnumSet;
+pub use link::LinkedPair;
 use mmu::Memory;
+pub use movie::Movie;
 pub use ppu::Color;
 pub use ppu::Mode;
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize)]
-pub struct Emulator {
-    // TODO: make this private and make a pub function that returns debug info instead
-    pub cpu: cpu::Cpu<mmu::Mmu>,
-    rom_name: String,
-    #[serde(skip)]
-    save_dir: PathBuf,
-    rom_hash: u64,
+/// The number of T-cycles the PPU takes to draw a frame: 154 lines of 456 cycles each.
+pub const T_CYCLES_PER_FRAME: u32 = 154 * 456;
+
+/// The size of the buffer that [`Emulator::frame_rgba`] fills: 160x144 pixels of 4 bytes each.
+pub const FRAME_RGBA_LEN: usize = 160 * 144 * 4;
+
+/// Configures how an [`Emulator`] is created.
+#[derive(Debug, Clone)]
+pub struct EmulatorBuilder {
+    sample_rate: u32,
+    dmg_revision: model::DmgRevision,
+    model: Option<model::HardwareModel>,
+    rtc_clock_source: RtcClockSource,
+    mapper: Option<Mapper>,
+    patch: Option<Vec<u8>>,
+    illegal_opcode_policy: IllegalOpcodePolicy,
+    ppu_access_blocking: bool,
+    renderer: ppu::Renderer,
+    boot_rom: Option<Vec<u8>>,
+    skip_boot_rom: bool,
 }
 
-impl Emulator {
-    pub fn for_rom(rom: &[u8], rom_path: &Path) -> Self {
+impl EmulatorBuilder {
+    pub fn new() -> Self {
+        EmulatorBuilder {
+            sample_rate: apu::DEFAULT_SAMPLE_RATE,
+            dmg_revision: model::DmgRevision::default(),
+            model: None,
+            rtc_clock_source: RtcClockSource::default(),
+            mapper: None,
+            patch: None,
+            illegal_opcode_policy: IllegalOpcodePolicy::default(),
+            ppu_access_blocking: true,
+            renderer: ppu::Renderer::default(),
+            boot_rom: None,
+            skip_boot_rom: false,
+        }
+    }
+
+    /// The rate, in Hz, of the samples returned by [`Emulator::drain_audio_samples`]. E.g. 44100 or 48000.
+    ///
+    /// Panics if the rate is 0 or higher than the 4 MiHz system clock.
+    pub fn sample_rate(mut self, sample_rate: u32) -> Self {
+        assert!(
+            (1..=apu::T_CYCLES_PER_SECOND).contains(&sample_rate),
+            "Unsupported sample rate: {sample_rate}"
+        );
+        self.sample_rate = sample_rate;
+        self
+    }
+
+    /// The DMG revision to emulate for ROMs that don't enable CGB features. Defaults to DMG-CPU-B.
+    pub fn dmg_revision(mut self, revision: model::DmgRevision) -> Self {
+        self.dmg_revision = revision;
+        self
+    }
+
+    /// Emulate `model` regardless of the cartridge header. By default, the model is picked from the header's CGB flag,
+    /// see [`model::HardwareModel::for_rom`].
+    pub fn model(mut self, model: model::HardwareModel) -> Self {
+        self.model = Some(model);
+        self
+    }
+
+    /// What drives the real-time clock of MBC3 cartridges. Defaults to [`RtcClockSource::HostTime`].
+    pub fn rtc_clock_source(mut self, source: RtcClockSource) -> Self {
+        self.rtc_clock_source = source;
+        self
+    }
+
+    /// Use `mapper` regardless of the cartridge type in the header, e.g. for homebrew or hacks with a wrong header.
+    /// Whether the cartridge has a battery, a clock, or rumble is still read from the header.
+    pub fn mapper(mut self, mapper: Mapper) -> Self {
+        self.mapper = Some(mapper);
+        self
+    }
+
+    /// Apply an IPS or BPS patch, e.g. a translation, to the ROM when it's loaded. The ROM's hash, which save states
+    /// are checked against, is of the patched ROM.
+    pub fn patch(mut self, patch: Vec<u8>) -> Self {
+        self.patch = Some(patch);
+        self
+    }
+
+    /// What happens when the game executes an illegal opcode. Defaults to [`IllegalOpcodePolicy::Lock`].
+    pub fn illegal_opcode_policy(mut self, policy: IllegalOpcodePolicy) -> Self {
+        self.illegal_opcode_policy = policy;
+        self
+    }
+
+    /// Whether the CPU is blocked from VRAM and OAM while the PPU uses them, like on hardware. Defaults to true.
+    ///
+    /// Disabling it helps to tell whether a glitch comes from the game accessing them at the wrong time.
+    pub fn ppu_access_blocking(mut self, enabled: bool) -> Self {
+        self.ppu_access_blocking = enabled;
+        self
+    }
+
+    /// How the PPU draws scanlines. Defaults to [`ppu::Renderer::PixelFifo`], which is accurate to the dot;
+    /// [`ppu::Renderer::Scanline`] is faster on slow hosts.
+    pub fn renderer(mut self, renderer: ppu::Renderer) -> Self {
+        self.renderer = renderer;
+        self
+    }
+
+    /// Run a dump of the DMG (256 bytes) or CGB (2304 bytes) boot ROM before the cartridge. gbrs doesn't ship the boot
+    /// ROMs, so without one, the emulator starts as if it had been skipped, see [`EmulatorBuilder::skip_boot_rom`].
+    pub fn boot_rom(mut self, boot_rom: Vec<u8>) -> Self {
+        self.boot_rom = Some(boot_rom);
+        self
+    }
+
+    /// Start at the cartridge entry point, with the registers and IO state that the boot ROM leaves behind, even if a
+    /// boot ROM was given. Defaults to false.
+    pub fn skip_boot_rom(mut self, skip: bool) -> Self {
+        self.skip_boot_rom = skip;
+        self
+    }
+
+    /// Unpack `rom` if it's an archive, and apply the patch.
+    fn prepare_rom<'a>(&self, rom: &'a [u8]) -> Result<Cow<'a, [u8]>, RomError> {
+        let rom = archive::extract_rom(rom)?;
+        match &self.patch {
+            Some(patch) => Ok(Cow::Owned(patch::apply(&rom, patch)?)),
+            None => Ok(rom),
+        }
+    }
+
+    /// `rom` can also be a zip or gzip archive containing the ROM.
+    ///
+    /// Fails if the ROM is corrupted, truncated, or for an unsupported cartridge, see [`validate_rom`].
+    pub fn for_rom(self, rom: &[u8], rom_path: &Path) -> Result<Emulator, RomError> {
+        let rom = &*self.prepare_rom(rom)?;
+        let mapper = validate_rom(rom, self.mapper)?;
+        if let Some(boot_rom) = &self.boot_rom {
+            if ![0x100, 0x900].contains(&boot_rom.len()) {
+                return Err(RomError::BootRomSize {
+                    len: boot_rom.len(),
+                });
+            }
+        }
+        let rom_path = &archive::unpacked_path(rom_path);
         let rom_name = rom_path
             .file_stem()
             .and_then(|path| path.to_str())
@@ -44,20 +214,62 @@ impl Emulator {
             .join(&rom_name)
             .to_path_buf();
         eprintln!("Will put save files in {:?}", save_dir);
-        let cpu = cpu::Cpu::new(mmu::Mmu::new(rom), false);
-        Self {
+        let model = self
+            .model
+            .unwrap_or_else(|| model::HardwareModel::for_rom(rom, self.dmg_revision));
+        let mut cpu = cpu::Cpu::new(mmu::Mmu::with_mapper(rom, model, mapper));
+        cpu.mmu.apu.set_sample_rate(self.sample_rate);
+        cpu.mmu.set_rtc_clock_source(self.rtc_clock_source);
+        cpu.mmu.ppu_access_blocking = self.ppu_access_blocking;
+        cpu.mmu.ppu.renderer = self.renderer;
+        match self.boot_rom {
+            Some(boot_rom) if !self.skip_boot_rom => cpu.mmu.load_boot_rom(boot_rom),
+            _ => model.skip_boot_rom(&mut cpu),
+        }
+        let mut emu = Emulator {
             cpu,
             rom_name,
             save_dir,
             rom_hash: xxh3::hash64(rom),
+            rom: rom.to_vec(),
+            frame_count: 0,
+            cycle_count: 0,
+            rewind: None,
+            events: Vec::new(),
+            cancel_token: None,
+            watchdog: None,
+            symbols: None,
+            held_buttons: Vec::new(),
+            battery_file: None,
+            illegal_opcode_policy: self.illegal_opcode_policy,
+            cycle_profile: None,
+            resuming_from_breakpoint: false,
+            movie: None,
+            palette: palette::default_shade_palette(),
+            frame_ready: false,
+            frame_hook: None,
+            scanline_hook: None,
+            blender: None,
+        };
+        emu.set_palette(emu.palette);
+        if let Err(e) = emu.attach_battery_file(battery::BatteryFile::for_rom(rom_path)) {
+            eprintln!("Failed to load the battery save: {e}");
         }
+        Ok(emu)
     }
 
+    /// Restore a save state made by [`Emulator::dump_save_state`] or [`Emulator::write_save_state`]. The battery backed
+    /// RAM of the state is written to the save file next to `rom_path` from then on, like with
+    /// [`EmulatorBuilder::for_rom`].
     pub fn load_save_state(
+        self,
         rom: &[u8],
+        rom_path: &Path,
         save_state_path: &Path,
         save_state: &[u8],
-    ) -> Result<Self, Box<dyn Error>> {
+    ) -> Result<Emulator, Box<dyn Error>> {
+        let rom = &*self.prepare_rom(rom)?;
+        validate_rom(rom, self.mapper)?;
         let save_state = zstd::decode_all(save_state)?;
         let mut emu: Emulator =
             rmp_serde::from_slice(&save_state).context("Error while deserializing emulator sav")?;
@@ -69,9 +281,324 @@ impl Emulator {
             .unwrap_or(Path::new("."))
             .to_path_buf();
         emu.save_dir = save_dir;
+        emu.illegal_opcode_policy = self.illegal_opcode_policy;
+        emu.cpu.mmu.ppu_access_blocking = self.ppu_access_blocking;
+        emu.cpu.mmu.ppu.renderer = self.renderer;
+        emu.set_palette(emu.palette);
+        emu.rom = rom.to_vec();
         emu.cpu.mmu.set_cart_rom(rom);
+        if emu.cpu.mmu.apu.sample_rate() != self.sample_rate {
+            emu.cpu.mmu.apu.set_sample_rate(self.sample_rate);
+        }
+        emu.keep_battery_file(battery::BatteryFile::for_rom(&archive::unpacked_path(
+            rom_path,
+        )));
         Ok(emu)
     }
+}
+
+impl Default for EmulatorBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Notable things that happened while stepping the emulator. See [`Emulator::take_events`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// Execution left the boot ROM and is about to run the cartridge entry point at 0x100.
+    BootRomExited,
+    /// An instruction wrote to an address added with [`Emulator::add_write_watch`].
+    WatchedWrite(u16),
+    /// The game seems to have locked up. See [`Emulator::enable_lockup_watchdog`].
+    LockedUp(watchdog::Lockup),
+    /// All frames of the movie passed to [`Emulator::play_movie`] have run.
+    MovieEnded,
+}
+
+/// What happens when the game executes an opcode that doesn't exist on the Game Boy, like 0xD3. Either way, the CPU
+/// hangs like on hardware, while the PPU and APU keep running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IllegalOpcodePolicy {
+    /// Only hang the CPU. The lock-up watchdog reports it, see [`Emulator::enable_lockup_watchdog`].
+    #[default]
+    Lock,
+    /// Also return a [`Stopped::IllegalOpcode`] error from the [`Emulator::step`] that executed the opcode.
+    Error,
+}
+
+/// Why [`Emulator::step`] stopped instead of running on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Stopped {
+    /// The next instruction is at a breakpoint, see [`Emulator::add_breakpoint`]. It hasn't been executed yet.
+    Breakpoint(Breakpoint),
+    /// The CPU executed an illegal opcode and the policy is [`IllegalOpcodePolicy::Error`].
+    IllegalOpcode(IllegalOpcode),
+}
+
+impl std::fmt::Display for Stopped {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Stopped::Breakpoint(Breakpoint {
+                addr,
+                rom_bank: Some(rom_bank),
+                ..
+            }) => write!(f, "Hit breakpoint at {rom_bank:02X}:{addr:04X}"),
+            Stopped::Breakpoint(Breakpoint { addr, .. }) => {
+                write!(f, "Hit breakpoint at {addr:04X}")
+            }
+            Stopped::IllegalOpcode(illegal_opcode) => illegal_opcode.fmt(f),
+        }
+    }
+}
+
+impl Error for Stopped {}
+
+/// How far [`Emulator::run_to`] runs, for stepping through a game at a coarser granularity than an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopCondition {
+    /// Until the PPU moves on to the next scanline
+    Scanline,
+    /// Until the PPU changes mode, e.g. from OAM scan to drawing
+    ModeChange,
+    /// Until the PPU completes the current frame
+    Frame,
+}
+
+/// Stops [`Emulator::run_until`] from another thread. See [`Emulator::set_cancel_token`].
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Emulator {
+    pub(crate) cpu: cpu::Cpu<mmu::Mmu>,
+    rom_name: String,
+    #[serde(skip)]
+    save_dir: PathBuf,
+    rom_hash: u64,
+    /// The cartridge ROM, kept around so that snapshots (which don't contain the ROM) can be restored.
+    #[serde(skip)]
+    rom: Vec<u8>,
+    /// The number of frames completed since power on. A frame completes when the PPU enters VBlank.
+    frame_count: u64,
+    /// The number of T-cycles executed since power on.
+    cycle_count: u64,
+    #[serde(skip)]
+    rewind: Option<rewind::RewindBuffer>,
+    /// Events that haven't been taken by the frontend yet
+    #[serde(skip)]
+    events: Vec<Event>,
+    #[serde(skip)]
+    cancel_token: Option<CancelToken>,
+    #[serde(skip)]
+    watchdog: Option<watchdog::LockupWatchdog>,
+    #[serde(skip)]
+    symbols: Option<symbols::SymbolTable>,
+    /// Buttons pressed with [`Emulator::hold_button`], and the frame at which to release each one.
+    ///
+    /// This is part of save states, so that loading a state in the middle of a hold reproduces the rest of it.
+    held_buttons: Vec<(joypad::Button, u64)>,
+    /// Where battery backed cartridge RAM is saved. `None` for cartridges without a battery, and for emulators loaded
+    /// from save states, which already contain the RAM.
+    #[serde(skip)]
+    battery_file: Option<battery::BatteryFile>,
+    #[serde(skip)]
+    illegal_opcode_policy: IllegalOpcodePolicy,
+    #[serde(skip)]
+    cycle_profile: Option<hotspots::CycleProfile>,
+    /// Set when [`Emulator::step`] stopped at a breakpoint, so that the next step executes the instruction there.
+    #[serde(skip)]
+    resuming_from_breakpoint: bool,
+    #[serde(skip)]
+    movie: Option<movie::MovieState>,
+    #[serde(skip, default = "palette::default_shade_palette")]
+    palette: palette::ShadePalette,
+    /// Set when a frame completes, and cleared by [`Emulator::frame_ready`]
+    #[serde(skip)]
+    frame_ready: bool,
+    #[serde(skip)]
+    frame_hook: Option<Box<dyn FnMut(u64) + Send>>,
+    #[serde(skip)]
+    scanline_hook: Option<Box<dyn FnMut(ppu::ScanlineSnapshot) + Send>>,
+    /// `None` while frame blending is off
+    #[serde(skip)]
+    blender: Option<Box<blending::Blender>>,
+}
+
+// Emulators share no global state, so each one can run on its own thread.
+const _: () = {
+    const fn assert_send<T: Send>() {}
+    assert_send::<Emulator>();
+};
+
+/// Everything attached to an emulator that isn't part of save states, like the frontend's devices and hooks, debugging
+/// aids, and the movie being recorded or played. Loading a state or rewinding moves them to the restored state.
+struct Attachments {
+    rewind: Option<rewind::RewindBuffer>,
+    movie: Option<movie::MovieState>,
+    /// Keep recording into the same file
+    capture: Option<wav::WavWriter>,
+    muted_channels: EnumSet<apu::Channel>,
+    cancel_token: Option<CancelToken>,
+    watchdog_timeout: Option<std::time::Duration>,
+    write_watches: Vec<u16>,
+    symbols: Option<symbols::SymbolTable>,
+    infrared: Option<Box<dyn infrared::InfraredTransceiver>>,
+    serial_device: Box<dyn serial::SerialDevice>,
+    bus_spy: Option<Box<bus_spy::BusSpy>>,
+    battery_file: Option<battery::BatteryFile>,
+    camera: Option<Box<dyn camera::CameraSource>>,
+    trace: Option<trace::ExecutionTrace>,
+    cycle_profile: Option<hotspots::CycleProfile>,
+    doctor_log: Option<trace::DoctorLog>,
+    breakpoints: Vec<Breakpoint>,
+    opcode_stats: Option<Box<opcode_stats::OpcodeStats>>,
+    palette: palette::ShadePalette,
+    frame_hook: Option<Box<dyn FnMut(u64) + Send>>,
+    scanline_hook: Option<Box<dyn FnMut(ppu::ScanlineSnapshot) + Send>>,
+    blender: Option<Box<blending::Blender>>,
+    profile_rendering: bool,
+}
+
+impl Emulator {
+    /// Switch to the emulated state of `restored`, keeping everything that isn't part of save states, see
+    /// [`Attachments`].
+    pub(crate) fn replace_state(&mut self, restored: Emulator) {
+        let attachments = self.detach();
+        *self = restored;
+        self.attach(attachments);
+        self.rerecord_movie();
+    }
+
+    fn detach(&mut self) -> Attachments {
+        Attachments {
+            rewind: self.rewind.take(),
+            movie: self.movie.take(),
+            capture: self.cpu.mmu.apu.capture.take(),
+            muted_channels: self.cpu.mmu.apu.muted_channels,
+            cancel_token: self.cancel_token.take(),
+            watchdog_timeout: self.watchdog.as_ref().map(|watchdog| watchdog.timeout()),
+            write_watches: std::mem::take(&mut self.cpu.mmu.write_watches),
+            symbols: self.symbols.take(),
+            infrared: self.cpu.mmu.infrared.transceiver.take(),
+            serial_device: self.disconnect_serial(),
+            bus_spy: self.cpu.mmu.bus_spy.take(),
+            battery_file: self.battery_file.take(),
+            camera: self.disconnect_camera(),
+            trace: self.cpu.trace.take(),
+            cycle_profile: self.cycle_profile.take(),
+            doctor_log: self.cpu.doctor_log.take(),
+            breakpoints: std::mem::take(&mut self.cpu.breakpoints),
+            opcode_stats: self.cpu.opcode_stats.take(),
+            palette: self.palette,
+            frame_hook: self.frame_hook.take(),
+            scanline_hook: self.scanline_hook.take(),
+            blender: self.blender.take(),
+            profile_rendering: self.cpu.mmu.ppu.profile_rendering,
+        }
+    }
+
+    fn attach(&mut self, attachments: Attachments) {
+        // destructured, so that a new attachment can't be left out
+        let Attachments {
+            rewind,
+            movie,
+            capture,
+            muted_channels,
+            cancel_token,
+            watchdog_timeout,
+            write_watches,
+            symbols,
+            infrared,
+            serial_device,
+            bus_spy,
+            battery_file,
+            camera,
+            trace,
+            cycle_profile,
+            doctor_log,
+            breakpoints,
+            opcode_stats,
+            palette,
+            frame_hook,
+            scanline_hook,
+            mut blender,
+            profile_rendering,
+        } = attachments;
+        self.rewind = rewind;
+        self.movie = movie;
+        self.cpu.mmu.apu.capture = capture;
+        self.cpu.mmu.apu.muted_channels = muted_channels;
+        self.cancel_token = cancel_token;
+        if let Some(timeout) = watchdog_timeout {
+            self.enable_lockup_watchdog(timeout);
+        }
+        self.cpu.mmu.write_watches = write_watches;
+        self.symbols = symbols;
+        if let Some(transceiver) = infrared {
+            self.connect_infrared(transceiver);
+        }
+        self.connect_serial(serial_device);
+        self.cpu.mmu.bus_spy = bus_spy;
+        self.battery_file = battery_file;
+        if let Some(camera) = camera {
+            self.connect_camera(camera);
+        }
+        self.cpu.trace = trace;
+        self.cycle_profile = cycle_profile;
+        self.cpu.doctor_log = doctor_log;
+        self.cpu.breakpoints = breakpoints;
+        self.cpu.opcode_stats = opcode_stats;
+        self.set_palette(palette);
+        self.frame_hook = frame_hook;
+        self.cpu.mmu.ppu.record_started_lines = scanline_hook.is_some();
+        self.scanline_hook = scanline_hook;
+        // the frames before belong to another state
+        if let Some(blender) = &mut blender {
+            blender.reset();
+        }
+        self.blender = blender;
+        self.cpu.mmu.ppu.profile_rendering = profile_rendering;
+    }
+}
+
+impl Emulator {
+    /// * `model` - The hardware to emulate, or `None` to pick it from the cartridge header
+    pub fn for_rom(
+        rom: &[u8],
+        rom_path: &Path,
+        model: Option<model::HardwareModel>,
+    ) -> Result<Self, RomError> {
+        let builder = EmulatorBuilder::new();
+        match model {
+            Some(model) => builder.model(model),
+            None => builder,
+        }
+        .for_rom(rom, rom_path)
+    }
+
+    pub fn load_save_state(
+        rom: &[u8],
+        rom_path: &Path,
+        save_state_path: &Path,
+        save_state: &[u8],
+    ) -> Result<Self, Box<dyn Error>> {
+        EmulatorBuilder::new().load_save_state(rom, rom_path, save_state_path, save_state)
+    }
 
     pub fn dump_save_state(&self) -> Result<(), Box<dyn std::error::Error>> {
         // create save dir if it doesn't exist
@@ -82,30 +609,550 @@ impl Emulator {
         );
         let save_file_path = self.save_dir.join(&file_name);
         eprintln!("Saving to {}", &file_name);
+        self.write_save_state(&save_file_path)
+    }
+
+    /// Write a save state to `path`, which can be loaded with [`Emulator::load_save_state`].
+    pub fn write_save_state(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        std::fs::write(path, self.save_state()?)?;
+        Ok(())
+    }
+
+    /// Serialize a save state in memory, in the format written by [`Emulator::write_save_state`].
+    pub fn save_state(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
         let bytes = rmp_serde::to_vec(self)
             .context("Failed to serialize emulator state with message pack protocol")?;
         let compressed_bytes = zstd::encode_all(std::io::Cursor::new(&bytes), 0)
             .context("Failed to compress with zstd")?;
-        std::fs::write(save_file_path, compressed_bytes)?;
+        Ok(compressed_bytes)
+    }
+
+    /// Restore a save state made by [`Emulator::save_state`] in place, keeping everything that isn't part of save
+    /// states, like connected devices and breakpoints.
+    ///
+    /// A movie that is being recorded continues from the frame of the save state, which counts as a re-record, see
+    /// [`Emulator::start_movie_recording`].
+    pub fn load_state(&mut self, save_state: &[u8]) -> Result<(), Box<dyn Error>> {
+        let save_state = zstd::decode_all(save_state)?;
+        let mut restored: Emulator =
+            rmp_serde::from_slice(&save_state).context("Error while deserializing emulator sav")?;
+        if restored.rom_hash != self.rom_hash {
+            return Err("The save state was made with another ROM".into());
+        }
+        restored.save_dir = self.save_dir.clone();
+        restored.illegal_opcode_policy = self.illegal_opcode_policy;
+        restored.cpu.mmu.ppu_access_blocking = self.cpu.mmu.ppu_access_blocking;
+        restored.cpu.mmu.ppu.renderer = self.cpu.mmu.ppu.renderer;
+        restored.cpu.mmu.set_cart_rom(&self.rom);
+        restored.rom = std::mem::take(&mut self.rom);
+        if restored.cpu.mmu.apu.sample_rate() != self.sample_rate() {
+            restored.cpu.mmu.apu.set_sample_rate(self.sample_rate());
+        }
+        self.replace_state(restored);
         Ok(())
     }
 
     /// Fetch, decode, and execute a single instruction.
     ///
     /// Returns the number of master clock cycles (at 4 MiHz) that the instruction takes. E.g. executing the NOP instruction will return 4
-    pub fn step(&mut self) -> u8 {
-        self.cpu.step()
+    ///
+    /// In CGB double speed mode, the CPU runs at 8 MiHz, so instructions take half as many master clock cycles.
+    ///
+    /// Stops without executing anything if the instruction is at a breakpoint, and fails after executing it if the
+    /// instruction is an illegal opcode and the policy is [`IllegalOpcodePolicy::Error`].
+    pub fn step(&mut self) -> Result<u8, Stopped> {
+        self.check_breakpoints()?;
+        let was_locked_up = self.cpu.locked_up.is_some();
+        let was_in_vblank = self.cpu.mmu.ppu.mode == Mode::VerticalBlank;
+        let was_in_boot_rom = self.cpu.mmu.in_boot_rom();
+        let was_double_speed = self.cpu.mmu.double_speed;
+        let pc = self.cpu.regs.pc;
+        let rom_bank = match self.cycle_profile {
+            Some(_) => self.cpu.mmu.rom_bank(),
+            None => 0,
+        };
+        let t_cycles = if self.cpu.trace.is_some() {
+            self.step_cpu_traced()
+        } else {
+            self.cpu.step()
+        };
+        let t_cycles = if was_double_speed {
+            t_cycles / 2
+        } else {
+            t_cycles
+        };
+        self.cycle_count += t_cycles as u64;
+        if let Some(profile) = &mut self.cycle_profile {
+            profile.record(pc, rom_bank, t_cycles);
+        }
+        if was_in_boot_rom && !self.cpu.mmu.in_boot_rom() {
+            if let model::HardwareModel::Dmg(revision) = self.cpu.mmu.model {
+                revision.apply_post_boot_state(&mut self.cpu);
+            }
+            self.events.push(Event::BootRomExited);
+        }
+        self.events.extend(
+            self.cpu
+                .mmu
+                .watched_writes
+                .drain(..)
+                .map(Event::WatchedWrite),
+        );
+        self.check_lockup();
+        if let Some(hook) = &mut self.scanline_hook {
+            for snapshot in self.cpu.mmu.ppu.started_lines.drain(..) {
+                hook(snapshot);
+            }
+        }
+        if !was_in_vblank && self.cpu.mmu.ppu.mode == Mode::VerticalBlank {
+            self.frame_count += 1;
+            self.record_movie_frame();
+            self.release_held_buttons();
+            self.play_movie_frame();
+            self.flush_battery_save_periodically();
+            self.deliver_bus_batches();
+            self.record_rewind_snapshot();
+            self.blend_frame();
+            self.frame_ready = true;
+            if let Some(hook) = &mut self.frame_hook {
+                hook(self.frame_count);
+            }
+        }
+        match self.cpu.locked_up {
+            Some(illegal_opcode) if !was_locked_up => {
+                self.dump_trace(&illegal_opcode.to_string());
+                match self.illegal_opcode_policy {
+                    IllegalOpcodePolicy::Lock => Ok(t_cycles),
+                    IllegalOpcodePolicy::Error => Err(Stopped::IllegalOpcode(illegal_opcode)),
+                }
+            }
+            _ => Ok(t_cycles),
+        }
+    }
+
+    /// Step until `stop` returns true, until at least `max_t_cycles` T-cycles have been executed, or until the cancel
+    /// token is cancelled.
+    ///
+    /// `stop` is called after every instruction. Returns the number of T-cycles executed, or the error from
+    /// [`Emulator::step`].
+    pub fn run_until(
+        &mut self,
+        max_t_cycles: u32,
+        mut stop: impl FnMut(&Self) -> bool,
+    ) -> Result<u32, Stopped> {
+        let mut t_cycles = 0;
+        while t_cycles < max_t_cycles {
+            t_cycles += self.step()? as u32;
+            if stop(self) || self.is_cancelled() {
+                break;
+            }
+        }
+        Ok(t_cycles)
+    }
+
+    /// Make [`Emulator::run_until`] return as soon as `token` is cancelled.
+    pub fn set_cancel_token(&mut self, token: CancelToken) {
+        self.cancel_token = Some(token);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel_token
+            .as_ref()
+            .is_some_and(|token| token.is_cancelled())
+    }
+
+    /// Run until the PPU completes the current frame. Returns the number of T-cycles executed.
+    ///
+    /// The PPU doesn't produce frames while the LCD is off, so this runs for at most one frame's worth of cycles.
+    pub fn run_frame(&mut self) -> Result<u32, Stopped> {
+        self.run_to(StopCondition::Frame)
     }
 
+    /// Run until the instruction that completes `condition`. Returns the number of T-cycles executed.
+    ///
+    /// Like [`Emulator::run_frame`], this runs for at most one frame's worth of cycles, since the PPU doesn't move
+    /// while the LCD is off.
+    pub fn run_to(&mut self, condition: StopCondition) -> Result<u32, Stopped> {
+        let ppu = &self.cpu.mmu.ppu;
+        let (line, mode, frame) = (ppu.line, ppu.mode, self.frame_count);
+        self.run_until(T_CYCLES_PER_FRAME, |emu| match condition {
+            StopCondition::Scanline => emu.cpu.mmu.ppu.line != line,
+            StopCondition::ModeChange => emu.cpu.mmu.ppu.mode != mode,
+            StopCondition::Frame => emu.frame_count != frame,
+        })
+    }
+
+    /// Ignored while a movie is playing, see [`Emulator::play_movie`].
     pub fn set_pressed_buttons(&mut self, pressed: EnumSet<joypad::Button>) {
+        if !self.playing_movie() {
+            self.press_buttons(pressed);
+        }
+    }
+
+    fn press_buttons(&mut self, pressed: EnumSet<joypad::Button>) {
+        if pressed != self.cpu.mmu.pressed_buttons() {
+            if let Some(rewind) = &mut self.rewind {
+                rewind.record_input(self.cycle_count, pressed);
+            }
+        }
         self.cpu.mmu.set_pressed_buttons(pressed);
     }
 
+    pub fn pressed_buttons(&self) -> EnumSet<joypad::Button> {
+        self.cpu.mmu.pressed_buttons()
+    }
+
+    /// Press `button` now and release it after `frames` frames have completed.
+    ///
+    /// Holding a button that is already held extends the hold if it would end later. Calling
+    /// [`Emulator::set_pressed_buttons`] overrides held buttons until they are released.
+    pub fn hold_button(&mut self, button: joypad::Button, frames: u64) {
+        let release_frame = self.frame_count + frames;
+        match self
+            .held_buttons
+            .iter_mut()
+            .find(|(held, _)| *held == button)
+        {
+            Some((_, release)) => *release = (*release).max(release_frame),
+            None => self.held_buttons.push((button, release_frame)),
+        }
+        self.set_pressed_buttons(self.pressed_buttons() | button);
+    }
+
+    fn release_held_buttons(&mut self) {
+        if self.held_buttons.is_empty() {
+            return;
+        }
+        let frame_count = self.frame_count;
+        let mut released = EnumSet::empty();
+        self.held_buttons.retain(|&(button, release_frame)| {
+            if release_frame <= frame_count {
+                released |= button;
+            }
+            release_frame > frame_count
+        });
+        if !released.is_empty() {
+            self.set_pressed_buttons(self.pressed_buttons() - released);
+        }
+    }
+
+    /// Emit an [`Event::WatchedWrite`] whenever an instruction writes to `addr`.
+    pub fn add_write_watch(&mut self, addr: u16) {
+        if !self.cpu.mmu.write_watches.contains(&addr) {
+            self.cpu.mmu.write_watches.push(addr);
+        }
+    }
+
+    /// The routines that execution is in, outermost first, as entered by CALL, RST, and interrupts and left by
+    /// returning from them.
+    ///
+    /// The call stack isn't part of save states, so it starts out empty after loading one or rewinding.
+    pub fn call_stack(&self) -> &[cpu::CallFrame] {
+        &self.cpu.call_stack
+    }
+
+    /// The directory that save states are written to by [`Emulator::dump_save_state`].
+    pub fn save_dir(&self) -> &Path {
+        &self.save_dir
+    }
+
+    /// Connect the CGB infrared port to `transceiver`, e.g. one end of an [`infrared::InfraredLink`] whose other end
+    /// is connected to another emulator. The port only exists in CGB mode.
+    pub fn connect_infrared(&mut self, transceiver: Box<dyn infrared::InfraredTransceiver>) {
+        self.cpu.mmu.infrared.connect(transceiver);
+    }
+
+    pub fn disconnect_infrared(&mut self) {
+        self.cpu.mmu.infrared.transceiver = None;
+    }
+
+    /// Plug the link cable into `device`, e.g. a [`serial::Capture`] to record the bytes that the game sends.
+    pub fn connect_serial(&mut self, device: Box<dyn serial::SerialDevice>) {
+        self.cpu.mmu.serial.device = device;
+    }
+
+    /// Unplug the link cable, and return the device it was plugged into.
+    pub fn disconnect_serial(&mut self) -> Box<dyn serial::SerialDevice> {
+        std::mem::replace(
+            &mut self.cpu.mmu.serial.device,
+            Box::new(serial::Disconnected),
+        )
+    }
+
+    /// Whether the boot ROM is still mapped over the start of the cartridge ROM.
+    pub fn in_boot_rom(&self) -> bool {
+        self.cpu.mmu.in_boot_rom()
+    }
+
+    /// The events that happened since the last call to [`Emulator::take_events`].
+    pub fn pending_events(&self) -> &[Event] {
+        &self.events
+    }
+
+    pub fn take_events(&mut self) -> Vec<Event> {
+        std::mem::take(&mut self.events)
+    }
+
+    /// Block the CPU from VRAM and OAM while the PPU uses them, or stop blocking it, e.g. from a debugger. See
+    /// [`EmulatorBuilder::ppu_access_blocking`]
+    pub fn set_ppu_access_blocking(&mut self, enabled: bool) {
+        self.cpu.mmu.ppu_access_blocking = enabled;
+    }
+
+    /// See [`EmulatorBuilder::renderer`]
+    pub fn set_renderer(&mut self, renderer: ppu::Renderer) {
+        self.cpu.mmu.ppu.renderer = renderer;
+    }
+
+    /// Display the 4 shades in `colors`, lightest first. See [`palette::Preset`] for named palettes.
+    ///
+    /// This is the mapping used by frontends and debug views for [`Color`]s, and by [`Emulator::resolve_display_rgb`]
+    /// on the DMG. On the CGB, DMG-only games keep the colorization picked by the boot ROM.
+    pub fn set_palette(&mut self, colors: palette::ShadePalette) {
+        self.palette = colors;
+        if !self.cpu.mmu.model.is_cgb() {
+            self.cpu.mmu.ppu.dmg_palette = palette::DmgPalette::from_rgb8(colors);
+        }
+    }
+
+    /// See [`Emulator::set_palette`]
+    pub fn palette(&self) -> palette::ShadePalette {
+        self.palette
+    }
+
+    /// The RGB color that `shade` is displayed as
+    pub fn shade_rgb(&self, shade: Color) -> [u8; 3] {
+        self.palette[shade as usize]
+    }
+
+    /// The hardware being emulated, picked by [`EmulatorBuilder::model`] or from the cartridge header
+    pub fn model(&self) -> model::HardwareModel {
+        self.cpu.mmu.model
+    }
+
+    /// The number of frames completed since power on.
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    /// Whether a frame completed since the last call, i.e. the PPU entered VBlank and [`Emulator::resolve_display`]
+    /// and [`Emulator::frame_rgba`] hold a new frame.
+    pub fn frame_ready(&mut self) -> bool {
+        std::mem::take(&mut self.frame_ready)
+    }
+
+    /// Call `hook` with the new [`Emulator::frame_count`] every time a frame completes, replacing the previous hook.
+    ///
+    /// The hook runs in the middle of [`Emulator::step`] and can't access the emulator, so it should only signal the
+    /// frontend, e.g. through a channel, to draw the frame once the step returns.
+    pub fn set_frame_hook(&mut self, hook: impl FnMut(u64) + Send + 'static) {
+        self.frame_hook = Some(Box::new(hook));
+    }
+
+    pub fn clear_frame_hook(&mut self) {
+        self.frame_hook = None;
+    }
+
+    /// Call `hook` with LY, SCX and SCY, WX and WY, and LCDC as they are when mode 3 starts on each line, replacing the
+    /// previous hook. Comparing them from line to line shows the raster effects of writes made during HBlank.
+    ///
+    /// Like [`Emulator::set_frame_hook`], the hook runs in the middle of [`Emulator::step`] and can't access the
+    /// emulator.
+    pub fn set_scanline_hook(&mut self, hook: impl FnMut(ppu::ScanlineSnapshot) + Send + 'static) {
+        self.scanline_hook = Some(Box::new(hook));
+        self.cpu.mmu.ppu.record_started_lines = true;
+    }
+
+    pub fn clear_scanline_hook(&mut self) {
+        self.scanline_hook = None;
+        self.cpu.mmu.ppu.record_started_lines = false;
+        self.cpu.mmu.ppu.started_lines.clear();
+    }
+
+    /// The number of T-cycles executed since power on.
+    pub fn cycle_count(&self) -> u64 {
+        self.cycle_count
+    }
+
+    /// Start or stop measuring the host time spent drawing scanlines.
+    pub fn set_profiling(&mut self, enabled: bool) {
+        self.cpu.mmu.ppu.profile_rendering = enabled;
+    }
+
+    /// The host time spent drawing scanlines since the last call to this function.
+    pub fn take_ppu_render_time(&mut self) -> std::time::Duration {
+        std::mem::take(&mut self.cpu.mmu.ppu.render_time)
+    }
+
+    /// Take the audio samples generated since the last call, at [`Emulator::sample_rate`] Hz, with the left and right
+    /// samples interleaved.
+    pub fn drain_audio_samples(&mut self) -> Vec<i16> {
+        std::mem::take(&mut self.cpu.mmu.apu.samples)
+    }
+
+    /// The rate of the audio samples in Hz, configured with [`EmulatorBuilder::sample_rate`].
+    pub fn sample_rate(&self) -> u32 {
+        self.cpu.mmu.apu.sample_rate()
+    }
+
+    /// Mute or unmute a single APU channel. Muted channels keep running, but are left out of the audio output.
+    pub fn set_channel_enabled(&mut self, channel: apu::Channel, enabled: bool) {
+        let muted = &mut self.cpu.mmu.apu.muted_channels;
+        if enabled {
+            muted.remove(channel);
+        } else {
+            muted.insert(channel);
+        }
+    }
+
+    pub fn channel_enabled(&self, channel: apu::Channel) -> bool {
+        !self.cpu.mmu.apu.muted_channels.contains(channel)
+    }
+
+    /// Start recording the generated audio to a WAV file at `path`, finishing any capture that is in progress.
+    pub fn start_audio_capture(&mut self, path: &Path) -> Result<(), Box<dyn Error>> {
+        self.stop_audio_capture()?;
+        let wav = wav::WavWriter::create(path, self.sample_rate(), apu::CHANNELS)
+            .context(format!("Unable to create WAV file: {:?}", path))?;
+        self.cpu.mmu.apu.capture = Some(wav);
+        Ok(())
+    }
+
+    /// Finish the audio capture that is in progress, if any.
+    pub fn stop_audio_capture(&mut self) -> Result<(), Box<dyn Error>> {
+        if let Some(wav) = self.cpu.mmu.apu.capture.take() {
+            wav.finish().context("Failed to write WAV file")?;
+        }
+        Ok(())
+    }
+
+    /// How strongly the rumble motor of the cartridge ran since the last call, from 0 to 1, or `None` for cartridges
+    /// without one. Games vary the strength by turning the motor on and off quickly, so frontends should call this
+    /// once per frame.
+    pub fn take_rumble_state(&mut self) -> Option<f32> {
+        self.cpu.mmu.take_rumble_state()
+    }
+
+    /// Use `source` for the image sensor of the Game Boy Camera. Has no effect for other cartridges.
+    pub fn connect_camera(&mut self, source: Box<dyn camera::CameraSource>) {
+        self.cpu.mmu.connect_camera(source);
+    }
+
+    /// Remove the camera source, after which pictures only show noise.
+    pub fn disconnect_camera(&mut self) -> Option<Box<dyn camera::CameraSource>> {
+        self.cpu.mmu.disconnect_camera()
+    }
+
+    /// Tilt cartridges with an accelerometer (MBC7). `x` is the left-right tilt and `y` the forward-backward tilt, both
+    /// in g, and games expect values between about -1 and 1.
+    pub fn set_accelerometer(&mut self, x: f32, y: f32) {
+        self.cpu.mmu.set_accelerometer(x, y);
+    }
+
+    /// The cartridge's battery backed RAM, followed by the footer with the real-time clock that BGB and VBA-M write, so
+    /// the save can be used with those emulators. `None` if the cartridge has no battery.
+    pub fn battery_save(&self) -> Option<Vec<u8>> {
+        self.cpu.mmu.battery_save()
+    }
+
+    /// Restore the cartridge RAM and clock from [`Emulator::battery_save`], or from another emulator's `.sav` file.
+    pub fn load_battery_save(&mut self, save: &[u8]) -> Result<(), Box<dyn Error>> {
+        Ok(self.cpu.mmu.load_battery_save(save)?)
+    }
+
+    /// Flush everything the emulator writes to disk in the background. Call this before exiting, or data may be lost.
+    ///
+    /// Currently, this finishes the audio capture and the Gameboy Doctor log, if they are in progress, and writes the
+    /// battery save.
+    pub fn shutdown(&mut self) -> Result<(), Box<dyn Error>> {
+        self.stop_audio_capture()?;
+        self.finish_doctor_log()?;
+        self.flush_battery_save()
+    }
+
     pub fn resolve_display(&self) -> [[Color; 160]; 144] {
         let display = self.cpu.mmu.ppu_as_ref().last_full_frame;
         display.map(|line| line.colors())
     }
 
+    /// Write the last full frame into `buffer` as 8-bit RGBA, row by row.
+    ///
+    /// On the DMG, the shades are displayed in the colors set with [`Emulator::set_palette`]. On the CGB, they are
+    /// the colors of the CGB palettes, or of the boot ROM's colorization for DMG-only games. The frame is blended with
+    /// the ones before it as set with [`Emulator::set_frame_blending`].
+    ///
+    /// Panics if `buffer` isn't [`FRAME_RGBA_LEN`] bytes long.
+    pub fn frame_rgba(&self, buffer: &mut [u8]) {
+        assert_eq!(
+            buffer.len(),
+            FRAME_RGBA_LEN,
+            "an RGBA frame is 160x144 pixels of 4 bytes"
+        );
+        match self.blended_frame() {
+            Some(blended) => buffer.copy_from_slice(blended),
+            None => self.draw_frame_rgba(buffer),
+        }
+    }
+
+    /// [`Emulator::frame_rgba`] without blending
+    fn draw_frame_rgba(&self, buffer: &mut [u8]) {
+        let ppu = self.cpu.mmu.ppu_as_ref();
+        let pixels = buffer.chunks_exact_mut(4);
+        if self.model().is_cgb() {
+            for (pixel, color) in pixels.zip(ppu.last_full_rgb_frame.as_flattened()) {
+                let [r, g, b] = color.to_rgb8();
+                pixel.copy_from_slice(&[r, g, b, 0xFF]);
+            }
+        } else {
+            let shades = ppu
+                .last_full_frame
+                .iter()
+                .flat_map(|line| (0..160).map(|x| line.pixel_at(x)));
+            for (pixel, shade) in pixels.zip(shades) {
+                let [r, g, b] = self.shade_rgb(shade);
+                pixel.copy_from_slice(&[r, g, b, 0xFF]);
+            }
+        }
+    }
+
+    /// An xxh3 hash of the last full frame, for comparing frames cheaply, e.g. against golden values in tests or
+    /// between linked emulators.
+    ///
+    /// The hash covers the shades of the frame, packed 4 pixels to a byte, and on the CGB also the RGB colors. It
+    /// doesn't depend on the palette set with [`Emulator::set_palette`], and stays the same across versions as long
+    /// as the emulated frame does.
+    pub fn frame_hash(&self) -> u64 {
+        let ppu = self.cpu.mmu.ppu_as_ref();
+        let mut bytes: Vec<u8> = ppu
+            .last_full_frame
+            .iter()
+            .flat_map(|line| *line.packed())
+            .collect();
+        if self.model().is_cgb() {
+            bytes.extend(
+                ppu.last_full_rgb_frame
+                    .as_flattened()
+                    .iter()
+                    .flat_map(|color| color.0.to_le_bytes()),
+            );
+        }
+        xxh3::hash64(&bytes)
+    }
+
+    /// The color IDs of the last full frame, before BGP, OBP0, and OBP1 or the CGB palettes are applied.
+    ///
+    /// Each pixel has the color ID of the object drawn there, or else of the background or window. Objects are
+    /// transparent in color ID 0, so color ID 0 always comes from the background, even if the palette shows it in the
+    /// same shade as an object.
+    pub fn resolve_display_color_ids(&self) -> [[ppu::ColorId; 160]; 144] {
+        *self.cpu.mmu.ppu_as_ref().last_full_color_id_frame
+    }
+
+    /// The last full frame in 15-bit RGB, which carries the CGB palette colors
+    pub fn resolve_display_rgb(&self) -> [[ppu::Rgb555; 160]; 144] {
+        self.cpu.mmu.ppu_as_ref().last_full_rgb_frame
+    }
+
     pub fn dbg_resolve_window(&self) -> [[Color; 256]; 256] {
         self.cpu.mmu.ppu_as_ref().dbg_resolve_window()
     }
@@ -114,11 +1161,363 @@ impl Emulator {
         self.cpu.mmu.ppu_as_ref().dbg_resolve_background()
     }
 
+    /// See [`ppu::Ppu::dbg_tilemap`]
+    pub fn dbg_tilemap(&self, layer: ppu::TilemapLayer, viewport: bool) -> ppu::TilemapView {
+        self.cpu.mmu.ppu_as_ref().dbg_tilemap(layer, viewport)
+    }
+
     pub fn dbg_resolve_obj_layer(&self) -> [[Color; 176]; 176] {
         self.cpu.mmu.ppu_as_ref().dbg_resolve_objects()
     }
 
+    pub fn dbg_resolve_tiles(&self, palette: ppu::TilePalette) -> [[Color; 128]; 192] {
+        self.cpu.mmu.ppu_as_ref().dbg_resolve_tiles(palette)
+    }
+
+    pub fn dbg_resolve_oam(&self) -> [[Color; 80]; 64] {
+        self.cpu.mmu.ppu_as_ref().dbg_resolve_oam()
+    }
+
+    /// See [`ppu::Ppu::dbg_oam_entries`]
+    pub fn dbg_oam_entries(&self) -> Vec<ppu::OamEntryDebug> {
+        self.cpu.mmu.ppu_as_ref().dbg_oam_entries()
+    }
+
     pub fn ppu_mode(&self) -> ppu::Mode {
         self.cpu.mmu.ppu.mode
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use twox_hash::xxh3;
+
+    use crate::cartridge::header_checksum;
+    use crate::joypad::Button;
+    use crate::mmu::Memory;
+    use crate::model::{DmgRevision, HardwareModel};
+    use crate::palette::{DmgPalette, Preset};
+    use crate::util::{emulator_for, rom_with_program, with_large_stack};
+    use crate::{
+        Color, Emulator, EmulatorBuilder, Event, IllegalOpcode, IllegalOpcodePolicy, RomError,
+        StopCondition, Stopped, FRAME_RGBA_LEN,
+    };
+
+    /// A program that turns on the LCD and loops forever
+    fn idle_rom() -> Vec<u8> {
+        rom_with_program(&[
+            0x3E, 0x80, // LD A,0x80
+            0xE0, 0x40, // LDH [0x40],A   (turn on the LCD)
+            0x18, 0xFE, // JR -2
+        ])
+    }
+
+    #[test]
+    fn illegal_opcode_policies() {
+        let mut rom = idle_rom();
+        // LD A,0x80; LDH [0x40],A; then an illegal opcode instead of the loop
+        rom[0x0004] = 0xD3;
+        rom[0x014D] = header_checksum(&rom);
+        let illegal_opcode = IllegalOpcode {
+            opcode: 0xD3,
+            addr: 0x0004,
+        };
+
+        let mut emu = EmulatorBuilder::new()
+            .for_rom(&rom, Path::new("illegal.gb"))
+            .unwrap();
+        emu.cpu.regs.pc = 0x0000;
+        emu.run_frame().unwrap();
+        emu.run_frame().unwrap();
+        // the CPU hangs, but the PPU keeps producing frames
+        assert_eq!(emu.cpu.locked_up, Some(illegal_opcode));
+        assert_eq!(emu.frame_count(), 2);
+
+        let mut emu = EmulatorBuilder::new()
+            .illegal_opcode_policy(IllegalOpcodePolicy::Error)
+            .for_rom(&rom, Path::new("illegal.gb"))
+            .unwrap();
+        emu.cpu.regs.pc = 0x0000;
+        assert_eq!(emu.run_frame(), Err(Stopped::IllegalOpcode(illegal_opcode)));
+        // the error is only returned once
+        emu.run_frame().unwrap();
+    }
+
+    #[test]
+    fn model_override_ignores_header() {
+        let rom = idle_rom();
+        let emu = Emulator::for_rom(&rom, Path::new("idle.gb"), None).unwrap();
+        assert_eq!(emu.cpu.mmu.model, HardwareModel::Dmg(DmgRevision::DmgB));
+        let emu = Emulator::for_rom(&rom, Path::new("idle.gb"), Some(HardwareModel::Cgb)).unwrap();
+        assert_eq!(emu.cpu.mmu.model, HardwareModel::Cgb);
+    }
+
+    #[test]
+    fn hold_button_releases_after_frames() {
+        let mut emu = Emulator::for_rom(&idle_rom(), Path::new("idle.gb"), None).unwrap();
+        emu.cpu.regs.pc = 0x0000;
+        emu.hold_button(Button::A, 2);
+        emu.hold_button(Button::Start, 1);
+        assert_eq!(emu.pressed_buttons(), Button::A | Button::Start);
+        emu.run_frame().unwrap();
+        assert_eq!(emu.pressed_buttons(), Button::A);
+        // holding again extends the hold
+        emu.hold_button(Button::A, 2);
+        emu.run_frame().unwrap();
+        assert_eq!(emu.pressed_buttons(), Button::A);
+        emu.run_frame().unwrap();
+        assert!(emu.pressed_buttons().is_empty());
+    }
+
+    #[test]
+    fn run_to_the_next_scanline_mode_and_frame() {
+        let mut emu = Emulator::for_rom(&idle_rom(), Path::new("idle.gb"), None).unwrap();
+        emu.cpu.regs.pc = 0x0000;
+        emu.run_frame().unwrap();
+
+        let line = emu.cpu.mmu.ppu.line;
+        let t_cycles = emu.run_to(StopCondition::Scanline).unwrap();
+        assert_eq!(emu.cpu.mmu.ppu.line, (line + 1) % 154);
+        assert!(t_cycles <= 456 + 12);
+
+        let mode = emu.ppu_mode();
+        emu.run_to(StopCondition::ModeChange).unwrap();
+        assert_ne!(emu.ppu_mode(), mode);
+
+        let frame = emu.frame_count();
+        emu.run_to(StopCondition::Frame).unwrap();
+        assert_eq!(emu.frame_count(), frame + 1);
+        assert_eq!(emu.ppu_mode(), crate::Mode::VerticalBlank);
+    }
+
+    #[test]
+    fn frame_hash() {
+        let mut emu = Emulator::for_rom(&idle_rom(), Path::new("idle.gb"), None).unwrap();
+        emu.cpu.regs.pc = 0x0000;
+        // a black screen until the first frame
+        let black = emu.frame_hash();
+        assert_eq!(black, xxh3::hash64(&[0xFF; 144 * 40]));
+        for _ in 0..3 {
+            emu.run_frame().unwrap();
+        }
+        let white = emu.frame_hash();
+        assert_eq!(white, xxh3::hash64(&[0; 144 * 40]));
+        emu.set_palette(Preset::Pocket.colors());
+        assert_eq!(emu.frame_hash(), white);
+
+        let mut cgb = EmulatorBuilder::new()
+            .model(HardwareModel::Cgb)
+            .for_rom(&idle_rom(), Path::new("idle.gb"))
+            .unwrap();
+        cgb.cpu.regs.pc = 0x0000;
+        for _ in 0..3 {
+            cgb.run_frame().unwrap();
+        }
+        assert_ne!(cgb.frame_hash(), white, "the CGB hash covers the colors");
+    }
+
+    #[test]
+    fn frame_ready_flag_and_hook() {
+        let mut emu = Emulator::for_rom(&idle_rom(), Path::new("idle.gb"), None).unwrap();
+        emu.cpu.regs.pc = 0x0000;
+        let (sender, receiver) = std::sync::mpsc::channel();
+        emu.set_frame_hook(move |frame| sender.send(frame).unwrap());
+        assert!(!emu.frame_ready());
+
+        emu.run_to(StopCondition::Scanline).unwrap();
+        assert!(!emu.frame_ready());
+        emu.run_frame().unwrap();
+        emu.run_frame().unwrap();
+        assert!(emu.frame_ready());
+        assert!(!emu.frame_ready(), "polling clears the flag");
+        assert_eq!(receiver.try_iter().collect::<Vec<_>>(), [1, 2]);
+
+        emu.clear_frame_hook();
+        emu.run_frame().unwrap();
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn scanline_hook() {
+        let mut emu = Emulator::for_rom(&idle_rom(), Path::new("idle.gb"), None).unwrap();
+        emu.cpu.regs.pc = 0x0000;
+        emu.run_frame().unwrap();
+        let (sender, receiver) = std::sync::mpsc::channel();
+        emu.set_scanline_hook(move |snapshot| sender.send(snapshot).unwrap());
+
+        while emu.cpu.mmu.ppu.line != 1 {
+            emu.run_to(StopCondition::Scanline).unwrap();
+        }
+        // as if the game scrolled during HBlank
+        emu.cpu.mmu.write_byte(0xFF43, 5);
+        emu.run_frame().unwrap();
+        let snapshots: Vec<_> = receiver.try_iter().collect();
+        assert_eq!(
+            snapshots.iter().map(|s| s.ly).collect::<Vec<_>>(),
+            (0..144).collect::<Vec<_>>()
+        );
+        assert_eq!(snapshots[0].lcdc, 0x80);
+        assert_eq!(snapshots[0].viewport.x, 0);
+        assert_eq!(snapshots[1].viewport.x, 5);
+
+        emu.clear_scanline_hook();
+        emu.run_frame().unwrap();
+        assert!(emu.cpu.mmu.ppu.started_lines.is_empty());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "save states are compressed with zstd, a C library")]
+    fn save_state_reproduces_held_buttons() {
+        with_large_stack(save_state_reproduces_held_buttons_impl);
+    }
+
+    fn save_state_reproduces_held_buttons_impl() {
+        let rom = idle_rom();
+        let mut emu = emulator_for(&rom);
+        emu.hold_button(Button::B, 3);
+        emu.run_frame().unwrap();
+        let state = emu.save_state().unwrap();
+        let mut loaded = EmulatorBuilder::new()
+            .load_save_state(
+                &rom,
+                Path::new("idle.gb"),
+                Path::new("idle.sav.zst"),
+                &state,
+            )
+            .unwrap();
+        for _ in 0..3 {
+            emu.run_frame().unwrap();
+            loaded.run_frame().unwrap();
+            assert_eq!(loaded.pressed_buttons(), emu.pressed_buttons());
+        }
+        assert!(loaded.pressed_buttons().is_empty());
+        assert_eq!(
+            rmp_serde::to_vec(&loaded).unwrap(),
+            rmp_serde::to_vec(&emu).unwrap()
+        );
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "save states are compressed with zstd, a C library")]
+    fn palette_applies_to_dmg_frames_and_survives_loading_states() {
+        with_large_stack(|| {
+            let rom = idle_rom();
+            let mut emu = Emulator::for_rom(&rom, Path::new("idle.gb"), None).unwrap();
+            assert_eq!(emu.palette(), Preset::Green.colors());
+            let state = emu.save_state().unwrap();
+            emu.set_palette(Preset::Pocket.colors());
+            emu.load_state(&state).unwrap();
+            assert_eq!(emu.shade_rgb(Color::Black), [31, 31, 31]);
+            assert_eq!(
+                emu.cpu.mmu.ppu.dmg_palette,
+                DmgPalette::from_rgb8(Preset::Pocket.colors())
+            );
+
+            let mut cgb = EmulatorBuilder::new()
+                .model(HardwareModel::Cgb)
+                .for_rom(&rom, Path::new("idle.gb"))
+                .unwrap();
+            let compat = cgb.cpu.mmu.ppu.dmg_palette;
+            cgb.set_palette(Preset::Grayscale.colors());
+            assert_eq!(cgb.cpu.mmu.ppu.dmg_palette, compat);
+        });
+    }
+
+    #[test]
+    fn frame_rgba_matches_the_displayed_colors() {
+        let rom = idle_rom();
+        let mut buffer = vec![0; FRAME_RGBA_LEN];
+        for model in [HardwareModel::Dmg(DmgRevision::DmgB), HardwareModel::Cgb] {
+            let mut emu = EmulatorBuilder::new()
+                .model(model)
+                .skip_boot_rom(true)
+                .for_rom(&rom, Path::new("idle.gb"))
+                .unwrap();
+            emu.set_palette(Preset::Pocket.colors());
+            for _ in 0..3 {
+                emu.run_frame().unwrap();
+            }
+            emu.frame_rgba(&mut buffer);
+            let expected: Vec<u8> = if model.is_cgb() {
+                emu.resolve_display_rgb()
+                    .as_flattened()
+                    .iter()
+                    .flat_map(|color| color.to_rgb8().into_iter().chain([0xFF]))
+                    .collect()
+            } else {
+                emu.resolve_display()
+                    .as_flattened()
+                    .iter()
+                    .flat_map(|&shade| emu.shade_rgb(shade).into_iter().chain([0xFF]))
+                    .collect()
+            };
+            assert_eq!(buffer, expected);
+        }
+    }
+
+    #[test]
+    fn skip_boot_rom() {
+        let rom = idle_rom();
+        let emu = EmulatorBuilder::new()
+            .skip_boot_rom(true)
+            .for_rom(&rom, Path::new("idle.gb"))
+            .unwrap();
+        assert!(!emu.in_boot_rom());
+        let regs = emu.debug_state().regs;
+        assert_eq!(
+            (regs.a, regs.f, regs.pc, regs.sp),
+            (0x01, 0xB0, 0x0100, 0xFFFE)
+        );
+        assert_eq!((regs.b, regs.c, regs.d, regs.e), (0x00, 0x13, 0x00, 0xD8));
+        assert_eq!((regs.h, regs.l), (0x01, 0x4D));
+        assert_eq!(emu.read_memory(0xFF04), 0xAB);
+        assert_eq!(emu.read_memory(0xFF40), 0x91);
+        assert_eq!(emu.read_memory(0xFF47), 0xFC);
+
+        let cgb = EmulatorBuilder::new()
+            .skip_boot_rom(true)
+            .model(HardwareModel::Cgb)
+            .for_rom(&rom, Path::new("idle.gb"))
+            .unwrap();
+        assert_eq!(cgb.debug_state().regs.a, 0x11);
+    }
+
+    #[test]
+    fn boot_rom_runs_before_the_cartridge() {
+        let rom = idle_rom();
+        let mut boot_rom = vec![0; 0x100];
+        let program = [
+            0x3E, 0x01, // LD A,0x01
+            0xE0, 0x50, // LDH [0x50],A   (unmap the boot ROM)
+        ];
+        boot_rom[0xFC..].copy_from_slice(&program);
+        let mut emu = EmulatorBuilder::new()
+            .boot_rom(boot_rom.clone())
+            .for_rom(&rom, Path::new("idle.gb"))
+            .unwrap();
+        assert!(emu.in_boot_rom());
+        assert_eq!(emu.read_memory(0x00FC), 0x3E);
+        assert_eq!(emu.read_memory(0x0100), rom[0x0100]);
+        while emu.in_boot_rom() {
+            emu.step().unwrap();
+        }
+        assert_eq!(emu.debug_state().regs.pc, 0x0100);
+        assert_eq!(emu.read_memory(0x00FC), rom[0x00FC]);
+        assert_eq!(emu.take_events(), [Event::BootRomExited]);
+
+        let skipped = EmulatorBuilder::new()
+            .boot_rom(boot_rom)
+            .skip_boot_rom(true)
+            .for_rom(&rom, Path::new("idle.gb"))
+            .unwrap();
+        assert!(!skipped.in_boot_rom());
+        assert!(matches!(
+            EmulatorBuilder::new()
+                .boot_rom(vec![0; 0x200])
+                .for_rom(&rom, Path::new("idle.gb")),
+            Err(RomError::BootRomSize { len: 0x200 })
+        ));
+    }
+}
@@ -0,0 +1,160 @@
+//! Two emulators in the same process, connected by a link cable, e.g. for two-player frontends and for testing link
+//! protocols.
+use std::sync::{Arc, Mutex};
+
+use crate::serial::{Clock, Disconnected, SerialDevice};
+use crate::{Emulator, Stopped, T_CYCLES_PER_FRAME};
+
+/// What is on the wire in each direction, indexed by the end it goes to
+#[derive(Debug, Default)]
+struct Wire {
+    /// The byte that an end waiting for a transfer with the external clock shifts out
+    waiting: [Option<u8>; 2],
+    /// The byte that the other end sent to an end waiting with the external clock
+    incoming: [Option<u8>; 2],
+}
+
+struct LinkEnd {
+    wire: Arc<Mutex<Wire>>,
+    idx: usize,
+}
+
+impl SerialDevice for LinkEnd {
+    /// The emulators are stepped in lockstep, so the bytes are swapped as soon as a transfer starts with the internal
+    /// clock, and the waiting end completes its transfer on its next step.
+    fn exchange(&mut self, sent: u8, clock: Clock) -> Option<u8> {
+        let mut wire = self.wire.lock().unwrap();
+        let other = 1 - self.idx;
+        match clock {
+            Clock::Internal => {
+                let received = wire.waiting[other].take()?;
+                wire.incoming[other] = Some(sent);
+                Some(received)
+            }
+            Clock::External => {
+                let received = wire.incoming[self.idx].take();
+                wire.waiting[self.idx] = match received {
+                    Some(_) => None,
+                    None => Some(sent),
+                };
+                received
+            }
+        }
+    }
+}
+
+/// Two emulators whose serial ports are connected to each other, stepped so that neither gets ahead of the other by
+/// more than an instruction.
+pub struct LinkedPair {
+    emulators: [Emulator; 2],
+}
+
+impl LinkedPair {
+    pub fn new(mut first: Emulator, mut second: Emulator) -> Self {
+        let wire = Arc::new(Mutex::new(Wire::default()));
+        for (idx, emu) in [&mut first, &mut second].into_iter().enumerate() {
+            emu.connect_serial(Box::new(LinkEnd {
+                wire: wire.clone(),
+                idx,
+            }));
+        }
+        LinkedPair {
+            emulators: [first, second],
+        }
+    }
+
+    pub fn first(&self) -> &Emulator {
+        &self.emulators[0]
+    }
+
+    pub fn first_mut(&mut self) -> &mut Emulator {
+        &mut self.emulators[0]
+    }
+
+    pub fn second(&self) -> &Emulator {
+        &self.emulators[1]
+    }
+
+    pub fn second_mut(&mut self) -> &mut Emulator {
+        &mut self.emulators[1]
+    }
+
+    /// Step the emulator that is behind, or the first one if neither is.
+    pub fn step(&mut self) -> Result<(), Stopped> {
+        let [first, second] = &mut self.emulators;
+        if second.cycle_count() < first.cycle_count() {
+            second.step()?;
+        } else {
+            first.step()?;
+        }
+        Ok(())
+    }
+
+    /// Run until the first emulator completes a frame, and the second one has caught up with it.
+    ///
+    /// Like [`Emulator::run_frame`], this runs for at most one frame's worth of cycles.
+    pub fn run_frame(&mut self) -> Result<(), Stopped> {
+        let frame = self.first().frame_count();
+        let end = self.first().cycle_count() + T_CYCLES_PER_FRAME as u64;
+        loop {
+            self.step()?;
+            let [first, second] = &self.emulators;
+            let first_done = first.frame_count() != frame || first.cycle_count() >= end;
+            if first_done && second.cycle_count() >= first.cycle_count() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Unplug the link cable, and hand back the emulators.
+    pub fn into_inner(self) -> (Emulator, Emulator) {
+        let [mut first, mut second] = self.emulators;
+        first.connect_serial(Box::new(Disconnected));
+        second.connect_serial(Box::new(Disconnected));
+        (first, second)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LinkedPair;
+    use crate::util::{emulator_running, with_large_stack};
+    use crate::Emulator;
+
+    /// Send `sent` with the clock chosen by `control`, and loop at 0x0016 if `expected` was received, or at 0x0014
+    /// otherwise.
+    fn transfer(sent: u8, control: u8, expected: u8) -> Emulator {
+        emulator_running(&[
+            0x3E, sent, // LD A,sent
+            0xE0, 0x01, // LDH [SB],A
+            0x3E, control, // LD A,control
+            0xE0, 0x02, // LDH [SC],A
+            0xF0, 0x02, // LDH A,[SC]
+            0xCB, 0x7F, // BIT 7,A
+            0x20, 0xFA, // JR NZ,-6
+            0xF0, 0x01, // LDH A,[SB]
+            0xFE, expected, // CP expected
+            0x28, 0x02, // JR Z,+2
+            0x18, 0xFE, // JR -2
+            0x18, 0xFE, // JR -2
+        ])
+    }
+
+    #[test]
+    fn linked_emulators_swap_bytes() {
+        with_large_stack(linked_emulators_swap_bytes_impl);
+    }
+
+    fn linked_emulators_swap_bytes_impl() {
+        let mut pair = LinkedPair::new(transfer(0x42, 0x80, 0x99), transfer(0x99, 0x81, 0x42));
+        pair.run_frame().unwrap();
+        let (first, second) = pair.into_inner();
+        assert_eq!(first.cpu.regs.pc, 0x0016);
+        assert_eq!(second.cpu.regs.pc, 0x0016);
+
+        // without the other end, the transfer with the internal clock receives 0xFF
+        let mut alone = transfer(0x99, 0x81, 0x42);
+        alone.run_frame().unwrap();
+        assert_eq!(alone.cpu.regs.pc, 0x0014);
+    }
+}
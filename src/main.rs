@@ -1,41 +1,63 @@
-use anyhow::Context;
 use std::path::PathBuf;
-use std::thread;
-use std::time::{self};
+use std::process::ExitCode;
 
-use enumset::EnumSet;
-use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
-use sdl2::pixels::PixelFormatEnum;
+use clap::{Args, Parser, Subcommand};
 
-use clap::Parser;
-
-use gbrs::joypad;
-use gbrs::Color;
-
-/// CPU frequency from pandocs: https://gbdev.io/pandocs/Specifications.html#dmg_clk
-const CYCLES_PER_SECOND: u32 = 4194304;
-const FPS: u32 = 60;
-const CYCLES_PER_FRAME: u32 = CYCLES_PER_SECOND / FPS;
-const NANOS_PER_FRAME: u64 = 1_000_000_000 / FPS as u64;
-const FRAME_DURATION: time::Duration = time::Duration::from_nanos(NANOS_PER_FRAME);
-use gbrs::mmu::Memory;
+mod frontend;
 
 /// A Game Boy emulator
 #[derive(Parser, Debug)]
-#[command(version = "0", author = "Hrishi Dharam", about = "A Game Boy emulator")]
+#[command(
+    version = "0",
+    author = "Hrishi Dharam",
+    about = "A Game Boy emulator",
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct Cli {
-    /// Path to the ROM file
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    play: Option<PlayArgs>,
+}
+
+/// Headless subcommands. These never open a window or an audio device, so they can run in containers and CI.
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run a ROM without a display for a fixed number of frames
+    Run(Box<frontend::headless::RunArgs>),
+    /// Measure how fast a ROM runs without a display
+    Bench(frontend::headless::BenchArgs),
+    /// Run test ROMs and report which ones pass
+    CompatRun(frontend::headless::CompatRunArgs),
+    /// Play a movie to its end, and fail unless it ends with the expected frame and state
+    VerifyMovie(frontend::headless::VerifyMovieArgs),
+    /// Write the LCD and all PPU debug views to a single labeled PNG
+    DebugSnapshot(frontend::headless::DebugSnapshotArgs),
+    /// Run a ROM in lockstep with a reference emulator, and stop at the first instruction where they diverge
+    Lockstep(frontend::lockstep::LockstepArgs),
+}
+
+/// Play a ROM in a window
+#[derive(Args, Debug)]
+struct PlayArgs {
+    /// Path to the ROM file, which can be in a .zip or .gz archive
     rom_path: PathBuf,
 
     /// Optional path to save state
     #[arg(long)]
     save: Option<PathBuf>,
 
-    /// Don't sleep between frames (runs beyond 60 fps)
+    /// Don't sleep between frames (runs beyond real hardware speed)
     #[arg(long, default_value = "false")]
     no_sleep: bool,
 
+    /// Present frames at the display's refresh rate with vsync, instead of the Game Boy's 59.73 Hz. Every frame is
+    /// shown exactly once, but emulation runs slightly fast on a 60 Hz display
+    #[arg(long, default_value = "false")]
+    match_host_refresh: bool,
+
     /// Show the gameboy ppu window state in a separate window for debugging
     #[arg(long, default_value = "false")]
     show_window: bool,
@@ -48,331 +70,154 @@ struct Cli {
     #[arg(long, default_value = "false")]
     show_obj_layer: bool,
 
+    /// Show all 384 tiles in VRAM in a separate window for debugging
+    #[arg(long, default_value = "false")]
+    show_tiles: bool,
+
+    /// The palette to draw tiles with in the `--show-tiles` window: bg, obj0, obj1, or ids (the raw color IDs)
+    #[arg(long, default_value = "bg")]
+    tile_palette: gbrs::ppu::TilePalette,
+
     /// Vertical and horizontal scaling for the gameboy display
     #[arg(long, default_value = "4")]
     scale: u8,
-}
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args = Cli::parse();
-    if args.scale == 0 {
-        return Err("scale value must be > 0".into());
-    }
-    let rom = std::fs::read(&args.rom_path)
-        .context(format!("Unable to read ROM: {:?}", args.rom_path))?;
-    let emu = match &args.save {
-        Some(sav_path) => {
-            let sav = std::fs::read(sav_path)
-                .context(format!("Unable to read sav file: {:?}", sav_path))?;
-            gbrs::Emulator::load_save_state(&rom, sav_path, &sav)?
-        }
-        None => gbrs::Emulator::for_rom(&rom, &args.rom_path),
-    };
-    let sdl_context = sdl2::init()?;
-    let video_subsystem = sdl_context.video()?;
-    // bg layer
-    let bg_canvas_and_texture = if args.show_bg {
-        let window = video_subsystem
-            .window(
-                "Background Debug View",
-                256 * args.scale as u32,
-                256 * args.scale as u32,
-            )
-            .position(0, 0)
-            .build()?;
-        let mut canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
-        canvas.set_scale(args.scale as f32, args.scale as f32)?;
-        let texture_creator = Box::new(canvas.texture_creator());
-        let texture_creator = Box::leak(texture_creator);
-        let texture = texture_creator.create_texture_streaming(
-            sdl2::pixels::PixelFormatEnum::RGB24,
-            256,
-            256,
-        )?;
-        Some((canvas, texture))
-    } else {
-        None
-    };
-
-    // window layer
-    let window_canvas_and_texture = if args.show_window {
-        let window = video_subsystem
-            .window(
-                "Window Debug View",
-                256 * args.scale as u32,
-                256 * args.scale as u32,
-            )
-            .position(512, 0)
-            .build()?;
-        let mut canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
-        canvas.set_scale(args.scale as f32, args.scale as f32)?;
-        let texture_creator = Box::new(canvas.texture_creator());
-        let texture_creator = Box::leak(texture_creator);
-        let texture = texture_creator
-            .create_texture_streaming(sdl2::pixels::PixelFormatEnum::RGB24, 256, 256)
-            .map_err(|e| e.to_string())?;
-        Some((canvas, texture))
-    } else {
-        None
-    };
-
-    // object tiles layer
-    let obj_canvas_and_texture = if args.show_obj_layer {
-        let window = video_subsystem
-            .window(
-                "OAM Debug View",
-                176 * args.scale as u32,
-                176 * args.scale as u32,
-            )
-            .position(512, 100)
-            .build()?;
-        let mut canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
-        canvas.set_scale(args.scale as f32, args.scale as f32)?;
-        let texture_creator = Box::new(canvas.texture_creator());
-        let texture_creator = Box::leak(texture_creator);
-        let texture = texture_creator.create_texture_streaming(
-            sdl2::pixels::PixelFormatEnum::RGB24,
-            176,
-            176,
-        )?;
-        Some((canvas, texture))
-    } else {
-        None
-    };
-
-    let window = video_subsystem
-        .window(
-            "GB Emulator",
-            160 * args.scale as u32,
-            144 * args.scale as u32,
-        )
-        .position_centered()
-        .build()
-        .map_err(|e| e.to_string())?;
-    let mut canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
-    canvas.set_scale(args.scale as f32, args.scale as f32)?;
-    let event_pump = sdl_context.event_pump()?;
-    let texture_creator = canvas.texture_creator();
-    let texture = texture_creator.create_texture_streaming(PixelFormatEnum::RGB24, 160, 144)?;
-
-    execute_rom(
-        emu,
-        event_pump,
-        canvas,
-        texture,
-        bg_canvas_and_texture,
-        window_canvas_and_texture,
-        obj_canvas_and_texture,
-        !args.no_sleep,
-    )
-}
+    /// The number of frames to emulate per displayed frame while fast-forwarding (holding left shift, unless rebound)
+    #[arg(long, default_value = "4")]
+    fast_forward_speed: u32,
 
-#[allow(clippy::too_many_arguments)]
-fn execute_rom(
-    mut emu: gbrs::Emulator,
-    mut event_pump: sdl2::EventPump,
-    mut lcd_canvas: sdl2::render::Canvas<sdl2::video::Window>,
-    mut lcd_texture: sdl2::render::Texture,
-    mut background_canvas_and_texture: Option<(
-        sdl2::render::Canvas<sdl2::video::Window>,
-        sdl2::render::Texture,
-    )>,
-    mut window_canvas_and_texture: Option<(
-        sdl2::render::Canvas<sdl2::video::Window>,
-        sdl2::render::Texture,
-    )>,
-    mut obj_canvas_and_texture: Option<(
-        sdl2::render::Canvas<sdl2::video::Window>,
-        sdl2::render::Texture,
-    )>,
-    sleep_enabled: bool,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let mut pressed_buttons = EnumSet::<joypad::Button>::empty();
-    let mut frame_count: u64 = 0;
-    let mut print_logs: bool = false;
-    let stdout = std::io::stdout();
-    let mut lock = stdout.lock();
-    let mut fast_mode = false;
-    use std::io::Write;
-    loop {
-        let frame_start = std::time::Instant::now();
-        // Handle events
-        for event in event_pump.poll_iter() {
-            match event {
-                Event::Quit { .. } => return Ok(()),
-                Event::KeyDown {
-                    keycode: Some(key), ..
-                } => {
-                    if let Some(button) = keycode_to_button(key) {
-                        pressed_buttons.insert(button);
-                    } else if key == Keycode::D {
-                        print_logs = true;
-                    } else if key == Keycode::LShift {
-                        fast_mode = true;
-                    } else if key == Keycode::S {
-                        match emu.dump_save_state() {
-                            Ok(_) => {}
-                            Err(e) => eprintln!("Failed to create save state: {e}"),
-                        };
-                    }
-                }
-                Event::KeyUp {
-                    keycode: Some(key), ..
-                } => {
-                    if let Some(button) = keycode_to_button(key) {
-                        pressed_buttons.remove(button);
-                    } else if key == Keycode::D {
-                        print_logs = false;
-                    } else if key == Keycode::LShift {
-                        fast_mode = false;
-                    }
-                }
-                _ => {}
-            };
-        }
-        emu.set_pressed_buttons(pressed_buttons);
-
-        // Execute CPU cycles for one frame
-        let mut cycles_in_frame: u32 = 0;
-        while cycles_in_frame < CYCLES_PER_FRAME {
-            let cycles = emu.step();
-            cycles_in_frame += cycles as u32;
-
-            if print_logs {
-                // dump cpu state
-                writeln!(lock, "CPU State:")?;
-                writeln!(lock,
-                "IME: {:?} HALTED: {:?}, IE: {:?}, IF: {:?}\nA:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
-                emu.cpu.ime, emu.cpu.is_halted, emu.cpu.mmu.interrupts_enabled(), emu.cpu.mmu.interrupts_requested(), emu.cpu.regs.a, emu.cpu.regs.f, emu.cpu.regs.b, emu.cpu.regs.c, emu.cpu.regs.d, emu.cpu.regs.e, emu.cpu.regs.h, emu.cpu.regs.l, emu.cpu.regs.sp, emu.cpu.regs.pc, emu.cpu.mmu.read_byte(emu.cpu.regs.pc), emu.cpu.mmu.read_byte(emu.cpu.regs.pc+1), emu.cpu.mmu.read_byte(emu.cpu.regs.pc+2), emu.cpu.mmu.read_byte(emu.cpu.regs.pc+3))?;
-                let ppu = emu.cpu.mmu.ppu_as_ref();
-                writeln!(lock, "PPU State:")?;
-                writeln!(lock, "  Mode: {:?}", ppu.mode)?;
-                writeln!(lock, "  Line: {}", ppu.line)?;
-                writeln!(lock, "  LCD Enabled: {}", ppu.lcd_enabled)?;
-                writeln!(lock, "  Window Enabled: {}", ppu.window_enabled)?;
-                writeln!(lock, "----------------------------------------")?;
-            }
-        }
-        frame_count = frame_count.wrapping_add(1);
-
-        let should_render = if fast_mode {
-            frame_count % 5 == 0
-        } else if !sleep_enabled {
-            frame_count % 10 == 0
-        } else {
-            true
-        };
-
-        if should_render {
-            // Update background texture
-            if let Some((ref mut canvas, ref mut texture)) = background_canvas_and_texture {
-                let background = emu.dbg_resolve_background();
-                texture.with_lock(None, |buffer: &mut [u8], _pitch: usize| {
-                    for (y, row) in background.iter().enumerate() {
-                        for (x, &color) in row.iter().enumerate() {
-                            let offset = (y * background[0].len() + x) * 3;
-                            let sdl_color = color_to_sdl_buf_values_dmg(color);
-                            buffer[offset..offset + 3].copy_from_slice(&sdl_color);
-                        }
-                    }
-                })?;
-                canvas.clear();
-                canvas.copy(texture, None, None)?;
-                canvas.present();
-            }
-
-            // Update OAM texture
-            if let Some((ref mut canvas, ref mut texture)) = obj_canvas_and_texture {
-                let oam_data = emu.dbg_resolve_obj_layer();
-                texture.with_lock(None, |buffer: &mut [u8], _pitch: usize| {
-                    for (y, row) in oam_data.iter().enumerate() {
-                        for (x, &color) in row.iter().enumerate() {
-                            let offset = (y * oam_data[0].len() + x) * 3;
-                            let sdl_color = color_to_sdl_buf_values_dmg(color);
-                            buffer[offset..offset + 3].copy_from_slice(&sdl_color);
-                        }
-                    }
-                })?;
-                canvas.clear();
-                canvas.copy(texture, None, None)?;
-                canvas.present();
-            }
-
-            // update window texture
-            if let Some((ref mut canvas, ref mut texture)) = window_canvas_and_texture {
-                let window = emu.dbg_resolve_window();
-                let window = window
-                    .iter()
-                    .map(|line| line.as_slice())
-                    .collect::<Vec<_>>();
-                update_canvas(canvas, texture, &window)?;
-            }
-
-            // update main display
-            let lcd: [[Color; 160]; 144] = emu.resolve_display();
-            let lcd: Vec<&[Color]> = lcd.iter().map(|line| line.as_slice()).collect();
-            update_canvas(&mut lcd_canvas, &mut lcd_texture, &lcd)?;
-        }
+    /// The number of frames that turbo buttons (e.g. `--bind C=turbo-a`) stay pressed, and then released, while held
+    #[arg(long, default_value = "2")]
+    turbo_period: u32,
 
-        // Sleep to maintain frame rate, if requested
-        if sleep_enabled {
-            let frame_duration = frame_start.elapsed();
-            let frame_duration_wanted = if fast_mode {
-                FRAME_DURATION / 10
-            } else {
-                FRAME_DURATION
-            };
-            if let Some(frame_remaining_duration) =
-                frame_duration_wanted.checked_sub(frame_duration)
-            {
-                thread::sleep(frame_remaining_duration);
-            }
-        }
-    }
+    /// Pause at the cartridge entry point at 0x100, once the boot ROM hands off to it. Press P to resume
+    #[arg(long, default_value = "false")]
+    break_at_entry: bool,
+
+    /// Pause emulation, and with it the audio, while the emulator's windows are minimized or don't have focus
+    #[arg(long, default_value = "false")]
+    pause_in_background: bool,
+
+    /// Record the audio output to a WAV file at this path
+    #[arg(long)]
+    record_audio: Option<PathBuf>,
+
+    /// Draw each scanline at once instead of dot by dot. Faster, but effects that change PPU registers in the middle
+    /// of a scanline apply to the whole line
+    #[arg(long, default_value = "false")]
+    scanline_renderer: bool,
+
+    /// Show host-side frame timings as colored bars over the display and in the window title
+    #[arg(long, default_value = "false")]
+    profile: bool,
+
+    /// The colors to display the 4 shades in: green, pocket, or grayscale
+    #[arg(long, default_value = "green")]
+    palette: gbrs::palette::Preset,
 
-    fn keycode_to_button(key: Keycode) -> Option<joypad::Button> {
-        match key {
-            Keycode::X => Some(joypad::Button::A),
-            Keycode::Z => Some(joypad::Button::B),
-            Keycode::Return => Some(joypad::Button::Start),
-            Keycode::RShift => Some(joypad::Button::Select),
-            Keycode::Up => Some(joypad::Button::Up),
-            Keycode::Down => Some(joypad::Button::Down),
-            Keycode::Left => Some(joypad::Button::Left),
-            Keycode::Right => Some(joypad::Button::Right),
-            _ => None,
+    /// Blend each frame with the ones before it, for games that flicker objects for transparency: off, mix (with the
+    /// previous frame), or lcd (fade like the DMG's LCD)
+    #[arg(long, default_value = "off")]
+    frame_blending: gbrs::blending::FrameBlending,
+
+    /// The DMG revision to emulate for ROMs that don't enable CGB features: dmg0 or dmg-b
+    #[arg(long, default_value = "dmg-b")]
+    dmg_revision: gbrs::model::DmgRevision,
+
+    /// Emulate this model instead of picking it from the cartridge header
+    #[arg(long, value_enum)]
+    model: Option<frontend::Model>,
+
+    /// Use this mapper regardless of the cartridge type in the header: none, mbc1, mbc3, mbc5, mbc7, camera, or
+    /// wisdom-tree
+    #[arg(long)]
+    force_mbc: Option<gbrs::Mapper>,
+
+    /// A dump of the DMG or CGB boot ROM to run before the cartridge. Without one, the emulator starts at the
+    /// cartridge entry point with the state that the boot ROM leaves behind
+    #[arg(long)]
+    boot_rom: Option<PathBuf>,
+
+    /// Start at the cartridge entry point with the state that the boot ROM leaves behind, even with --boot-rom
+    #[arg(long, default_value = "false")]
+    skip_boot: bool,
+
+    /// An IPS or BPS patch, e.g. a translation, to apply to the ROM when it's loaded
+    #[arg(long)]
+    patch: Option<PathBuf>,
+
+    /// Notify when the game spends this many seconds in a tight loop with interrupts disabled and no IO activity.
+    /// Press F12 to write a diagnostics bundle
+    #[arg(long)]
+    lockup_watchdog: Option<f64>,
+
+    /// Record the last N instructions, and print them if the CPU hits an illegal opcode or the emulator panics
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    trace: Option<u32>,
+
+    /// A symbol file (e.g. from rgblink -n) for naming jump and call targets in the logs
+    #[arg(long)]
+    symbols: Option<PathBuf>,
+
+    /// A PNG image for the Game Boy Camera to take pictures of. It's converted to grayscale and cropped to fit
+    #[arg(long)]
+    camera_image: Option<PathBuf>,
+
+    /// A file of key and controller bindings, one `INPUT = ACTION` per line, e.g. `Space = a` or `pad:x = b`. They
+    /// replace the default bindings of the same inputs
+    #[arg(long)]
+    bindings: Option<PathBuf>,
+
+    /// Bind a key or controller button, e.g. `--bind "Left Ctrl=fast-forward"` or `--bind pad:y=save-state`. Applied
+    /// after the bindings file. `none` unbinds the input
+    #[arg(long, value_name = "INPUT=ACTION")]
+    bind: Vec<String>,
+
+    /// Record the pressed buttons of every frame from the start, and write the movie to this file on exit. Press F5
+    /// and F9 to save and restore a state to re-record from, and N to advance a single frame while paused
+    #[arg(long)]
+    record_movie: Option<PathBuf>,
+
+    /// Play a movie recorded with --record-movie. Press R to continue recording from the current frame
+    #[arg(long)]
+    play_movie: Option<PathBuf>,
+
+    /// Wait for another gbrs to connect its link cable to this port
+    #[arg(long, value_name = "PORT", conflicts_with = "link_connect")]
+    link_listen: Option<u16>,
+
+    /// Connect the link cable to another gbrs that was started with --link-listen, e.g. 192.168.0.2:5555
+    #[arg(long, value_name = "HOST:PORT")]
+    link_connect: Option<String>,
+}
+
+fn main() -> Result<ExitCode, Box<dyn std::error::Error>> {
+    let args = Cli::parse();
+    match args.command {
+        Some(Command::Run(args)) => frontend::headless::run(&args),
+        Some(Command::Bench(args)) => frontend::headless::bench(&args),
+        Some(Command::CompatRun(args)) => frontend::headless::compat_run(&args),
+        Some(Command::VerifyMovie(args)) => frontend::headless::verify_movie(&args),
+        Some(Command::DebugSnapshot(args)) => frontend::headless::debug_snapshot(&args),
+        Some(Command::Lockstep(args)) => frontend::lockstep::lockstep(&args),
+        None => {
+            let args = args
+                .play
+                .expect("clap requires the play args without a subcommand");
+            play(&args).map(|_| ExitCode::SUCCESS)
         }
     }
+}
 
-    /// original Game Boy green
-    #[inline(always)]
-    fn color_to_sdl_buf_values_dmg(color: Color) -> [u8; 3] {
-        static COLOR_LOOKUP: [[u8; 3]; 4] = [
-            [224, 248, 208], // White
-            [136, 192, 112], // LightGray
-            [52, 104, 86],   // DarkGray
-            [8, 24, 32],     // Black
-        ];
-        COLOR_LOOKUP[color as usize]
-    }
+#[cfg(feature = "sdl")]
+fn play(args: &PlayArgs) -> Result<(), Box<dyn std::error::Error>> {
+    frontend::sdl::run(args)
+}
 
-    fn update_canvas(
-        canvas: &mut sdl2::render::Canvas<sdl2::video::Window>,
-        texture: &mut sdl2::render::Texture,
-        image: &[&[Color]],
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        texture.with_lock(None, |buffer: &mut [u8], _pitch: usize| {
-            for (y, row) in image.iter().enumerate() {
-                for (x, &color) in row.iter().enumerate() {
-                    let offset = (y * image[0].len() + x) * 3;
-                    let sdl_color = color_to_sdl_buf_values_dmg(color);
-                    buffer[offset..offset + 3].copy_from_slice(&sdl_color);
-                }
-            }
-        })?;
-        canvas.clear();
-        canvas.copy(texture, None, None)?;
-        canvas.present();
-        Ok(())
-    }
+#[cfg(not(feature = "sdl"))]
+fn play(_: &PlayArgs) -> Result<(), Box<dyn std::error::Error>> {
+    Err(
+        "gbrs was built without the `sdl` feature, so only the headless subcommands are available"
+            .into(),
+    )
 }
@@ -2,19 +2,30 @@ use enumset::{EnumSet, EnumSetType};
 use serde::{Deserialize, Serialize};
 use serde_big_array::BigArray;
 
+use crate::apu::Apu;
+use crate::bus_spy::{AccessKind, BusSpy};
+use crate::camera::CameraSource;
+use crate::infrared::InfraredPort;
+use crate::model::{DmgRevision, HardwareModel};
+use crate::palette::DmgPalette;
 use crate::ppu::{
-    self, BgAndWindowTileDataArea, ColorPalette, LcdStatus, ObjColorPaletteIdx, ObjSize, Ppu,
+    BgAndWindowTileDataArea, ColorPalette, LcdStatus, Mode, ObjColorPaletteIdx, ObjSize, Ppu,
     Priority, TileMapArea,
 };
-use crate::timer::{Timer, TimerFrequency};
+use crate::serial::SerialPort;
+use crate::timer::{Divider, Timer, TimerFrequency};
 use crate::util::U8Ext;
 use crate::{cartridge, joypad};
-use cartridge::Cartridge;
+use cartridge::{Cartridge, Mapper, RomError};
 use core::panic;
 use joypad::Button;
 
 pub trait Memory {
     fn read_byte(&self, addr: u16) -> u8;
+    /// Read a byte for a debugger or a log, without notifying the bus spy or being blocked by the PPU or DMA
+    fn read_byte_unobserved(&self, addr: u16) -> u8 {
+        self.read_byte(addr)
+    }
     fn write_byte(&mut self, addr: u16, byte: u8);
     fn step(&mut self, t_cycles: u8);
     fn interrupts_enabled(&self) -> EnumSet<InterruptKind>;
@@ -28,19 +39,45 @@ pub trait Memory {
 
     fn ppu_as_ref(&self) -> &Ppu;
 
+    /// Read the little-endian word at `addr`.
+    ///
+    /// Like the 16-bit address bus, the address of the high byte wraps around, so reading at 0xFFFF reads the high
+    /// byte from 0x0000.
     fn read_word(&self, addr: u16) -> u16 {
         let lo = self.read_byte(addr);
-        let hi = self.read_byte(addr + 1);
+        let hi = self.read_byte(addr.wrapping_add(1));
         u16::from_le_bytes([lo, hi])
     }
 
+    /// Write a little-endian word to `addr`. The address of the high byte wraps around like in [`Memory::read_word`].
     fn write_word(&mut self, addr: u16, word: u16) {
         let [lo, hi] = word.to_le_bytes();
         self.write_byte(addr, lo);
-        self.write_byte(addr + 1, hi);
+        self.write_byte(addr.wrapping_add(1), hi);
     }
 
     fn set_cart_rom(&mut self, rom: &[u8]);
+
+    /// Called when the CPU executes STOP. Switch between normal and double speed if a switch was requested through
+    /// KEY1, and return whether the speed was switched.
+    fn try_speed_switch(&mut self) -> bool {
+        false
+    }
+
+    /// Reset DIV, like writing to it does. Also called when the CPU executes STOP.
+    fn reset_divider(&mut self) {}
+}
+
+/// The number of bytes that an OAM DMA transfer copies, one per M-cycle: all of OAM
+const OAM_DMA_LEN: u8 = 0xA0;
+
+/// An OAM DMA transfer in progress, started by writing the high byte of the source address to FF46
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct OamDma {
+    source: u16,
+    copied: u8,
+    /// T-cycles towards copying the next byte
+    t_cycles: u8,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -50,10 +87,11 @@ pub struct Mmu {
     work_ram: [u8; 0x2000],
     #[serde(with = "BigArray")]
     high_ram: [u8; 0x80],
-    #[serde(with = "BigArray")]
-    boot_rom: [u8; 0x100],
+    /// Mapped over 0x0000-0x00FF, and for the CGB's boot ROM also over 0x0200-0x08FF, until it exits
+    boot_rom: Vec<u8>,
     pub in_boot_rom: bool,
     pub ppu: Ppu,
+    pub apu: Apu,
     /// A set of flags that indicates whether the interrupt handler for each corresponding piece of hardware may be called.
     ///
     /// also referred to as `IE`
@@ -63,55 +101,243 @@ pub struct Mmu {
     /// Any set flags only indicate that an interrupt is being *requested*. The actual *execution* of the interrupt handler only happens if both the `IME` register and the corresponding flag in `IE` are set.
     pub interrupts_requested: EnumSet<InterruptKind>,
     pub timer: Timer,
-    /// TODO: reset when executing STOP instruction and only begin ticking once stop mode ends
-    pub divider: Timer,
+    /// Reset by STOP, and doesn't tick while the CPU is stopped
+    pub divider: Divider,
     joypad_select: JoypadSelect,
     pub pressed_buttons: EnumSet<joypad::Button>,
+    pub model: HardwareModel,
+    /// Whether CGB features like KEY1 are enabled: the model is a CGB and the cartridge header enables CGB features.
+    ///
+    /// A CGB runs DMG-only ROMs in compatibility mode, where the CGB registers are unavailable.
+    cgb_mode: bool,
+    /// KEY1 bit 0: the next STOP switches the CPU speed
+    speed_switch_armed: bool,
+    /// KEY1 bit 7: the CPU, timer, and divider run at twice the normal speed, while the PPU and APU don't
+    pub double_speed: bool,
+    pub(crate) infrared: InfraredPort,
+    pub(crate) serial: SerialPort,
+    /// The last value written to FF46
+    dma_register: u8,
+    /// While a transfer is in progress, the CPU can only access HRAM and the IO registers
+    oam_dma: Option<OamDma>,
+    /// Whether the CPU sees 0xFF when reading VRAM or OAM while the PPU is using it, and its writes are ignored
+    pub(crate) ppu_access_blocking: bool,
+    /// Addresses whose writes are recorded in `watched_writes`
+    #[serde(skip)]
+    pub write_watches: Vec<u16>,
+    /// Writes to watched addresses that haven't been collected by the emulator yet
+    #[serde(skip)]
+    pub watched_writes: Vec<u16>,
+    /// The number of writes to IO registers (0xFF00-0xFF7F), which the lock-up watchdog counts as signs of life
+    #[serde(skip)]
+    pub(crate) io_writes: u64,
+    /// Only present while something is subscribed to bus accesses
+    #[serde(skip)]
+    pub(crate) bus_spy: Option<Box<BusSpy>>,
 }
 
 impl Mmu {
     pub fn new(rom: &[u8]) -> Self {
-        let mbc_type = rom[0x0147];
-        let cartridge: Box<dyn Cartridge> = match mbc_type {
-            0x00 | 0x08 | 0x09 => Box::new(cartridge::NoMbc::from_game_rom(rom)),
-            0x01..=0x03 => {
-                // MBC1
-                Box::new(cartridge::Mbc1::from_game_rom(rom))
-            }
-            0x0F..=0x13 => {
-                // MBC3
-                Box::new(cartridge::Mbc3::from_game_rom(rom))
-            }
-            0x19..=0x1E => {
-                todo!("Support MBC 5")
-            }
-            _ => {
-                todo!("Unsupported MBC: {:0X}", mbc_type)
-            }
-        };
+        Mmu::with_model(rom, HardwareModel::for_rom(rom, DmgRevision::default()))
+    }
+
+    /// Panics if the cartridge type in the header isn't supported. See [`crate::validate_rom`].
+    pub fn with_model(rom: &[u8], model: HardwareModel) -> Self {
+        let mapper = Mapper::for_header(rom)
+            .unwrap_or_else(|| panic!("{}", RomError::UnsupportedMapper(rom[0x0147])));
+        Mmu::with_mapper(rom, model, mapper)
+    }
+
+    pub fn with_mapper(rom: &[u8], model: HardwareModel, mapper: Mapper) -> Self {
+        let cartridge = mapper.create(rom);
+        // https://gbdev.io/pandocs/The_Cartridge_Header.html#0143--cgb-flag
+        let cgb_rom = rom[0x0143] & 0x80 != 0;
+        let cgb_mode = model.is_cgb() && cgb_rom;
+        let mut ppu = Ppu::new();
+        ppu.cgb_mode = cgb_mode;
+        if model.is_cgb() && !cgb_rom {
+            ppu.dmg_palette = DmgPalette::cgb_compat_for_rom(rom);
+        }
+        let mut apu = Apu::new();
+        apu.cgb = model.is_cgb();
         Mmu {
             cartridge,
             work_ram: [0; 0x2000],
             high_ram: [0; 0x80],
-            ppu: Ppu::new(),
+            ppu,
+            apu,
             interrupts_enabled: EnumSet::empty(),
             interrupts_requested: EnumSet::empty(),
-            timer: Timer::disabled(TimerFrequency::F4KiHz),
-            divider: Timer::enabled(TimerFrequency::F16KiHz),
-            boot_rom: *include_bytes!("../roms/dmg_boot.bin"),
-            in_boot_rom: true,
+            timer: Timer::new(),
+            divider: Divider::default(),
+            boot_rom: Vec::new(),
+            in_boot_rom: false,
             joypad_select: JoypadSelect::None,
             pressed_buttons: EnumSet::empty(),
+            model,
+            cgb_mode,
+            speed_switch_armed: false,
+            double_speed: false,
+            infrared: InfraredPort::default(),
+            serial: SerialPort::default(),
+            dma_register: 0xFF,
+            oam_dma: None,
+            ppu_access_blocking: true,
+            write_watches: Vec::new(),
+            watched_writes: Vec::new(),
+            io_writes: 0,
+            bus_spy: None,
         }
     }
 }
 
+impl Mmu {
+    /// The cartridge ROM bank mapped at 0x4000-0x7FFF
+    pub fn rom_bank(&self) -> usize {
+        self.cartridge.rom_bank()
+    }
+
+    pub(crate) fn set_rtc_clock_source(&mut self, source: cartridge::RtcClockSource) {
+        self.cartridge.set_rtc_clock_source(source);
+    }
+
+    /// Map `boot_rom` over the cartridge ROM, so that it runs first. See [`crate::EmulatorBuilder::boot_rom`].
+    pub(crate) fn load_boot_rom(&mut self, boot_rom: Vec<u8>) {
+        self.boot_rom = boot_rom;
+        self.in_boot_rom = true;
+    }
+
+    pub(crate) fn take_rumble_state(&mut self) -> Option<f32> {
+        self.cartridge.take_rumble_state()
+    }
+
+    pub(crate) fn connect_camera(&mut self, source: Box<dyn CameraSource>) {
+        self.cartridge.connect_camera(source);
+    }
+
+    pub(crate) fn disconnect_camera(&mut self) -> Option<Box<dyn CameraSource>> {
+        self.cartridge.disconnect_camera()
+    }
+
+    pub(crate) fn set_accelerometer(&mut self, x: f32, y: f32) {
+        self.cartridge.set_accelerometer(x, y);
+    }
+
+    pub(crate) fn battery_save(&self) -> Option<Vec<u8>> {
+        self.cartridge.battery_save()
+    }
+
+    pub(crate) fn load_battery_save(&mut self, save: &[u8]) -> Result<(), String> {
+        self.cartridge.load_battery_save(save)
+    }
+
+    /// Let the timer and the APU's frame sequencer observe a change of the divider's internal counter from `before`.
+    /// Both only see the falling edges of the counter's bits, so ticking and resetting the counter clock them alike.
+    ///
+    /// In double speed mode, the divider ticks twice as fast, so the frame sequencer observes the next higher bit to
+    /// keep stepping at 512 Hz.
+    fn observe_divider(&mut self, before: u16) {
+        let after = self.divider.internal_counter();
+        self.timer.observe_counter(before, after);
+        if self.double_speed {
+            self.apu.observe_div(before >> 1, after >> 1);
+        } else {
+            self.apu.observe_div(before, after);
+        }
+    }
+
+    fn write_oam_byte(&mut self, addr: u16, byte: u8) {
+        // The obj entry is 4 bytes
+        let object_entry_idx = (addr - 0xFE00) >> 2;
+        assert!(
+            (0..40).contains(&object_entry_idx),
+            "invalid obj entry idx: {object_entry_idx} calculated from address {addr}"
+        );
+        let obj = &mut self.ppu.obj_attribute_memory[object_entry_idx as usize];
+        let byte_offset = addr % 4;
+        match byte_offset {
+            0 => obj.y_pos = byte,
+            1 => obj.x_pos = byte,
+            2 => obj.tile_idx = byte,
+            3 => {
+                // WARNING: This strategy throws away the VRAM bank bit used in CGB mode
+                let [priority, y_flip, x_flip, dmg_palette, _, _, _, _] = byte.bits();
+                obj.cgb_palette = byte & 0x07;
+                obj.y_flip = y_flip;
+                obj.x_flip = x_flip;
+                obj.bg_over_obj_priority = match priority {
+                    true => Priority::One,
+                    false => Priority::Zero,
+                };
+                obj.palette = match dmg_palette {
+                    true => ObjColorPaletteIdx::One,
+                    false => ObjColorPaletteIdx::Zero,
+                };
+            }
+            _ => panic!("BUG"),
+        }
+    }
+
+    /// Whether the CPU can't access `addr` right now, because the bus is busy with an OAM DMA transfer, or the PPU is
+    /// using the memory: OAM while scanning it for objects and while drawing, and VRAM while drawing.
+    fn inaccessible(&self, addr: u16) -> bool {
+        if self.oam_dma.is_some() && addr < 0xFF00 {
+            return true;
+        }
+        if !self.ppu_access_blocking || !self.ppu.lcd_enabled {
+            return false;
+        }
+        match addr {
+            0x8000..=0x9FFF => self.ppu.mode == Mode::ScanlineVRAM,
+            // including the unusable area after OAM, which reads as 0xFF instead of 0x00 on the DMG
+            0xFE00..=0xFEFF => matches!(self.ppu.mode, Mode::ScanlineOAM | Mode::ScanlineVRAM),
+            _ => false,
+        }
+    }
+
+    /// Copy a byte to OAM for every M-cycle of the OAM DMA transfer in `t_cycles`.
+    fn step_oam_dma(&mut self, t_cycles: u8) {
+        let Some(mut dma) = self.oam_dma else {
+            return;
+        };
+        dma.t_cycles += t_cycles;
+        while dma.t_cycles >= 4 && dma.copied < OAM_DMA_LEN {
+            dma.t_cycles -= 4;
+            let source = dma.source + dma.copied as u16;
+            // sources above work RAM read work RAM instead, like echo RAM
+            let source = if source >= 0xE000 {
+                source - 0x2000
+            } else {
+                source
+            };
+            let byte = self.read_byte_unobserved(source);
+            self.write_oam_byte(0xFE00 + dma.copied as u16, byte);
+            dma.copied += 1;
+        }
+        self.oam_dma = (dma.copied < OAM_DMA_LEN).then_some(dma);
+    }
+}
+
 impl Memory for Mmu {
     fn read_byte(&self, addr: u16) -> u8 {
+        let byte = if self.inaccessible(addr) {
+            0xFF
+        } else {
+            self.read_byte_unobserved(addr)
+        };
+        if let Some(spy) = &self.bus_spy {
+            spy.record(addr, AccessKind::Read, byte);
+        }
+        byte
+    }
+
+    fn read_byte_unobserved(&self, addr: u16) -> u8 {
         match addr {
             // ROM
             0x0000..=0x7FFF => {
-                if self.in_boot_rom && addr < 0x100 {
+                if self.in_boot_rom
+                    && (addr < 0x100 || (0x200..self.boot_rom.len() as u16).contains(&addr))
+                {
                     self.boot_rom[addr as usize]
                 } else {
                     self.cartridge.read(addr)
@@ -138,9 +364,11 @@ impl Memory for Mmu {
                 object_attributes.as_bytes()[byte_offset as usize]
             }
             // not usable
-            0xFEA0..=0xFEFF => {
-                panic!("Program accessed invalid memory: {addr:X}")
-            }
+            0xFEA0..=0xFEFF => match self.model {
+                HardwareModel::Dmg(_) => 0x00,
+                // CGB revision E repeats the high nibble of the low address byte
+                HardwareModel::Cgb => (addr as u8 & 0xF0) | (addr as u8 >> 4),
+            },
             // io registers
             0xFF00 => {
                 let (select_hi, select_lo) = self.joypad_select.to_be_bits();
@@ -184,8 +412,9 @@ impl Memory for Mmu {
                     ]),
                 }
             }
-            0xFF01 | 0xFF02 => 0, // TODO: serial
-            0xFF04 => self.divider.value,
+            0xFF01 => self.serial.read_data(),
+            0xFF02 => self.serial.read_control(self.cgb_mode),
+            0xFF04 => self.divider.div(),
             0xFF05 => self.timer.value,
             0xFF06 => self.timer.tma,
             0xFF07 => {
@@ -209,24 +438,11 @@ impl Memory for Mmu {
                 ])
             }
             0xFF0F => self.interrupts_requested.as_u8(),
-            0xFF10..=0xFF3F => {
-                // TODO: audio
-                0x00
-            }
+            0xFF10..=0xFF3F => self.apu.read_register(addr),
             // LCD control
-            0xFF40 => u8::from_bits([
-                self.ppu.lcd_enabled,
-                self.ppu.window_tile_map_select.to_bit(),
-                self.ppu.window_enabled,
-                self.ppu.bg_and_window_tile_data_select.to_bit(),
-                self.ppu.bg_tile_map_select.to_bit(),
-                self.ppu.obj_size.to_bit(),
-                self.ppu.obj_enabled,
-                self.ppu.bg_enabled,
-            ]),
+            0xFF40 => self.ppu.lcdc(),
             // LCD status
             0xFF41 => {
-                use ppu::Mode;
                 let (b1, b0) = match self.ppu.mode {
                     Mode::HorizontalBlank => (false, false),
                     Mode::VerticalBlank => (false, true),
@@ -240,7 +456,7 @@ impl Memory for Mmu {
                     stat.mode_2_int_select,
                     stat.mode_1_int_select,
                     stat.mode_0_int_select,
-                    self.ppu.line == self.ppu.lyc,
+                    self.ppu.ly() == self.ppu.lyc,
                     b1,
                     b0,
                 ])
@@ -248,11 +464,9 @@ impl Memory for Mmu {
             // Background viewport position
             0xFF42 => self.ppu.viewport_offset.y,
             0xFF43 => self.ppu.viewport_offset.x,
-            0xFF44 => self.ppu.line,
+            0xFF44 => self.ppu.ly(),
             0xFF45 => self.ppu.lyc,
-            0xFF46 => {
-                panic!("Attempted to read from DMA transfer register")
-            }
+            0xFF46 => self.dma_register,
             0xFF47 => self.ppu.bg_color_palette.into(),
             0xFF48 => self.ppu.obj_color_palettes[0].into(),
             0xFF49 => self.ppu.obj_color_palettes[1].into(),
@@ -260,26 +474,36 @@ impl Memory for Mmu {
             0xFF4A => self.ppu.window_top_left.y,
             0xFF4B => self.ppu.window_top_left.x,
             0xFF4D => {
-                // todo!("CGB mode only, prepare speed switch")
-                0xFF
+                if self.cgb_mode {
+                    0x7E | ((self.double_speed as u8) << 7) | self.speed_switch_armed as u8
+                } else {
+                    0xFF
+                }
             }
             0xFF4F => {
-                // todo!("CGB mode only, VRAM bank select")
-                0xFF
+                if self.cgb_mode {
+                    0xFE | self.ppu.vram_bank
+                } else {
+                    0xFF
+                }
             }
-            0xFF50 => {
-                // set to non-zero to disable boot ROM
-                panic!("Attempted to read from boot ROM disable register")
+            0xFF56 => {
+                if self.cgb_mode {
+                    self.infrared.read()
+                } else {
+                    0xFF
+                }
             }
             0xFF51..=0xFF55 => {
                 // VRAM DMA
                 // todo!("CGB mode only, LCD VRAM DMA transfers")
                 0xFF
             }
-            0xFF68..=0xFF6B => {
-                // todo!("CGB only, BG/OBJ Palettes")
-                0xFF
-            }
+            0xFF68..=0xFF6B if !self.cgb_mode => 0xFF,
+            0xFF68 => self.ppu.bg_palette_ram.read_spec(),
+            0xFF69 => self.ppu.bg_palette_ram.read_data(),
+            0xFF6A => self.ppu.obj_palette_ram.read_spec(),
+            0xFF6B => self.ppu.obj_palette_ram.read_data(),
             0xFF70 => {
                 // todo!("CGB mode only, WRAM Bank select")
                 0xFF
@@ -288,12 +512,27 @@ impl Memory for Mmu {
             0xFF80..=0xFFFE => self.high_ram[addr as usize - 0xFF80],
             // interrupt enable register
             0xFFFF => self.interrupts_enabled.as_u8(),
-            _ => panic!("Unhandled register read for addr: {addr:X}"),
+            // the boot ROM disable register is write-only
+            0xFF50 => 0xFF,
+            // unmapped registers float high
+            _ => 0xFF,
         }
     }
 
     fn write_byte(&mut self, addr: u16, byte: u8) {
         // println!("MMU: Write byte {:#X}: {:#X}", addr, byte);
+        if self.write_watches.contains(&addr) {
+            self.watched_writes.push(addr);
+        }
+        if (0xFF00..=0xFF7F).contains(&addr) {
+            self.io_writes += 1;
+        }
+        if let Some(spy) = &self.bus_spy {
+            spy.record(addr, AccessKind::Write, byte);
+        }
+        if self.inaccessible(addr) {
+            return;
+        }
         match addr {
             // ROM banks
             0x0000..=0x7FFF => {
@@ -310,36 +549,7 @@ impl Memory for Mmu {
             // echo RAM
             0xE000..=0xFDFF => self.work_ram[(addr & 0x1FFF) as usize] = byte,
             // object attribute memory
-            0xFE00..=0xFE9F => {
-                // The obj entry is 4 bytes
-                let object_entry_idx = (addr - 0xFE00) >> 2;
-                assert!(
-                    (0..40).contains(&object_entry_idx),
-                    "invalid obj entry idx: {object_entry_idx} calculated from address {addr}"
-                );
-                let obj = &mut self.ppu.obj_attribute_memory[object_entry_idx as usize];
-                let byte_offset = addr % 4;
-                match byte_offset {
-                    0 => obj.y_pos = byte,
-                    1 => obj.x_pos = byte,
-                    2 => obj.tile_idx = byte,
-                    3 => {
-                        // WARNING: This strategy throws away the parts of the byte that are used in CGB mode
-                        let [priority, y_flip, x_flip, dmg_palette, _, _, _, _] = byte.bits();
-                        obj.y_flip = y_flip;
-                        obj.x_flip = x_flip;
-                        obj.bg_over_obj_priority = match priority {
-                            true => Priority::One,
-                            false => Priority::Zero,
-                        };
-                        obj.palette = match dmg_palette {
-                            true => ObjColorPaletteIdx::One,
-                            false => ObjColorPaletteIdx::Zero,
-                        };
-                    }
-                    _ => panic!("BUG"),
-                }
-            }
+            0xFE00..=0xFE9F => self.write_oam_byte(addr, byte),
             // not usable
             0xFEA0..=0xFEFF => {}
             // io registers
@@ -349,19 +559,11 @@ impl Memory for Mmu {
                 let joypad_select = JoypadSelect::from_be_bits(select_hi, select_lo);
                 self.joypad_select = joypad_select;
             }
-            0xFF01 | 0xFF02 => {
-                // serial transfer
-                // This is a noop to pass Blargg's test ROMs
-            }
-            0xFF04 => {
-                self.divider.value = 0;
-            }
-            0xFF05 => {
-                self.timer.value = byte;
-            }
-            0xFF06 => {
-                self.timer.tma = byte;
-            }
+            0xFF01 => self.serial.write_data(byte),
+            0xFF02 => self.serial.write_control(byte, self.cgb_mode),
+            0xFF04 => self.reset_divider(),
+            0xFF05 => self.timer.write_tima(byte),
+            0xFF06 => self.timer.write_tma(byte),
             0xFF07 => {
                 // TAC timer control
                 let [.., enable, clock_select_1, clock_select_0] = byte.bits();
@@ -371,28 +573,16 @@ impl Memory for Mmu {
                     [true, false] => TimerFrequency::F64KiHz,
                     [true, true] => TimerFrequency::F16KiHz,
                 };
-                self.timer.enabled = enable;
-                self.timer.frequency = frequency;
+                self.timer
+                    .write_control(enable, frequency, self.divider.internal_counter());
             }
             0xFF0F => self.interrupts_requested = EnumSet::<InterruptKind>::from_u8_truncated(byte),
-            0xFF10..=0xFF26 => {
-                // TODO: implement audio
-            }
-            0xFF30..=0xFF3F => {
-                // wave pattern
-                // TODO implement audio
-            }
+            0xFF10..=0xFF3F => self.apu.write_register(addr, byte),
             // LCD control
             0xFF40 => {
                 let [lcd_enable, window_tile_map_bit, window_enable, bg_and_window_tile_data_bit, bg_tile_map_area_bit, obj_size_bit, obj_enable, bg_enable] =
                     byte.bits();
-                if !lcd_enable {
-                    // turn ppu off
-                    self.ppu.line = 0;
-                    self.ppu.mode = ppu::Mode::HorizontalBlank;
-                    self.ppu.cycles_in_mode = 0
-                }
-                self.ppu.lcd_enabled = lcd_enable;
+                self.ppu.set_lcd_enabled(lcd_enable);
                 self.ppu.bg_tile_map_select = TileMapArea::from_bit(bg_tile_map_area_bit);
                 self.ppu.window_tile_map_select = TileMapArea::from_bit(window_tile_map_bit);
                 self.ppu.window_enabled = window_enable;
@@ -418,6 +608,15 @@ impl Memory for Mmu {
                     mode_2_int_select,
                     mode_1_int_select,
                     mode_0_int_select,
+                };
+                // the DMG briefly enables all the conditions during the write
+                let write_bug = self.model.has_stat_write_bug()
+                    && self.ppu.lcd_enabled
+                    && !self.ppu.stat_line
+                    && (matches!(self.ppu.mode, Mode::HorizontalBlank | Mode::VerticalBlank)
+                        || self.ppu.ly() == self.ppu.lyc);
+                if self.ppu.update_stat_line() || write_bug {
+                    self.interrupts_requested |= InterruptKind::LcdStat;
                 }
             }
             // Background viewport position
@@ -450,16 +649,18 @@ impl Memory for Mmu {
             }
             0xFF45 => {
                 self.ppu.lyc = byte;
+                if self.ppu.update_stat_line() {
+                    self.interrupts_requested |= InterruptKind::LcdStat;
+                }
             }
             0xFF46 => {
-                // Perform OAM DMA transfer.
-                // DMA on the real system takes 160 µs to complete.
-                // This implementation doesn't simulate the DMA timing.
-                let source_addr = (byte as u16) << 8;
-                let dest_addr = 0xFE00;
-                for offset in 0..0xA0 {
-                    self.write_byte(dest_addr + offset, self.read_byte(source_addr + offset));
-                }
+                // copies a byte from XX00-XX9F to OAM every M-cycle, restarting a transfer in progress
+                self.dma_register = byte;
+                self.oam_dma = Some(OamDma {
+                    source: (byte as u16) << 8,
+                    copied: 0,
+                    t_cycles: 0,
+                });
             }
             0xFF47 => self.ppu.bg_color_palette = ColorPalette::from(byte),
             0xFF48 => self.ppu.obj_color_palettes[0] = ColorPalette::from(byte),
@@ -468,13 +669,17 @@ impl Memory for Mmu {
             0xFF4A => self.ppu.window_top_left.y = byte,
             0xFF4B => self.ppu.window_top_left.x = byte,
             0xFF4D => {
-                // todo!("CGB mode only, prepare speed switch")
+                if self.cgb_mode {
+                    self.speed_switch_armed = byte.bit(0);
+                }
             }
             0xFF4F => {
-                // todo!("CGB mode only, VRAM bank select")
+                if self.cgb_mode {
+                    self.ppu.vram_bank = byte & 0x01;
+                }
             }
             0xFF50 => {
-                // set to non-zero to disable boot ROM
+                // a non-zero value unmaps the boot ROM, and nothing maps it again until the next power on
                 if byte != 0 {
                     self.in_boot_rom = false;
                 }
@@ -482,15 +687,17 @@ impl Memory for Mmu {
             0xFF51..=0xFF55 => {
                 // TODO VRAM DMA (CDB mode only)
             }
-            0xFF68..=0xFF69 => {
-                // TODO: BG / OBJ palettes (CGB mode only)
-            }
-            0xFF6A => {
-                // Obj color palette spec (CGB mode only)
-            }
-            0xFF6B => {
-                // Obj color palette data (CGB mode only)
+            0xFF56 => {
+                if self.cgb_mode {
+                    self.infrared.write(byte);
+                }
             }
+            // BG / OBJ palettes (CGB mode only)
+            0xFF68..=0xFF6B if !self.cgb_mode => {}
+            0xFF68 => self.ppu.bg_palette_ram.write_spec(byte),
+            0xFF69 => self.ppu.bg_palette_ram.write_data(byte),
+            0xFF6A => self.ppu.obj_palette_ram.write_spec(byte),
+            0xFF6B => self.ppu.obj_palette_ram.write_data(byte),
             0xFF6C => {
                 // Obj priority mode (CGB mode only)
             }
@@ -503,19 +710,54 @@ impl Memory for Mmu {
             }
             // interrupt enable register
             0xFFFF => self.interrupts_enabled = EnumSet::<InterruptKind>::from_u8_truncated(byte),
-            _ => eprintln!("unhandled register write for addr: {addr:X}"),
+            // unmapped registers ignore writes
+            _ => {}
         }
     }
 
+    /// `t_cycles` are CPU clock cycles, which are twice as fast as the PPU and APU clocks in double speed mode.
     fn step(&mut self, t_cycles: u8) {
-        let overflowed = self.timer.update(t_cycles);
-        if overflowed {
-            self.interrupts_requested |= InterruptKind::Timer;
+        self.step_oam_dma(t_cycles);
+        if self.serial.step(t_cycles) {
+            self.interrupts_requested |= InterruptKind::Serial;
         }
-        let ppu_interrupts = self.ppu.step(t_cycles);
+        let normal_speed_t_cycles = if self.double_speed {
+            t_cycles / 2
+        } else {
+            t_cycles
+        };
+        let ppu_interrupts = self.ppu.step(normal_speed_t_cycles);
         self.interrupts_requested |= ppu_interrupts;
+        self.apu.step(normal_speed_t_cycles);
+        self.cartridge.step(normal_speed_t_cycles);
+
+        let mut remaining = t_cycles;
+        while remaining > 0 {
+            let m_cycle = remaining.min(4);
+            if self.timer.step_reload() {
+                self.interrupts_requested |= InterruptKind::Timer;
+            }
+            let before = self.divider.internal_counter();
+            self.divider.advance(m_cycle);
+            self.observe_divider(before);
+            remaining -= m_cycle;
+        }
+    }
+
+    /// The speed switch takes about 2050 M-cycles, during which the CPU is stopped. That pause isn't modeled.
+    fn try_speed_switch(&mut self) -> bool {
+        if !self.cgb_mode || !self.speed_switch_armed {
+            return false;
+        }
+        self.speed_switch_armed = false;
+        self.double_speed = !self.double_speed;
+        true
+    }
 
-        self.divider.update(t_cycles);
+    fn reset_divider(&mut self) {
+        let div_before = self.divider.internal_counter();
+        self.divider.reset();
+        self.observe_divider(div_before);
     }
 
     fn interrupts_enabled(&self) -> EnumSet<InterruptKind> {
@@ -595,7 +837,7 @@ impl JoypadSelect {
 
 #[cfg(test)]
 mod tests {
-    use ppu::ObjectAttributes;
+    use crate::ppu::{Color, ColorId, ObjectAttributes, Rgb555};
 
     use super::*;
     #[test]
@@ -616,6 +858,636 @@ mod tests {
         assert_eq!(flags, all_set);
     }
 
+    #[test]
+    fn word_access_wraps_around_address_space() {
+        let mut rom = [0; 0x8000];
+        rom[0] = 0xAB;
+        let mut mmu = Mmu::new(&rom);
+        mmu.set_not_in_boot_rom();
+        mmu.write_byte(0xFFFF, 0x1F);
+        assert_eq!(mmu.read_word(0xFFFF), 0xAB1F);
+        // the high byte goes to the cartridge, which ignores the write
+        mmu.write_word(0xFFFF, 0x1204);
+        assert_eq!(mmu.read_byte(0xFFFF), 0x04);
+        assert_eq!(mmu.read_byte(0x0000), 0xAB);
+
+        // words that straddle region boundaries are split between the regions
+        mmu.write_word(0xFFFE, 0x0F42);
+        assert_eq!(mmu.read_byte(0xFFFE), 0x42);
+        assert_eq!(mmu.read_byte(0xFFFF), 0x0F);
+        // the high byte goes to echo RAM, which mirrors the start of work RAM
+        mmu.write_word(0xDFFF, 0x3456);
+        assert_eq!(mmu.read_byte(0xDFFF), 0x56);
+        assert_eq!(mmu.read_byte(0xC000), 0x34);
+        assert_eq!(mmu.read_word(0xDFFF), 0x3456);
+    }
+
+    #[test]
+    fn oam_dma() {
+        let mut mmu = Mmu::new(&[0; 0x8000]);
+        mmu.set_not_in_boot_rom();
+        for offset in 0..0xA0 {
+            mmu.write_byte(0xC100 + offset, offset as u8 + 1);
+        }
+        mmu.write_byte(0xFF80, 0x42);
+        mmu.write_byte(0xFF46, 0xC1);
+        assert_eq!(mmu.read_byte(0xFF46), 0xC1);
+
+        // only HRAM and the IO registers are accessible during the transfer
+        mmu.step(4);
+        assert_eq!(mmu.read_byte(0xC100), 0xFF);
+        mmu.write_byte(0xC100, 0x99);
+        assert_eq!(mmu.read_byte(0xFF80), 0x42);
+        // a byte is copied every M-cycle
+        assert_eq!(mmu.ppu.obj_attribute_memory[0].y_pos, 0x01);
+        assert_eq!(mmu.ppu.obj_attribute_memory[0].x_pos, 0x00);
+        for _ in 0..158 {
+            mmu.step(4);
+        }
+        assert_eq!(mmu.read_byte(0xFE00), 0xFF);
+        mmu.step(4);
+        // 160 M-cycles
+        assert_eq!(mmu.read_byte(0xC100), 0x01);
+        assert_eq!(mmu.read_byte(0xFE01), 0x02);
+        assert_eq!(mmu.read_byte(0xFE9C), 0x9D);
+        assert_eq!(mmu.ppu.obj_attribute_memory[39].tile_idx, 0x9F);
+    }
+
+    #[test]
+    fn ppu_blocks_vram_and_oam() {
+        let mut mmu = Mmu::new(&[0; 0x8000]);
+        mmu.set_not_in_boot_rom();
+        mmu.write_byte(0x8000, 0x12);
+        mmu.write_byte(0xFE00, 0x34);
+        mmu.write_byte(0xFF40, 0x80);
+        mmu.ppu.mode = Mode::ScanlineOAM;
+        assert_eq!(mmu.read_byte(0x8000), 0x12);
+        assert_eq!(mmu.read_byte(0xFE00), 0xFF);
+        mmu.ppu.mode = Mode::ScanlineVRAM;
+        assert_eq!(mmu.read_byte(0x8000), 0xFF);
+        mmu.write_byte(0x8000, 0x56);
+        mmu.write_byte(0xFE00, 0x78);
+        assert_eq!(mmu.read_byte(0xFE00), 0xFF);
+
+        mmu.ppu_access_blocking = false;
+        assert_eq!(mmu.read_byte(0x8000), 0x12);
+        assert_eq!(mmu.read_byte(0xFE00), 0x34);
+        mmu.ppu_access_blocking = true;
+        mmu.ppu.mode = Mode::HorizontalBlank;
+        assert_eq!(mmu.read_byte(0xFE00), 0x34);
+    }
+
+    #[test]
+    fn open_bus_reads() {
+        let mut dmg = Mmu::new(&[0; 0x8000]);
+        dmg.set_not_in_boot_rom();
+        dmg.write_byte(0xFEA0, 0x12);
+        assert_eq!(dmg.read_byte(0xFEA0), 0x00);
+        assert_eq!(dmg.read_byte(0xFF50), 0xFF);
+        for addr in [0xFF03, 0xFF08, 0xFF0E, 0xFF4C, 0xFF7F] {
+            dmg.write_byte(addr, 0x00);
+            assert_eq!(dmg.read_byte(addr), 0xFF, "{addr:04X}");
+        }
+        // unusable memory is blocked with OAM
+        dmg.write_byte(0xFF40, 0x80);
+        dmg.ppu.mode = Mode::ScanlineOAM;
+        assert_eq!(dmg.read_byte(0xFEFF), 0xFF);
+
+        let mut rom = [0; 0x8000];
+        rom[0x0143] = 0x80;
+        let cgb = Mmu::new(&rom);
+        assert_eq!(cgb.read_byte(0xFEA0), 0xAA);
+        assert_eq!(cgb.read_byte(0xFEC7), 0xCC);
+    }
+
+    #[test]
+    fn boot_rom_disable_register_is_write_once() {
+        let mut rom = [0; 0x8000];
+        rom[0x0000] = 0x12;
+        let mut mmu = Mmu::new(&rom);
+        mmu.load_boot_rom(vec![0x34; 0x100]);
+        assert_eq!(mmu.read_byte(0x0000), 0x34);
+        mmu.write_byte(0xFF50, 0x00);
+        assert!(mmu.in_boot_rom());
+        assert_eq!(mmu.read_byte(0xFF50), 0xFF);
+        mmu.write_byte(0xFF50, 0x01);
+        assert!(!mmu.in_boot_rom());
+        assert_eq!(mmu.read_byte(0x0000), 0x12);
+        assert_eq!(mmu.read_byte(0xFF50), 0xFF);
+        mmu.write_byte(0xFF50, 0x00);
+        assert!(!mmu.in_boot_rom());
+        assert_eq!(mmu.read_byte(0x0000), 0x12);
+    }
+
+    #[test]
+    fn stat_write_bug() {
+        let mut dmg = Mmu::new(&[0; 0x8000]);
+        let mut rom = [0; 0x8000];
+        rom[0x0143] = 0x80;
+        let mut cgb = Mmu::new(&rom);
+        for mmu in [&mut dmg, &mut cgb] {
+            mmu.write_byte(0xFF40, 0x80);
+            mmu.write_byte(0xFF45, 0x10);
+            mmu.ppu.mode = Mode::HorizontalBlank;
+            mmu.write_byte(0xFF41, 0x00);
+        }
+        assert_eq!(dmg.interrupts_requested, InterruptKind::LcdStat);
+        assert!(cgb.interrupts_requested.is_empty());
+
+        // no spurious interrupt while drawing, unless LY=LYC
+        dmg.interrupts_requested = EnumSet::empty();
+        dmg.ppu.mode = Mode::ScanlineVRAM;
+        dmg.write_byte(0xFF41, 0x00);
+        assert!(dmg.interrupts_requested.is_empty());
+        dmg.ppu.line = 0x10;
+        dmg.write_byte(0xFF41, 0x00);
+        assert_eq!(dmg.interrupts_requested, InterruptKind::LcdStat);
+    }
+
+    #[test]
+    fn cgb_palette_registers() {
+        let mut rom = [0; 0x8000];
+        rom[0x0143] = 0x80;
+        let mut mmu = Mmu::new(&rom);
+        // auto-increment from byte 62
+        mmu.write_byte(0xFF6A, 0xBE);
+        mmu.write_byte(0xFF6B, 0x12);
+        mmu.write_byte(0xFF6B, 0x34);
+        // the index wraps around to the start of palette RAM
+        assert_eq!(mmu.read_byte(0xFF6A), 0xC0);
+        mmu.write_byte(0xFF6B, 0x56);
+        mmu.write_byte(0xFF6A, 0x3E);
+        assert_eq!(mmu.read_byte(0xFF6A), 0x7E);
+        assert_eq!(mmu.read_byte(0xFF6B), 0x12);
+        // reads don't advance the index
+        assert_eq!(mmu.read_byte(0xFF6B), 0x12);
+        assert_eq!(
+            mmu.ppu.obj_palette_ram.color(7, ColorId::Id3),
+            Rgb555(0x3412)
+        );
+        assert_eq!(
+            mmu.ppu.obj_palette_ram.color(0, ColorId::Id0),
+            Rgb555(0x7F56)
+        );
+        // the BG palettes are untouched
+        assert_eq!(mmu.read_byte(0xFF69), 0xFF);
+    }
+
+    #[test]
+    fn wisdom_tree_bank_switch() {
+        let mut rom = vec![0; 4 * 0x8000];
+        for (bank_idx, bank) in rom.chunks_mut(0x8000).enumerate() {
+            bank[0x0000] = bank_idx as u8;
+            bank[0x7FFF] = 0x80 | bank_idx as u8;
+        }
+        let mut mmu = Mmu::new(&rom);
+        mmu.set_not_in_boot_rom();
+        assert_eq!(mmu.read_byte(0x0000), 0x00);
+        // the written value is ignored, the bank is in the address
+        mmu.write_byte(0x0102, 0x00);
+        assert_eq!(mmu.read_byte(0x0000), 0x02);
+        assert_eq!(mmu.read_byte(0x7FFF), 0x82);
+        assert_eq!(mmu.rom_bank(), 5);
+    }
+
+    #[test]
+    fn mbc3_banks_wrap_around_the_cartridge() {
+        // 64 KiB of ROM, without RAM
+        let mut rom = vec![0; 0x4000 * 4];
+        for (idx, bank) in rom.chunks_mut(0x4000).enumerate() {
+            bank[0] = idx as u8;
+        }
+        rom[0x0147] = 0x11;
+        rom[0x0148] = 0x01;
+        let mut mmu = Mmu::new(&rom);
+        mmu.set_not_in_boot_rom();
+        mmu.write_byte(0x2000, 0x7E);
+        assert_eq!(mmu.read_byte(0x4000), 2);
+        assert_eq!(mmu.rom_bank(), 2);
+        mmu.write_byte(0x0000, 0x0A);
+        mmu.write_byte(0x4000, 0x03);
+        mmu.write_byte(0xA000, 0x42);
+        assert_eq!(mmu.read_byte(0xA000), 0xFF);
+
+        // 8 KiB of RAM, which every RAM bank maps
+        rom[0x0147] = 0x13;
+        rom[0x0149] = 0x02;
+        let mut mmu = Mmu::new(&rom);
+        mmu.set_not_in_boot_rom();
+        mmu.write_byte(0x0000, 0x0A);
+        mmu.write_byte(0x4000, 0x03);
+        mmu.write_byte(0xA000, 0x42);
+        mmu.write_byte(0x4000, 0x00);
+        assert_eq!(mmu.read_byte(0xA000), 0x42);
+    }
+
+    #[test]
+    fn mbc3_rtc_latch_halt_and_day_carry() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0147] = 0x10;
+        let mut mmu = Mmu::new(&rom);
+        mmu.set_not_in_boot_rom();
+        mmu.set_rtc_clock_source(cartridge::RtcClockSource::EmulatedTime);
+        let read_rtc = |mmu: &mut Mmu, register: u8| {
+            mmu.write_byte(0x4000, register);
+            mmu.read_byte(0xA000)
+        };
+        let latch = |mmu: &mut Mmu| {
+            mmu.write_byte(0x6000, 0x00);
+            mmu.write_byte(0x6000, 0x01);
+        };
+        mmu.write_byte(0x0000, 0x0A);
+        // set the clock to day 511, 23:59:58
+        for (register, value) in [
+            (0x08, 58),
+            (0x09, 59),
+            (0x0A, 23),
+            (0x0B, 0xFF),
+            (0x0C, 0x01),
+        ] {
+            mmu.write_byte(0x4000, register);
+            mmu.write_byte(0xA000, value);
+        }
+        for _ in 0..(crate::apu::T_CYCLES_PER_SECOND / 4) {
+            mmu.step(4);
+        }
+        // the registers only change when latched
+        assert_eq!(read_rtc(&mut mmu, 0x08), 58);
+        latch(&mut mmu);
+        assert_eq!(read_rtc(&mut mmu, 0x08), 59);
+
+        // halt the clock
+        mmu.write_byte(0x4000, 0x0C);
+        mmu.write_byte(0xA000, 0x41);
+        for _ in 0..(crate::apu::T_CYCLES_PER_SECOND / 4) {
+            mmu.step(4);
+        }
+        latch(&mut mmu);
+        assert_eq!(read_rtc(&mut mmu, 0x08), 59);
+
+        // resume, and overflow the day counter
+        mmu.write_byte(0x4000, 0x0C);
+        mmu.write_byte(0xA000, 0x01);
+        for _ in 0..(crate::apu::T_CYCLES_PER_SECOND / 4) {
+            mmu.step(4);
+        }
+        latch(&mut mmu);
+        assert_eq!(
+            [0x08, 0x09, 0x0A, 0x0B, 0x0C].map(|register| read_rtc(&mut mmu, register)),
+            [0, 0, 0, 0, 0x80]
+        );
+    }
+
+    #[test]
+    fn mbc3_battery_save_with_rtc_footer() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0147] = 0x10;
+        rom[0x0149] = 0x02;
+        let mut mmu = Mmu::new(&rom);
+        mmu.set_not_in_boot_rom();
+        mmu.write_byte(0x0000, 0x0A);
+        mmu.write_byte(0xA123, 0x42);
+        mmu.write_byte(0x4000, 0x0A);
+        mmu.write_byte(0xA000, 5);
+        let save = mmu.battery_save().unwrap();
+        assert_eq!(save.len(), 0x2000 + 48);
+        assert_eq!(save[0x123], 0x42);
+        // the live hours
+        assert_eq!(save[0x2000 + 8], 5);
+
+        // a 44 byte footer from another emulator, saved an hour ago
+        let mut other_save = save[..0x2000].to_vec();
+        for _ in 0..2 {
+            other_save.extend([0, 0, 0, 0, 0, 0, 0, 0, 5, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        }
+        let an_hour_ago = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            - 3600;
+        other_save.extend((an_hour_ago as u32).to_le_bytes());
+        let mut mmu = Mmu::new(&rom);
+        mmu.set_not_in_boot_rom();
+        mmu.load_battery_save(&other_save).unwrap();
+        mmu.write_byte(0x0000, 0x0A);
+        assert_eq!(mmu.read_byte(0xA123), 0x42);
+        mmu.write_byte(0x6000, 0x00);
+        mmu.write_byte(0x6000, 0x01);
+        mmu.write_byte(0x4000, 0x0A);
+        assert_eq!(mmu.read_byte(0xA000), 6);
+        assert!(mmu.load_battery_save(&save[..0x2000 + 10]).is_err());
+    }
+
+    #[test]
+    fn mbc1_multicart_bank_wiring() {
+        let mut rom = vec![0; 64 * 0x4000];
+        for (bank_idx, bank) in rom.chunks_mut(0x4000).enumerate() {
+            bank[0x0000] = bank_idx as u8;
+        }
+        rom[0x0147] = 0x01;
+        rom[0x0148] = 0x05;
+        let mut mmu = Mmu::new(&rom);
+        mmu.set_not_in_boot_rom();
+        mmu.write_byte(0x4000, 0x01);
+        mmu.write_byte(0x2000, 0x02);
+        assert_eq!(mmu.read_byte(0x4000), 0x22);
+
+        // the second game's header makes it a multicart
+        rom[0x4_0104..0x4_0134].copy_from_slice(&cartridge::NINTENDO_LOGO);
+        let mut mmu = Mmu::new(&rom);
+        mmu.set_not_in_boot_rom();
+        mmu.write_byte(0x4000, 0x01);
+        mmu.write_byte(0x2000, 0x02);
+        assert_eq!(mmu.read_byte(0x4000), 0x12);
+        assert_eq!(mmu.read_byte(0x0000), 0x00);
+        mmu.write_byte(0x6000, 0x01);
+        assert_eq!(mmu.read_byte(0x0000), 0x10);
+        // bit 4 of the low register is ignored, but it still counts as non-zero
+        mmu.write_byte(0x2000, 0x10);
+        assert_eq!(mmu.read_byte(0x4000), 0x10);
+    }
+
+    #[test]
+    fn mbc7_accelerometer_and_eeprom() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0147] = 0x22;
+        let mut mmu = Mmu::new(&rom);
+        mmu.set_not_in_boot_rom();
+        mmu.write_byte(0x0000, 0x0A);
+        mmu.write_byte(0x4000, 0x40);
+
+        mmu.set_accelerometer(1.0, -0.5);
+        mmu.write_byte(0xA000, 0x55);
+        mmu.write_byte(0xA010, 0xAA);
+        let x = u16::from_le_bytes([mmu.read_byte(0xA020), mmu.read_byte(0xA030)]);
+        let y = u16::from_le_bytes([mmu.read_byte(0xA040), mmu.read_byte(0xA050)]);
+        assert_eq!((x, y), (0x81D0 + 0x70, 0x81D0 - 0x38));
+
+        // clock bits into the EEPROM with chip select held, and return what it sends back
+        let transfer = |mmu: &mut Mmu, bits: &[u8]| -> Vec<u8> {
+            bits.iter()
+                .map(|&bit| {
+                    mmu.write_byte(0xA080, 0x80 | bit << 1);
+                    mmu.write_byte(0xA080, 0xC0 | bit << 1);
+                    mmu.read_byte(0xA080) & 1
+                })
+                .collect()
+        };
+        let word = [1, 0, 1, 0, 0, 1, 0, 1, 1, 1, 0, 0, 0, 0, 1, 1];
+        // EWEN, then WRITE 0xA5C3 to address 5
+        transfer(&mut mmu, &[1, 0, 0, 1, 1, 0, 0, 0, 0, 0, 0]);
+        mmu.write_byte(0xA080, 0x00);
+        transfer(
+            &mut mmu,
+            &[[1, 0, 1, 0, 0, 0, 0, 0, 1, 0, 1].as_slice(), &word].concat(),
+        );
+        mmu.write_byte(0xA080, 0x00);
+        // READ address 5
+        transfer(&mut mmu, &[1, 1, 0, 0, 0, 0, 0, 0, 1, 0, 1]);
+        assert_eq!(transfer(&mut mmu, &[0; 16]), word);
+        assert_eq!(&mmu.battery_save().unwrap()[10..12], [0xC3, 0xA5]);
+    }
+
+    #[test]
+    fn rom_validation_errors() {
+        use cartridge::{header_checksum, validate_rom};
+        let with_header = |cartridge_type: u8, rom_size: u8, len: usize| {
+            let mut rom = vec![0; len];
+            rom[0x0147] = cartridge_type;
+            rom[0x0148] = rom_size;
+            rom[0x014D] = header_checksum(&rom);
+            rom
+        };
+        assert_eq!(
+            validate_rom(&with_header(0x01, 0x01, 0x10000), None),
+            Ok(Mapper::Mbc1)
+        );
+        assert_eq!(
+            validate_rom(&[0; 0x100], None),
+            Err(RomError::TooShort { len: 0x100 })
+        );
+        let mut corrupted = with_header(0x00, 0x00, 0x8000);
+        corrupted[0x0134] = b'X';
+        assert!(matches!(
+            validate_rom(&corrupted, None),
+            Err(RomError::HeaderChecksum { .. })
+        ));
+        assert_eq!(
+            validate_rom(&with_header(0x01, 0x01, 0xC000), None),
+            Err(RomError::SizeMismatch {
+                declared: 0x10000,
+                actual: 0xC000
+            })
+        );
+        assert_eq!(
+            validate_rom(&with_header(0xFE, 0x00, 0x8000), None),
+            Err(RomError::UnsupportedMapper(0xFE))
+        );
+        // ROM-only carts larger than 32 KiB are Wisdom Tree games, but not with RAM
+        assert_eq!(
+            validate_rom(&with_header(0x08, 0x01, 0x10000), None),
+            Err(RomError::UnsupportedRomSize {
+                mapper: Mapper::NoMbc,
+                rom_size: 0x01
+            })
+        );
+        // a forced mapper is checked instead of the header's
+        assert_eq!(
+            validate_rom(&with_header(0xFE, 0x01, 0x10000), Some(Mapper::Mbc5)),
+            Ok(Mapper::Mbc5)
+        );
+        let message = RomError::UnsupportedMapper(0xFE).to_string();
+        assert!(message.contains("mbc5 (cartridge type 19-1E)"), "{message}");
+    }
+
+    #[test]
+    fn mbc5_banking_and_rumble() {
+        // 8 MiB, so the 9th bit of the ROM bank matters
+        let mut rom = vec![0; 0x4000 * 512];
+        for (idx, bank) in rom.chunks_mut(0x4000).enumerate() {
+            bank[0] = idx as u8;
+            bank[1] = (idx >> 8) as u8;
+        }
+        // MBC5+RUMBLE+RAM+BATTERY, with 8 KiB of RAM
+        rom[0x0147] = 0x1E;
+        rom[0x0148] = 0x08;
+        rom[0x0149] = 0x02;
+        let mut mmu = Mmu::new(&rom);
+        mmu.set_not_in_boot_rom();
+        let mapped_bank =
+            |mmu: &Mmu| u16::from_le_bytes([mmu.read_byte(0x4000), mmu.read_byte(0x4001)]);
+        assert_eq!(mapped_bank(&mmu), 1);
+        mmu.write_byte(0x2000, 0x00);
+        assert_eq!(mapped_bank(&mmu), 0);
+        mmu.write_byte(0x3000, 0x01);
+        mmu.write_byte(0x2000, 0x23);
+        assert_eq!(mapped_bank(&mmu), 0x123);
+
+        // the motor bit doesn't select a RAM bank
+        mmu.write_byte(0x0000, 0x0A);
+        mmu.write_byte(0x4000, 0x08);
+        mmu.write_byte(0xA000, 0x42);
+        mmu.write_byte(0x4000, 0x00);
+        assert_eq!(mmu.read_byte(0xA000), 0x42);
+
+        assert_eq!(mmu.take_rumble_state(), Some(0.0));
+        for on in [true, false, false, false] {
+            mmu.write_byte(0x4000, if on { 0x08 } else { 0x00 });
+            for _ in 0..100 {
+                mmu.step(4);
+            }
+        }
+        assert_eq!(mmu.take_rumble_state(), Some(0.25));
+
+        rom[0x0147] = 0x1B;
+        assert_eq!(Mmu::new(&rom).take_rumble_state(), None);
+    }
+
+    #[test]
+    fn pocket_camera_capture() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0147] = 0xFC;
+        let mut mmu = Mmu::new(&rom);
+        mmu.set_not_in_boot_rom();
+        // black on the left half, white on the right
+        let mut frame = Box::new([[0; crate::camera::WIDTH]; crate::camera::HEIGHT]);
+        for row in frame.iter_mut() {
+            row[64..].fill(0xFF);
+        }
+        mmu.connect_camera(Box::new(crate::camera::StaticImage(frame)));
+
+        mmu.write_byte(0x4000, 0x10);
+        // N bit, and the exposure that passes the image through
+        mmu.write_byte(0xA001, 0x80);
+        mmu.write_byte(0xA002, 0x08);
+        mmu.write_byte(0xA003, 0x00);
+        for register in (0xA006..0xA036).step_by(3) {
+            mmu.write_byte(register, 0x40);
+            mmu.write_byte(register + 1, 0x80);
+            mmu.write_byte(register + 2, 0xC0);
+        }
+        mmu.write_byte(0xA000, 0x01);
+        assert_eq!(mmu.read_byte(0xA000) & 1, 1);
+        for _ in 0..(32446 + 16 * 0x800) {
+            mmu.step(4);
+        }
+        assert_eq!(mmu.read_byte(0xA000) & 1, 0);
+
+        mmu.write_byte(0x4000, 0x00);
+        // the first tile is black, and the last tile of the first row is white
+        assert_eq!(mmu.read_byte(0xA100), 0xFF);
+        assert_eq!(mmu.read_byte(0xA101), 0xFF);
+        assert_eq!(mmu.read_byte(0xA100 + 15 * 16), 0x00);
+        assert_eq!(mmu.read_byte(0xA101 + 15 * 16), 0x00);
+        // pictures are kept in battery backed RAM
+        assert_eq!(mmu.battery_save().unwrap()[0x100], 0xFF);
+    }
+
+    #[test]
+    fn cgb_vram_bank_select() {
+        let mut rom = [0; 0x8000];
+        rom[0x0143] = 0x80;
+        let mut mmu = Mmu::new(&rom);
+        mmu.write_byte(0x8000, 0x12);
+        mmu.write_byte(0x9800, 0x34);
+        mmu.write_byte(0xFF4F, 0x01);
+        assert_eq!(mmu.read_byte(0xFF4F), 0xFF);
+        assert_eq!(mmu.read_byte(0x8000), 0x00);
+        mmu.write_byte(0x8000, 0x56);
+        mmu.write_byte(0x9800, 0x8B);
+        assert_eq!(mmu.read_byte(0x9800), 0x8B);
+        assert!(mmu.ppu.lo_tile_attributes.attributes[0][0].bank_1);
+        mmu.write_byte(0xFF4F, 0x00);
+        assert_eq!(mmu.read_byte(0xFF4F), 0xFE);
+        assert_eq!(mmu.read_byte(0x8000), 0x12);
+        assert_eq!(mmu.read_byte(0x9800), 0x34);
+    }
+
+    #[test]
+    fn dmg_rom_on_cgb_uses_compatibility_mode() {
+        let mut rom = [0; 0x8000];
+        rom[0x0134..0x013F].copy_from_slice(b"POKEMON RED");
+        rom[0x014B] = 0x01;
+        let mmu = Mmu::with_model(&rom, HardwareModel::Cgb);
+        assert_eq!(mmu.ppu.dmg_palette, DmgPalette::cgb_compat_for_rom(&rom));
+        assert_ne!(mmu.ppu.dmg_palette, crate::palette::compat::DEFAULT);
+        assert!(!mmu.ppu.cgb_mode);
+        // CGB registers are unavailable
+        assert_eq!(mmu.read_byte(0xFF4D), 0xFF);
+
+        let mmu = Mmu::new(&rom);
+        assert_eq!(mmu.ppu.dmg_palette, DmgPalette::GRAYSCALE);
+    }
+
+    #[test]
+    fn lcd_disable_and_enable() {
+        let mut mmu = Mmu::new(&[0; 0x8000]);
+        let run_frame = |mmu: &mut Mmu| {
+            for _ in 0..154 * 456 / 4 {
+                mmu.step(4);
+            }
+        };
+        // a black background
+        mmu.write_byte(0xFF47, 0xFF);
+        mmu.write_byte(0xFF40, 0x91);
+        run_frame(&mut mmu);
+        run_frame(&mut mmu);
+        assert_eq!(mmu.ppu.last_full_frame[0].colors(), [Color::Black; 160]);
+
+        for _ in 0..100 {
+            mmu.step(4);
+        }
+        mmu.write_byte(0xFF40, 0x11);
+        assert_eq!(mmu.read_byte(0xFF44), 0);
+        assert_eq!(
+            mmu.read_byte(0xFF41) & 0b11,
+            0,
+            "mode 0 while the LCD is off"
+        );
+        assert_eq!(mmu.ppu.last_full_frame[0].colors(), [Color::White; 160]);
+
+        // the first frame after the LCD is turned back on isn't displayed
+        mmu.write_byte(0xFF40, 0x91);
+        run_frame(&mut mmu);
+        assert_eq!(mmu.ppu.last_full_frame[0].colors(), [Color::White; 160]);
+        run_frame(&mut mmu);
+        assert_eq!(mmu.ppu.last_full_frame[0].colors(), [Color::Black; 160]);
+    }
+
+    #[test]
+    fn cgb_double_speed() {
+        let mut rom = [0; 0x8000];
+        rom[0x0143] = 0x80;
+        let mut mmu = Mmu::new(&rom);
+        assert_eq!(mmu.read_byte(0xFF4D), 0x7E);
+        mmu.write_byte(0xFF4D, 0x01);
+        assert_eq!(mmu.read_byte(0xFF4D), 0x7F);
+        assert!(mmu.try_speed_switch());
+        assert_eq!(mmu.read_byte(0xFF4D), 0xFE);
+        assert_eq!(mmu.read_byte(0xFF04), 0);
+
+        // the CPU and divider run at twice the speed of the PPU
+        mmu.write_byte(0xFF40, 0x80);
+        for _ in 0..(2 * 456 / 4) {
+            mmu.step(4);
+        }
+        assert_eq!(mmu.ppu.line, 1);
+        assert_eq!(mmu.read_byte(0xFF04), 3);
+
+        // switch back to normal speed
+        mmu.write_byte(0xFF4D, 0x01);
+        assert!(mmu.try_speed_switch());
+        assert_eq!(mmu.read_byte(0xFF4D), 0x7E);
+    }
+
+    #[test]
+    fn no_speed_switch_on_dmg() {
+        let mut mmu = Mmu::new(&[0; 0x8000]);
+        mmu.write_byte(0xFF4D, 0x01);
+        assert_eq!(mmu.read_byte(0xFF4D), 0xFF);
+        assert!(!mmu.try_speed_switch());
+    }
+
     #[test]
     fn oam_memory_rw() {
         let mut mmu = Mmu::new(&[0; 0x8000]);
@@ -641,7 +1513,8 @@ mod tests {
                 bg_over_obj_priority: Priority::One,
                 y_flip: false,
                 x_flip: true,
-                palette: ObjColorPaletteIdx::Zero
+                palette: ObjColorPaletteIdx::Zero,
+                cgb_palette: 0,
             }
         );
 
@@ -650,4 +1523,49 @@ mod tests {
         assert_eq!(mmu.read_byte(obj_addr + 2), tile_idx);
         assert_eq!(mmu.read_byte(obj_addr + 3), attributes);
     }
+
+    #[test]
+    fn div_reset_clocks_frame_sequencer() {
+        let mut mmu = Mmu::new(&[0; 0x8000]);
+        let ch1_enabled = |mmu: &Mmu| mmu.read_byte(0xFF26).bit(0);
+        mmu.write_byte(0xFF26, 0x80); // APU on
+        mmu.write_byte(0xFF12, 0xF0); // DAC on
+        mmu.write_byte(0xFF11, 0x3F); // 1 length tick remaining
+        mmu.write_byte(0xFF14, 0xC0); // trigger with length enabled
+        assert!(ch1_enabled(&mmu));
+
+        // resetting DIV while DIV bit 4 is clear doesn't clock the frame sequencer
+        for _ in 0..8 {
+            mmu.step(128);
+        }
+        mmu.write_byte(0xFF04, 0);
+        assert!(ch1_enabled(&mmu));
+
+        // DIV bit 4 is set, so resetting DIV is a falling edge that clocks the length counter
+        for _ in 0..32 {
+            mmu.step(128);
+        }
+        assert_eq!(mmu.read_byte(0xFF04), 0x10);
+        assert!(ch1_enabled(&mmu));
+        mmu.write_byte(0xFF04, 0);
+        assert!(!ch1_enabled(&mmu));
+    }
+
+    #[test]
+    fn div_and_timer_share_the_counter() {
+        let mut mmu = Mmu::new(&[0; 0x8000]);
+        mmu.write_byte(0xFF07, 0x04); // timer on, incrementing every 1024 T-cycles
+                                      // steps of any length add up the same way
+        let mut t_cycles = 0;
+        for len in [4, 8, 12, 24].iter().cycle().take(400) {
+            mmu.step(*len);
+            t_cycles += *len as u32;
+        }
+        assert_eq!(mmu.read_byte(0xFF04) as u32, t_cycles / 256);
+        assert_eq!(mmu.read_byte(0xFF05) as u32, t_cycles / 1024);
+        // TIMA's bit is set, so resetting DIV increments it before it is due
+        assert_eq!(t_cycles % 1024, 704);
+        mmu.write_byte(0xFF04, 0);
+        assert_eq!(mmu.read_byte(0xFF05) as u32, t_cycles / 1024 + 1);
+    }
 }
@@ -0,0 +1,170 @@
+//! The console hardware being emulated, and the quirks that differ between models.
+use std::fmt::Display;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::cpu::Cpu;
+use crate::mmu::{Memory, Mmu};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HardwareModel {
+    Dmg(DmgRevision),
+    /// Game Boy Color
+    Cgb,
+}
+
+impl HardwareModel {
+    /// Emulate a CGB for ROMs whose header enables CGB features, and a DMG of the given revision otherwise.
+    pub fn for_rom(rom: &[u8], dmg_revision: DmgRevision) -> Self {
+        // https://gbdev.io/pandocs/The_Cartridge_Header.html#0143--cgb-flag
+        if rom[0x0143] & 0x80 != 0 {
+            HardwareModel::Cgb
+        } else {
+            HardwareModel::Dmg(dmg_revision)
+        }
+    }
+
+    pub fn is_cgb(self) -> bool {
+        self == HardwareModel::Cgb
+    }
+
+    /// On the DMG, writing to STAT briefly enables every STAT interrupt source, so a write during HBlank, VBlank, or
+    /// while LY=LYC requests a STAT interrupt. Some games depend on this.
+    ///
+    /// https://gbdev.io/pandocs/STAT.html#spurious-stat-interrupts
+    pub fn has_stat_write_bug(self) -> bool {
+        matches!(self, HardwareModel::Dmg(_))
+    }
+
+    /// Put the hardware in the state that the boot ROM hands off to the cartridge in, without running it. The logo
+    /// isn't drawn into VRAM, and the chime isn't played.
+    ///
+    /// https://gbdev.io/pandocs/Power_Up_Sequence.html
+    pub(crate) fn skip_boot_rom(self, cpu: &mut Cpu<Mmu>) {
+        for (addr, byte) in [
+            (0xFF26, 0x80), // NR52: sound on
+            (0xFF11, 0x80), // NR11
+            (0xFF12, 0xF3), // NR12
+            (0xFF25, 0xF3), // NR51
+            (0xFF24, 0x77), // NR50
+            (0xFF47, 0xFC), // BGP
+            (0xFF40, 0x91), // LCDC: LCD and background on
+            (0xFF50, 0x01), // unmap the boot ROM
+        ] {
+            cpu.mmu.write_byte(addr, byte);
+        }
+        // the boot ROM has been displaying frames for a while
+        cpu.mmu.ppu.skip_frame = false;
+        cpu.mmu.divider.set_div(0xAB);
+        let cgb_mode = cpu.mmu.ppu.cgb_mode;
+        let regs = &mut cpu.regs;
+        (regs.sp, regs.pc) = (0xFFFE, 0x0100);
+        match self {
+            HardwareModel::Dmg(revision) => {
+                (regs.a, regs.f) = (0x01, 0xB0);
+                (regs.b, regs.c) = (0x00, 0x13);
+                (regs.d, regs.e) = (0x00, 0xD8);
+                (regs.h, regs.l) = (0x01, 0x4D);
+                revision.apply_post_boot_state(cpu);
+            }
+            HardwareModel::Cgb => {
+                (regs.a, regs.f) = (0x11, 0x80);
+                (regs.b, regs.c) = (0x00, 0x00);
+                if cgb_mode {
+                    (regs.d, regs.e) = (0xFF, 0x56);
+                    (regs.h, regs.l) = (0x00, 0x0D);
+                } else {
+                    (regs.d, regs.e) = (0x00, 0x08);
+                    (regs.h, regs.l) = (0x00, 0x7C);
+                }
+            }
+        }
+    }
+}
+
+/// DMG board revisions with observable differences. Revisions that aren't listed behave like `DmgB`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum DmgRevision {
+    /// The earliest revision, only sold in Japan
+    Dmg0,
+    /// DMG-CPU-B, the most common revision
+    #[default]
+    DmgB,
+}
+
+impl DmgRevision {
+    /// Other revisions' boot ROMs hand off to the cartridge with different register and DIV values than DMG-CPU-B's,
+    /// the most common dump. They're applied once the boot ROM exits, so that any DMG boot ROM emulates the revision.
+    ///
+    /// https://gbdev.io/pandocs/Power_Up_Sequence.html#cpu-registers
+    pub(crate) fn apply_post_boot_state(self, cpu: &mut Cpu<Mmu>) {
+        match self {
+            DmgRevision::Dmg0 => {
+                let regs = &mut cpu.regs;
+                (regs.a, regs.f) = (0x01, 0x00);
+                (regs.b, regs.c) = (0xFF, 0x13);
+                (regs.d, regs.e) = (0x00, 0xC1);
+                (regs.h, regs.l) = (0x84, 0x03);
+                cpu.mmu.divider.set_div(0x18);
+            }
+            DmgRevision::DmgB => {}
+        }
+    }
+}
+
+impl FromStr for DmgRevision {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "dmg0" => Ok(DmgRevision::Dmg0),
+            "dmg-b" | "dmgb" => Ok(DmgRevision::DmgB),
+            _ => Err(format!(
+                "unknown DMG revision {s:?}, expected dmg0 or dmg-b"
+            )),
+        }
+    }
+}
+
+impl Display for DmgRevision {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DmgRevision::Dmg0 => write!(f, "dmg0"),
+            DmgRevision::DmgB => write!(f, "dmg-b"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn for_rom_reads_cgb_flag() {
+        let mut rom = [0; 0x8000];
+        assert_eq!(
+            HardwareModel::for_rom(&rom, DmgRevision::Dmg0),
+            HardwareModel::Dmg(DmgRevision::Dmg0)
+        );
+        rom[0x0143] = 0xC0;
+        assert_eq!(
+            HardwareModel::for_rom(&rom, DmgRevision::Dmg0),
+            HardwareModel::Cgb
+        );
+    }
+
+    #[test]
+    fn dmg0_post_boot_state() {
+        let rom = [0; 0x8000];
+        let mut cpu = Cpu::new(Mmu::new(&rom));
+        DmgRevision::DmgB.apply_post_boot_state(&mut cpu);
+        assert_eq!(cpu.regs.b, 0x00);
+        DmgRevision::Dmg0.apply_post_boot_state(&mut cpu);
+        assert_eq!((cpu.regs.a, cpu.regs.f, cpu.regs.b), (0x01, 0x00, 0xFF));
+        assert_eq!((cpu.regs.h, cpu.regs.l), (0x84, 0x03));
+        assert_eq!(cpu.mmu.divider.div(), 0x18);
+        assert_eq!("DMG0".parse(), Ok(DmgRevision::Dmg0));
+        assert_eq!(DmgRevision::DmgB.to_string().parse(), Ok(DmgRevision::DmgB));
+    }
+}
@@ -0,0 +1,484 @@
+//! Input movies: the buttons pressed in every frame, recorded from a known state so that they can be replayed exactly,
+//! e.g. in regression tests, or to share how to reproduce a bug.
+//!
+//! For tool-assisted speedruns, a recording can go back to an earlier save state or rewind snapshot and continue from
+//! there (a re-record), and movies are written in a text format that is easy to edit:
+//!
+//! ```text
+//! start-hash 0123456789ABCDEF
+//! rerecords 12
+//! # the pressed buttons in the order UDLRsSBA, with . for released buttons. A count before them repeats them
+//! 120 ........
+//! .......A
+//! U.......
+//! ```
+use std::error::Error;
+use std::fmt::Display;
+use std::str::FromStr;
+
+use enumset::EnumSet;
+use serde::{Deserialize, Serialize};
+use twox_hash::xxh3;
+
+use crate::joypad::Button;
+use crate::{Emulator, Event, RtcClockSource};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Movie {
+    /// The hash of the emulator's state when the recording started, see [`Emulator::state_hash`]. The pressed buttons
+    /// aren't part of it, since the movie sets them.
+    pub start_hash: u64,
+    /// The buttons pressed during each frame, starting with the frame that was running when the recording started
+    pub frames: Vec<EnumSet<Button>>,
+    /// The number of times that the recording went back to an earlier state
+    pub rerecords: u32,
+}
+
+/// The buttons in the order of the columns of a movie file, with their letters
+const COLUMNS: [(Button, char); 8] = [
+    (Button::Up, 'U'),
+    (Button::Down, 'D'),
+    (Button::Left, 'L'),
+    (Button::Right, 'R'),
+    (Button::Select, 's'),
+    (Button::Start, 'S'),
+    (Button::B, 'B'),
+    (Button::A, 'A'),
+];
+
+fn format_buttons(buttons: EnumSet<Button>) -> String {
+    COLUMNS
+        .iter()
+        .map(|&(button, letter)| {
+            if buttons.contains(button) {
+                letter
+            } else {
+                '.'
+            }
+        })
+        .collect()
+}
+
+fn parse_buttons(s: &str) -> Option<EnumSet<Button>> {
+    if s.chars().count() != COLUMNS.len() {
+        return None;
+    }
+    let mut buttons = EnumSet::empty();
+    for (c, &(button, letter)) in s.chars().zip(&COLUMNS) {
+        match c {
+            '.' => {}
+            c if c == letter => buttons |= button,
+            _ => return None,
+        }
+    }
+    Some(buttons)
+}
+
+/// Write the movie file format described in the [module docs](self), with runs of equal frames on one line.
+impl Display for Movie {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "start-hash {:016X}", self.start_hash)?;
+        writeln!(f, "rerecords {}", self.rerecords)?;
+        writeln!(
+            f,
+            "# the pressed buttons in the order UDLRsSBA, with . for released buttons. A count before them repeats them"
+        )?;
+        for run in self.frames.chunk_by(|a, b| a == b) {
+            match run.len() {
+                1 => writeln!(f, "{}", format_buttons(run[0]))?,
+                len => writeln!(f, "{len} {}", format_buttons(run[0]))?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parse the movie file format described in the [module docs](self). Everything after a `#` is a comment.
+impl FromStr for Movie {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut start_hash = None;
+        let mut movie = Movie::default();
+        for (line_idx, line) in s.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            let parse_line = |movie: &mut Movie, start_hash: &mut Option<u64>| {
+                match tokens[..] {
+                    ["start-hash", hash] => *start_hash = Some(u64::from_str_radix(hash, 16).ok()?),
+                    ["rerecords", count] => movie.rerecords = count.parse().ok()?,
+                    [buttons] => movie.frames.push(parse_buttons(buttons)?),
+                    [count, buttons] => {
+                        let count: usize = count.parse().ok()?;
+                        let buttons = parse_buttons(buttons)?;
+                        movie.frames.extend(std::iter::repeat_n(buttons, count));
+                    }
+                    _ => return None,
+                }
+                Some(())
+            };
+            parse_line(&mut movie, &mut start_hash)
+                .ok_or_else(|| format!("invalid movie line {}: {line:?}", line_idx + 1))?;
+        }
+        movie.start_hash = start_hash.ok_or("the movie has no start-hash line")?;
+        Ok(movie)
+    }
+}
+
+pub(crate) enum MovieState {
+    /// `start_frame` is the frame that was running when the recording started, which is the movie's first frame
+    Recording {
+        movie: Movie,
+        start_frame: u64,
+    },
+    Playing {
+        movie: Movie,
+        start_frame: u64,
+    },
+}
+
+/// Why [`Emulator::play_movie`] refused to play a movie: it was recorded from another state, so playing it wouldn't
+/// reproduce the recording.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StateMismatch {
+    pub expected: u64,
+    pub actual: u64,
+}
+
+impl std::fmt::Display for StateMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "The movie was recorded from a state with hash {:016X}, but the emulator's state has hash {:016X}",
+            self.expected, self.actual
+        )
+    }
+}
+
+impl Error for StateMismatch {}
+
+impl Emulator {
+    /// A hash of everything that save states contain. Emulators with equal hashes run the same way given the same
+    /// inputs.
+    pub fn state_hash(&self) -> u64 {
+        let state = rmp_serde::to_vec(self).expect("BUG: failed to serialize the emulator state");
+        xxh3::hash64(&state)
+    }
+
+    /// The state hash that movies start from, which leaves out the pressed buttons. Switches the cartridge's clock to
+    /// [`RtcClockSource::EmulatedTime`], since a clock that follows the host's time would make the movie play
+    /// differently each time.
+    fn movie_start_hash(&mut self) -> u64 {
+        self.cpu
+            .mmu
+            .set_rtc_clock_source(RtcClockSource::EmulatedTime);
+        let pressed = std::mem::take(&mut self.cpu.mmu.pressed_buttons);
+        let hash = self.state_hash();
+        self.cpu.mmu.pressed_buttons = pressed;
+        hash
+    }
+
+    /// Start recording the buttons pressed in each frame, replacing a movie that is being recorded or played. The
+    /// cartridge's real-time clock counts emulated time from now on.
+    ///
+    /// Restoring an earlier state with [`Emulator::load_state`] or [`Emulator::rewind`] while recording drops the
+    /// frames after it, and the recording continues from there. Restoring a state that the recording doesn't reach,
+    /// e.g. one from before the recording started, starts the recording over from that state.
+    pub fn start_movie_recording(&mut self) {
+        self.start_movie_recording_with(0);
+    }
+
+    fn start_movie_recording_with(&mut self, rerecords: u32) {
+        self.movie = Some(MovieState::Recording {
+            movie: Movie {
+                start_hash: self.movie_start_hash(),
+                frames: Vec::new(),
+                rerecords,
+            },
+            start_frame: self.frame_count,
+        });
+    }
+
+    /// Stop recording, and return the movie of the frames completed since the recording started. `None` if nothing is
+    /// being recorded.
+    pub fn finish_movie_recording(&mut self) -> Option<Movie> {
+        match self.movie.take() {
+            Some(MovieState::Recording { movie, .. }) => Some(movie),
+            movie => {
+                self.movie = movie;
+                None
+            }
+        }
+    }
+
+    /// Press the buttons of each frame of `movie` as the frames run, starting with the current one. Fails if the
+    /// emulator isn't in the state that the movie was recorded from. Like recording, playing switches the cartridge's
+    /// real-time clock to emulated time.
+    ///
+    /// While the movie plays, [`Emulator::set_pressed_buttons`] and [`Emulator::hold_button`] are ignored. Once all
+    /// of its frames have run, the emulator emits an [`Event::MovieEnded`]. Restoring an earlier state while playing
+    /// continues playing from the frame of that state.
+    pub fn play_movie(&mut self, movie: Movie) -> Result<(), StateMismatch> {
+        let actual = self.movie_start_hash();
+        if actual != movie.start_hash {
+            return Err(StateMismatch {
+                expected: movie.start_hash,
+                actual,
+            });
+        }
+        self.movie = Some(MovieState::Playing {
+            movie,
+            start_frame: self.frame_count,
+        });
+        self.play_movie_frame();
+        Ok(())
+    }
+
+    /// The movie being recorded or played
+    pub fn movie(&self) -> Option<&Movie> {
+        match &self.movie {
+            Some(MovieState::Recording { movie, .. } | MovieState::Playing { movie, .. }) => {
+                Some(movie)
+            }
+            None => None,
+        }
+    }
+
+    /// Turn the movie being played into a recording from the current frame on, dropping its later frames, e.g. to
+    /// redo the end of a movie. Counts as a re-record.
+    pub fn resume_movie_recording(&mut self) {
+        if let Some(MovieState::Playing { movie, start_frame }) = self.movie.take() {
+            self.movie = Some(MovieState::Recording { movie, start_frame });
+            self.rerecord_movie();
+        }
+    }
+
+    pub fn playing_movie(&self) -> bool {
+        matches!(self.movie, Some(MovieState::Playing { .. }))
+    }
+
+    /// Stop recording or playing a movie. The buttons that the movie pressed stay pressed.
+    pub fn stop_movie(&mut self) {
+        self.movie = None;
+    }
+
+    /// Called at the end of every frame, before held buttons are released.
+    pub(crate) fn record_movie_frame(&mut self) {
+        if let Some(MovieState::Recording { movie, .. }) = &mut self.movie {
+            movie.frames.push(self.cpu.mmu.pressed_buttons);
+        }
+    }
+
+    /// Press the buttons of the movie that is playing for the frame that is starting. Called when playing starts, and
+    /// at the end of every frame, after held buttons are released.
+    pub(crate) fn play_movie_frame(&mut self) {
+        let Some(MovieState::Playing { movie, start_frame }) = &self.movie else {
+            return;
+        };
+        let pressed = self
+            .frame_count
+            .checked_sub(*start_frame)
+            .and_then(|frame| movie.frames.get(frame as usize));
+        match pressed {
+            Some(&pressed) => self.press_buttons(pressed),
+            None => {
+                self.movie = None;
+                self.events.push(Event::MovieEnded);
+            }
+        }
+    }
+
+    /// Called after the state was replaced by another one, e.g. an earlier save state. Frames recorded after that
+    /// state belong to a future that no longer exists.
+    pub(crate) fn rerecord_movie(&mut self) {
+        if self.movie.is_some() {
+            // the restored state may be from before the movie switched the clock
+            self.cpu
+                .mmu
+                .set_rtc_clock_source(RtcClockSource::EmulatedTime);
+        }
+        let rerecords = match &mut self.movie {
+            Some(MovieState::Recording { movie, start_frame }) => {
+                movie.rerecords += 1;
+                match self.frame_count.checked_sub(*start_frame) {
+                    Some(recorded) if recorded as usize <= movie.frames.len() => {
+                        movie.frames.truncate(recorded as usize);
+                        return;
+                    }
+                    _ => movie.rerecords,
+                }
+            }
+            Some(MovieState::Playing { .. }) => {
+                self.play_movie_frame();
+                return;
+            }
+            None => return,
+        };
+        // the recording doesn't lead to the state, e.g. because it's from before the recording started
+        self.start_movie_recording_with(rerecords);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use enumset::EnumSet;
+
+    use crate::cartridge::header_checksum;
+    use crate::joypad::Button;
+    use crate::util::{emulator_running, with_large_stack};
+    use crate::{Emulator, Event, Movie};
+
+    fn joypad_reader() -> Emulator {
+        emulator_running(&[
+            0x3E, 0x20, // LD A, 0x20
+            0xE0, 0x00, // LDH (0x00), A: select the d-pad
+            0xF0, 0x00, // LDH A, (0x00)
+            0xEA, 0x00, 0xC0, // LD (0xC000), A
+            0xC3, 0x00, 0x00, // JP 0x0000
+        ])
+    }
+
+    #[test]
+    fn replays_the_recorded_buttons() {
+        with_large_stack(|| {
+            let inputs = [
+                EnumSet::only(Button::Up),
+                EnumSet::empty(),
+                Button::Down | Button::A,
+            ];
+            let mut recorded = joypad_reader();
+            recorded.set_pressed_buttons(EnumSet::only(Button::Left));
+            recorded.start_movie_recording();
+            let mut joypad_values = Vec::new();
+            for pressed in inputs {
+                recorded.set_pressed_buttons(pressed);
+                recorded.run_frame().unwrap();
+                joypad_values.push(recorded.read_memory(0xC000));
+            }
+            let movie = recorded.finish_movie_recording().unwrap();
+            assert_eq!(movie.frames, inputs);
+            assert_eq!(recorded.finish_movie_recording(), None);
+
+            let mut replayed = joypad_reader();
+            replayed.play_movie(movie.clone()).unwrap();
+            assert!(replayed.playing_movie());
+            for &joypad_value in &joypad_values {
+                // the movie overrides the frontend's buttons
+                replayed.set_pressed_buttons(EnumSet::only(Button::Right));
+                replayed.run_frame().unwrap();
+                assert_eq!(replayed.read_memory(0xC000), joypad_value);
+            }
+            assert_eq!(replayed.take_events(), [Event::MovieEnded]);
+            assert!(!replayed.playing_movie());
+            assert_eq!(replayed.state_hash(), recorded.state_hash());
+
+            // the movie can't be played from another state
+            let mut later = joypad_reader();
+            later.run_frame().unwrap();
+            assert!(later.play_movie(movie).is_err());
+        });
+    }
+
+    #[test]
+    fn replays_a_game_with_a_real_time_clock() {
+        with_large_stack(|| {
+            let mut rom = vec![0; 0x8000];
+            // MBC3+TIMER+RAM+BATTERY
+            rom[0x0147] = 0x10;
+            let program = [
+                0x3E, 0x0A, // LD A, 0x0A
+                0xEA, 0x00, 0x00, // LD (0x0000), A: enable the clock
+                0x3E, 0x08, // LD A, 0x08
+                0xEA, 0x00, 0x40, // LD (0x4000), A: select the seconds
+                0xAF, // XOR A
+                0xEA, 0x00, 0x60, // LD (0x6000), A
+                0x3C, // INC A
+                0xEA, 0x00, 0x60, // LD (0x6000), A: latch the clock
+                0xFA, 0x00, 0xA0, // LD A, (0xA000)
+                0xEA, 0x00, 0xC0, // LD (0xC000), A
+                0xC3, 0x0A, 0x01, // JP 0x010A
+            ];
+            rom[0x100..0x100 + program.len()].copy_from_slice(&program);
+            rom[0x014D] = header_checksum(&rom);
+            let emulator = || Emulator::for_rom(&rom, Path::new("rtc-movie.gb"), None).unwrap();
+
+            let mut recorded = emulator();
+            recorded.start_movie_recording();
+            for _ in 0..70 {
+                recorded.run_frame().unwrap();
+            }
+            let movie = recorded.finish_movie_recording().unwrap();
+            // the clock counted the second that the frames took, however long running them took
+            assert_eq!(recorded.read_memory(0xC000), 1);
+
+            let mut replayed = emulator();
+            replayed.play_movie(movie).unwrap();
+            for _ in 0..70 {
+                replayed.run_frame().unwrap();
+            }
+            assert_eq!(replayed.read_memory(0xC000), 1);
+            assert_eq!(replayed.state_hash(), recorded.state_hash());
+        });
+    }
+
+    #[test]
+    fn rerecords_from_a_save_state() {
+        with_large_stack(|| {
+            let mut recorded = joypad_reader();
+            recorded.start_movie_recording();
+            recorded.set_pressed_buttons(EnumSet::only(Button::Up));
+            recorded.run_frame().unwrap();
+            let state = recorded.save_state().unwrap();
+            recorded.set_pressed_buttons(EnumSet::only(Button::A));
+            recorded.run_frame().unwrap();
+            recorded.run_frame().unwrap();
+
+            recorded.load_state(&state).unwrap();
+            assert_eq!(
+                recorded.movie().unwrap().frames,
+                [EnumSet::only(Button::Up)]
+            );
+            recorded.set_pressed_buttons(EnumSet::only(Button::Down));
+            recorded.run_frame().unwrap();
+            let movie = recorded.finish_movie_recording().unwrap();
+            assert_eq!(movie.frames, [Button::Up, Button::Down].map(EnumSet::only));
+            assert_eq!(movie.rerecords, 1);
+
+            let mut replayed = joypad_reader();
+            replayed.play_movie(movie).unwrap();
+            replayed.run_frame().unwrap();
+            replayed.run_frame().unwrap();
+            assert_eq!(replayed.state_hash(), recorded.state_hash());
+        });
+    }
+
+    #[test]
+    fn movie_file_format() {
+        let movie = Movie {
+            start_hash: 0x0123456789ABCDEF,
+            frames: vec![
+                EnumSet::empty(),
+                EnumSet::empty(),
+                EnumSet::empty(),
+                Button::Up | Button::A,
+                Button::Select | Button::Start,
+            ],
+            rerecords: 7,
+        };
+        let text = movie.to_string();
+        assert!(text.starts_with("start-hash 0123456789ABCDEF\nrerecords 7\n"));
+        assert!(text.ends_with("3 ........\nU......A\n....sS..\n"));
+        assert_eq!(text.parse(), Ok(movie));
+
+        assert!("rerecords 1\n".parse::<Movie>().is_err());
+        assert_eq!(
+            "start-hash 1\n........\nA.......\n".parse::<Movie>(),
+            Err("invalid movie line 3: \"A.......\"".to_string())
+        );
+    }
+}
@@ -0,0 +1,109 @@
+//! Opt-in counts of the opcodes executed, for finding out which instructions real games spend their time on.
+use crate::Emulator;
+
+/// An opcode, or the second byte of an instruction with the 0xCB prefix
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Opcode {
+    Base(u8),
+    Cb(u8),
+}
+
+/// The number of times each opcode has been executed, filled by the CPU as it fetches opcodes.
+///
+/// Instructions with the 0xCB prefix count as both a `Base(0xCB)` and a `Cb` opcode.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpcodeStats {
+    base: [u64; 256],
+    cb: [u64; 256],
+}
+
+impl Default for OpcodeStats {
+    fn default() -> Self {
+        OpcodeStats {
+            base: [0; 256],
+            cb: [0; 256],
+        }
+    }
+}
+
+impl OpcodeStats {
+    pub(crate) fn record(&mut self, opcode: Opcode) {
+        match opcode {
+            Opcode::Base(opcode) => self.base[opcode as usize] += 1,
+            Opcode::Cb(opcode) => self.cb[opcode as usize] += 1,
+        }
+    }
+
+    pub fn count(&self, opcode: Opcode) -> u64 {
+        match opcode {
+            Opcode::Base(opcode) => self.base[opcode as usize],
+            Opcode::Cb(opcode) => self.cb[opcode as usize],
+        }
+    }
+
+    /// The number of instructions executed, counting prefixed instructions once
+    pub fn total(&self) -> u64 {
+        self.base.iter().sum()
+    }
+
+    /// The opcodes that were executed at least once, most frequent first
+    pub fn most_frequent(&self) -> Vec<(Opcode, u64)> {
+        let base = (0..=0xFF).map(|opcode| (Opcode::Base(opcode), self.base[opcode as usize]));
+        let cb = (0..=0xFF).map(|opcode| (Opcode::Cb(opcode), self.cb[opcode as usize]));
+        let mut counts: Vec<(Opcode, u64)> =
+            base.chain(cb).filter(|&(_, count)| count > 0).collect();
+        counts.sort_by_key(|&(opcode, count)| (std::cmp::Reverse(count), opcode));
+        counts
+    }
+}
+
+impl Emulator {
+    /// Start counting the opcodes executed, see [`Emulator::opcode_stats`]. Restarts the counts if they were already
+    /// enabled.
+    pub fn enable_opcode_stats(&mut self) {
+        self.cpu.opcode_stats = Some(Box::default());
+    }
+
+    pub fn disable_opcode_stats(&mut self) {
+        self.cpu.opcode_stats = None;
+    }
+
+    /// The counts of the opcodes executed since [`Emulator::enable_opcode_stats`], or `None` if they aren't enabled.
+    pub fn opcode_stats(&self) -> Option<&OpcodeStats> {
+        self.cpu.opcode_stats.as_deref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Opcode;
+    use crate::util::emulator_running;
+
+    #[test]
+    fn counts_base_and_prefixed_opcodes() {
+        let mut emu = emulator_running(&[
+            0x3C, // INC A
+            0xCB, 0x37, // SWAP A
+            0x3C, // INC A
+            0x18, 0xFA, // JR -6
+        ]);
+        assert!(emu.opcode_stats().is_none());
+        emu.enable_opcode_stats();
+        for _ in 0..8 {
+            emu.step().unwrap();
+        }
+
+        let stats = emu.opcode_stats().unwrap();
+        assert_eq!(stats.total(), 8);
+        assert_eq!(
+            stats.most_frequent(),
+            [
+                (Opcode::Base(0x3C), 4),
+                (Opcode::Base(0x18), 2),
+                (Opcode::Base(0xCB), 2),
+                (Opcode::Cb(0x37), 2),
+            ]
+        );
+        assert_eq!(stats.count(Opcode::Cb(0x00)), 0);
+    }
+}
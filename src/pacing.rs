@@ -0,0 +1,183 @@
+//! Frame pacing for frontends.
+//!
+//! A frontend runs one or more emulated frames per host frame (more than one when fast-forwarding), then asks the
+//! [`FramePacer`] how long to sleep so that host frames are presented at a steady rate.
+use std::time::{Duration, Instant};
+
+use crate::{apu::T_CYCLES_PER_SECOND, T_CYCLES_PER_FRAME};
+
+/// How long real hardware takes to draw a frame, about 16.74 ms. The LCD refreshes at about 59.73 Hz, not 60 Hz.
+pub const FRAME_DURATION: Duration =
+    Duration::from_nanos(T_CYCLES_PER_FRAME as u64 * 1_000_000_000 / T_CYCLES_PER_SECOND as u64);
+
+/// How host frames are timed relative to real hardware.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RefreshMode {
+    /// Present a frame every [`FRAME_DURATION`], so emulation runs at exactly real hardware speed. On a 60 Hz display,
+    /// a frame is shown twice about every 4 seconds.
+    Exact,
+    /// Present frames at the host display's refresh rate, in Hz, so every frame is shown exactly once. Emulation, and
+    /// the pitch of its audio, runs slightly off real hardware speed, e.g. 0.46% fast on a 60 Hz display.
+    MatchHost(f64),
+}
+
+impl RefreshMode {
+    pub fn host_frame_duration(self) -> Duration {
+        match self {
+            RefreshMode::Exact => FRAME_DURATION,
+            RefreshMode::MatchHost(refresh_rate) => Duration::from_secs_f64(1.0 / refresh_rate),
+        }
+    }
+}
+
+/// How often the pacing stats are recalculated
+const STATS_WINDOW: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PacingStats {
+    /// Emulated frames per second
+    pub emulated_fps: f64,
+    /// Host frames (i.e. presented frames) per second
+    pub host_fps: f64,
+    /// Emulation speed relative to real hardware, e.g. 4.0 when fast-forwarding at 4x
+    pub speed: f64,
+}
+
+pub struct FramePacer {
+    frame_duration: Duration,
+    /// How long an emulated frame takes on real hardware, for calculating the emulation speed
+    hardware_frame_duration: Duration,
+    /// When the current host frame should end
+    deadline: Option<Instant>,
+    window_start: Option<Instant>,
+    window_emulated_frames: u64,
+    window_host_frames: u64,
+    updated_stats: Option<PacingStats>,
+}
+
+impl FramePacer {
+    /// Create a pacer that presents one host frame every `frame_duration`, which is treated as the duration of a frame
+    /// on real hardware.
+    pub fn new(frame_duration: Duration) -> Self {
+        FramePacer {
+            frame_duration,
+            hardware_frame_duration: frame_duration,
+            deadline: None,
+            window_start: None,
+            window_emulated_frames: 0,
+            window_host_frames: 0,
+            updated_stats: None,
+        }
+    }
+
+    /// Create a pacer that presents host frames at the rate chosen by `mode`.
+    pub fn for_refresh_mode(mode: RefreshMode) -> Self {
+        FramePacer {
+            hardware_frame_duration: FRAME_DURATION,
+            ..FramePacer::new(mode.host_frame_duration())
+        }
+    }
+
+    /// Record that a host frame which ran `emulated_frames` frames has ended at `now`.
+    ///
+    /// Returns how long to sleep before starting the next host frame. If the host has fallen more than a frame
+    /// behind, the schedule is reset instead of running faster than real time to catch up.
+    pub fn end_host_frame(&mut self, emulated_frames: u32, now: Instant) -> Duration {
+        self.record_stats(emulated_frames, now);
+        let deadline = match self.deadline {
+            Some(deadline) if now <= deadline + self.frame_duration => deadline,
+            _ => now,
+        };
+        self.deadline = Some(deadline + self.frame_duration);
+        deadline.saturating_duration_since(now)
+    }
+
+    /// Like [`FramePacer::end_host_frame`], for frontends that don't sleep between frames.
+    pub fn end_unthrottled_host_frame(&mut self, emulated_frames: u32, now: Instant) {
+        self.record_stats(emulated_frames, now);
+        self.deadline = None;
+    }
+
+    fn record_stats(&mut self, emulated_frames: u32, now: Instant) {
+        let window_start = *self.window_start.get_or_insert(now);
+        self.window_emulated_frames += emulated_frames as u64;
+        self.window_host_frames += 1;
+        let elapsed = now.saturating_duration_since(window_start);
+        if elapsed >= STATS_WINDOW {
+            let elapsed = elapsed.as_secs_f64();
+            let emulated_fps = self.window_emulated_frames as f64 / elapsed;
+            self.updated_stats = Some(PacingStats {
+                emulated_fps,
+                host_fps: self.window_host_frames as f64 / elapsed,
+                speed: emulated_fps * self.hardware_frame_duration.as_secs_f64(),
+            });
+            self.window_start = Some(now);
+            self.window_emulated_frames = 0;
+            self.window_host_frames = 0;
+        }
+    }
+
+    /// Returns the stats once each time they are recalculated, about once per second.
+    pub fn take_updated_stats(&mut self) -> Option<PacingStats> {
+        self.updated_stats.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{FramePacer, RefreshMode, FRAME_DURATION};
+
+    #[test]
+    fn sleeps_until_deadline() {
+        let frame = Duration::from_millis(10);
+        let mut pacer = FramePacer::new(frame);
+        let start = Instant::now();
+        assert_eq!(pacer.end_host_frame(1, start), Duration::ZERO);
+        let now = start + Duration::from_millis(4);
+        assert_eq!(pacer.end_host_frame(1, now), Duration::from_millis(6));
+        // slightly late: don't sleep, but keep the schedule
+        let now = start + Duration::from_millis(23);
+        assert_eq!(pacer.end_host_frame(1, now), Duration::ZERO);
+        let now = start + Duration::from_millis(25);
+        assert_eq!(pacer.end_host_frame(1, now), Duration::from_millis(5));
+        // far behind: resync to now
+        let now = start + Duration::from_millis(100);
+        assert_eq!(pacer.end_host_frame(1, now), Duration::ZERO);
+        let now = start + Duration::from_millis(101);
+        assert_eq!(pacer.end_host_frame(1, now), Duration::from_millis(9));
+    }
+
+    #[test]
+    fn stats_count_emulated_frames() {
+        let frame = Duration::from_millis(10);
+        let mut pacer = FramePacer::new(frame);
+        let start = Instant::now();
+        for host_frame in 0..=100 {
+            pacer.end_host_frame(4, start + frame * host_frame);
+        }
+        let stats = pacer.take_updated_stats().unwrap();
+        assert!((stats.host_fps - 101.0).abs() < 1e-6);
+        assert!((stats.emulated_fps - 404.0).abs() < 1e-6);
+        assert!((stats.speed - 4.04).abs() < 1e-6);
+        assert_eq!(pacer.take_updated_stats(), None);
+    }
+
+    #[test]
+    fn matching_host_refresh() {
+        assert_eq!(FRAME_DURATION, Duration::from_nanos(16_742_706));
+        let mode = RefreshMode::MatchHost(50.0);
+        let host_frame = mode.host_frame_duration();
+        assert_eq!(host_frame, Duration::from_millis(20));
+        let mut pacer = FramePacer::for_refresh_mode(mode);
+        let start = Instant::now();
+        for host_frame_idx in 0..=50 {
+            pacer.end_host_frame(1, start + host_frame * host_frame_idx);
+        }
+        let stats = pacer.take_updated_stats().unwrap();
+        assert!((stats.emulated_fps - 51.0).abs() < 1e-6);
+        // speed is relative to real hardware, not to the host's refresh rate
+        assert!((stats.speed - 51.0 * 0.016_742_706).abs() < 1e-6);
+    }
+}
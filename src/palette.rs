@@ -0,0 +1,452 @@
+//! The RGB colors of DMG shades, including the colorization the CGB boot ROM applies to DMG-only games.
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::ppu::{Color, Rgb555};
+
+/// The RGB colors that the shades of BGP, OBP0, and OBP1 are displayed as.
+///
+/// On a CGB running a DMG-only ROM, the boot ROM picks these based on the cartridge header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DmgPalette {
+    pub bg: [Rgb555; 4],
+    pub obj: [[Rgb555; 4]; 2],
+}
+
+impl DmgPalette {
+    /// Evenly spaced grays, used on the DMG
+    pub const GRAYSCALE: DmgPalette = DmgPalette::uniform([
+        Rgb555(0x7FFF),
+        Rgb555(0x56B5),
+        Rgb555(0x294A),
+        Rgb555(0x0000),
+    ]);
+
+    /// Every layer in the same 8-bit colors, lightest first, like a [`ShadePalette`]
+    pub fn from_rgb8(colors: ShadePalette) -> Self {
+        DmgPalette::uniform(colors.map(|[r, g, b]| rgb(u32::from_be_bytes([0, r, g, b]))))
+    }
+
+    const fn uniform(colors: [Rgb555; 4]) -> Self {
+        DmgPalette {
+            bg: colors,
+            obj: [colors, colors],
+        }
+    }
+
+    pub fn bg_color(&self, shade: Color) -> Rgb555 {
+        self.bg[shade as usize]
+    }
+
+    /// * `palette` - 0 for OBP0, 1 for OBP1
+    pub fn obj_color(&self, palette: usize, shade: Color) -> Rgb555 {
+        self.obj[palette][shade as usize]
+    }
+
+    /// The palette that the CGB boot ROM picks for a DMG-only ROM.
+    ///
+    /// Games published by Nintendo are looked up by the checksum of their title, and the 4th letter of the title when
+    /// the checksum is ambiguous. Every other game gets `DEFAULT`.
+    ///
+    /// https://gbdev.io/pandocs/Power_Up_Sequence.html#compatibility-palettes
+    pub fn cgb_compat_for_rom(rom: &[u8]) -> Self {
+        let old_licensee = rom[0x014B];
+        let nintendo =
+            old_licensee == 0x01 || (old_licensee == 0x33 && &rom[0x0144..=0x0145] == b"01");
+        if !nintendo {
+            return compat::DEFAULT;
+        }
+        let checksum = rom[0x0134..=0x0143]
+            .iter()
+            .fold(0u8, |acc, &byte| acc.wrapping_add(byte));
+        let fourth_letter = rom[0x0137];
+        compat::BY_TITLE_CHECKSUM
+            .iter()
+            .find(|(entry_checksum, entry_letter, _)| {
+                *entry_checksum == checksum
+                    && entry_letter.is_none_or(|letter| letter == fourth_letter)
+            })
+            .map_or(compat::DEFAULT, |&(_, _, index)| compat::combination(index))
+    }
+}
+
+impl Default for DmgPalette {
+    fn default() -> Self {
+        DmgPalette::GRAYSCALE
+    }
+}
+
+/// The 8-bit RGB colors that the 4 shades are displayed as, lightest first. See [`crate::Emulator::set_palette`].
+pub type ShadePalette = [[u8; 3]; 4];
+
+/// Named shade palettes for [`crate::Emulator::set_palette`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Preset {
+    /// The green tint of the original Game Boy's LCD
+    #[default]
+    Green,
+    /// The Game Boy Pocket's grayish LCD
+    Pocket,
+    /// Evenly spaced grays
+    Grayscale,
+}
+
+impl Preset {
+    pub const fn colors(self) -> ShadePalette {
+        match self {
+            Preset::Green => [[224, 248, 208], [136, 192, 112], [52, 104, 86], [8, 24, 32]],
+            Preset::Pocket => [[196, 207, 161], [139, 149, 109], [77, 83, 60], [31, 31, 31]],
+            Preset::Grayscale => [[255, 255, 255], [170, 170, 170], [85, 85, 85], [0, 0, 0]],
+        }
+    }
+}
+
+impl FromStr for Preset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "green" => Ok(Preset::Green),
+            "pocket" => Ok(Preset::Pocket),
+            "grayscale" | "greyscale" => Ok(Preset::Grayscale),
+            _ => Err(format!(
+                "unknown palette {s:?}, expected green, pocket, or grayscale"
+            )),
+        }
+    }
+}
+
+pub(crate) fn default_shade_palette() -> ShadePalette {
+    Preset::default().colors()
+}
+
+/// Convert a 24-bit `0xRRGGBB` color, as palettes are usually documented, to the nearest darker `Rgb555`
+const fn rgb(hex: u32) -> Rgb555 {
+    let r = (hex >> 19) & 0x1F;
+    let g = (hex >> 11) & 0x1F;
+    let b = (hex >> 3) & 0x1F;
+    Rgb555((r | (g << 5) | (b << 10)) as u16)
+}
+
+/// The CGB boot ROM's compatibility palettes. Players can also pick these by holding a button combination during boot.
+pub mod compat {
+    use super::DmgPalette;
+    use crate::ppu::Rgb555;
+
+    /// The boot ROM's 4-color palettes, lightest first, in RGB555
+    const PALETTES: [[u16; 4]; 30] = [
+        [0x7FFF, 0x32BF, 0x00D0, 0x0000],
+        [0x639F, 0x4279, 0x15B0, 0x04CB],
+        [0x7FFF, 0x6E31, 0x454A, 0x0000],
+        [0x7FFF, 0x1BEF, 0x0200, 0x0000],
+        [0x7FFF, 0x421F, 0x1CF2, 0x0000],
+        [0x7FFF, 0x5294, 0x294A, 0x0000],
+        [0x7FFF, 0x03FF, 0x012F, 0x0000],
+        [0x7FFF, 0x03EF, 0x01D6, 0x0000],
+        [0x7FFF, 0x42B5, 0x3DC8, 0x0000],
+        [0x7E74, 0x03FF, 0x0180, 0x0000],
+        [0x67FF, 0x77AC, 0x1A13, 0x2D6B],
+        [0x7ED6, 0x4BFF, 0x2175, 0x0000],
+        [0x53FF, 0x4A5F, 0x7E52, 0x0000],
+        [0x4FFF, 0x7ED2, 0x3A4C, 0x1CE0],
+        [0x03ED, 0x7FFF, 0x255F, 0x0000],
+        [0x036A, 0x021F, 0x03FF, 0x7FFF],
+        [0x7FFF, 0x01DF, 0x0112, 0x0000],
+        [0x231F, 0x035F, 0x00F2, 0x0009],
+        [0x7FFF, 0x03EA, 0x011F, 0x0000],
+        [0x299F, 0x001A, 0x000C, 0x0000],
+        [0x7FFF, 0x027F, 0x001F, 0x0000],
+        [0x7FFF, 0x03E0, 0x0206, 0x0120],
+        [0x7FFF, 0x7EEB, 0x001F, 0x7C00],
+        [0x7FFF, 0x3FFF, 0x7E00, 0x001F],
+        [0x7FFF, 0x03FF, 0x001F, 0x0000],
+        [0x03FF, 0x001F, 0x000C, 0x0000],
+        [0x7FFF, 0x033F, 0x0193, 0x0000],
+        [0x0000, 0x4200, 0x037F, 0x7FFF],
+        [0x7FFF, 0x7E8C, 0x7C00, 0x0000],
+        [0x7FFF, 0x1BEF, 0x6180, 0x0000],
+    ];
+
+    /// The offset of a palette in the colors of [`PALETTES`], one after the other
+    const fn palette(index: usize) -> usize {
+        index * 4
+    }
+
+    /// The colors of OBP0, OBP1, and BGP, as offsets into the colors of [`PALETTES`]. A few start at the last color of
+    /// one palette and continue into the next, which the boot ROM does as well.
+    const COMBINATIONS: [[usize; 3]; 51] = [
+        [palette(4), palette(4), palette(29)],         // 0: Right + A
+        [palette(18), palette(18), palette(18)],       // 1: Right
+        [palette(20), palette(20), palette(20)],       // 2
+        [palette(24), palette(24), palette(24)],       // 3: Down + A
+        [palette(9), palette(9), palette(9)],          // 4
+        [palette(0), palette(0), palette(0)],          // 5: Up
+        [palette(27), palette(27), palette(27)],       // 6: Right + B
+        [palette(5), palette(5), palette(5)],          // 7: Left + B
+        [palette(12), palette(12), palette(12)],       // 8: Down
+        [palette(26), palette(26), palette(26)],       // 9
+        [palette(16), palette(8), palette(8)],         // 10
+        [palette(4), palette(28), palette(28)],        // 11
+        [palette(4), palette(2), palette(2)],          // 12
+        [palette(3), palette(4), palette(4)],          // 13
+        [palette(4), palette(29), palette(29)],        // 14
+        [palette(28), palette(4), palette(28)],        // 15
+        [palette(2), palette(17), palette(2)],         // 16
+        [palette(16), palette(16), palette(8)],        // 17
+        [palette(4), palette(4), palette(7)],          // 18
+        [palette(4), palette(4), palette(18)],         // 19
+        [palette(4), palette(4), palette(20)],         // 20
+        [palette(19), palette(19), palette(9)],        // 21
+        [palette(4) - 1, palette(4) - 1, palette(11)], // 22
+        [palette(17), palette(17), palette(2)],        // 23
+        [palette(4), palette(4), palette(2)],          // 24
+        [palette(4), palette(4), palette(3)],          // 25
+        [palette(28), palette(28), palette(0)],        // 26
+        [palette(3), palette(3), palette(0)],          // 27
+        [palette(0), palette(0), palette(1)],          // 28: Up + B
+        [palette(18), palette(22), palette(18)],       // 29
+        [palette(20), palette(22), palette(20)],       // 30
+        [palette(24), palette(22), palette(24)],       // 31
+        [palette(16), palette(22), palette(8)],        // 32
+        [palette(17), palette(4), palette(13)],        // 33
+        [palette(28) - 1, palette(0), palette(14)],    // 34
+        [palette(28) - 1, palette(4), palette(15)],    // 35
+        [palette(19), palette(23) - 1, palette(9)],    // 36
+        [palette(16), palette(28), palette(10)],       // 37
+        [palette(4), palette(23), palette(28)],        // 38
+        [palette(17), palette(22), palette(2)],        // 39
+        [palette(4), palette(0), palette(2)],          // 40: Left + A
+        [palette(4), palette(28), palette(3)],         // 41
+        [palette(28), palette(3), palette(0)],         // 42
+        [palette(3), palette(28), palette(4)],         // 43: Up + A
+        [palette(21), palette(28), palette(4)],        // 44
+        [palette(3), palette(28), palette(0)],         // 45
+        [palette(25), palette(3), palette(28)],        // 46
+        [palette(0), palette(28), palette(8)],         // 47
+        [palette(4), palette(3), palette(28)],         // 48: Left
+        [palette(28), palette(3), palette(6)],         // 49: Down + B
+        [palette(4), palette(28), palette(29)],        // 50
+    ];
+
+    const fn color(offset: usize) -> Rgb555 {
+        Rgb555(PALETTES[offset / 4][offset % 4])
+    }
+
+    const fn colors_at(offset: usize) -> [Rgb555; 4] {
+        [
+            color(offset),
+            color(offset + 1),
+            color(offset + 2),
+            color(offset + 3),
+        ]
+    }
+
+    /// Palette combination `index` of the boot ROM
+    pub(super) const fn combination(index: usize) -> DmgPalette {
+        let [obj0, obj1, bg] = COMBINATIONS[index];
+        DmgPalette {
+            bg: colors_at(bg),
+            obj: [colors_at(obj0), colors_at(obj1)],
+        }
+    }
+
+    /// Up
+    pub const BROWN: DmgPalette = combination(5);
+    /// Up + A
+    pub const RED_GREEN_BLUE: DmgPalette = combination(43);
+    /// Up + B
+    pub const DARK_BROWN: DmgPalette = combination(28);
+    /// Left
+    pub const BLUE_RED_GREEN: DmgPalette = combination(48);
+    /// Left + A
+    pub const DARK_BLUE: DmgPalette = combination(40);
+    /// Left + B
+    pub const GRAY: DmgPalette = combination(7);
+    /// Down
+    pub const PALE_YELLOW: DmgPalette = combination(8);
+    /// Down + A
+    pub const ORANGE: DmgPalette = combination(3);
+    /// Down + B
+    pub const YELLOW: DmgPalette = combination(49);
+    /// Right
+    pub const LIME: DmgPalette = combination(1);
+    /// Right + A, and the palette for games that weren't published by Nintendo
+    pub const DEFAULT: DmgPalette = combination(0);
+    /// Right + B
+    pub const INVERTED: DmgPalette = combination(6);
+
+    /// (title checksum, 4th letter of the title, palette combination), in the order the boot ROM searches them. Titles
+    /// without an entry get [`DEFAULT`].
+    pub(super) const BY_TITLE_CHECKSUM: &[(u8, Option<u8>, usize)] = &[
+        (0x88, None, 4),  // ALLEY WAY
+        (0x16, None, 5),  // YAKUMAN
+        (0x36, None, 35), // BASEBALL, GAME&WATCH 2
+        (0xD1, None, 34), // TENNIS
+        (0xDB, None, 3),  // TETRIS
+        (0xF2, None, 31), // QIX
+        (0x3C, None, 15), // DR.MARIO
+        (0x8C, None, 10), // RADARMISSION
+        (0x92, None, 5),  // F1RACE
+        (0x3D, None, 19), // YOSSY NO TAMAGO
+        (0x5C, None, 36),
+        (0x58, None, 7),  // X
+        (0xC9, None, 37), // MARIOLAND2
+        (0x3E, None, 30), // YOSSY NO COOKIE
+        (0x70, None, 44), // ZELDA
+        (0x1D, None, 21),
+        (0x59, None, 32),
+        (0x69, None, 31), // TETRIS FLASH
+        (0x19, None, 20), // DONKEY KONG
+        (0x35, None, 5),  // MARIO'S PICROSS
+        (0xA8, None, 33),
+        (0x14, None, 13), // POKEMON RED, GAMEBOYCAMERA G
+        (0xAA, None, 14), // POKEMON GREEN
+        (0x75, None, 5),  // PICROSS 2
+        (0x95, None, 29), // YOSSY NO PANEPON
+        (0x99, None, 5),  // KIRAKIRA KIDS
+        (0x34, None, 18), // GAMEBOY GALLERY
+        (0x6F, None, 9),  // POCKETCAMERA
+        (0x15, None, 3),
+        (0xFF, None, 2),  // BALLOON KID
+        (0x97, None, 26), // KINGOFTHEZOO
+        (0x4B, None, 25), // DMG FOOTBALL
+        (0x90, None, 25), // WORLD CUP
+        (0x17, None, 41), // OTHELLO
+        (0x10, None, 42), // SUPER RC PRO-AM
+        (0x39, None, 26), // DYNABLASTER
+        (0xF7, None, 45), // BOY AND BLOB GB2
+        (0xF6, None, 42), // MEGAMAN
+        (0xA2, None, 45), // STAR WARS-NOA
+        (0x49, None, 36),
+        (0x4E, None, 38), // WAVERACE
+        (0x43, None, 26),
+        (0x68, None, 42), // LOLO2
+        (0xE0, None, 30), // YOSHI'S COOKIE
+        (0x8B, None, 41), // MYSTIC QUEST
+        (0xF0, None, 34),
+        (0xCE, None, 34), // TOPRANKINGTENNIS
+        (0x0C, None, 5),  // MANSELL
+        (0x29, None, 42), // MEGAMAN3
+        (0xE8, None, 6),  // SPACE INVADERS
+        (0xB7, None, 5),  // GAME&WATCH
+        (0x86, None, 33), // DONKEYKONGLAND95
+        (0x9A, None, 25), // ASTEROIDS/MISCMD
+        (0x52, None, 42), // STREET FIGHTER 2
+        (0x01, None, 42), // DEFENDER/JOUST
+        (0x9D, None, 40), // KILLERINSTINCT95
+        (0x71, None, 2),  // TETRIS BLAST
+        (0x9C, None, 16), // PINOCCHIO
+        (0xBD, None, 25),
+        (0x5D, None, 42), // BA.TOSHINDEN
+        (0x6D, None, 42), // NETTOU KOF 95
+        (0x67, None, 5),
+        (0x3F, None, 0),  // TETRIS PLUS
+        (0x6B, None, 39), // DONKEYKONGLAND 3
+        // these checksums are shared by several titles, so the 4th letter tells them apart
+        (0xB3, Some(b'B'), 36),
+        (0x46, Some(b'E'), 22), // SUPER MARIOLAND
+        (0x28, Some(b'F'), 25), // GOLF
+        (0xA5, Some(b'A'), 6),  // SOLARSTRIKER
+        (0xC6, Some(b'A'), 32), // GBWARS
+        (0xD3, Some(b'R'), 12), // KAERUNOTAMENI
+        (0x27, Some(b'B'), 36),
+        (0x61, Some(b'E'), 11), // POKEMON BLUE
+        (0x18, Some(b'K'), 39), // DONKEYKONGLAND
+        (0x66, Some(b'E'), 18), // GAMEBOY GALLERY2
+        (0x6A, Some(b'K'), 39), // DONKEYKONGLAND 2
+        (0xBF, Some(b' '), 24), // KID ICARUS
+        (0x0D, Some(b'R'), 31), // TETRIS2
+        (0xF4, Some(b'-'), 50),
+        (0xB3, Some(b'U'), 17), // MOGURANYA
+        (0x46, Some(b'R'), 46),
+        (0x28, Some(b'A'), 6),  // GALAGA&GALAXIAN
+        (0xA5, Some(b'R'), 27), // BT2RAGNAROKWORLD
+        (0xC6, Some(b' '), 0),  // KEN GRIFFEY JR
+        (0xD3, Some(b'I'), 47),
+        (0x27, Some(b'N'), 41), // MAGNETIC SOCCER
+        (0x61, Some(b'A'), 41), // VEGAS STAKES
+        (0x18, Some(b'I'), 0),
+        (0x66, Some(b'L'), 0),  // MILLI/CENTI/PEDE
+        (0x6A, Some(b'I'), 19), // MARIO & YOSHI
+        (0xBF, Some(b'C'), 34), // SOCCER
+        (0x0D, Some(b'E'), 23), // POKEBOM
+        (0xF4, Some(b' '), 18), // G&W GALLERY
+        (0xB3, Some(b'R'), 29), // TETRIS ATTACK
+    ];
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rom_with_header(title: &[u8], old_licensee: u8) -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0134..0x0134 + title.len()].copy_from_slice(title);
+        rom[0x014B] = old_licensee;
+        rom
+    }
+
+    #[test]
+    fn cgb_compat_palette_lookup() {
+        let red = DmgPalette::cgb_compat_for_rom(&rom_with_header(b"POKEMON RED", 0x01));
+        assert_eq!(red.bg_color(Color::LightGray), rgb(0xFF8484));
+        assert_eq!(red.obj_color(0, Color::LightGray), rgb(0x7BFF31));
+        assert_eq!(red.obj_color(1, Color::LightGray), rgb(0xFF8484));
+        assert_eq!(
+            DmgPalette::cgb_compat_for_rom(&rom_with_header(b"TETRIS", 0x01)),
+            compat::ORANGE
+        );
+        // Super Mario Land's object palettes start at the last color of the palette before them
+        let mario = DmgPalette::cgb_compat_for_rom(&rom_with_header(b"SUPER MARIOLAND", 0x01));
+        assert_eq!(mario.obj_color(0, Color::White), rgb(0x000000));
+        assert_eq!(mario.obj_color(0, Color::Black), rgb(0x943A3A));
+        // POKEMON BLUE and VEGAS STAKES have the same checksum, and differ in the 4th letter
+        let blue = DmgPalette::cgb_compat_for_rom(&rom_with_header(b"POKEMON BLUE", 0x01));
+        assert_eq!(blue.bg_color(Color::LightGray), rgb(0x63A5FF));
+        assert_eq!(blue.obj_color(0, Color::LightGray), rgb(0xFF8484));
+        let vegas = DmgPalette::cgb_compat_for_rom(&rom_with_header(b"VEGAS STAKES", 0x01));
+        assert_eq!(vegas.bg_color(Color::LightGray), rgb(0x7BFF31));
+        assert_eq!(
+            DmgPalette::cgb_compat_for_rom(&rom_with_header(b"POKFMON BLUD", 0x01)),
+            compat::DEFAULT,
+            "same checksum, but a 4th letter without an entry"
+        );
+        // only games published by Nintendo are looked up
+        assert_eq!(
+            DmgPalette::cgb_compat_for_rom(&rom_with_header(b"POKEMON RED", 0x02)),
+            compat::DEFAULT
+        );
+        let mut new_licensee = rom_with_header(b"POKEMON RED", 0x33);
+        new_licensee[0x0144..=0x0145].copy_from_slice(b"01");
+        assert_eq!(DmgPalette::cgb_compat_for_rom(&new_licensee), red);
+        assert_eq!(
+            DmgPalette::cgb_compat_for_rom(&rom_with_header(b"UNKNOWN", 0x01)),
+            compat::DEFAULT
+        );
+    }
+
+    #[test]
+    fn rgb_conversion() {
+        assert_eq!(rgb(0xFFFFFF), Rgb555(0x7FFF));
+        assert_eq!(rgb(0x7BFF31), Rgb555(0x1BEF));
+        assert_eq!(rgb(0x0063C5), Rgb555(0x6180));
+        assert_eq!(
+            DmgPalette::GRAYSCALE.bg_color(Color::LightGray),
+            Rgb555::from(Color::LightGray)
+        );
+    }
+
+    #[test]
+    fn presets() {
+        assert_eq!("Pocket".parse(), Ok(Preset::Pocket));
+        assert!("sepia".parse::<Preset>().is_err());
+        assert_eq!(
+            DmgPalette::from_rgb8(Preset::Grayscale.colors()),
+            DmgPalette::GRAYSCALE
+        );
+        let green = DmgPalette::from_rgb8(Preset::Green.colors());
+        assert_eq!(green.bg_color(Color::White), rgb(0xE0F8D0));
+        assert_eq!(green.obj_color(1, Color::Black), rgb(0x081820));
+    }
+}
@@ -0,0 +1,330 @@
+//! IPS and BPS patches, the formats that translations and ROM hacks are distributed in.
+//!
+//! IPS patches overwrite ranges of the ROM, while BPS patches (from byuu's beat patcher) describe the patched ROM as
+//! copies from the original, and check both with CRC32s.
+use flate2::Crc;
+
+/// The largest ROM a BPS patch can produce. The largest cartridges are 8 MiB.
+const MAX_TARGET_LEN: usize = 8 << 20;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PatchError {
+    /// The patch doesn't start with `PATCH` or `BPS1`
+    UnknownFormat,
+    /// The patch ends in the middle of a record, or doesn't match its own checksum
+    Corrupted,
+    /// A BPS patch reads or copies outside of the ROMs it describes
+    OutOfBounds,
+    /// The BPS patch was made for a different ROM
+    WrongSourceRom { expected_crc: u32, actual_crc: u32 },
+    /// The result doesn't match the checksum in the BPS patch
+    TargetChecksum,
+    /// The BPS patch produces a ROM larger than any cartridge
+    TargetTooLarge { len: usize },
+}
+
+impl std::fmt::Display for PatchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PatchError::UnknownFormat => write!(f, "Unknown patch format, expected IPS or BPS"),
+            PatchError::Corrupted => write!(f, "The patch is truncated or corrupted"),
+            PatchError::OutOfBounds => write!(f, "The patch accesses data outside of the ROM"),
+            PatchError::WrongSourceRom {
+                expected_crc,
+                actual_crc,
+            } => write!(
+                f,
+                "The patch is for a ROM with CRC32 {expected_crc:08X}, but this ROM's is {actual_crc:08X}"
+            ),
+            PatchError::TargetChecksum => {
+                write!(f, "The patched ROM doesn't match the patch's checksum")
+            }
+            PatchError::TargetTooLarge { len } => write!(
+                f,
+                "The patched ROM would be {len} bytes, more than the largest cartridge's {MAX_TARGET_LEN}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PatchError {}
+
+/// Apply an IPS or BPS `patch` to `rom`, picking the format from the patch's header.
+pub fn apply(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    if let Some(records) = patch.strip_prefix(b"PATCH") {
+        apply_ips(rom, records)
+    } else if let Some(body) = patch.strip_prefix(b"BPS1") {
+        apply_bps(rom, patch, body)
+    } else {
+        Err(PatchError::UnknownFormat)
+    }
+}
+
+/// Reads the fields of a patch in order.
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], PatchError> {
+        if self.data.len() < len {
+            return Err(PatchError::Corrupted);
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn big_endian(&mut self, len: usize) -> Result<usize, PatchError> {
+        Ok(self
+            .bytes(len)?
+            .iter()
+            .fold(0, |value, &byte| value << 8 | byte as usize))
+    }
+
+    /// BPS numbers: 7 bits per byte, least significant first, with the last byte marked by bit 7
+    fn varint(&mut self) -> Result<usize, PatchError> {
+        let mut value: usize = 0;
+        let mut shift: usize = 1;
+        loop {
+            let byte = self.bytes(1)?[0];
+            value = (byte as usize & 0x7F)
+                .checked_mul(shift)
+                .and_then(|bits| value.checked_add(bits))
+                .ok_or(PatchError::OutOfBounds)?;
+            if byte & 0x80 != 0 {
+                return Ok(value);
+            }
+            shift = shift.checked_shl(7).ok_or(PatchError::OutOfBounds)?;
+            value = value.checked_add(shift).ok_or(PatchError::OutOfBounds)?;
+        }
+    }
+}
+
+/// Records of a 3 byte offset and 2 byte length, followed by the data, or by a run length and a byte if the length
+/// is 0. After the `EOF` marker, there can be a 3 byte length to truncate the ROM to.
+fn apply_ips(rom: &[u8], records: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let mut rom = rom.to_vec();
+    let mut reader = Reader { data: records };
+    loop {
+        if reader.data.starts_with(b"EOF") && matches!(reader.data.len(), 3 | 6) {
+            reader.bytes(3)?;
+            if !reader.data.is_empty() {
+                rom.truncate(reader.big_endian(3)?);
+            }
+            return Ok(rom);
+        }
+        let offset = reader.big_endian(3)?;
+        let len = reader.big_endian(2)?;
+        let data = if len == 0 {
+            let run_len = reader.big_endian(2)?;
+            vec![reader.bytes(1)?[0]; run_len]
+        } else {
+            reader.bytes(len)?.to_vec()
+        };
+        if rom.len() < offset + data.len() {
+            rom.resize(offset + data.len(), 0);
+        }
+        rom[offset..offset + data.len()].copy_from_slice(&data);
+    }
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc::new();
+    crc.update(data);
+    crc.sum()
+}
+
+/// Actions that build the target ROM from the source ROM, from the patch itself, or from what was already written,
+/// followed by the CRC32s of the source, the target, and the patch.
+fn apply_bps(source: &[u8], patch: &[u8], body: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let (actions, footer) = body
+        .split_at_checked(body.len().saturating_sub(12))
+        .filter(|(_, footer)| footer.len() == 12)
+        .ok_or(PatchError::Corrupted)?;
+    let crc = |idx: usize| u32::from_le_bytes(footer[idx * 4..idx * 4 + 4].try_into().unwrap());
+    let actual_crc = crc32(source);
+    if crc(0) != actual_crc {
+        return Err(PatchError::WrongSourceRom {
+            expected_crc: crc(0),
+            actual_crc,
+        });
+    }
+    if crc(2) != crc32(&patch[..patch.len() - 4]) {
+        return Err(PatchError::Corrupted);
+    }
+
+    let mut reader = Reader { data: actions };
+    let source_len = reader.varint()?;
+    let target_len = reader.varint()?;
+    let metadata_len = reader.varint()?;
+    reader.bytes(metadata_len)?;
+    if source_len != source.len() {
+        return Err(PatchError::OutOfBounds);
+    }
+    if target_len > MAX_TARGET_LEN {
+        return Err(PatchError::TargetTooLarge { len: target_len });
+    }
+    let mut target = Vec::with_capacity(target_len);
+    let (mut source_offset, mut target_offset) = (0usize, 0usize);
+    // offsets are relative to the end of the last copy, with the sign in the lowest bit
+    let seek = |reader: &mut Reader, offset: usize| -> Result<usize, PatchError> {
+        let relative = reader.varint()?;
+        let distance = relative >> 1;
+        if relative & 1 == 0 {
+            offset.checked_add(distance)
+        } else {
+            offset.checked_sub(distance)
+        }
+        .ok_or(PatchError::OutOfBounds)
+    };
+    while !reader.data.is_empty() {
+        let action = reader.varint()?;
+        let len = (action >> 2) + 1;
+        let out = target.len();
+        if len > target_len - out {
+            return Err(PatchError::OutOfBounds);
+        }
+        match action & 3 {
+            // source read
+            0 => {
+                target.extend_from_slice(source.get(out..out + len).ok_or(PatchError::OutOfBounds)?)
+            }
+            // target read
+            1 => target.extend_from_slice(reader.bytes(len)?),
+            // source copy
+            2 => {
+                source_offset = seek(&mut reader, source_offset)?;
+                let end = source_offset
+                    .checked_add(len)
+                    .ok_or(PatchError::OutOfBounds)?;
+                target.extend_from_slice(
+                    source
+                        .get(source_offset..end)
+                        .ok_or(PatchError::OutOfBounds)?,
+                );
+                source_offset = end;
+            }
+            // target copy, one byte at a time because the copy can overlap what it writes
+            _ => {
+                target_offset = seek(&mut reader, target_offset)?;
+                for _ in 0..len {
+                    let byte = *target.get(target_offset).ok_or(PatchError::OutOfBounds)?;
+                    target.push(byte);
+                    target_offset = target_offset
+                        .checked_add(1)
+                        .ok_or(PatchError::OutOfBounds)?;
+                }
+            }
+        }
+    }
+    if target.len() != target_len || crc32(&target) != crc(1) {
+        return Err(PatchError::TargetChecksum);
+    }
+    Ok(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply, crc32, PatchError};
+
+    #[test]
+    fn ips_records_runs_and_truncation() {
+        let rom = [0u8; 8];
+        let mut patch = b"PATCH".to_vec();
+        // 2 bytes at 1
+        patch.extend_from_slice(&[0x00, 0x00, 0x01, 0x00, 0x02, 0xAA, 0xBB]);
+        // a run of 3 0xCC at 6, past the end of the ROM
+        patch.extend_from_slice(&[0x00, 0x00, 0x06, 0x00, 0x00, 0x00, 0x03, 0xCC]);
+        patch.extend_from_slice(b"EOF");
+        assert_eq!(
+            apply(&rom, &patch).unwrap(),
+            [0x00, 0xAA, 0xBB, 0x00, 0x00, 0x00, 0xCC, 0xCC, 0xCC]
+        );
+        patch.extend_from_slice(&[0x00, 0x00, 0x02]);
+        assert_eq!(apply(&rom, &patch).unwrap(), [0x00, 0xAA]);
+        assert_eq!(apply(&rom, b"PATCH\x00\x00"), Err(PatchError::Corrupted));
+        assert_eq!(apply(&rom, b"NOT A PATCH"), Err(PatchError::UnknownFormat));
+    }
+
+    fn varint(mut value: usize, out: &mut Vec<u8>) {
+        loop {
+            let bits = (value & 0x7F) as u8;
+            value >>= 7;
+            if value == 0 {
+                out.push(bits | 0x80);
+                return;
+            }
+            out.push(bits);
+            value -= 1;
+        }
+    }
+
+    #[test]
+    fn bps_actions() {
+        let source = b"xyz";
+        let target = b"zababab";
+        let mut patch = b"BPS1".to_vec();
+        varint(source.len(), &mut patch);
+        varint(target.len(), &mut patch);
+        varint(0, &mut patch);
+        // copy "z" from the source
+        varint(2, &mut patch);
+        varint(2 << 1, &mut patch);
+        // read "ab" from the patch
+        varint((1 << 2) | 1, &mut patch);
+        patch.extend_from_slice(b"ab");
+        // copy 4 bytes from the target, starting at "ab" and overlapping what's written
+        varint((3 << 2) | 3, &mut patch);
+        varint(1 << 1, &mut patch);
+        patch.extend_from_slice(&crc32(source).to_le_bytes());
+        patch.extend_from_slice(&crc32(target).to_le_bytes());
+        patch.extend_from_slice(&crc32(&patch).to_le_bytes());
+        assert_eq!(apply(source, &patch).unwrap(), target);
+
+        assert!(matches!(
+            apply(b"abc", &patch),
+            Err(PatchError::WrongSourceRom { .. })
+        ));
+    }
+
+    #[test]
+    fn bps_seek_far_past_the_end() {
+        let source = b"xyz";
+        // source copy and target copy
+        for copy in [2, 3] {
+            let mut patch = b"BPS1".to_vec();
+            varint(source.len(), &mut patch);
+            varint(4, &mut patch);
+            varint(0, &mut patch);
+            // read "ab" from the patch
+            varint((1 << 2) | 1, &mut patch);
+            patch.extend_from_slice(b"ab");
+            // copy 2 bytes from as far forward as a seek goes, close to usize::MAX
+            varint((1 << 2) | copy, &mut patch);
+            varint(usize::MAX - 1, &mut patch);
+            patch.extend_from_slice(&crc32(source).to_le_bytes());
+            patch.extend_from_slice(&0u32.to_le_bytes());
+            patch.extend_from_slice(&crc32(&patch).to_le_bytes());
+            assert_eq!(apply(source, &patch), Err(PatchError::OutOfBounds));
+        }
+    }
+
+    #[test]
+    fn bps_target_too_large() {
+        let source = b"xyz";
+        let mut patch = b"BPS1".to_vec();
+        varint(source.len(), &mut patch);
+        varint(usize::MAX >> 8, &mut patch);
+        varint(0, &mut patch);
+        patch.extend_from_slice(&crc32(source).to_le_bytes());
+        patch.extend_from_slice(&0u32.to_le_bytes());
+        patch.extend_from_slice(&crc32(&patch).to_le_bytes());
+        assert_eq!(
+            apply(source, &patch),
+            Err(PatchError::TargetTooLarge {
+                len: usize::MAX >> 8
+            })
+        );
+    }
+}
@@ -1,10 +1,18 @@
 use std::assert_matches::assert_matches;
 
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
 use enumset::EnumSet;
 use serde::{Deserialize, Serialize};
 use serde_big_array::BigArray;
 
-use crate::{mmu::InterruptKind, util::U8Ext};
+use crate::{mmu::InterruptKind, palette::DmgPalette, util::U8Ext};
+
+mod fifo;
+
+use fifo::PixelFifo;
+pub use fifo::Renderer;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Ppu {
@@ -12,23 +20,51 @@ pub struct Ppu {
     pub last_full_frame: [DisplayLine; 144],
     #[serde(skip, default = "DisplayLine::blank_display")]
     lcd_display: [DisplayLine; 144],
+    /// `last_full_frame` resolved to 15-bit RGB. In CGB mode, colors come from the CGB palette RAM.
+    #[serde(skip, default = "Rgb555::blank_display")]
+    pub last_full_rgb_frame: [[Rgb555; 160]; 144],
+    #[serde(skip, default = "Rgb555::blank_display")]
+    lcd_rgb_display: [[Rgb555; 160]; 144],
+    /// The color IDs of `last_full_frame`, before the palettes are applied. Each pixel has the color ID of the object
+    /// that was drawn there, or else of the background or window, so color ID 0 always comes from the background.
+    ///
+    /// Boxed, unlike the other frames, so that emulators still fit on the stacks of test threads.
+    #[serde(skip, default = "ColorId::blank_display")]
+    pub last_full_color_id_frame: Box<[[ColorId; 160]; 144]>,
+    #[serde(skip, default = "ColorId::blank_display")]
+    lcd_color_id_display: Box<[[ColorId; 160]; 144]>,
     pub vram_tile_data: VRamTileData,
     /// At address 0x9800
     pub lo_tile_map: TileMap,
     /// At address 0x9C00
     pub hi_tile_map: TileMap,
+    /// CGB only: the tile data in VRAM bank 1
+    pub vram_bank_1_tile_data: VRamTileData,
+    /// CGB only: the attributes of the tiles in `lo_tile_map`, at address 0x9800 in VRAM bank 1
+    pub lo_tile_attributes: TileAttributeMap,
+    /// CGB only: the attributes of the tiles in `hi_tile_map`, at address 0x9C00 in VRAM bank 1
+    pub hi_tile_attributes: TileAttributeMap,
+    /// VBK
+    ///
+    /// The VRAM bank (0 or 1) mapped to 0x8000-0x9FFF. Always 0 outside of CGB mode.
+    pub vram_bank: u8,
     /// There are 144 visible lines (0-143) and 10 additional invisible lines (144-153)
     ///
-    /// This is equivalent to the LCD y coordinate (LY)
+    /// This is the LCD y coordinate (LY), except at the end of line 153, see [`Ppu::ly`]
     pub line: u8,
     /// The number of T-clock cycles spent in the current mode.
     ///
     /// Used to know when to switch modes and move the line index.
     pub cycles_in_mode: u32,
     pub mode: Mode,
+    /// The number of dots that mode 3 takes on the current line, 172 to 289. Mode 0 takes the rest of the line.
+    pub mode_3_length: u32,
 
     // -- LCD Control flags
+    /// Set with [`Ppu::set_lcd_enabled`]
     pub lcd_enabled: bool,
+    /// Set when the LCD is turned on, since the first frame after that isn't displayed
+    pub skip_frame: bool,
     pub window_tile_map_select: TileMapArea,
     // Draw the window only when this bit is set
     pub window_enabled: bool,
@@ -54,6 +90,18 @@ pub struct Ppu {
     ///
     /// Palette for background and window tiles.
     pub bg_color_palette: ColorPalette,
+    /// BCPS/BCPD
+    ///
+    /// The 8 CGB background palettes, used instead of BGP in CGB mode.
+    pub bg_palette_ram: CgbPaletteRam,
+    /// OCPS/OCPD
+    ///
+    /// The 8 CGB object palettes, used instead of OBP0 and OBP1 in CGB mode.
+    pub obj_palette_ram: CgbPaletteRam,
+    /// Whether colors are resolved through the CGB palette RAM instead of the DMG palettes
+    pub cgb_mode: bool,
+    /// The RGB colors of the DMG shades, used outside of CGB mode
+    pub dmg_palette: DmgPalette,
     /// The on-screen coordinates of the visible 160x144 pixel area within the 256x256 pixel background map.
     ///
     /// AKA SCY (ScrollY) and SCX (ScrollX)
@@ -65,33 +113,62 @@ pub struct Ppu {
     /// So if you want to draw the window in the upper left corner (0,0), this coordinate would be (0,7)
     /// The window is visible, if enabled, when x is in \[0,166\] and y is in \[0, 143\]
     pub window_top_left: Position,
+    /// The window's internal line counter: the row of the window drawn on the next line where the window is visible.
+    ///
+    /// It only advances on lines where the window was drawn, so a window that is hidden for a few lines in the middle
+    /// of a frame continues where it left off. It restarts every frame.
+    pub window_line: u8,
 
     /// LCD Y compare. Used to set flags when compared with LY
     pub lyc: u8,
     /// LCD status register
     pub lcd_status: LcdStatus,
+    /// The internal STAT interrupt line, which is high while any of the conditions selected in STAT holds. The LcdStat
+    /// interrupt is only requested when the line rises, so a condition that starts while another one holds the line
+    /// high doesn't request another interrupt ("STAT blocking").
+    pub stat_line: bool,
+
+    /// How scanlines are drawn
+    pub renderer: Renderer,
+    /// The state of mode 3 on the current line, with the pixel FIFO renderer
+    pixel_fifo: PixelFifo,
+
+    /// When set, the host time spent drawing scanlines is accumulated in `render_time`.
+    #[serde(skip)]
+    pub(crate) profile_rendering: bool,
+    #[serde(skip)]
+    pub(crate) render_time: Duration,
+    /// When set, a snapshot of the registers is pushed to `started_lines` whenever mode 3 starts.
+    #[serde(skip)]
+    pub(crate) record_started_lines: bool,
+    #[serde(skip)]
+    pub(crate) started_lines: Vec<ScanlineSnapshot>,
 }
 
 impl Ppu {
     pub(crate) fn new() -> Self {
         Self {
-            vram_tile_data: VRamTileData {
-                tile_data_blocks: [TileBlock(
-                    [Tile {
-                        lines: [TileLine { lsbs: 0, msbs: 0 }; 8],
-                    }; 128],
-                ); 3],
-            },
+            vram_tile_data: VRamTileData::blank(),
             lo_tile_map: TileMap {
                 tile_indices: [[0; 32]; 32],
             },
             hi_tile_map: TileMap {
                 tile_indices: [[0; 32]; 32],
             },
+            vram_bank_1_tile_data: VRamTileData::blank(),
+            lo_tile_attributes: TileAttributeMap {
+                attributes: [[TileAttributes::from(0x00); 32]; 32],
+            },
+            hi_tile_attributes: TileAttributeMap {
+                attributes: [[TileAttributes::from(0x00); 32]; 32],
+            },
+            vram_bank: 0,
             line: 0,
             cycles_in_mode: 0,
             mode: Mode::ScanlineOAM,
+            mode_3_length: 172,
             lcd_enabled: false,
+            skip_frame: false,
             window_tile_map_select: TileMapArea::from_bit(false),
             window_enabled: false,
             bg_and_window_tile_data_select: BgAndWindowTileDataArea::X8800,
@@ -100,6 +177,10 @@ impl Ppu {
             obj_enabled: false,
             bg_enabled: false,
             bg_color_palette: ColorPalette::from(0x00),
+            bg_palette_ram: CgbPaletteRam::new(),
+            obj_palette_ram: CgbPaletteRam::new(),
+            cgb_mode: false,
+            dmg_palette: DmgPalette::GRAYSCALE,
             viewport_offset: Position { x: 0, y: 0 },
             lyc: 0,
             lcd_status: LcdStatus {
@@ -108,8 +189,10 @@ impl Ppu {
                 mode_1_int_select: false,
                 mode_0_int_select: false,
             },
+            stat_line: false,
             obj_color_palettes: [ColorPalette::from(0x00); 2],
             window_top_left: Position { x: 0, y: 0 },
+            window_line: 0,
             obj_attribute_memory: [ObjectAttributes {
                 y_pos: 0,
                 x_pos: 0,
@@ -118,20 +201,55 @@ impl Ppu {
                 y_flip: false,
                 x_flip: false,
                 palette: ObjColorPaletteIdx::Zero,
+                cgb_palette: 0,
             }; 40],
             lcd_display: [DisplayLine::black_line(); 144],
             last_full_frame: [DisplayLine::black_line(); 144],
+            lcd_rgb_display: Rgb555::blank_display(),
+            last_full_rgb_frame: Rgb555::blank_display(),
+            lcd_color_id_display: ColorId::blank_display(),
+            last_full_color_id_frame: ColorId::blank_display(),
+            renderer: Renderer::default(),
+            pixel_fifo: PixelFifo::default(),
+            profile_rendering: false,
+            render_time: Duration::ZERO,
+            record_started_lines: false,
+            started_lines: Vec::new(),
         }
     }
 
+    /// LCDC
+    pub fn lcdc(&self) -> u8 {
+        u8::from_bits([
+            self.lcd_enabled,
+            self.window_tile_map_select.to_bit(),
+            self.window_enabled,
+            self.bg_and_window_tile_data_select.to_bit(),
+            self.bg_tile_map_select.to_bit(),
+            self.obj_size.to_bit(),
+            self.obj_enabled,
+            self.bg_enabled,
+        ])
+    }
+
     pub(crate) fn read_vram_byte(&self, addr: u16) -> u8 {
+        self.read_vram_bank_byte(self.vram_bank, addr)
+    }
+
+    /// Read from VRAM `bank`, regardless of which bank is mapped.
+    pub(crate) fn read_vram_bank_byte(&self, bank: u8, addr: u16) -> u8 {
         // Tile ID is the middle 2 bytes of the address
         match addr {
             // Tiles
             0x8000..=0x97FF => {
                 let idx = TileByteIdx::from_addr(addr);
+                let tile_data = if bank == 1 {
+                    &self.vram_bank_1_tile_data
+                } else {
+                    &self.vram_tile_data
+                };
                 let tile = {
-                    let block = &self.vram_tile_data.tile_data_blocks[idx.block_idx];
+                    let block = &tile_data.tile_data_blocks[idx.block_idx];
                     &block.as_slice()[idx.tile_idx]
                 };
                 let line = tile.lines[idx.line_idx];
@@ -142,14 +260,24 @@ impl Ppu {
                 }
             } // Tile map
             0x9800..=0x9FFF => {
-                let tile_map = if (0x9800..=0x9BFF).contains(&addr) {
-                    &self.lo_tile_map
-                } else {
-                    &self.hi_tile_map
-                };
+                let is_lo = (0x9800..=0x9BFF).contains(&addr);
                 let row_idx = ((addr / 32) % 32) as usize;
                 let col_idx = (addr % 32) as usize;
-                tile_map.tile_indices[row_idx][col_idx]
+                if bank == 1 {
+                    let attribute_map = if is_lo {
+                        &self.lo_tile_attributes
+                    } else {
+                        &self.hi_tile_attributes
+                    };
+                    attribute_map.attributes[row_idx][col_idx].into()
+                } else {
+                    let tile_map = if is_lo {
+                        &self.lo_tile_map
+                    } else {
+                        &self.hi_tile_map
+                    };
+                    tile_map.tile_indices[row_idx][col_idx]
+                }
             }
             _ => {
                 panic!("Invalid address into VRAM: {addr:#0x}")
@@ -164,8 +292,12 @@ impl Ppu {
             0x8000..=0x97FF => {
                 let idx = TileByteIdx::from_addr(addr);
                 let tile = {
-                    let this = &mut *self;
-                    let block = &mut this.vram_tile_data.tile_data_blocks[idx.block_idx];
+                    let tile_data = if self.vram_bank == 1 {
+                        &mut self.vram_bank_1_tile_data
+                    } else {
+                        &mut self.vram_tile_data
+                    };
+                    let block = &mut tile_data.tile_data_blocks[idx.block_idx];
                     &mut block.as_mut_slice()[idx.tile_idx]
                 };
                 let line = &mut tile.lines[idx.line_idx];
@@ -176,14 +308,24 @@ impl Ppu {
                 }
             } // Tile map
             0x9800..=0x9FFF => {
-                let tile_map = if (0x9800..=0x9BFF).contains(&addr) {
-                    &mut self.lo_tile_map
-                } else {
-                    &mut self.hi_tile_map
-                };
+                let is_lo = (0x9800..=0x9BFF).contains(&addr);
                 let row_idx = ((addr / 32) % 32) as usize;
                 let col_idx = (addr % 32) as usize;
-                tile_map.tile_indices[row_idx][col_idx] = byte;
+                if self.vram_bank == 1 {
+                    let attribute_map = if is_lo {
+                        &mut self.lo_tile_attributes
+                    } else {
+                        &mut self.hi_tile_attributes
+                    };
+                    attribute_map.attributes[row_idx][col_idx] = TileAttributes::from(byte);
+                } else {
+                    let tile_map = if is_lo {
+                        &mut self.lo_tile_map
+                    } else {
+                        &mut self.hi_tile_map
+                    };
+                    tile_map.tile_indices[row_idx][col_idx] = byte;
+                }
             }
             _ => {
                 panic!("Invalid address into VRAM: {addr:#0x}")
@@ -191,6 +333,34 @@ impl Ppu {
         }
     }
 
+    /// LCDC bit 7. Turning the LCD off resets LY to 0 and the mode to 0, and blanks the screen. Once it's turned back
+    /// on, the PPU starts again from line 0, but the first frame isn't displayed.
+    ///
+    /// https://gbdev.io/pandocs/LCDC.html#lcdc7--lcd-enable
+    pub(crate) fn set_lcd_enabled(&mut self, enabled: bool) {
+        if enabled == self.lcd_enabled {
+            return;
+        }
+        self.lcd_enabled = enabled;
+        self.line = 0;
+        self.window_line = 0;
+        self.cycles_in_mode = 0;
+        if enabled {
+            self.mode = Mode::ScanlineOAM;
+            self.skip_frame = true;
+        } else {
+            self.mode = Mode::HorizontalBlank;
+            self.stat_line = false;
+            self.last_full_frame = [DisplayLine::white_line(); 144];
+            let white = match self.cgb_mode {
+                true => Rgb555::from(Color::White),
+                false => self.dmg_palette.bg_color(Color::White),
+            };
+            self.last_full_rgb_frame = [[white; 160]; 144];
+            self.last_full_color_id_frame = ColorId::blank_display();
+        }
+    }
+
     pub(crate) fn step(&mut self, t_cycles: u8) -> EnumSet<InterruptKind> {
         let mut interrupts = EnumSet::empty();
         if !self.lcd_enabled {
@@ -202,43 +372,86 @@ impl Ppu {
                 if self.cycles_in_mode >= 80 {
                     self.cycles_in_mode -= 80;
                     self.mode = Mode::ScanlineVRAM;
+                    if self.record_started_lines {
+                        self.started_lines.push(ScanlineSnapshot {
+                            ly: self.line,
+                            viewport: self.viewport_offset,
+                            window: self.window_top_left,
+                            lcdc: self.lcdc(),
+                        });
+                    }
+                    match self.renderer {
+                        Renderer::PixelFifo => self.start_pixel_fifo(),
+                        Renderer::Scanline => self.mode_3_length = self.estimate_mode_3_length(),
+                    }
                 }
             }
             Mode::ScanlineVRAM => {
-                if self.cycles_in_mode >= 172 {
-                    self.cycles_in_mode -= 172;
-                    self.mode = Mode::HorizontalBlank;
-                    if self.lcd_status.mode_0_int_select {
-                        interrupts |= InterruptKind::LcdStat;
+                let mode_3_length = match self.renderer {
+                    Renderer::PixelFifo => {
+                        let start = self.profile_rendering.then(Instant::now);
+                        self.run_pixel_fifo(self.cycles_in_mode);
+                        if let Some(start) = start {
+                            self.render_time += start.elapsed();
+                        }
+                        // unknown until the line is drawn
+                        self.pixel_fifo.mode_3_length()
                     }
+                    Renderer::Scanline => Some(self.mode_3_length),
+                };
+                if let Some(length) = mode_3_length.filter(|&length| self.cycles_in_mode >= length)
+                {
+                    self.mode_3_length = length;
+                    self.cycles_in_mode -= length;
+                    self.mode = Mode::HorizontalBlank;
 
                     // Now GPU has finished drawing the line, write it to the LCD
-                    if self.line < 144 {
-                        self.lcd_display[self.line as usize] = self.draw_scan_line();
+                    if self.line < 144 && self.renderer == Renderer::Scanline {
+                        let start = self.profile_rendering.then(Instant::now);
+                        let (line, rgb_line, color_ids) = self.draw_scan_line();
+                        self.lcd_display[self.line as usize] = line;
+                        self.lcd_rgb_display[self.line as usize] = rgb_line;
+                        self.lcd_color_id_display[self.line as usize] = color_ids;
+                        if let Some(start) = start {
+                            self.render_time += start.elapsed();
+                        }
+                    }
+                    let window_drawn = match self.renderer {
+                        Renderer::PixelFifo => self.pixel_fifo.window_drawn(),
+                        Renderer::Scanline => Ppu::window_visible_on_line(
+                            self.bg_enabled,
+                            self.window_enabled,
+                            self.window_top_left,
+                            self.line,
+                        ),
+                    };
+                    if window_drawn {
+                        self.window_line += 1;
                     }
                 }
             }
             Mode::HorizontalBlank => {
                 assert!(self.line < 144);
-                if self.cycles_in_mode >= 204 {
-                    self.cycles_in_mode -= 204;
+                // the rest of the 376 dots after mode 2
+                let hblank_length = 376 - self.mode_3_length;
+                if self.cycles_in_mode >= hblank_length {
+                    self.cycles_in_mode -= hblank_length;
                     self.line += 1;
-                    if self.should_trigger_lyc_interrupt() {
-                        interrupts |= InterruptKind::LcdStat;
-                    }
                     if self.line == 144 {
                         self.mode = Mode::VerticalBlank;
-                        self.last_full_frame = self.lcd_display;
-                        interrupts |= InterruptKind::Vblank;
-                        if self.lcd_status.mode_1_int_select {
-                            interrupts |= InterruptKind::LcdStat;
+                        self.window_line = 0;
+                        if self.skip_frame {
+                            self.skip_frame = false;
+                        } else {
+                            self.last_full_frame = self.lcd_display;
+                            self.last_full_rgb_frame = self.lcd_rgb_display;
+                            self.last_full_color_id_frame
+                                .clone_from(&self.lcd_color_id_display);
                         }
+                        interrupts |= InterruptKind::Vblank;
                     } else {
                         assert!(self.line < 144);
                         self.mode = Mode::ScanlineOAM;
-                        if self.lcd_status.mode_2_int_select {
-                            interrupts |= InterruptKind::LcdStat;
-                        }
                     }
                 }
             }
@@ -254,18 +467,47 @@ impl Ppu {
                         self.line = 0;
                         self.mode = Mode::ScanlineOAM;
                     }
-                    if self.should_trigger_lyc_interrupt() {
-                        interrupts |= InterruptKind::LcdStat;
-                    }
                 }
             }
         }
+        if self.update_stat_line() {
+            interrupts |= InterruptKind::LcdStat;
+        }
         interrupts
     }
 
+    /// Update the STAT interrupt line after a change to the mode, LY, LYC, or STAT. Returns whether it rose, which
+    /// requests the LcdStat interrupt.
+    pub(crate) fn update_stat_line(&mut self) -> bool {
+        let was_high = self.stat_line;
+        self.stat_line = self.lcd_enabled && self.stat_conditions_met();
+        self.stat_line && !was_high
+    }
+
+    /// LY, the line that the CPU reads and that LYC is compared with. It already reads 0 after the first 4 dots of line
+    /// 153, so LY=LYC holds with LYC=0 from then until the end of line 0.
+    ///
+    /// https://gbdev.io/pandocs/STAT.html#ff44--ly-lcd-y-coordinate-read-only
+    pub fn ly(&self) -> u8 {
+        if self.line == 153 && self.cycles_in_mode >= 4 {
+            0
+        } else {
+            self.line
+        }
+    }
+
+    /// Whether any of the conditions selected in STAT holds
+    fn stat_conditions_met(&self) -> bool {
+        let status = self.lcd_status;
+        (status.lyc_int_select && self.lyc == self.ly())
+            || (status.mode_0_int_select && self.mode == Mode::HorizontalBlank)
+            || (status.mode_1_int_select && self.mode == Mode::VerticalBlank)
+            || (status.mode_2_int_select && self.mode == Mode::ScanlineOAM)
+    }
+
     /// Draw a single scanline of the LCD display based on the current PPU state
     ///
-    /// Returns an array of 160 colors representing one horizontal line of pixels
+    /// Returns one horizontal line of 160 pixels, both as DMG shades and as 15-bit RGB colors
     ///
     /// # Arguments
     ///
@@ -279,10 +521,13 @@ impl Ppu {
     /// * `window_enabled` - Whether window rendering is enabled
     /// * `window_tile_map` - The tile map to use for window rendering
     /// * `window_top_left_pos` - The window's position on screen (WX,WY). The window's x coordinate on the LCD coordinate system is WX-7
+    /// * `window_line` - The window's internal line counter, which is the row of the window to draw
     /// * `obj_enabled` - Whether sprite/object rendering is enabled
     /// * `obj_size` - Whether sprites are 8x8 or 8x16 pixels
     /// * `obj_attr_memory` - Object Attribute Memory containing sprite data
     /// * `obj_palettes` - The two color palettes available for sprites
+    /// * `cgb` - The CGB tile attributes, VRAM bank 1, and palette RAM. When set, RGB colors are resolved through the CGB palettes instead of the DMG shades
+    /// * `dmg_palette` - The RGB colors of the DMG shades, used when `cgb` isn't set
     #[allow(clippy::too_many_arguments)]
     fn draw_scan_line_internal(
         // common args
@@ -299,19 +544,60 @@ impl Ppu {
         window_enabled: bool,
         window_tile_map: &TileMap,
         window_top_left_pos: Position,
+        window_line: u8,
         // obj-specific args
         obj_enabled: bool,
         obj_size: ObjSize,
         obj_attr_memory: &[ObjectAttributes; 40],
         obj_palettes: [ColorPalette; 2],
-    ) -> DisplayLine {
-        let mut result = if bg_enabled {
-            DisplayLine::black_line()
+        cgb: Option<CgbRenderState>,
+        dmg_palette: &DmgPalette,
+    ) -> (DisplayLine, [Rgb555; 160], [ColorId; 160]) {
+        let (mut result, mut rgb_result) = if bg_enabled {
+            (
+                DisplayLine::black_line(),
+                [dmg_palette.bg_color(Color::Black); 160],
+            )
         } else {
-            DisplayLine::white_line()
+            (
+                DisplayLine::white_line(),
+                [dmg_palette.bg_color(Color::White); 160],
+            )
+        };
+        // Resolve the color id and CGB attributes of the pixel at (row, col) of a 256x256 background or window map
+        let resolve_map_pixel = |tile_map: &TileMap,
+                                 attribute_map: Option<&TileAttributeMap>,
+                                 row: usize,
+                                 col: usize| {
+            let tile_idx = tile_map.tile_indices[row / 8][col / 8];
+            let attributes = attribute_map.map(|map| map.attributes[row / 8][col / 8]);
+            let tiles = match (attributes, cgb) {
+                (Some(attributes), Some(cgb)) if attributes.bank_1 => cgb.bank_1_tiles,
+                _ => vram_tiles,
+            };
+            let tile = match bg_and_window_tile_data_select {
+                BgAndWindowTileDataArea::X8800 => tiles.get_tile_from_0x8800_signed(tile_idx),
+                BgAndWindowTileDataArea::X8000 => tiles.get_tile_from_0x8000(tile_idx),
+            };
+            let (mut line_idx, mut pixel_idx) = (row % 8, col % 8);
+            if attributes.is_some_and(|attributes| attributes.y_flip) {
+                line_idx = 7 - line_idx;
+            }
+            if attributes.is_some_and(|attributes| attributes.x_flip) {
+                pixel_idx = 7 - pixel_idx;
+            }
+            (tile.lines[line_idx].color_ids()[pixel_idx], attributes)
+        };
+        let bg_rgb = |color_id: ColorId, shade: Color, attributes: Option<TileAttributes>| match (
+            cgb, attributes,
+        ) {
+            (Some(cgb), Some(attributes)) => cgb.bg_palette_ram.color(attributes.palette, color_id),
+            _ => dmg_palette.bg_color(shade),
         };
         // Preserve the color ids while drawing the background and window to resolve priority when drawing objects
         let mut bg_line_color_ids = [ColorId::Id0; 160];
+        // Whether the CGB tile attributes give the background and window priority over objects
+        let mut bg_line_priority = [false; 160];
         if bg_enabled {
             // the index of the line being drawn in the 256x256 background coordinate system
             let bg_row: u8 = bg_viewport_offset.y.wrapping_add(lcd_line);
@@ -319,31 +605,25 @@ impl Ppu {
                 let bg_col: u8 = bg_viewport_offset.x.wrapping_add(lcd_col);
                 // bg_row and bg_col represent the position of a pixel in the 256x256 background layer
                 // Now we need to find the corresponding color id for this pixel in the background map
-                let pixel_color_id = {
-                    // First, find the corresponding tile for this pixel
-                    let tile_idx =
-                        bg_tile_map.tile_indices[bg_row as usize / 8][bg_col as usize / 8];
-                    let tile = match bg_and_window_tile_data_select {
-                        BgAndWindowTileDataArea::X8800 => {
-                            vram_tiles.get_tile_from_0x8800_signed(tile_idx)
-                        }
-                        BgAndWindowTileDataArea::X8000 => vram_tiles.get_tile_from_0x8000(tile_idx),
-                    };
-                    tile.lines[bg_row as usize % 8].color_ids()[bg_col as usize % 8]
-                };
-                result.set_pixel(lcd_col, bg_and_window_palette.lookup(pixel_color_id));
+                let (pixel_color_id, attributes) = resolve_map_pixel(
+                    bg_tile_map,
+                    cgb.map(|cgb| cgb.bg_tile_attributes),
+                    bg_row as usize,
+                    bg_col as usize,
+                );
+                let shade = bg_and_window_palette.lookup(pixel_color_id);
+                result.set_pixel(lcd_col, shade);
+                rgb_result[lcd_col as usize] = bg_rgb(pixel_color_id, shade, attributes);
                 bg_line_color_ids[lcd_col as usize] = pixel_color_id;
+                bg_line_priority[lcd_col as usize] =
+                    attributes.is_some_and(|attributes| attributes.bg_over_obj_priority);
             }
         }
-        // the window is only visible if both the window and background are enabled, and the window offset falls within the ranges WX=0..166, WY=0..143
-        let window_visible = bg_enabled
-            && window_enabled
-            && window_top_left_pos.y <= lcd_line
-            && (0..=166).contains(&window_top_left_pos.x)
-            && (0..=143).contains(&window_top_left_pos.y);
+        let window_visible =
+            Ppu::window_visible_on_line(bg_enabled, window_enabled, window_top_left_pos, lcd_line);
         if bg_enabled && window_visible {
             // the index of the line being drawn in the 256x256 window coordinate system
-            let window_row = (lcd_line - window_top_left_pos.y) as usize;
+            let window_row = window_line as usize;
             for lcd_col in 0..160 {
                 // window_row, window_col are the index of a pixel in the 256x256 window coordinate system
                 let window_col = lcd_col as i16 + 7 - window_top_left_pos.x as i16;
@@ -351,41 +631,25 @@ impl Ppu {
                     // window is not visible at (line, lcd_col)
                     // (the window does not wrap around)
                 } else {
-                    let window_col = window_col as usize;
-                    let pixel_color_id = {
-                        let tile_idx = window_tile_map.tile_indices[window_row / 8][window_col / 8];
-                        let tile = match bg_and_window_tile_data_select {
-                            BgAndWindowTileDataArea::X8800 => {
-                                vram_tiles.get_tile_from_0x8800_signed(tile_idx)
-                            }
-                            BgAndWindowTileDataArea::X8000 => {
-                                vram_tiles.get_tile_from_0x8000(tile_idx)
-                            }
-                        };
-                        tile.lines[window_row % 8].color_ids()[window_col % 8]
-                    };
-                    result.set_pixel(lcd_col, bg_and_window_palette.lookup(pixel_color_id));
-                    bg_line_color_ids[lcd_col as usize] = pixel_color_id
+                    let (pixel_color_id, attributes) = resolve_map_pixel(
+                        window_tile_map,
+                        cgb.map(|cgb| cgb.window_tile_attributes),
+                        window_row,
+                        window_col as usize,
+                    );
+                    let shade = bg_and_window_palette.lookup(pixel_color_id);
+                    result.set_pixel(lcd_col, shade);
+                    rgb_result[lcd_col as usize] = bg_rgb(pixel_color_id, shade, attributes);
+                    bg_line_color_ids[lcd_col as usize] = pixel_color_id;
+                    bg_line_priority[lcd_col as usize] =
+                        attributes.is_some_and(|attributes| attributes.bg_over_obj_priority);
                 }
             }
         }
+        let mut color_ids = bg_line_color_ids;
         if obj_enabled {
-            // let mut objs_on_line = Vec::with_capacity(10);
-            let obj_lines = |obj: ObjectAttributes| {
-                let obj_lcd_y = obj.y_pos as i16 - 16;
-                obj_lcd_y..(obj_lcd_y + obj_size.height() as i16)
-            };
-            // These are the (at-most) 10 objects on the line sorted from highest to lowest priority
-            let prioritized_objects_on_line = {
-                let mut objects_on_line = obj_attr_memory
-                    .iter()
-                    // filter only objects on line
-                    .filter(|&&obj| obj_lines(obj).contains(&(lcd_line as i16)))
-                    .take(10)
-                    .collect::<Vec<_>>();
-                objects_on_line.sort_by_key(|obj| obj.x_pos);
-                objects_on_line
-            };
+            let prioritized_objects_on_line =
+                Ppu::objects_on_line(obj_attr_memory, obj_size, lcd_line, cgb.is_some());
             // draw the objects in lowest to highest priority, so that higher priority objects hide lower priority objects
             for obj in prioritized_objects_on_line.iter().rev() {
                 // get the tile of this object that is on the current line
@@ -428,24 +692,34 @@ impl Ppu {
                         let is_transparent = pixel_color_id == ColorId::Id0;
                         if !is_transparent
                             // Draw if the bg does not have priority over the object
-                            && (obj.bg_over_obj_priority == Priority::Zero
+                            && ((obj.bg_over_obj_priority == Priority::Zero
+                                && !bg_line_priority[lcd_col_idx as usize])
                                 || bg_line_color_ids[lcd_col_idx as usize] == ColorId::Id0)
                         {
-                            let palette = obj_palettes[match obj.palette {
+                            let palette_idx = match obj.palette {
                                 ObjColorPaletteIdx::Zero => 0,
                                 ObjColorPaletteIdx::One => 1,
-                            }];
-                            result.set_pixel(lcd_col_idx, palette.lookup(pixel_color_id));
+                            };
+                            let palette = obj_palettes[palette_idx];
+                            let shade = palette.lookup(pixel_color_id);
+                            result.set_pixel(lcd_col_idx, shade);
+                            color_ids[lcd_col_idx as usize] = pixel_color_id;
+                            rgb_result[lcd_col_idx as usize] = match cgb {
+                                Some(cgb) => {
+                                    cgb.obj_palette_ram.color(obj.cgb_palette, pixel_color_id)
+                                }
+                                None => dmg_palette.obj_color(palette_idx, shade),
+                            };
                         }
                     }
                 }
             }
         }
-        result
+        (result, rgb_result, color_ids)
     }
 
     /// Resolve pixel values for a line of the LCD display
-    fn draw_scan_line(&self) -> DisplayLine {
+    fn draw_scan_line(&self) -> (DisplayLine, [Rgb555; 160], [ColorId; 160]) {
         Ppu::draw_scan_line_internal(
             &self.vram_tile_data,
             self.line,
@@ -463,99 +737,142 @@ impl Ppu {
                 TileMapArea::X9C00 => &self.hi_tile_map,
             },
             self.window_top_left,
+            self.window_line,
             self.obj_enabled,
             self.obj_size,
             &self.obj_attribute_memory,
             self.obj_color_palettes,
+            self.cgb_mode.then_some(CgbRenderState {
+                bank_1_tiles: &self.vram_bank_1_tile_data,
+                bg_tile_attributes: match self.bg_tile_map_select {
+                    TileMapArea::X9800 => &self.lo_tile_attributes,
+                    TileMapArea::X9C00 => &self.hi_tile_attributes,
+                },
+                window_tile_attributes: match self.window_tile_map_select {
+                    TileMapArea::X9800 => &self.lo_tile_attributes,
+                    TileMapArea::X9C00 => &self.hi_tile_attributes,
+                },
+                bg_palette_ram: &self.bg_palette_ram,
+                obj_palette_ram: &self.obj_palette_ram,
+            }),
+            &self.dmg_palette,
         )
     }
 
-    /// This condition should be checked every time the current line is updated.
-    fn should_trigger_lyc_interrupt(&self) -> bool {
-        self.lcd_status.lyc_int_select && self.lyc == self.line
+    /// The OAM scan of mode 2: the (at most) 10 objects on `lcd_line`, sorted from highest to lowest priority.
+    ///
+    /// Objects are selected in OAM order by their y coordinate alone, so objects that are off the screen horizontally
+    /// (X = 0 or X >= 168) still count towards the limit. Outside of CGB mode, the object with the smaller x coordinate
+    /// has priority, and the one earlier in OAM breaks ties. In CGB mode, the one earlier in OAM always has priority.
+    ///
+    /// https://gbdev.io/pandocs/OAM.html#selection-priority
+    fn objects_on_line(
+        obj_attr_memory: &[ObjectAttributes; 40],
+        obj_size: ObjSize,
+        lcd_line: u8,
+        cgb_priority: bool,
+    ) -> Vec<ObjectAttributes> {
+        let mut objects = obj_attr_memory
+            .iter()
+            .filter(|obj| {
+                let obj_lcd_y = obj.y_pos as i16 - 16;
+                (obj_lcd_y..obj_lcd_y + obj_size.height() as i16).contains(&(lcd_line as i16))
+            })
+            .take(10)
+            .copied()
+            .collect::<Vec<_>>();
+        if !cgb_priority {
+            // the sort is stable, so objects at the same x coordinate stay in OAM order
+            objects.sort_by_key(|obj| obj.x_pos);
+        }
+        objects
+    }
+
+    /// How long mode 3 lasts on the current line with the scanline renderer, which doesn't fetch pixels dot by dot:
+    /// 172 dots, plus a dot for each pixel thrown away for fine scrolling (SCX % 8), plus 6 dots for each object.
+    ///
+    /// https://gbdev.io/pandocs/Rendering.html#mode-3-length
+    fn estimate_mode_3_length(&self) -> u32 {
+        let objects = if self.obj_enabled {
+            Ppu::objects_on_line(
+                &self.obj_attribute_memory,
+                self.obj_size,
+                self.line,
+                self.cgb_mode,
+            )
+            .iter()
+            .filter(|obj| obj.x_pos < 168)
+            .count()
+        } else {
+            0
+        };
+        172 + (self.viewport_offset.x % 8) as u32 + 6 * objects as u32
+    }
+
+    /// Whether the window is drawn on `lcd_line`: both the window and background are enabled, and the window offset
+    /// falls within the ranges WX=0..166, WY=0..143
+    fn window_visible_on_line(
+        bg_enabled: bool,
+        window_enabled: bool,
+        window_top_left_pos: Position,
+        lcd_line: u8,
+    ) -> bool {
+        bg_enabled
+            && window_enabled
+            && window_top_left_pos.y <= lcd_line
+            && (0..=166).contains(&window_top_left_pos.x)
+            && (0..=143).contains(&window_top_left_pos.y)
     }
 
     /// Construct a 256x256 grid of colors based on the ppu's background tile map and color palette.
     /// This returns the entire background and draws the viewport outline on the background
     /// This function ignores the background window enable bit.
     pub fn dbg_resolve_background(&self) -> [[Color; 256]; 256] {
-        let mut background = [[Color::Black; 256]; 256];
+        self.dbg_tilemap(TilemapLayer::Background, true).pixels
+    }
 
-        // Get the correct tile map based on bg_tile_map_select
-        let tile_map = match self.bg_tile_map_select {
-            TileMapArea::X9800 => &self.lo_tile_map,
-            TileMapArea::X9C00 => &self.hi_tile_map,
-        };
+    pub fn dbg_resolve_window(&self) -> [[Color; 256]; 256] {
+        self.dbg_tilemap(TilemapLayer::Window, false).pixels
+    }
 
-        // Iterate through each tile position in the 32x32 tile map
-        for tile_y in 0..32 {
-            for tile_x in 0..32 {
-                // Get the tile index from the map
+    /// Draw the whole 32x32 tile map that `layer` uses with the background palette, along with where each tile came
+    /// from, e.g. for tooltips in a debugger.
+    ///
+    /// * `viewport` - Outline the part of the background that SCX and SCY put on the LCD
+    pub fn dbg_tilemap(&self, layer: TilemapLayer, viewport: bool) -> TilemapView {
+        let map_area = match layer {
+            TilemapLayer::Background => self.bg_tile_map_select,
+            TilemapLayer::Window => self.window_tile_map_select,
+        };
+        let (tile_map, attribute_map, map_base) = match map_area {
+            TileMapArea::X9800 => (&self.lo_tile_map, &self.lo_tile_attributes, 0x9800),
+            TileMapArea::X9C00 => (&self.hi_tile_map, &self.hi_tile_attributes, 0x9C00),
+        };
+        let tiles = std::array::from_fn(|tile_y| {
+            std::array::from_fn(|tile_x| {
                 let tile_idx = tile_map.tile_indices[tile_y][tile_x];
-
-                // Get the actual tile based on bg_and_window_tile_data_select
-                let tile = match self.bg_and_window_tile_data_select {
-                    BgAndWindowTileDataArea::X8000 => {
-                        self.vram_tile_data.get_tile_from_0x8000(tile_idx)
-                    }
+                let tile_addr = match self.bg_and_window_tile_data_select {
+                    BgAndWindowTileDataArea::X8000 => 0x8000 + tile_idx as u16 * 16,
                     BgAndWindowTileDataArea::X8800 => {
-                        self.vram_tile_data.get_tile_from_0x8800_signed(tile_idx)
+                        0x9000u16.wrapping_add_signed(tile_idx as i8 as i16 * 16)
                     }
                 };
-
-                // Each tile is 8x8 pixels
-                // Calculate the starting pixel position in the background
-                let start_x = tile_x * 8;
-                let start_y = tile_y * 8;
-
-                // Copy each pixel from the tile to the background
-                for (line_idx, line) in tile.lines.iter().enumerate() {
-                    for (pixel_idx, color_id) in line.color_ids().iter().enumerate() {
-                        let bg_x = start_x + pixel_idx;
-                        let bg_y = start_y + line_idx;
-                        background[bg_y][bg_x] = self.bg_color_palette.lookup(*color_id);
-                    }
+                MapTile {
+                    map_addr: map_base + (tile_y * 32 + tile_x) as u16,
+                    tile_idx,
+                    tile_addr,
+                    block: ((tile_addr - 0x8000) / 0x800) as u8,
+                    attributes: attribute_map.attributes[tile_y][tile_x],
                 }
-            }
-        }
-
-        // horizontal lines of viewport
-        for i in 0..160 {
-            let top_y = self.viewport_offset.y as usize;
-            let bottom_y = (top_y + 144) % 256;
-            let x = (self.viewport_offset.x as usize + i) % 256;
-            background[top_y][x] = Color::Black;
-            background[bottom_y][x] = Color::Black;
-        }
-
-        // vertical lines of viewport
-        for i in 0..144 {
-            let left_x = self.viewport_offset.x as usize;
-            let right_x = (left_x + 160) % 256;
-            let y = (self.viewport_offset.y as usize + i) % 256;
-            background[y][left_x] = Color::Black;
-            background[y][right_x] = Color::Black;
-        }
-
-        background
-    }
-
-    pub fn dbg_resolve_window(&self) -> [[Color; 256]; 256] {
-        let mut window = [[Color::Black; 256]; 256];
-
-        // Get the correct tile map based on window_tile_map_select
-        let tile_map = match self.window_tile_map_select {
-            TileMapArea::X9800 => &self.lo_tile_map,
-            TileMapArea::X9C00 => &self.hi_tile_map,
+            })
+        });
+        let mut view = TilemapView {
+            pixels: [[Color::Black; 256]; 256],
+            tiles,
         };
-
-        // Iterate through each tile position in the 32x32 tile map
         for tile_y in 0..32 {
             for tile_x in 0..32 {
-                // Get the tile index from the map
-                let tile_idx = tile_map.tile_indices[tile_y][tile_x];
-
-                // Get the actual tile based on bg_and_window_tile_data_select
+                let tile_idx = view.tiles[tile_y][tile_x].tile_idx;
                 let tile = match self.bg_and_window_tile_data_select {
                     BgAndWindowTileDataArea::X8000 => {
                         self.vram_tile_data.get_tile_from_0x8000(tile_idx)
@@ -564,23 +881,35 @@ impl Ppu {
                         self.vram_tile_data.get_tile_from_0x8800_signed(tile_idx)
                     }
                 };
-
-                // Each tile is 8x8 pixels
-                // Calculate the starting pixel position in the window
-                let start_x = tile_x * 8;
-                let start_y = tile_y * 8;
-
-                // Copy each pixel from the tile to the window
                 for (line_idx, line) in tile.lines.iter().enumerate() {
                     for (pixel_idx, color_id) in line.color_ids().iter().enumerate() {
-                        let window_x = start_x + pixel_idx;
-                        let window_y = start_y + line_idx;
-                        window[window_y][window_x] = self.bg_color_palette.lookup(*color_id);
+                        view.pixels[tile_y * 8 + line_idx][tile_x * 8 + pixel_idx] =
+                            self.bg_color_palette.lookup(*color_id);
                     }
                 }
             }
         }
-        window
+        if viewport {
+            let background = &mut view.pixels;
+            // horizontal lines of viewport
+            for i in 0..160 {
+                let top_y = self.viewport_offset.y as usize;
+                let bottom_y = (top_y + 144) % 256;
+                let x = (self.viewport_offset.x as usize + i) % 256;
+                background[top_y][x] = Color::Black;
+                background[bottom_y][x] = Color::Black;
+            }
+
+            // vertical lines of viewport
+            for i in 0..144 {
+                let left_x = self.viewport_offset.x as usize;
+                let right_x = (left_x + 160) % 256;
+                let y = (self.viewport_offset.y as usize + i) % 256;
+                background[y][left_x] = Color::Black;
+                background[y][right_x] = Color::Black;
+            }
+        }
+        view
     }
 
     /// Draw the objects in the object attribute memory as a grid of pixels
@@ -643,6 +972,90 @@ impl Ppu {
         }
         grid
     }
+
+    /// Construct a 16x24 grid of all 384 tiles in VRAM, in address order: the blocks at 0x8000, 0x8800, and 0x9000
+    /// take up 8 rows each.
+    pub fn dbg_resolve_tiles(&self, palette: TilePalette) -> [[Color; 128]; 192] {
+        let palette = match palette {
+            TilePalette::Bg => self.bg_color_palette,
+            TilePalette::Obj0 => self.obj_color_palettes[0],
+            TilePalette::Obj1 => self.obj_color_palettes[1],
+            TilePalette::ColorIds => ColorPalette::from(0b11_10_01_00),
+        };
+        let mut grid = [[Color::White; 128]; 192];
+        let tiles = self
+            .vram_tile_data
+            .tile_data_blocks
+            .iter()
+            .flat_map(|block| block.as_slice());
+        for (tile_idx, tile) in tiles.enumerate() {
+            let (tile_y, tile_x) = (tile_idx / 16 * 8, tile_idx % 16 * 8);
+            for (y_offset, line) in tile.lines.iter().enumerate() {
+                for (x_offset, color_id) in line.color_ids().into_iter().enumerate() {
+                    grid[tile_y + y_offset][tile_x + x_offset] = palette.lookup(color_id);
+                }
+            }
+        }
+        grid
+    }
+
+    /// Decode the 40 objects in OAM, in OAM order, e.g. for listing them in a debugger.
+    pub fn dbg_oam_entries(&self) -> Vec<OamEntryDebug> {
+        let height = self.obj_size.height();
+        // the number of objects selected so far on each line
+        let mut objects_per_line = [0u8; 144];
+        self.obj_attribute_memory
+            .iter()
+            .enumerate()
+            .map(|(index, &attributes)| {
+                let lcd_x = attributes.x_pos as i16 - 8;
+                let lcd_y = attributes.y_pos as i16 - 16;
+                let lines = lcd_y.max(0) as usize..(lcd_y + height as i16).clamp(0, 144) as usize;
+                let within_line_limit = objects_per_line[lines.clone()]
+                    .iter()
+                    .all(|&count| count < 10);
+                for count in &mut objects_per_line[lines.clone()] {
+                    *count += 1;
+                }
+                OamEntryDebug {
+                    index: index as u8,
+                    attributes,
+                    lcd_x,
+                    lcd_y,
+                    height,
+                    on_screen: !lines.is_empty() && (-7..160).contains(&lcd_x),
+                    within_line_limit,
+                }
+            })
+            .collect()
+    }
+
+    /// Construct a 10x4 grid of the 40 objects in OAM, in OAM order, regardless of their position on screen.
+    ///
+    /// Each cell is 8x16 pixels. 8x8 objects only fill the top half of their cell.
+    pub fn dbg_resolve_oam(&self) -> [[Color; 80]; 64] {
+        let mut grid = [[Color::White; 80]; 64];
+        for (obj_idx, obj) in self.obj_attribute_memory.iter().enumerate() {
+            let (cell_y, cell_x) = (obj_idx / 10 * 16, obj_idx % 10 * 8);
+            let tile_indices = match self.obj_size {
+                ObjSize::Dim8x8 => vec![obj.tile_idx],
+                ObjSize::Dim8x16 => vec![obj.tile_idx & 0b1111_1110, obj.tile_idx | 1],
+            };
+            let palette = self.obj_color_palettes[match obj.palette {
+                ObjColorPaletteIdx::Zero => 0,
+                ObjColorPaletteIdx::One => 1,
+            }];
+            let lines = tile_indices
+                .into_iter()
+                .flat_map(|idx| self.vram_tile_data.get_tile_from_0x8000(idx).lines);
+            for (y_offset, line) in lines.enumerate() {
+                for (x_offset, color_id) in line.color_ids().into_iter().enumerate() {
+                    grid[cell_y + y_offset][cell_x + x_offset] = palette.lookup(color_id);
+                }
+            }
+        }
+        grid
+    }
 }
 
 /// A packed representation of the colors within a line
@@ -671,6 +1084,10 @@ impl DisplayLine {
         [DisplayLine::black_line(); 144]
     }
 
+    pub(crate) fn packed(&self) -> &[u8; 40] {
+        &self.0
+    }
+
     pub fn colors(&self) -> [Color; 160] {
         let mut result = [Color::White; 160];
         for idx in 0..160 {
@@ -792,6 +1209,63 @@ pub struct TileMap {
     pub tile_indices: [[u8; 32]; 32],
 }
 
+/// CGB only: the attributes of each tile in a `TileMap`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TileAttributeMap {
+    pub attributes: [[TileAttributes; 32]; 32],
+}
+
+/// CGB only: how to draw a background or window tile
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TileAttributes {
+    /// The background palette, 0-7
+    pub palette: u8,
+    /// Whether the tile data is in VRAM bank 1 instead of bank 0
+    pub bank_1: bool,
+    pub x_flip: bool,
+    pub y_flip: bool,
+    /// When set, the tile's non-zero color ids are drawn over objects, regardless of the objects' priority
+    pub bg_over_obj_priority: bool,
+}
+
+impl From<u8> for TileAttributes {
+    fn from(value: u8) -> Self {
+        let [priority, y_flip, x_flip, _, bank_1, _, _, _] = value.bits();
+        TileAttributes {
+            palette: value & 0x07,
+            bank_1,
+            x_flip,
+            y_flip,
+            bg_over_obj_priority: priority,
+        }
+    }
+}
+
+impl From<TileAttributes> for u8 {
+    fn from(value: TileAttributes) -> Self {
+        u8::from_bits([
+            value.bg_over_obj_priority,
+            value.y_flip,
+            value.x_flip,
+            false,
+            value.bank_1,
+            false,
+            false,
+            false,
+        ]) | value.palette
+    }
+}
+
+/// The CGB-only state used to draw a scan line
+#[derive(Debug, Clone, Copy)]
+struct CgbRenderState<'a> {
+    bank_1_tiles: &'a VRamTileData,
+    bg_tile_attributes: &'a TileAttributeMap,
+    window_tile_attributes: &'a TileAttributeMap,
+    bg_palette_ram: &'a CgbPaletteRam,
+    obj_palette_ram: &'a CgbPaletteRam,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum TileMapArea {
     X9800,
@@ -895,6 +1369,98 @@ impl Color {
     }
 }
 
+/// A CGB color with 5 bits per channel: red in bits 0-4, green in bits 5-9, and blue in bits 10-14
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rgb555(pub u16);
+
+impl Rgb555 {
+    fn blank_display() -> [[Rgb555; 160]; 144] {
+        [[Rgb555::from(Color::Black); 160]; 144]
+    }
+
+    /// Scale each 5-bit channel to 8 bits
+    pub fn to_rgb8(self) -> [u8; 3] {
+        let channel = |shift: u16| {
+            let c = ((self.0 >> shift) & 0x1F) as u8;
+            (c << 3) | (c >> 2)
+        };
+        [channel(0), channel(5), channel(10)]
+    }
+}
+
+impl From<Color> for Rgb555 {
+    /// The DMG shades as evenly spaced grays
+    fn from(value: Color) -> Self {
+        let c = match value {
+            Color::White => 0x1F,
+            Color::LightGray => 0x15,
+            Color::DarkGray => 0x0A,
+            Color::Black => 0x00,
+        };
+        Rgb555(c | (c << 5) | (c << 10))
+    }
+}
+
+/// CGB palette memory: 8 palettes of 4 colors, each color stored as a little-endian `Rgb555`.
+///
+/// The CPU can't address this memory directly. A specification register (BCPS/OCPS) selects a byte, which is read or written through a data register (BCPD/OCPD).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CgbPaletteRam {
+    #[serde(with = "BigArray")]
+    data: [u8; 64],
+    /// The index of the byte accessed through the data register
+    index: u8,
+    /// Whether writes to the data register advance `index`
+    auto_increment: bool,
+}
+
+impl CgbPaletteRam {
+    fn new() -> Self {
+        CgbPaletteRam {
+            // all white
+            data: [0xFF; 64],
+            index: 0,
+            auto_increment: false,
+        }
+    }
+
+    pub fn read_spec(&self) -> u8 {
+        u8::from_bits([
+            self.auto_increment,
+            true,
+            false,
+            false,
+            false,
+            false,
+            false,
+            false,
+        ]) | self.index
+    }
+
+    pub fn write_spec(&mut self, byte: u8) {
+        self.auto_increment = byte.bit(7);
+        self.index = byte & 0x3F;
+    }
+
+    /// Reads don't advance the index, even with auto-increment enabled
+    pub fn read_data(&self) -> u8 {
+        self.data[self.index as usize]
+    }
+
+    pub fn write_data(&mut self, byte: u8) {
+        self.data[self.index as usize] = byte;
+        if self.auto_increment {
+            self.index = (self.index + 1) & 0x3F;
+        }
+    }
+
+    /// * `palette` - The index of a palette, 0-7
+    pub fn color(&self, palette: u8, id: ColorId) -> Rgb555 {
+        let idx = (palette as usize & 0x07) * 8 + id as usize * 2;
+        Rgb555(u16::from_le_bytes([self.data[idx], self.data[idx + 1]]) & 0x7FFF)
+    }
+}
+
 /// field i of the strict corresponds to the ith color id
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ColorPalette(Color, Color, Color, Color);
@@ -934,6 +1500,98 @@ impl From<u8> for ColorPalette {
     }
 }
 
+/// The registers that shape a scanline, as they were when mode 3 started drawing it. See
+/// [`crate::Emulator::set_scanline_hook`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScanlineSnapshot {
+    pub ly: u8,
+    /// SCX and SCY
+    pub viewport: Position,
+    /// WX and WY
+    pub window: Position,
+    pub lcdc: u8,
+}
+
+/// An object in OAM, decoded by [`Ppu::dbg_oam_entries`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OamEntryDebug {
+    /// The position in OAM, 0-39
+    pub index: u8,
+    /// The position, tile, and flags, as stored in OAM
+    pub attributes: ObjectAttributes,
+    /// The left edge on the LCD, which is off the screen below 0 or from 160
+    pub lcd_x: i16,
+    /// The top edge on the LCD, which is off the screen below 0 or from 144
+    pub lcd_y: i16,
+    /// 8 or 16 pixels, depending on the object size in LCDC. Objects are always 8 pixels wide.
+    pub height: u8,
+    /// Whether any of the object's pixels are on the screen
+    pub on_screen: bool,
+    /// Whether the object is among the first 10 in OAM on every line that it covers. Otherwise it's missing from the
+    /// lines with too many objects.
+    pub within_line_limit: bool,
+}
+
+/// The layers whose tile maps [`Ppu::dbg_tilemap`] draws
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TilemapLayer {
+    Background,
+    Window,
+}
+
+/// A whole tile map, drawn by [`Ppu::dbg_tilemap`]
+#[derive(Debug, Clone)]
+pub struct TilemapView {
+    pub pixels: [[Color; 256]; 256],
+    /// The 32x32 tiles of the map, by row and then column
+    pub tiles: [[MapTile; 32]; 32],
+}
+
+/// Where a tile in [`TilemapView`] came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MapTile {
+    /// The address of the tile map entry
+    pub map_addr: u16,
+    /// The tile index in the tile map entry
+    pub tile_idx: u8,
+    /// The address of the tile data that `tile_idx` selects
+    pub tile_addr: u16,
+    /// The tile data block that holds the tile: 0 at 0x8000, 1 at 0x8800, or 2 at 0x9000
+    pub block: u8,
+    /// CGB only: the attributes of the tile map entry, in VRAM bank 1
+    pub attributes: TileAttributes,
+}
+
+/// The palette that [`Ppu::dbg_resolve_tiles`] draws tiles with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TilePalette {
+    /// BGP
+    #[default]
+    Bg,
+    /// OBP0
+    Obj0,
+    /// OBP1
+    Obj1,
+    /// Color ID 0 as white up to color ID 3 as black, regardless of the palettes the game set up
+    ColorIds,
+}
+
+impl FromStr for TilePalette {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "bg" | "bgp" => Ok(TilePalette::Bg),
+            "obj0" | "obp0" => Ok(TilePalette::Obj0),
+            "obj1" | "obp1" => Ok(TilePalette::Obj1),
+            "ids" | "color-ids" => Ok(TilePalette::ColorIds),
+            _ => Err(format!(
+                "unknown tile palette {s:?}, expected bg, obj0, obj1, or ids"
+            )),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Mode {
     /// Takes 80 clock cycles. While in this mode, the PPU fetches assets from memory
@@ -968,6 +1626,16 @@ pub struct VRamTileData {
 }
 
 impl VRamTileData {
+    fn blank() -> Self {
+        VRamTileData {
+            tile_data_blocks: [TileBlock(
+                [Tile {
+                    lines: [TileLine { lsbs: 0, msbs: 0 }; 8],
+                }; 128],
+            ); 3],
+        }
+    }
+
     /// Read a tile from blocks 0 or 1, using unsigned addressing.
     ///
     /// idx 0 to 127 gets from block 0
@@ -1055,6 +1723,12 @@ pub enum ColorId {
     Id3,
 }
 
+impl ColorId {
+    fn blank_display() -> Box<[[ColorId; 160]; 144]> {
+        Box::new([[ColorId::Id0; 160]; 144])
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ObjectAttributes {
     /// Object’s vertical position on the screen + 16.
@@ -1067,7 +1741,8 @@ pub struct ObjectAttributes {
     pub y_pos: u8,
     /// Object’s horizontal position on the screen + 8.
     ///
-    /// An off-screen value (X=0 or X>=168) hides the object.
+    /// An off-screen value (X=0 or X>=168) hides the object, but it still counts towards the limit of 10 objects per
+    /// line.
     pub x_pos: u8,
     pub tile_idx: u8,
 
@@ -1076,6 +1751,8 @@ pub struct ObjectAttributes {
     pub y_flip: bool,
     pub x_flip: bool,
     pub palette: ObjColorPaletteIdx,
+    /// The object palette used in CGB mode, 0-7
+    pub cgb_palette: u8,
 }
 
 impl ObjectAttributes {
@@ -1095,7 +1772,7 @@ impl ObjectAttributes {
             false,
             false,
             false,
-        ]);
+        ]) | self.cgb_palette;
         [self.y_pos, self.x_pos, self.tile_idx, byte_3]
     }
 }
@@ -1120,6 +1797,7 @@ mod tests {
     use super::*;
 
     proptest! {
+        #![proptest_config(crate::util::proptest_config())]
         #[test]
         fn display_line_roundtrip(color_id in 0..4, pixel_idx in 0..160u8) {
             let colors = [
@@ -1275,20 +1953,20 @@ mod tests {
         ppu.lo_tile_map.tile_indices[0][1..].fill(1);
 
         // The first row of the LCD should be 8 white pixels followed by 152 light gray pixels
-        let lcd_row = ppu.draw_scan_line();
+        let lcd_row = ppu.draw_scan_line().0;
         assert_eq!(lcd_row.colors()[..8], [Color::White; 8]);
         assert_eq!(lcd_row.colors()[8..], [Color::LightGray; 152]);
 
         // move the viewport to the right by 1 pixel
         ppu.viewport_offset.x = 1;
         // Now the first row of the LCD should be 7 white pixels followed by 152 light gray pixels
-        let lcd_row = ppu.draw_scan_line();
+        let lcd_row = ppu.draw_scan_line().0;
         assert_eq!(lcd_row.colors()[..7], [Color::White; 7]);
         assert_eq!(lcd_row.colors()[7..], [Color::LightGray; 153]);
 
         // we should get the same line even as we scroll the viewport down up to line 7, because each row of tiles 1 and 2 is identical
         for y_offset in 1..7 {
-            let lcd_row = ppu.draw_scan_line();
+            let lcd_row = ppu.draw_scan_line().0;
             ppu.viewport_offset.y = y_offset;
             assert_eq!(lcd_row.colors()[..7], [Color::White; 7]);
             assert_eq!(lcd_row.colors()[7..], [Color::LightGray; 153]);
@@ -1301,7 +1979,7 @@ mod tests {
         ppu.line = 5;
         // we are now drawing line 5 of the LCD screen, which is offset 3 from the top of the background map
         // This should display the second row of tiles
-        let lcd_row = ppu.draw_scan_line();
+        let lcd_row = ppu.draw_scan_line().0;
         assert_eq!(lcd_row.colors()[..8], [Color::DarkGray; 8]);
         assert_eq!(lcd_row.colors()[8..], [Color::Black; 152]);
     }
@@ -1348,20 +2026,21 @@ mod tests {
             y_flip: false,
             x_flip: false,
             palette: ObjColorPaletteIdx::Zero,
+            cgb_palette: 0,
         };
         // first, at position 0,0, the object should be invisible
-        let line = ppu.draw_scan_line();
+        let line = ppu.draw_scan_line().0;
         assert_eq!(line.colors(), [Color::White; 160]);
 
         // now, make the object visible by moving it down 9 rows and to the right 1 column
         ppu.obj_attribute_memory[0].y_pos = 9;
         ppu.obj_attribute_memory[0].x_pos = 1;
-        let line = ppu.draw_scan_line();
+        let line = ppu.draw_scan_line().0;
         assert_eq!(line.colors()[0], Color::DarkGray);
         // The rest of the screen should still be blank
         assert_eq!(line.colors()[1..], [Color::White; 159]);
         ppu.line = 1;
-        assert_eq!(ppu.draw_scan_line().colors(), [Color::White; 160]);
+        assert_eq!(ppu.draw_scan_line().0.colors(), [Color::White; 160]);
 
         // Now, flip the object vertically and render the last line of the object on the first line of the lcd
         ppu.line = 0;
@@ -1369,20 +2048,20 @@ mod tests {
         ppu.obj_attribute_memory[0].y_pos = 9;
         ppu.obj_attribute_memory[0].y_flip = true;
 
-        let line = ppu.draw_scan_line();
+        let line = ppu.draw_scan_line().0;
         assert_eq!(line.colors()[..4], [Color::White; 4]);
         assert_eq!(line.colors()[4..8], [Color::LightGray; 4]);
         assert_eq!(line.colors()[8..], [Color::White; 152]);
 
         // Now flip the object horizontally and vertically and render the last line of the object
         ppu.obj_attribute_memory[0].x_flip = true;
-        let line = ppu.draw_scan_line();
+        let line = ppu.draw_scan_line().0;
         assert_eq!(line.colors()[..4], [Color::LightGray; 4]);
         assert_eq!(line.colors()[4..], [Color::White; 156]);
 
         // Now unflip the object vertically and render the last line of the object
         ppu.obj_attribute_memory[0].y_flip = false;
-        let line = ppu.draw_scan_line();
+        let line = ppu.draw_scan_line().0;
         assert_eq!(line.colors()[..4], [Color::DarkGray; 4]);
         assert_eq!(line.colors()[4..8], [Color::Black; 4]);
         assert_eq!(line.colors()[8..], [Color::White; 152]);
@@ -1451,33 +2130,34 @@ mod tests {
             y_flip: false,
             x_flip: false,
             palette: ObjColorPaletteIdx::Zero,
+            cgb_palette: 0,
         };
         // first, at position 0,0, the object should be invisible
-        let line = ppu.draw_scan_line();
+        let line = ppu.draw_scan_line().0;
         assert_eq!(line.colors(), [Color::White; 160]);
 
         // now, make the object visible by moving it down a single row row and to the right 8 columns
         ppu.obj_attribute_memory[0].y_pos = 1;
         ppu.obj_attribute_memory[0].x_pos = 8;
-        let line = ppu.draw_scan_line();
+        let line = ppu.draw_scan_line().0;
         assert_eq!(line.colors()[..8], [Color::LightGray; 8]);
         // The rest of the screen should still be black
         assert_eq!(line.colors()[8..], [Color::White; 152]);
         ppu.line = 1;
-        assert_eq!(ppu.draw_scan_line().colors(), [Color::White; 160]);
+        assert_eq!(ppu.draw_scan_line().0.colors(), [Color::White; 160]);
 
         // Now flip the object vertically and rerender the first line of the object
         ppu.obj_attribute_memory[0].y_flip = true;
         ppu.line = 0;
 
-        let line = ppu.draw_scan_line();
+        let line = ppu.draw_scan_line().0;
         assert_eq!(line.colors()[0], Color::LightGray);
         assert_eq!(line.colors()[1..8], [Color::DarkGray; 7]);
         assert_eq!(line.colors()[8..], [Color::White; 152]);
 
         // Now flip the object horizontally and vertically and render the first line of the object
         ppu.obj_attribute_memory[0].x_flip = true;
-        let line = ppu.draw_scan_line();
+        let line = ppu.draw_scan_line().0;
         assert_eq!(line.colors()[..7], [Color::DarkGray; 7]);
         assert_eq!(line.colors()[7], Color::LightGray);
         assert_eq!(line.colors()[8..], [Color::White; 152]);
@@ -1487,18 +2167,386 @@ mod tests {
         ppu.obj_attribute_memory[0].x_flip = false;
         ppu.obj_attribute_memory[0].y_pos = 16;
         ppu.line = 0;
-        let top_line = ppu.draw_scan_line();
+        let top_line = ppu.draw_scan_line().0;
         assert_eq!(top_line.colors()[..8], [Color::LightGray; 8]);
         ppu.line = 7;
-        let first_tile_bottom_line = ppu.draw_scan_line();
+        let first_tile_bottom_line = ppu.draw_scan_line().0;
         assert_eq!(first_tile_bottom_line.pixel_at(0), Color::DarkGray);
         assert_eq!(first_tile_bottom_line.colors()[1..8], [Color::LightGray; 7]);
         ppu.line = 8;
-        let second_tile_top_line = ppu.draw_scan_line();
+        let second_tile_top_line = ppu.draw_scan_line().0;
         assert_eq!(second_tile_top_line.colors()[..8], [Color::DarkGray; 8]);
         ppu.line = 15;
-        let second_tile_bottom_line = ppu.draw_scan_line();
+        let second_tile_bottom_line = ppu.draw_scan_line().0;
         assert_eq!(second_tile_bottom_line.pixel_at(0), Color::LightGray);
         assert_eq!(second_tile_bottom_line.colors()[1..8], [Color::DarkGray; 7]);
     }
+
+    #[test]
+    fn draw_bg_with_cgb_palette() {
+        let mut ppu = Ppu::new();
+        ppu.cgb_mode = true;
+        ppu.bg_enabled = true;
+        ppu.bg_and_window_tile_data_select = BgAndWindowTileDataArea::X8000;
+        ppu.vram_tile_data.tile_data_blocks[0].as_mut_slice()[1] = mono_color_tile(ColorId::Id1);
+        ppu.lo_tile_map.tile_indices[0][1..].fill(1);
+        // BG palette 0, colors 0 and 1: pure red and pure blue
+        ppu.bg_palette_ram.write_spec(0x80);
+        for byte in [0x1F, 0x00, 0x00, 0x7C] {
+            ppu.bg_palette_ram.write_data(byte);
+        }
+
+        let (_, rgb, _) = ppu.draw_scan_line();
+        assert_eq!(rgb[..8], [Rgb555(0x001F); 8]);
+        assert_eq!(rgb[8..], [Rgb555(0x7C00); 152]);
+        assert_eq!(rgb[0].to_rgb8(), [0xFF, 0, 0]);
+
+        // Outside of CGB mode, the RGB colors follow the DMG shades
+        ppu.cgb_mode = false;
+        ppu.bg_color_palette = ColorPalette::from(0b11_10_01_00);
+        let (_, rgb, _) = ppu.draw_scan_line();
+        assert_eq!(rgb[0], Rgb555(0x7FFF));
+        assert_eq!(rgb[8], Rgb555::from(Color::LightGray));
+    }
+
+    #[test]
+    fn draw_bg_with_cgb_tile_attributes() {
+        let mut ppu = Ppu::new();
+        ppu.cgb_mode = true;
+        ppu.bg_enabled = true;
+        ppu.obj_enabled = true;
+        ppu.bg_and_window_tile_data_select = BgAndWindowTileDataArea::X8000;
+        // tile 0 in bank 1 has color id 1 in its left-most column and color id 0 elsewhere
+        let mut color_ids = [ColorId::Id0; 8];
+        color_ids[0] = ColorId::Id1;
+        ppu.vram_bank_1_tile_data.tile_data_blocks[0].as_mut_slice()[0] = Tile {
+            lines: [TileLine::from_color_ids(color_ids); 8],
+        };
+        ppu.lo_tile_attributes.attributes[0][0] = TileAttributes::from(0b1010_1011);
+        // BG palette 3, color 1: pure green
+        ppu.bg_palette_ram.write_spec(3 * 8 + 2);
+        ppu.bg_palette_ram.write_data(0xE0);
+        ppu.bg_palette_ram.write_spec(3 * 8 + 3);
+        ppu.bg_palette_ram.write_data(0x03);
+        // an object with color id 3 over the first tile
+        ppu.vram_tile_data.tile_data_blocks[0].as_mut_slice()[1] = mono_color_tile(ColorId::Id3);
+        ppu.obj_attribute_memory[0] = ObjectAttributes {
+            y_pos: 16,
+            x_pos: 8,
+            tile_idx: 1,
+            bg_over_obj_priority: Priority::Zero,
+            y_flip: false,
+            x_flip: false,
+            palette: ObjColorPaletteIdx::Zero,
+            cgb_palette: 0,
+        };
+
+        let (_, rgb, _) = ppu.draw_scan_line();
+        // the tile is flipped horizontally, so color id 1 is in the right-most column, where the tile attributes give the background priority over the object
+        assert_eq!(rgb[7], Rgb555(0x03E0));
+        // the object is drawn over the background's color id 0
+        assert_eq!(rgb[..7], [Rgb555(0x7FFF); 7]);
+        assert_eq!(
+            ppu.obj_palette_ram.color(0, ColorId::Id3),
+            Rgb555(0x7FFF),
+            "the object's palette should be white"
+        );
+    }
+
+    /// Draw the current line with both renderers, check that they agree, and return it
+    fn draw_line_with_both_renderers(ppu: &mut Ppu) -> [Color; 160] {
+        let (scanline, _, color_ids) = ppu.draw_scan_line();
+        ppu.start_pixel_fifo();
+        ppu.run_pixel_fifo(u32::MAX);
+        assert_eq!(
+            ppu.lcd_display[ppu.line as usize].colors(),
+            scanline.colors()
+        );
+        assert_eq!(ppu.lcd_color_id_display[ppu.line as usize], color_ids);
+        scanline.colors()
+    }
+
+    #[test]
+    fn color_ids_before_palettes() {
+        let mut ppu = Ppu::new();
+        ppu.bg_enabled = true;
+        ppu.obj_enabled = true;
+        // everything is white, whatever the color ID
+        ppu.bg_color_palette = ColorPalette::from(0);
+        ppu.obj_color_palettes[0] = ColorPalette::from(0);
+        ppu.vram_tile_data.tile_data_blocks[0].as_mut_slice()[1] = mono_color_tile(ColorId::Id1);
+        ppu.obj_attribute_memory[0] = ObjectAttributes {
+            y_pos: 16,
+            x_pos: 16,
+            tile_idx: 1,
+            bg_over_obj_priority: Priority::Zero,
+            y_flip: false,
+            x_flip: false,
+            palette: ObjColorPaletteIdx::Zero,
+            cgb_palette: 0,
+        };
+        assert_eq!(draw_line_with_both_renderers(&mut ppu), [Color::White; 160]);
+        let color_ids = ppu.lcd_color_id_display[0];
+        assert_eq!(color_ids[8..16], [ColorId::Id1; 8]);
+        assert_eq!(color_ids[..8], [ColorId::Id0; 8]);
+        assert_eq!(color_ids[16..], [ColorId::Id0; 144]);
+    }
+
+    #[test]
+    fn object_selection_and_priority() {
+        let mut ppu = Ppu::new();
+        ppu.obj_enabled = true;
+        ppu.obj_color_palettes[0] = ColorPalette::from(0b11_10_01_00);
+        ppu.vram_tile_data.tile_data_blocks[0].as_mut_slice()[1] = mono_color_tile(ColorId::Id3);
+        ppu.vram_tile_data.tile_data_blocks[0].as_mut_slice()[2] = mono_color_tile(ColorId::Id1);
+        let obj = ObjectAttributes {
+            y_pos: 16,
+            x_pos: 0,
+            tile_idx: 1,
+            bg_over_obj_priority: Priority::Zero,
+            y_flip: false,
+            x_flip: false,
+            palette: ObjColorPaletteIdx::Zero,
+            cgb_palette: 0,
+        };
+
+        // objects off the screen horizontally count towards the limit of 10 objects
+        for (idx, x_pos) in [0, 168, 0, 200, 0, 0, 255, 0, 0, 20, 40]
+            .into_iter()
+            .enumerate()
+        {
+            ppu.obj_attribute_memory[idx] = ObjectAttributes { x_pos, ..obj };
+        }
+        let colors = draw_line_with_both_renderers(&mut ppu);
+        assert_eq!(colors[12..20], [Color::Black; 8]);
+        assert_eq!(
+            colors[32..40],
+            [Color::White; 8],
+            "the 11th object isn't drawn"
+        );
+
+        // outside of CGB mode, the object with the smaller x coordinate has priority
+        ppu.obj_attribute_memory = [ObjectAttributes { y_pos: 0, ..obj }; 40];
+        ppu.obj_attribute_memory[0] = ObjectAttributes {
+            x_pos: 12,
+            tile_idx: 2,
+            ..obj
+        };
+        ppu.obj_attribute_memory[1] = ObjectAttributes { x_pos: 8, ..obj };
+        let colors = draw_line_with_both_renderers(&mut ppu);
+        assert_eq!(colors[..8], [Color::Black; 8]);
+        assert_eq!(colors[8..12], [Color::LightGray; 4]);
+
+        // at the same x coordinate, the object earlier in OAM has priority
+        ppu.obj_attribute_memory[1].x_pos = 12;
+        let colors = draw_line_with_both_renderers(&mut ppu);
+        assert_eq!(colors[4..12], [Color::LightGray; 8]);
+
+        // in CGB mode, the object earlier in OAM has priority
+        ppu.cgb_mode = true;
+        ppu.obj_attribute_memory[1].x_pos = 8;
+        let colors = draw_line_with_both_renderers(&mut ppu);
+        assert_eq!(colors[..4], [Color::Black; 4]);
+        assert_eq!(colors[4..12], [Color::LightGray; 8]);
+    }
+
+    #[test]
+    fn window_line_counter() {
+        for renderer in [Renderer::PixelFifo, Renderer::Scanline] {
+            let mut ppu = Ppu::new();
+            ppu.renderer = renderer;
+            ppu.lcd_enabled = true;
+            ppu.bg_enabled = true;
+            ppu.bg_color_palette = ColorPalette::from(0b11_10_01_00);
+            ppu.bg_and_window_tile_data_select = BgAndWindowTileDataArea::X8000;
+            ppu.window_tile_map_select = TileMapArea::X9C00;
+            ppu.window_top_left = Position { x: 7, y: 0 };
+            // the window's rows of tiles have color ids 1, 2, and 3
+            for (row, color_id) in [ColorId::Id1, ColorId::Id2, ColorId::Id3]
+                .into_iter()
+                .enumerate()
+            {
+                ppu.vram_tile_data.tile_data_blocks[0].as_mut_slice()[row + 1] =
+                    mono_color_tile(color_id);
+                ppu.hi_tile_map.tile_indices[row].fill(row as u8 + 1);
+            }
+            for line in 0..144 {
+                // hide the window on lines 8 to 15
+                ppu.window_enabled = !(8..16).contains(&line);
+                for _ in 0..456 / 4 {
+                    ppu.step(4);
+                }
+            }
+            let frame = ppu.last_full_frame.map(|line| line.colors());
+            assert_eq!(frame[7], [Color::LightGray; 160], "{renderer:?}");
+            assert_eq!(frame[8], [Color::White; 160], "{renderer:?}");
+            // the window continues from its second row of tiles after it was hidden
+            assert_eq!(frame[16], [Color::DarkGray; 160], "{renderer:?}");
+            assert_eq!(frame[23], [Color::DarkGray; 160], "{renderer:?}");
+            assert_eq!(frame[24], [Color::Black; 160], "{renderer:?}");
+        }
+    }
+
+    #[test]
+    fn stat_interrupt_blocking() {
+        let mut ppu = Ppu::new();
+        ppu.lcd_enabled = true;
+        ppu.lcd_status.mode_0_int_select = true;
+        ppu.lcd_status.lyc_int_select = true;
+        ppu.lyc = 1;
+        let mut interrupt_lines = Vec::new();
+        while ppu.line < 3 {
+            let line = ppu.line;
+            if ppu.step(4).contains(InterruptKind::LcdStat) {
+                interrupt_lines.push(line);
+            }
+        }
+        // LY=LYC on line 1 holds the line high from the end of line 0's mode 0 until line 2, so there's no interrupt
+        // when line 1 starts or at its mode 0
+        assert_eq!(interrupt_lines, [0, 2]);
+
+        // the line rises when LYC is written to match LY
+        ppu.lcd_status.mode_0_int_select = false;
+        assert!(!ppu.update_stat_line());
+        ppu.lyc = 3;
+        assert!(ppu.update_stat_line());
+        assert!(!ppu.update_stat_line());
+    }
+
+    #[test]
+    fn ly_resets_early_on_line_153() {
+        let mut ppu = Ppu::new();
+        ppu.lcd_enabled = true;
+        ppu.lcd_status.lyc_int_select = true;
+        let mut interrupts = Vec::new();
+        // run until the middle of line 0 of the next frame
+        for _ in 0..(154 * 456 + 200) / 4 {
+            if ppu.step(4).contains(InterruptKind::LcdStat) {
+                interrupts.push((ppu.line, ppu.cycles_in_mode, ppu.ly()));
+            }
+        }
+        // once at the start of the first frame, then early on line 153, where LY already reads 0, but not again when
+        // line 0 starts
+        assert_eq!(interrupts, [(0, 4, 0), (153, 4, 0)]);
+
+        ppu.lyc = 153;
+        interrupts.clear();
+        for _ in 0..154 * 456 / 4 {
+            if ppu.step(4).contains(InterruptKind::LcdStat) {
+                interrupts.push((ppu.line, ppu.cycles_in_mode, ppu.ly()));
+            }
+        }
+        assert_eq!(interrupts, [(153, 0, 153)]);
+    }
+
+    #[test]
+    fn tile_viewer_palettes() {
+        let mut ppu = Ppu::new();
+        // the first line of the first tile at 0x9000, in color ID 1
+        ppu.write_vram_byte(0x9000, 0xFF);
+        ppu.bg_color_palette = ColorPalette::from(0);
+        ppu.obj_color_palettes[1] = ColorPalette::from(0b00_00_11_00);
+
+        // the block at 0x9000 starts at the 17th row of tiles
+        let pixel = |palette| ppu.dbg_resolve_tiles(palette)[128][0];
+        assert_eq!(pixel(TilePalette::Bg), Color::White);
+        assert_eq!(pixel(TilePalette::Obj1), Color::Black);
+        assert_eq!(pixel(TilePalette::ColorIds), Color::LightGray);
+        assert_eq!(
+            ppu.dbg_resolve_tiles(TilePalette::ColorIds)[129][0],
+            Color::White
+        );
+        assert_eq!("obj1".parse(), Ok(TilePalette::Obj1));
+    }
+
+    #[test]
+    fn tilemap_metadata() {
+        let mut ppu = Ppu::new();
+        ppu.bg_color_palette = ColorPalette::from(0b11_10_01_00);
+        ppu.write_vram_byte(0x9800, 0x05);
+        ppu.write_vram_byte(0x9800 + 32 + 2, 0x80);
+        // the first line of tile 5 at 0x9000 in color ID 3
+        ppu.write_vram_byte(0x9050, 0xFF);
+        ppu.write_vram_byte(0x9051, 0xFF);
+
+        ppu.bg_and_window_tile_data_select = BgAndWindowTileDataArea::X8800;
+        let view = ppu.dbg_tilemap(TilemapLayer::Background, false);
+        assert_eq!(
+            view.tiles[0][0],
+            MapTile {
+                map_addr: 0x9800,
+                tile_idx: 0x05,
+                tile_addr: 0x9050,
+                block: 2,
+                attributes: TileAttributes::from(0),
+            }
+        );
+        assert_eq!(view.tiles[1][2].map_addr, 0x9822);
+        assert_eq!(
+            (view.tiles[1][2].tile_addr, view.tiles[1][2].block),
+            (0x8800, 1)
+        );
+        assert_eq!(view.pixels[0][1], Color::Black);
+        assert_eq!(view.pixels[1][0], Color::White);
+        assert_eq!(
+            ppu.dbg_tilemap(TilemapLayer::Background, true).pixels[1][0],
+            Color::Black,
+            "the viewport outline"
+        );
+
+        ppu.bg_and_window_tile_data_select = BgAndWindowTileDataArea::X8000;
+        ppu.window_tile_map_select = TileMapArea::X9C00;
+        let view = ppu.dbg_tilemap(TilemapLayer::Window, false);
+        assert_eq!(view.tiles[0][0].map_addr, 0x9C00);
+        assert_eq!(
+            (view.tiles[0][0].tile_addr, view.tiles[0][0].block),
+            (0x8000, 0)
+        );
+    }
+
+    #[test]
+    fn oam_entries() {
+        let mut ppu = Ppu::new();
+        let obj = ObjectAttributes {
+            y_pos: 16,
+            x_pos: 0,
+            tile_idx: 1,
+            bg_over_obj_priority: Priority::Zero,
+            y_flip: false,
+            x_flip: false,
+            palette: ObjColorPaletteIdx::Zero,
+            cgb_palette: 0,
+        };
+        // 10 objects off the screen horizontally fill up lines 0-7
+        ppu.obj_attribute_memory[..10].fill(obj);
+        ppu.obj_attribute_memory[10] = ObjectAttributes {
+            y_pos: 20,
+            x_pos: 40,
+            ..obj
+        };
+        ppu.obj_attribute_memory[11] = ObjectAttributes {
+            y_pos: 24,
+            x_pos: 1,
+            ..obj
+        };
+
+        let entries = ppu.dbg_oam_entries();
+        assert_eq!(entries.len(), 40);
+        assert_eq!(
+            (entries[0].lcd_x, entries[0].lcd_y, entries[0].height),
+            (-8, 0, 8)
+        );
+        assert!(!entries[0].on_screen);
+        assert!(entries[9].within_line_limit);
+        assert_eq!(entries[10].index, 10);
+        assert_eq!((entries[10].lcd_x, entries[10].lcd_y), (32, 4));
+        assert!(entries[10].on_screen);
+        assert!(!entries[10].within_line_limit);
+        assert!(entries[11].on_screen);
+        assert!(entries[11].within_line_limit);
+        assert!(!entries[39].on_screen);
+
+        ppu.obj_size = ObjSize::Dim8x16;
+        assert_eq!(ppu.dbg_oam_entries()[0].height, 16);
+    }
 }
@@ -0,0 +1,663 @@
+//! The pixel FIFO renderer, which draws a scanline dot by dot during mode 3 like the hardware does, so that writes to the
+//! PPU registers in the middle of a scanline take effect from the pixel where they happen.
+//!
+//! https://gbdev.io/pandocs/pixel_fifo.html
+use std::collections::VecDeque;
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    BgAndWindowTileDataArea, Color, ColorId, ObjColorPaletteIdx, ObjSize, ObjectAttributes, Ppu,
+    Priority, TileAttributes, TileMapArea,
+};
+
+/// How the PPU draws scanlines
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Renderer {
+    /// Push pixels through the background and object FIFOs dot by dot during mode 3, like the hardware
+    #[default]
+    PixelFifo,
+    /// Draw each scanline at once at the end of mode 3, from the registers at that time. This is faster, but writes to
+    /// the PPU registers in the middle of a scanline apply to the whole line.
+    Scanline,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct BgPixel {
+    color_id: ColorId,
+    /// CGB only: the attributes of the pixel's tile
+    attributes: Option<TileAttributes>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct ObjPixel {
+    /// Id0 is transparent
+    color_id: ColorId,
+    /// The priority of the pixel's object among the objects on the line, 0 being the highest
+    rank: u8,
+    obj: ObjectAttributes,
+}
+
+/// The step of the background fetcher. Each of the first three takes 2 dots, and pushing waits until the background
+/// FIFO is empty.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+enum FetcherStep {
+    Tile,
+    DataLow,
+    DataHigh,
+    Push,
+}
+
+/// The state of mode 3 on the current scanline
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct PixelFifo {
+    line: u8,
+    bg_fifo: VecDeque<BgPixel>,
+    /// The object pixels over the next background pixels, front first
+    obj_fifo: VecDeque<ObjPixel>,
+    step: FetcherStep,
+    /// The dots spent in the current fetcher step
+    step_dots: u8,
+    /// The first fetch of a scanline is thrown away
+    first_fetch: bool,
+    /// The tile column of the next fetch, counted from the left of the viewport or the window
+    fetcher_x: u8,
+    /// Whether the fetcher is fetching the window
+    window: bool,
+    /// Whether the window was drawn on this line
+    window_drawn: bool,
+    tile_idx: u8,
+    attributes: Option<TileAttributes>,
+    fetched: [ColorId; 8],
+    /// The background pixels left to throw away before pixels are sent to the LCD, for fine scrolling
+    discard: u8,
+    /// The x coordinate of the next pixel sent to the LCD
+    lcd_x: u8,
+    /// The objects on this line that haven't been fetched yet, with their priority rank, 0 being the highest
+    objects: Vec<(u8, ObjectAttributes)>,
+    /// The object being fetched with its rank, and the dots spent fetching it
+    obj_fetch: Option<((u8, ObjectAttributes), u8)>,
+    /// The number of dots run since mode 3 started
+    dots: u32,
+    done: bool,
+}
+
+impl Default for PixelFifo {
+    /// A FIFO that has already drawn its line
+    fn default() -> Self {
+        PixelFifo {
+            line: 0,
+            bg_fifo: VecDeque::new(),
+            obj_fifo: VecDeque::new(),
+            step: FetcherStep::Tile,
+            step_dots: 0,
+            first_fetch: true,
+            fetcher_x: 0,
+            window: false,
+            window_drawn: false,
+            tile_idx: 0,
+            attributes: None,
+            fetched: [ColorId::Id0; 8],
+            discard: 0,
+            lcd_x: 0,
+            objects: Vec::new(),
+            obj_fetch: None,
+            dots: 0,
+            done: true,
+        }
+    }
+}
+
+impl PixelFifo {
+    /// Start mode 3 of the current line, with the objects found during the OAM scan
+    fn start(ppu: &Ppu) -> Self {
+        PixelFifo {
+            line: ppu.line,
+            bg_fifo: VecDeque::with_capacity(16),
+            obj_fifo: VecDeque::with_capacity(8),
+            discard: ppu.viewport_offset.x % 8,
+            objects: Ppu::objects_on_line(
+                &ppu.obj_attribute_memory,
+                ppu.obj_size,
+                ppu.line,
+                ppu.cgb_mode,
+            )
+            .into_iter()
+            .enumerate()
+            .map(|(rank, obj)| (rank as u8, obj))
+            .collect(),
+            done: ppu.line >= 144,
+            ..Default::default()
+        }
+    }
+
+    /// Run a single dot of mode 3
+    fn dot(&mut self, ppu: &mut Ppu) {
+        self.dots += 1;
+        // mode 3 ends a dot after the last pixel is sent to the LCD
+        if self.lcd_x == 160 {
+            self.done = true;
+            return;
+        }
+        if let Some((obj, dots)) = self.obj_fetch {
+            // the background fetcher finishes fetching its tile before the object is fetched
+            if self.step != FetcherStep::Push {
+                self.fetcher_dot(ppu);
+            } else if dots + 1 < 6 {
+                self.obj_fetch = Some((obj, dots + 1));
+            } else {
+                self.merge_obj(ppu, obj);
+                self.obj_fetch = None;
+            }
+            return;
+        }
+        self.fetcher_dot(ppu);
+        if self.bg_fifo.is_empty() {
+            return;
+        }
+        if self.window && !ppu.window_enabled {
+            // the background continues from the next tile that isn't in the FIFO
+            self.window = false;
+            self.fetcher_x =
+                (self.lcd_x + self.bg_fifo.len() as u8 + ppu.viewport_offset.x % 8) / 8;
+        }
+        if !self.window && self.window_starts(ppu) {
+            let wx = ppu.window_top_left.x;
+            self.window = true;
+            self.window_drawn = true;
+            self.bg_fifo.clear();
+            self.fetcher_x = 0;
+            self.step = FetcherStep::Tile;
+            self.step_dots = 0;
+            // with WX < 7, the window starts left of the LCD
+            self.discard = if self.lcd_x == 0 {
+                7u8.saturating_sub(wx)
+            } else {
+                0
+            };
+            return;
+        }
+        if self.discard > 0 {
+            self.bg_fifo.pop_front();
+            self.discard -= 1;
+            return;
+        }
+        if ppu.obj_enabled {
+            let lcd_x = self.lcd_x as i16;
+            if let Some(idx) = self
+                .objects
+                .iter()
+                .position(|(_, obj)| obj.x_pos as i16 - 8 <= lcd_x)
+            {
+                self.obj_fetch = Some((self.objects.remove(idx), 0));
+                return;
+            }
+        }
+        let bg = self.bg_fifo.pop_front().expect("BUG: checked above");
+        let obj = self.obj_fifo.pop_front();
+        self.output_pixel(ppu, bg, obj);
+        self.lcd_x += 1;
+    }
+
+    fn window_starts(&self, ppu: &Ppu) -> bool {
+        Ppu::window_visible_on_line(
+            ppu.bg_enabled,
+            ppu.window_enabled,
+            ppu.window_top_left,
+            self.line,
+        ) && self.lcd_x + 7 >= ppu.window_top_left.x
+    }
+
+    /// The number of dots that mode 3 took, once the line is drawn
+    pub(super) fn mode_3_length(&self) -> Option<u32> {
+        self.done.then_some(self.dots)
+    }
+
+    /// Whether the window was drawn on the line, so that its line counter advances
+    pub(super) fn window_drawn(&self) -> bool {
+        self.window_drawn
+    }
+
+    /// The row of the background or window map that the fetcher fetches from. For the background, SCY is read again
+    /// for every fetch, so writes to it take effect from the next fetch.
+    fn map_row(&self, ppu: &Ppu) -> u8 {
+        if self.window {
+            ppu.window_line
+        } else {
+            ppu.viewport_offset.y.wrapping_add(self.line)
+        }
+    }
+
+    fn fetcher_dot(&mut self, ppu: &Ppu) {
+        if self.step == FetcherStep::Push {
+            self.push();
+            return;
+        }
+        self.step_dots += 1;
+        if self.step_dots < 2 {
+            return;
+        }
+        self.step_dots = 0;
+        self.step = match self.step {
+            FetcherStep::Tile => {
+                self.fetch_tile(ppu);
+                FetcherStep::DataLow
+            }
+            FetcherStep::DataLow => FetcherStep::DataHigh,
+            FetcherStep::DataHigh if self.first_fetch => {
+                self.first_fetch = false;
+                FetcherStep::Tile
+            }
+            FetcherStep::DataHigh => {
+                self.fetch_data(ppu);
+                FetcherStep::Push
+            }
+            FetcherStep::Push => unreachable!("pushing is handled above"),
+        };
+        if self.step == FetcherStep::Push {
+            self.push();
+        }
+    }
+
+    fn push(&mut self) {
+        if !self.bg_fifo.is_empty() {
+            return;
+        }
+        let attributes = self.attributes;
+        self.bg_fifo.extend(self.fetched.map(|color_id| BgPixel {
+            color_id,
+            attributes,
+        }));
+        self.fetcher_x = self.fetcher_x.wrapping_add(1);
+        self.step = FetcherStep::Tile;
+    }
+
+    fn fetch_tile(&mut self, ppu: &Ppu) {
+        let row = self.map_row(ppu);
+        let (map_select, col) = if self.window {
+            (ppu.window_tile_map_select, self.fetcher_x % 32)
+        } else {
+            (
+                ppu.bg_tile_map_select,
+                (ppu.viewport_offset.x / 8 + self.fetcher_x) % 32,
+            )
+        };
+        let (map, attribute_map) = match map_select {
+            TileMapArea::X9800 => (&ppu.lo_tile_map, &ppu.lo_tile_attributes),
+            TileMapArea::X9C00 => (&ppu.hi_tile_map, &ppu.hi_tile_attributes),
+        };
+        self.tile_idx = map.tile_indices[row as usize / 8][col as usize];
+        self.attributes = ppu
+            .cgb_mode
+            .then(|| attribute_map.attributes[row as usize / 8][col as usize]);
+    }
+
+    fn fetch_data(&mut self, ppu: &Ppu) {
+        let tiles = match self.attributes {
+            Some(attributes) if attributes.bank_1 => &ppu.vram_bank_1_tile_data,
+            _ => &ppu.vram_tile_data,
+        };
+        let tile = match ppu.bg_and_window_tile_data_select {
+            BgAndWindowTileDataArea::X8800 => tiles.get_tile_from_0x8800_signed(self.tile_idx),
+            BgAndWindowTileDataArea::X8000 => tiles.get_tile_from_0x8000(self.tile_idx),
+        };
+        let mut tile_row = self.map_row(ppu) % 8;
+        if self.attributes.is_some_and(|attributes| attributes.y_flip) {
+            tile_row = 7 - tile_row;
+        }
+        self.fetched = tile.lines[tile_row as usize].color_ids();
+        if self.attributes.is_some_and(|attributes| attributes.x_flip) {
+            self.fetched.reverse();
+        }
+    }
+
+    /// Mix the object's pixels into the object FIFO, where they replace transparent pixels and the pixels of objects
+    /// with lower priority.
+    fn merge_obj(&mut self, ppu: &Ppu, (rank, obj): (u8, ObjectAttributes)) {
+        let obj_size = ppu.obj_size;
+        let obj_lcd_y = obj.y_pos as i16 - 16;
+        let mut row = (self.line as i16 - obj_lcd_y) as usize;
+        if obj.y_flip {
+            row = obj_size.height() as usize - 1 - row;
+        }
+        let tile_idx = match obj_size {
+            ObjSize::Dim8x8 => obj.tile_idx,
+            ObjSize::Dim8x16 => (obj.tile_idx & 0b1111_1110) + row as u8 / 8,
+        };
+        let mut color_ids =
+            ppu.vram_tile_data.get_tile_from_0x8000(tile_idx).lines[row % 8].color_ids();
+        if obj.x_flip {
+            color_ids.reverse();
+        }
+        let transparent = ObjPixel {
+            color_id: ColorId::Id0,
+            rank: u8::MAX,
+            obj,
+        };
+        self.obj_fifo
+            .resize(8.max(self.obj_fifo.len()), transparent);
+        for (pixel_idx, color_id) in color_ids.into_iter().enumerate() {
+            // the position of the pixel in the FIFO, which starts at the next pixel sent to the LCD
+            let fifo_idx = obj.x_pos as i16 - 8 + pixel_idx as i16 - self.lcd_x as i16;
+            if fifo_idx < 0 {
+                continue;
+            }
+            let slot = &mut self.obj_fifo[fifo_idx as usize];
+            if color_id != ColorId::Id0 && (slot.color_id == ColorId::Id0 || rank < slot.rank) {
+                *slot = ObjPixel {
+                    color_id,
+                    rank,
+                    obj,
+                };
+            }
+        }
+    }
+
+    fn output_pixel(&self, ppu: &mut Ppu, bg: BgPixel, obj: Option<ObjPixel>) {
+        let (bg_color_id, attributes) = if ppu.bg_enabled {
+            (bg.color_id, bg.attributes)
+        } else {
+            (ColorId::Id0, None)
+        };
+        let bg_priority = attributes.is_some_and(|attributes| attributes.bg_over_obj_priority);
+        let obj = obj.filter(|obj| {
+            ppu.obj_enabled
+                && obj.color_id != ColorId::Id0
+                && ((obj.obj.bg_over_obj_priority == Priority::Zero && !bg_priority)
+                    || bg_color_id == ColorId::Id0)
+        });
+        let (shade, rgb, color_id) = match obj {
+            Some(ObjPixel { color_id, obj, .. }) => {
+                let palette_idx = match obj.palette {
+                    ObjColorPaletteIdx::Zero => 0,
+                    ObjColorPaletteIdx::One => 1,
+                };
+                let shade = ppu.obj_color_palettes[palette_idx].lookup(color_id);
+                let rgb = match ppu.cgb_mode {
+                    true => ppu.obj_palette_ram.color(obj.cgb_palette, color_id),
+                    false => ppu.dmg_palette.obj_color(palette_idx, shade),
+                };
+                (shade, rgb, color_id)
+            }
+            None if ppu.bg_enabled => {
+                let shade = ppu.bg_color_palette.lookup(bg_color_id);
+                let rgb = match attributes {
+                    Some(attributes) => ppu.bg_palette_ram.color(attributes.palette, bg_color_id),
+                    None => ppu.dmg_palette.bg_color(shade),
+                };
+                (shade, rgb, bg_color_id)
+            }
+            None => (
+                Color::White,
+                ppu.dmg_palette.bg_color(Color::White),
+                ColorId::Id0,
+            ),
+        };
+        ppu.lcd_display[self.line as usize].set_pixel(self.lcd_x, shade);
+        ppu.lcd_rgb_display[self.line as usize][self.lcd_x as usize] = rgb;
+        ppu.lcd_color_id_display[self.line as usize][self.lcd_x as usize] = color_id;
+    }
+}
+
+impl Ppu {
+    /// Called when mode 3 starts.
+    pub(super) fn start_pixel_fifo(&mut self) {
+        self.pixel_fifo = PixelFifo::start(self);
+    }
+
+    /// Run mode 3 until `dot` dots have passed since it started, or the line is done.
+    pub(super) fn run_pixel_fifo(&mut self, dot: u32) {
+        let mut fifo = std::mem::take(&mut self.pixel_fifo);
+        while !fifo.done && fifo.dots < dot {
+            fifo.dot(self);
+        }
+        self.pixel_fifo = fifo;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ppu::{ColorPalette, Mode, Position, Tile, TileLine};
+
+    fn run_frame(ppu: &mut Ppu) {
+        for _ in 0..154 * 456 / 4 {
+            ppu.step(4);
+        }
+    }
+
+    fn object(x_pos: u8, y_pos: u8, tile_idx: u8, flags: u8) -> ObjectAttributes {
+        ObjectAttributes {
+            y_pos,
+            x_pos,
+            tile_idx,
+            bg_over_obj_priority: if flags & 0x80 != 0 {
+                Priority::One
+            } else {
+                Priority::Zero
+            },
+            y_flip: flags & 0x40 != 0,
+            x_flip: flags & 0x20 != 0,
+            palette: if flags & 0x10 != 0 {
+                ObjColorPaletteIdx::One
+            } else {
+                ObjColorPaletteIdx::Zero
+            },
+            cgb_palette: 0,
+        }
+    }
+
+    #[test]
+    fn matches_the_scanline_renderer() {
+        let mut ppu = Ppu::new();
+        ppu.lcd_enabled = true;
+        ppu.bg_enabled = true;
+        ppu.obj_enabled = true;
+        ppu.window_enabled = true;
+        ppu.window_tile_map_select = TileMapArea::X9C00;
+        ppu.bg_and_window_tile_data_select = BgAndWindowTileDataArea::X8000;
+        ppu.viewport_offset = Position { x: 3, y: 5 };
+        ppu.window_top_left = Position { x: 50, y: 20 };
+        ppu.bg_color_palette = ColorPalette::from(0b00_01_10_11);
+        ppu.obj_color_palettes = [
+            ColorPalette::from(0b11_10_01_00),
+            ColorPalette::from(0b01_11_00_10),
+        ];
+        // tiles of diagonal stripes, which differ in every row and column
+        for block in &mut ppu.vram_tile_data.tile_data_blocks {
+            for (tile_idx, tile) in block.as_mut_slice().iter_mut().enumerate() {
+                *tile = Tile {
+                    lines: std::array::from_fn(|row| {
+                        TileLine::from_color_ids(std::array::from_fn(|col| {
+                            match (tile_idx + row + col) % 4 {
+                                0 => ColorId::Id0,
+                                1 => ColorId::Id1,
+                                2 => ColorId::Id2,
+                                _ => ColorId::Id3,
+                            }
+                        }))
+                    }),
+                };
+            }
+        }
+        for (row, tiles) in ppu.lo_tile_map.tile_indices.iter_mut().enumerate() {
+            for (col, tile_idx) in tiles.iter_mut().enumerate() {
+                *tile_idx = (row * 7 + col * 3) as u8;
+            }
+        }
+        for (row, tiles) in ppu.hi_tile_map.tile_indices.iter_mut().enumerate() {
+            for (col, tile_idx) in tiles.iter_mut().enumerate() {
+                *tile_idx = (row * 5 + col) as u8;
+            }
+        }
+        // 12 objects on lines 10 to 17, of which only the first 10 are drawn, overlapping each other and the edges. The
+        // objects behind the background don't overlap others, where the scanline renderer wrongly lets lower priority
+        // objects show through.
+        for (idx, obj) in ppu.obj_attribute_memory.iter_mut().take(12).enumerate() {
+            let x_pos = [4, 40, 24, 24, 60, 100, 104, 140, 160, 166, 30, 90][idx];
+            *obj = object(
+                x_pos,
+                26,
+                idx as u8,
+                [0x00, 0x80, 0x20, 0x40, 0x10, 0x60][idx % 6],
+            );
+        }
+        // objects over the window
+        ppu.obj_attribute_memory[20] = object(70, 50, 7, 0x80);
+        ppu.obj_attribute_memory[21] = object(150, 56, 8, 0x30);
+
+        let mut scanline = ppu.clone();
+        scanline.renderer = Renderer::Scanline;
+        run_frame(&mut ppu);
+        run_frame(&mut scanline);
+        for line in 0..144 {
+            assert_eq!(
+                ppu.last_full_frame[line].colors(),
+                scanline.last_full_frame[line].colors(),
+                "line {line}"
+            );
+            assert_eq!(
+                ppu.last_full_rgb_frame[line], scanline.last_full_rgb_frame[line],
+                "line {line}"
+            );
+            assert_eq!(
+                ppu.last_full_color_id_frame[line], scanline.last_full_color_id_frame[line],
+                "line {line}"
+            );
+        }
+    }
+
+    #[test]
+    fn mid_scanline_writes() {
+        let mut ppu = Ppu::new();
+        ppu.lcd_enabled = true;
+        ppu.bg_enabled = true;
+        ppu.vram_tile_data.tile_data_blocks[2].as_mut_slice()[0] = Tile {
+            lines: [TileLine::from_color_ids([ColorId::Id1; 8]); 8],
+        };
+        ppu.bg_color_palette = ColorPalette::from(0b00_00_01_00);
+        ppu.step(80);
+        assert_eq!(ppu.mode, Mode::ScanlineVRAM);
+        ppu.step(80);
+        ppu.bg_color_palette = ColorPalette::from(0b00_00_11_00);
+        ppu.step(92);
+        assert_eq!(ppu.mode, Mode::HorizontalBlank);
+        let colors = ppu.lcd_display[0].colors();
+        assert_eq!(colors[..40], [Color::LightGray; 40]);
+        assert_eq!(colors[120..], [Color::Black; 40]);
+
+        // the scanline renderer draws the whole line with the palette at the end of mode 3
+        ppu.renderer = Renderer::Scanline;
+        ppu.bg_color_palette = ColorPalette::from(0b00_00_01_00);
+        ppu.step(204);
+        ppu.step(80);
+        ppu.step(80);
+        ppu.bg_color_palette = ColorPalette::from(0b00_00_11_00);
+        ppu.step(92);
+        assert_eq!(ppu.lcd_display[1].colors(), [Color::Black; 160]);
+    }
+
+    /// Run line 0 and return how long its mode 3 took
+    fn mode_3_length(ppu: &mut Ppu) -> u32 {
+        ppu.line = 0;
+        ppu.mode = Mode::ScanlineOAM;
+        ppu.cycles_in_mode = 0;
+        let mut dots = 0;
+        let mut mode_3_dots = 0;
+        while ppu.line == 0 {
+            ppu.step(1);
+            dots += 1;
+            if ppu.mode == Mode::ScanlineVRAM {
+                mode_3_dots += 1;
+            }
+        }
+        assert_eq!(
+            dots, 456,
+            "the line should take 456 dots regardless of mode 3"
+        );
+        assert_eq!(mode_3_dots, ppu.mode_3_length);
+        ppu.mode_3_length
+    }
+
+    #[test]
+    fn mode_3_length_penalties() {
+        for renderer in [Renderer::PixelFifo, Renderer::Scanline] {
+            let mut ppu = Ppu::new();
+            ppu.renderer = renderer;
+            ppu.lcd_enabled = true;
+            ppu.bg_enabled = true;
+            ppu.obj_enabled = true;
+            assert_eq!(mode_3_length(&mut ppu), 172, "{renderer:?}");
+
+            // the pixels thrown away for fine scrolling
+            ppu.viewport_offset.x = 5;
+            assert_eq!(mode_3_length(&mut ppu), 177, "{renderer:?}");
+
+            // fetching an object stalls the background fetcher
+            ppu.viewport_offset.x = 0;
+            ppu.obj_attribute_memory[0] = object(50, 16, 0, 0);
+            assert!(
+                (178..=183).contains(&mode_3_length(&mut ppu)),
+                "{renderer:?}"
+            );
+        }
+    }
+
+    fn run_dots(ppu: &mut Ppu, dots: u32) {
+        for _ in 0..dots {
+            ppu.step(1);
+        }
+    }
+
+    #[test]
+    fn mid_scanline_scroll_and_window_writes() {
+        let mut ppu = Ppu::new();
+        ppu.lcd_enabled = true;
+        ppu.bg_enabled = true;
+        ppu.bg_color_palette = ColorPalette::from(0b11_10_01_00);
+        ppu.bg_and_window_tile_data_select = BgAndWindowTileDataArea::X8000;
+        ppu.window_tile_map_select = TileMapArea::X9C00;
+        // the background's first row of tiles has color id 1 and its second, color id 2. The window has color id 3.
+        for (tile_idx, color_id) in [ColorId::Id1, ColorId::Id2, ColorId::Id3]
+            .into_iter()
+            .enumerate()
+        {
+            ppu.vram_tile_data.tile_data_blocks[0].as_mut_slice()[tile_idx + 1] = Tile {
+                lines: [TileLine::from_color_ids([color_id; 8]); 8],
+            };
+        }
+        ppu.lo_tile_map.tile_indices[0].fill(1);
+        ppu.lo_tile_map.tile_indices[1].fill(2);
+        ppu.hi_tile_map.tile_indices = [[3; 32]; 32];
+
+        // SCY is read for every tile fetch
+        run_dots(&mut ppu, 140);
+        ppu.viewport_offset.y = 8;
+        run_dots(&mut ppu, 316);
+        let colors = ppu.lcd_display[0].colors();
+        assert_eq!(colors[..32], [Color::LightGray; 32]);
+        assert_eq!(colors[120..], [Color::DarkGray; 40]);
+
+        // the background continues when the window is disabled
+        ppu.viewport_offset.y = 0;
+        ppu.window_enabled = true;
+        ppu.window_top_left = Position { x: 7, y: 0 };
+        run_dots(&mut ppu, 140);
+        ppu.window_enabled = false;
+        run_dots(&mut ppu, 316);
+        let colors = ppu.lcd_display[1].colors();
+        assert_eq!(colors[..32], [Color::Black; 32]);
+        assert_eq!(colors[120..], [Color::LightGray; 40]);
+        assert_eq!(ppu.window_line, 1, "the window was drawn on line 1");
+
+        // the window starts when WX is written to a position that hasn't been drawn yet
+        ppu.window_enabled = true;
+        ppu.window_top_left.x = 200;
+        run_dots(&mut ppu, 140);
+        ppu.window_top_left.x = 107;
+        run_dots(&mut ppu, 316);
+        let colors = ppu.lcd_display[2].colors();
+        assert_eq!(colors[..32], [Color::LightGray; 32]);
+        assert_eq!(colors[100..], [Color::Black; 60]);
+    }
+}
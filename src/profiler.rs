@@ -0,0 +1,93 @@
+//! Lightweight host-side timers, used to show where the time goes in each frame.
+use std::time::Duration;
+
+/// The parts of a host frame that are timed separately.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Section {
+    /// Executing instructions, excluding the time spent drawing scanlines.
+    CpuStep,
+    /// Drawing scanlines in the PPU.
+    PpuRender,
+    /// Converting the LCD to pixels and uploading it to the GPU.
+    TextureUpload,
+    /// Resolving and drawing the background, window, and object debug views.
+    DebugViews,
+}
+
+impl Section {
+    pub const ALL: [Section; 4] = [
+        Section::CpuStep,
+        Section::PpuRender,
+        Section::TextureUpload,
+        Section::DebugViews,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Section::CpuStep => "cpu",
+            Section::PpuRender => "ppu",
+            Section::TextureUpload => "upload",
+            Section::DebugViews => "debug",
+        }
+    }
+}
+
+/// Accumulates the time spent in each [`Section`] during a frame.
+#[derive(Debug, Default)]
+pub struct Profiler {
+    current_frame: [Duration; Section::ALL.len()],
+    last_frame: [Duration; Section::ALL.len()],
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, section: Section, duration: Duration) {
+        self.current_frame[section as usize] += duration;
+    }
+
+    /// Remove time that was attributed to `section` but was actually spent elsewhere.
+    pub fn subtract(&mut self, section: Section, duration: Duration) {
+        let total = &mut self.current_frame[section as usize];
+        *total = total.saturating_sub(duration);
+    }
+
+    /// Publish the timings of the current frame and start timing a new one.
+    pub fn end_frame(&mut self) {
+        self.last_frame = std::mem::take(&mut self.current_frame);
+    }
+
+    /// The time spent in `section` during the last completed frame.
+    pub fn last_frame(&self, section: Section) -> Duration {
+        self.last_frame[section as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Profiler, Section};
+
+    #[test]
+    fn end_frame_publishes_and_resets() {
+        let mut profiler = Profiler::new();
+        profiler.add(Section::CpuStep, Duration::from_millis(3));
+        profiler.add(Section::PpuRender, Duration::from_millis(1));
+        profiler.subtract(Section::CpuStep, Duration::from_millis(1));
+        assert_eq!(profiler.last_frame(Section::CpuStep), Duration::ZERO);
+        profiler.end_frame();
+        assert_eq!(
+            profiler.last_frame(Section::CpuStep),
+            Duration::from_millis(2)
+        );
+        assert_eq!(
+            profiler.last_frame(Section::PpuRender),
+            Duration::from_millis(1)
+        );
+        profiler.end_frame();
+        assert_eq!(profiler.last_frame(Section::CpuStep), Duration::ZERO);
+    }
+}
@@ -0,0 +1,317 @@
+//! Rewind ring buffer and the time-travel queries built on top of it.
+//!
+//! When enabled, the emulator serializes its state every few frames into a fixed-size ring buffer.
+//! Together with a log of input changes, the snapshots can be used to restore an earlier state, or to
+//! answer questions like "when did 0xC123 become 0x05?" by re-executing from a snapshot.
+use std::collections::VecDeque;
+
+use enumset::EnumSet;
+
+use crate::joypad::Button;
+use crate::mmu::Memory;
+use crate::Emulator;
+
+pub struct RewindBuffer {
+    snapshots: VecDeque<Snapshot>,
+    /// The maximum number of snapshots to keep. Older snapshots are evicted first.
+    capacity: usize,
+    /// Take a snapshot every `interval_frames` frames.
+    interval_frames: u64,
+    /// Every change to the pressed buttons since the oldest snapshot, as (cycle count, buttons) pairs.
+    ///
+    /// Replaying these is what makes re-execution from a snapshot deterministic.
+    input_log: VecDeque<(u64, EnumSet<Button>)>,
+}
+
+struct Snapshot {
+    cycle: u64,
+    /// The message pack serialized emulator
+    state: Vec<u8>,
+}
+
+/// The result of a time-travel query.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ValueChange {
+    /// The address of the instruction that caused the value to change.
+    pub pc: u16,
+    /// The frame in which the value changed.
+    pub frame: u64,
+    /// The cycle count right after the instruction that changed the value was executed.
+    pub cycle: u64,
+    /// The value before the change.
+    pub previous: u8,
+}
+
+impl RewindBuffer {
+    pub(crate) fn new(capacity: usize, interval_frames: u64) -> Self {
+        assert!(capacity > 0, "Rewind buffer capacity must be > 0");
+        assert!(interval_frames > 0, "Rewind interval must be > 0 frames");
+        RewindBuffer {
+            snapshots: VecDeque::with_capacity(capacity),
+            capacity,
+            interval_frames,
+            input_log: VecDeque::new(),
+        }
+    }
+
+    pub(crate) fn record_input(&mut self, cycle: u64, buttons: EnumSet<Button>) {
+        self.input_log.push_back((cycle, buttons));
+    }
+
+    fn push(&mut self, snapshot: Snapshot) {
+        if self.snapshots.len() == self.capacity {
+            self.snapshots.pop_front();
+        }
+        self.snapshots.push_back(snapshot);
+        // inputs from before the oldest snapshot can never be replayed
+        let oldest_cycle = self.snapshots[0].cycle;
+        while self
+            .input_log
+            .front()
+            .is_some_and(|&(cycle, _)| cycle < oldest_cycle)
+        {
+            self.input_log.pop_front();
+        }
+    }
+
+    /// The number of snapshots currently stored.
+    pub fn len(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.snapshots.is_empty()
+    }
+}
+
+impl Emulator {
+    /// Start recording a snapshot every `interval_frames` frames, keeping at most `capacity` snapshots.
+    pub fn enable_rewind(&mut self, capacity: usize, interval_frames: u64) {
+        self.rewind = Some(RewindBuffer::new(capacity, interval_frames));
+    }
+
+    pub fn disable_rewind(&mut self) {
+        self.rewind = None;
+    }
+
+    pub fn rewind_buffer(&self) -> Option<&RewindBuffer> {
+        self.rewind.as_ref()
+    }
+
+    /// Called at the end of every frame.
+    pub(crate) fn record_rewind_snapshot(&mut self) {
+        let Some(rewind) = &self.rewind else {
+            return;
+        };
+        if !self.frame_count.is_multiple_of(rewind.interval_frames) {
+            return;
+        }
+        let state = rmp_serde::to_vec(self).expect("BUG: failed to serialize rewind snapshot");
+        let snapshot = Snapshot {
+            cycle: self.cycle_count,
+            state,
+        };
+        if let Some(rewind) = &mut self.rewind {
+            rewind.push(snapshot);
+        }
+    }
+
+    /// Restore the most recent snapshot, removing it from the rewind buffer.
+    ///
+    /// Returns false if there is no snapshot to rewind to.
+    pub fn rewind(&mut self) -> bool {
+        let Some(mut rewind) = self.rewind.take() else {
+            return false;
+        };
+        let Some(snapshot) = rewind.snapshots.pop_back() else {
+            self.rewind = Some(rewind);
+            return false;
+        };
+        let restored = self.restore_snapshot(&snapshot);
+        // the inputs after the snapshot belong to a future that no longer exists
+        rewind
+            .input_log
+            .retain(|&(cycle, _)| cycle < snapshot.cycle);
+        self.replace_state(restored);
+        self.rewind = Some(rewind);
+        true
+    }
+
+    /// Find the instruction that most recently changed the byte at `addr` to `value`.
+    ///
+    /// Execution is replayed with a watchpoint on `addr` from each stored snapshot up to the next one (or the present),
+    /// newest first, until a replay changes the byte to `value`. Snapshots that hold `value` don't rule out a later
+    /// change, since the byte may have changed and changed back in between.
+    ///
+    /// Returns `None` if the byte does not currently hold `value`, or if the change happened before the oldest snapshot.
+    pub fn find_last_change(&self, addr: u16, value: u8) -> Option<ValueChange> {
+        let rewind = self.rewind.as_ref()?;
+        if self.cpu.mmu.read_byte_unobserved(addr) != value {
+            return None;
+        }
+        let end_cycles: Vec<u64> = rewind
+            .snapshots
+            .iter()
+            .skip(1)
+            .map(|snapshot| snapshot.cycle)
+            .chain([self.cycle_count])
+            .collect();
+        rewind
+            .snapshots
+            .iter()
+            .zip(end_cycles)
+            .rev()
+            .find_map(|(start, end_cycle)| self.replay_for_change(start, end_cycle, addr, value))
+    }
+
+    /// The last change of the byte at `addr` to `value` when replaying from `start` up to `end_cycle`.
+    fn replay_for_change(
+        &self,
+        start: &Snapshot,
+        end_cycle: u64,
+        addr: u16,
+        value: u8,
+    ) -> Option<ValueChange> {
+        let rewind = self.rewind.as_ref()?;
+        let mut emu = self.restore_snapshot(start);
+        let mut inputs = rewind
+            .input_log
+            .iter()
+            .filter(|&&(cycle, _)| cycle >= start.cycle)
+            .peekable();
+        let mut last_change = None;
+        while emu.cycle_count < end_cycle {
+            while let Some(&(_, buttons)) = inputs.next_if(|&&(cycle, _)| cycle <= emu.cycle_count)
+            {
+                emu.cpu.mmu.set_pressed_buttons(buttons);
+            }
+            let previous = emu.cpu.mmu.read_byte_unobserved(addr);
+            let pc = emu.cpu.regs.pc;
+            // an illegal opcode was already reported when the game first ran into it
+            let _ = emu.step();
+            if previous != value && emu.cpu.mmu.read_byte_unobserved(addr) == value {
+                last_change = Some(ValueChange {
+                    pc,
+                    frame: emu.frame_count,
+                    cycle: emu.cycle_count,
+                    previous,
+                });
+            }
+        }
+        last_change
+    }
+
+    fn restore_snapshot(&self, snapshot: &Snapshot) -> Emulator {
+        let mut emu: Emulator = rmp_serde::from_slice(&snapshot.state)
+            .expect("BUG: failed to deserialize rewind snapshot");
+        emu.cpu.mmu.set_cart_rom(&self.rom);
+        emu.rom = self.rom.clone();
+        emu.save_dir = self.save_dir.clone();
+        emu.illegal_opcode_policy = self.illegal_opcode_policy;
+        emu
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mmu::Memory;
+    use crate::util::{emulator_for, emulator_running, rom_with_program, with_large_stack};
+
+    /// A program that turns on the LCD, then counts up forever, storing the counter at 0xC123
+    fn counter_rom() -> Vec<u8> {
+        rom_with_program(&[
+            0x3E, 0x80, // LD A,0x80
+            0xE0, 0x40, // LDH [0x40],A   (turn on the LCD)
+            0x04, // 0x0004: INC B
+            0x78, // LD A,B
+            0xEA, 0x23, 0xC1, // 0x0006: LD [0xC123],A
+            0x18, 0xF9, // JR 0x0004
+        ])
+    }
+
+    #[test]
+    fn find_last_change_reports_writing_instruction() {
+        with_large_stack(find_last_change_reports_writing_instruction_impl);
+    }
+
+    fn find_last_change_reports_writing_instruction_impl() {
+        let rom = counter_rom();
+        let mut emu = emulator_for(&rom);
+        emu.enable_rewind(8, 1);
+        while emu.frame_count() < 5 {
+            emu.step().unwrap();
+        }
+        while emu.cpu.mmu.read_byte(0xC123) != 0x05 {
+            emu.step().unwrap();
+        }
+        let change = emu.find_last_change(0xC123, 0x05).unwrap();
+        assert_eq!(change.pc, 0x0006);
+        assert_eq!(change.previous, 0x04);
+        assert_eq!(change.cycle, emu.cycle_count());
+        assert_eq!(change.frame, emu.frame_count());
+
+        // the value doesn't currently hold 0x06
+        assert_eq!(emu.find_last_change(0xC123, 0x06), None);
+    }
+
+    #[test]
+    fn find_last_change_of_a_value_that_comes_back() {
+        with_large_stack(find_last_change_of_a_value_that_comes_back_impl);
+    }
+
+    fn find_last_change_of_a_value_that_comes_back_impl() {
+        let mut emu = emulator_running(&[
+            0x3E, 0x80, // LD A,0x80
+            0xE0, 0x40, // LDH [0x40],A   (turn on the LCD)
+            0xF0, 0x44, // 0x0004: LDH A,[0x44]
+            0xFE, 0x90, // CP 0x90
+            0x20, 0xFA, // JR NZ,0x0004   (wait for VBlank)
+            0xFA, 0x23, 0xC1, // LD A,[0xC123]
+            0xEE, 0x01, // XOR 0x01
+            0xEA, 0x23, 0xC1, // 0x000F: LD [0xC123],A
+            0xF0, 0x44, // 0x0012: LDH A,[0x44]
+            0xFE, 0x00, // CP 0x00
+            0x20, 0xFA, // JR NZ,0x0012   (wait for the next frame)
+            0x18, 0xEA, // JR 0x0004
+        ]);
+        emu.enable_rewind(8, 1);
+        // the value flips at the start of every frame, so the snapshots alternate between holding it and not
+        while emu.frame_count() < 6 {
+            emu.step().unwrap();
+        }
+        loop {
+            let previous = emu.cpu.mmu.read_byte(0xC123);
+            emu.step().unwrap();
+            if previous == 0x00 && emu.cpu.mmu.read_byte(0xC123) == 0x01 {
+                break;
+            }
+        }
+        let (cycle, frame) = (emu.cycle_count(), emu.frame_count());
+        for _ in 0..1000 {
+            emu.step().unwrap();
+        }
+        let change = emu.find_last_change(0xC123, 0x01).unwrap();
+        assert_eq!(change.pc, 0x000F);
+        assert_eq!(change.previous, 0x00);
+        assert_eq!((change.cycle, change.frame), (cycle, frame));
+    }
+
+    #[test]
+    fn rewind_restores_previous_snapshot() {
+        with_large_stack(rewind_restores_previous_snapshot_impl);
+    }
+
+    fn rewind_restores_previous_snapshot_impl() {
+        let rom = counter_rom();
+        let mut emu = emulator_for(&rom);
+        emu.enable_rewind(4, 2);
+        while emu.frame_count() < 7 {
+            emu.step().unwrap();
+        }
+        assert!(emu.rewind());
+        assert_eq!(emu.frame_count(), 6);
+        assert!(emu.rewind());
+        assert_eq!(emu.frame_count(), 4);
+        assert_eq!(emu.rewind_buffer().unwrap().len(), 1);
+    }
+}
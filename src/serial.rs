@@ -0,0 +1,211 @@
+//! The serial port, SB (0xFF01) and SC (0xFF02), and the devices that the link cable connects it to.
+//!
+//! https://gbdev.io/pandocs/Serial_Data_Transfer_(Link_Cable).html
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+
+use crate::util::U8Ext;
+
+/// Which end of the link cable drives the clock of a transfer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Clock {
+    /// This Game Boy shifts the bits at its own pace
+    Internal,
+    /// The other end shifts the bits, and this Game Boy waits for it
+    External,
+}
+
+/// Whatever is at the other end of the link cable.
+pub trait SerialDevice: Send {
+    /// Exchange a byte with the other end: `sent` goes out, and the returned byte comes in.
+    ///
+    /// With the internal clock, this is called once when the transfer starts, and `None` receives 0xFF like an
+    /// unplugged cable. With the external clock, this is called repeatedly while the Game Boy waits, until the other
+    /// end clocks the transfer by returning the byte that it sent.
+    fn exchange(&mut self, sent: u8, clock: Clock) -> Option<u8>;
+}
+
+/// No cable: transfers with the internal clock receive 0xFF, and transfers with the external clock never happen.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Disconnected;
+
+impl SerialDevice for Disconnected {
+    fn exchange(&mut self, _sent: u8, _clock: Clock) -> Option<u8> {
+        None
+    }
+}
+
+/// A cable whose output is wired to its input, so that transfers with the internal clock receive the byte sent.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Loopback;
+
+impl SerialDevice for Loopback {
+    fn exchange(&mut self, sent: u8, clock: Clock) -> Option<u8> {
+        (clock == Clock::Internal).then_some(sent)
+    }
+}
+
+/// Records the bytes sent with the internal clock, and receives 0xFF like [`Disconnected`]. Clones share the
+/// buffer, so keep a clone to read the bytes after connecting one to the emulator, e.g. the results that Blargg's
+/// test ROMs print.
+#[derive(Debug, Clone, Default)]
+pub struct Capture(Arc<Mutex<Vec<u8>>>);
+
+impl Capture {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The bytes sent since the last call
+    pub fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut self.0.lock().unwrap())
+    }
+}
+
+impl SerialDevice for Capture {
+    fn exchange(&mut self, sent: u8, clock: Clock) -> Option<u8> {
+        if clock == Clock::Internal {
+            self.0.lock().unwrap().push(sent);
+        }
+        None
+    }
+}
+
+fn disconnected() -> Box<dyn SerialDevice> {
+    Box::new(Disconnected)
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct SerialPort {
+    /// SB. Bits are shifted out at the top, and shifted in at the bottom.
+    data: u8,
+    /// SC bit 7
+    transferring: bool,
+    /// SC bit 0: this Game Boy drives the clock, rather than the other end of the cable
+    internal_clock: bool,
+    /// SC bit 1, CGB mode only: shift at 262144 Hz instead of 8192 Hz
+    fast_clock: bool,
+    /// The byte being received with the internal clock, shifted in a bit at a time
+    incoming: u8,
+    bits_left: u8,
+    /// The T-cycles since the last bit was shifted
+    t_cycles: u16,
+    #[serde(skip, default = "disconnected")]
+    pub(crate) device: Box<dyn SerialDevice>,
+}
+
+impl Default for SerialPort {
+    fn default() -> Self {
+        SerialPort {
+            data: 0,
+            transferring: false,
+            internal_clock: false,
+            fast_clock: false,
+            incoming: 0xFF,
+            bits_left: 0,
+            t_cycles: 0,
+            device: disconnected(),
+        }
+    }
+}
+
+impl SerialPort {
+    pub(crate) fn read_data(&self) -> u8 {
+        self.data
+    }
+
+    pub(crate) fn read_control(&self, cgb_mode: bool) -> u8 {
+        // the unused bits read as 1, including the clock speed outside of CGB mode
+        let fast_clock = if cgb_mode { self.fast_clock } else { true };
+        0x7C | ((self.transferring as u8) << 7)
+            | ((fast_clock as u8) << 1)
+            | self.internal_clock as u8
+    }
+
+    pub(crate) fn write_data(&mut self, byte: u8) {
+        self.data = byte;
+    }
+
+    pub(crate) fn write_control(&mut self, byte: u8, cgb_mode: bool) {
+        self.transferring = byte.bit(7);
+        self.fast_clock = cgb_mode && byte.bit(1);
+        self.internal_clock = byte.bit(0);
+        self.bits_left = 8;
+        self.t_cycles = 0;
+        if self.transferring && self.internal_clock {
+            self.incoming = self
+                .device
+                .exchange(self.data, Clock::Internal)
+                .unwrap_or(0xFF);
+        }
+    }
+
+    /// Shift the bits of a transfer with the internal clock, or complete a transfer with the external clock once the
+    /// other end clocks it. `t_cycles` are CPU clock cycles, since the serial clock runs twice as fast in double speed
+    /// mode. Returns whether the transfer completed, which requests the serial interrupt.
+    pub(crate) fn step(&mut self, t_cycles: u8) -> bool {
+        if !self.transferring {
+            return false;
+        }
+        if !self.internal_clock {
+            let Some(received) = self.device.exchange(self.data, Clock::External) else {
+                return false;
+            };
+            self.data = received;
+            self.transferring = false;
+            return true;
+        }
+        let t_cycles_per_bit = if self.fast_clock { 16 } else { 512 };
+        self.t_cycles += t_cycles as u16;
+        while self.t_cycles >= t_cycles_per_bit {
+            self.t_cycles -= t_cycles_per_bit;
+            self.data = (self.data << 1) | (self.incoming >> 7);
+            self.incoming <<= 1;
+            self.bits_left -= 1;
+            if self.bits_left == 0 {
+                self.transferring = false;
+                return true;
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Capture, Loopback, SerialPort};
+
+    #[test]
+    fn internal_clock_transfer() {
+        let capture = Capture::new();
+        let mut port = SerialPort {
+            device: Box::new(capture.clone()),
+            ..SerialPort::default()
+        };
+        port.write_data(0x42);
+        port.write_control(0x81, false);
+        assert_eq!(port.read_control(false), 0xFF);
+        for _ in 0..7 * 512 / 4 {
+            assert!(!port.step(4));
+        }
+        assert_eq!(port.read_data(), 0x7F);
+        assert!((0..512 / 4).any(|_| port.step(4)));
+        assert_eq!(port.read_data(), 0xFF);
+        assert_eq!(port.read_control(false), 0x7F);
+        assert_eq!(capture.take(), [0x42]);
+
+        // nothing is shifted without a clock from the other end
+        port.write_data(0x00);
+        port.write_control(0x80, false);
+        assert!(!port.step(255));
+        assert_eq!(port.read_data(), 0x00);
+        assert!(capture.take().is_empty());
+
+        port.device = Box::new(Loopback);
+        port.write_data(0xA5);
+        port.write_control(0x83, true);
+        assert!((0..8 * 16 / 4).any(|_| port.step(4)));
+        assert_eq!(port.read_data(), 0xA5);
+    }
+}
@@ -0,0 +1,90 @@
+//! Symbol names for addresses, loaded from the `.sym` files that RGBDS and other assemblers produce.
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// Labels by bank and address.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SymbolTable {
+    by_location: HashMap<(u16, u16), String>,
+}
+
+impl SymbolTable {
+    /// The label at `addr` when `rom_bank` is mapped at 0x4000-0x7FFF.
+    ///
+    /// Labels outside of cartridge ROM are matched in any bank, since their bank (of VRAM, WRAM, or cartridge RAM)
+    /// isn't tracked.
+    pub fn lookup(&self, addr: u16, rom_bank: usize) -> Option<&str> {
+        let bank = match addr {
+            0x0000..=0x3FFF => 0,
+            0x4000..=0x7FFF => rom_bank as u16,
+            _ => {
+                return self
+                    .by_location
+                    .iter()
+                    .filter(|((_, label_addr), _)| *label_addr == addr)
+                    .map(|(_, label)| label.as_str())
+                    .min();
+            }
+        };
+        self.by_location.get(&(bank, addr)).map(String::as_str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_location.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_location.is_empty()
+    }
+}
+
+/// Parse `BB:AAAA Label` lines, with the bank and address in hex. Everything after a `;` is a comment.
+impl FromStr for SymbolTable {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut by_location = HashMap::new();
+        for (line_idx, line) in s.lines().enumerate() {
+            let line = line.split(';').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let parse_line = || {
+                let (location, label) = line.split_once(char::is_whitespace)?;
+                let (bank, addr) = location.split_once(':')?;
+                let bank = u16::from_str_radix(bank, 16).ok()?;
+                let addr = u16::from_str_radix(addr, 16).ok()?;
+                Some(((bank, addr), label.trim().to_string()))
+            };
+            let (location, label) = parse_line()
+                .ok_or_else(|| format!("invalid symbol on line {}: {line:?}", line_idx + 1))?;
+            // keep the first of several labels at the same address, which is usually the least local one
+            by_location.entry(location).or_insert(label);
+        }
+        Ok(SymbolTable { by_location })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SymbolTable;
+
+    #[test]
+    fn parse_and_lookup() {
+        let symbols: SymbolTable = "; File generated by rgblink\n\
+            00:0150 Main\n\
+            00:0150 Main.loop\n\
+            01:4000 BankedRoutine\n\
+            02:4000 OtherBank ; comment\n\
+            00:C000 wBuffer\n"
+            .parse()
+            .unwrap();
+        assert_eq!(symbols.len(), 4);
+        assert_eq!(symbols.lookup(0x0150, 1), Some("Main"));
+        assert_eq!(symbols.lookup(0x4000, 1), Some("BankedRoutine"));
+        assert_eq!(symbols.lookup(0x4000, 2), Some("OtherBank"));
+        assert_eq!(symbols.lookup(0x4000, 3), None);
+        assert_eq!(symbols.lookup(0xC000, 1), Some("wBuffer"));
+        assert!("0150 Main".parse::<SymbolTable>().is_err());
+    }
+}
@@ -1,3 +1,7 @@
+//! DIV and the timer (TIMA, TMA, and TAC), which are both driven by a 16-bit counter that increments every T-cycle.
+//! The APU's frame sequencer is clocked by the same counter, so the MMU lets both observe every change to it.
+//!
+//! https://gbdev.io/pandocs/Timer_Obscure_Behaviour.html
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
@@ -10,20 +14,62 @@ pub enum TimerFrequency {
 }
 
 impl TimerFrequency {
-    /// The timer's frequency can be expressed as the number of system clock cycles (T-cycles) per tick of the timer.
+    /// The bit of the divider's internal counter whose falling edges increment TIMA.
     ///
-    /// The system clock runs at 4 MiHZ, so we divide the system clock frequency by the timer frequency to get the number of clock cycles per timer tick.
-    fn t_cycles_per_tick(self) -> u16 {
+    /// The counter increments at the 4 MiHz system clock, and bit n falls every 2^(n+1) T-cycles.
+    fn counter_bit(self) -> u16 {
         use TimerFrequency::*;
         match self {
-            F4KiHz => 1024,
-            F16KiHz => 256,
-            F64KiHz => 64,
-            F256KiHz => 16,
+            F4KiHz => 9,
+            F16KiHz => 7,
+            F64KiHz => 5,
+            F256KiHz => 3,
         }
     }
 }
 
+/// The system counter. DIV is its upper byte, and writing DIV resets the whole counter.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Divider {
+    counter: u16,
+}
+
+impl Divider {
+    /// The APU's frame sequencer observes the falling edges of bit 12 (bit 4 of DIV).
+    pub fn internal_counter(&self) -> u16 {
+        self.counter
+    }
+
+    pub fn div(&self) -> u8 {
+        (self.counter >> 8) as u8
+    }
+
+    /// Set DIV without resetting the lower byte of the counter, e.g. to the value that the boot ROM leaves behind.
+    pub fn set_div(&mut self, div: u8) {
+        self.counter = (div as u16) << 8 | self.counter & 0xFF;
+    }
+
+    pub fn advance(&mut self, t_cycles: u8) {
+        self.counter = self.counter.wrapping_add(t_cycles as u16);
+    }
+
+    /// Reset the counter, which happens when DIV is written.
+    pub fn reset(&mut self) {
+        self.counter = 0;
+    }
+}
+
+/// Where TIMA is in reloading from TMA after an overflow
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+enum Reload {
+    Idle,
+    /// TIMA overflowed during this M-cycle and reads 0x00. Writing TIMA now cancels the reload and the interrupt.
+    Pending,
+    /// TIMA was loaded from TMA at the start of this M-cycle. Writes to TIMA are ignored, and writes to TMA also go to
+    /// TIMA.
+    Reloaded,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Timer {
     pub frequency: TimerFrequency,
@@ -32,47 +78,190 @@ pub struct Timer {
     ///
     /// When the timer overflows, it is reset to the value in this register.
     pub tma: u8,
+    /// TIMA
     pub value: u8,
-    /// The number of t-cycles since the last tick of the timer
-    t_cycles_count: u16,
+    reload: Reload,
 }
 
 impl Timer {
-    pub fn disabled(frequency: TimerFrequency) -> Self {
+    pub fn new() -> Self {
         Timer {
-            frequency,
+            frequency: TimerFrequency::F4KiHz,
             enabled: false,
             tma: 0,
             value: 0,
-            t_cycles_count: 0,
+            reload: Reload::Idle,
         }
     }
 
-    pub fn enabled(frequency: TimerFrequency) -> Self {
-        Timer {
-            frequency,
-            enabled: true,
-            tma: 0,
-            value: 0,
-            t_cycles_count: 0,
+    /// Advance a reload from TMA by an M-cycle. TIMA itself only increments when the divider's counter changes, see
+    /// [`Timer::observe_counter`].
+    ///
+    /// Returns whether the timer interrupt is requested, which happens an M-cycle after TIMA overflows.
+    pub fn step_reload(&mut self) -> bool {
+        match self.reload {
+            Reload::Pending => {
+                self.value = self.tma;
+                self.reload = Reload::Reloaded;
+                true
+            }
+            Reload::Reloaded => {
+                self.reload = Reload::Idle;
+                false
+            }
+            Reload::Idle => false,
         }
     }
 
-    /// Update the state of the timer by simulating `tCycles` T-cycles and return whether the timer overflowed.
-    pub fn update(&mut self, t_cycles: u8) -> bool {
-        if !self.enabled {
-            return false;
+    /// Increment TIMA if the counter changing from `before` to `after` made the selected bit fall. Besides the counter
+    /// ticking, this happens when DIV is written while the bit is set.
+    pub fn observe_counter(&mut self, before: u16, after: u16) {
+        if self.signal(before) && !self.signal(after) {
+            self.increment();
         }
+    }
 
-        self.t_cycles_count += t_cycles as u16;
-        while self.t_cycles_count >= self.frequency.t_cycles_per_tick() {
-            self.t_cycles_count -= self.frequency.t_cycles_per_tick();
-            self.value = self.value.wrapping_add(1);
-            if self.value == 0 {
-                self.value = self.tma;
-                return true;
+    /// TIMA follows the selected bit of the counter ANDed with the enable bit of TAC.
+    fn signal(&self, counter: u16) -> bool {
+        self.enabled && counter & (1 << self.frequency.counter_bit()) != 0
+    }
+
+    fn increment(&mut self) {
+        let (value, overflowed) = self.value.overflowing_add(1);
+        self.value = value;
+        if overflowed {
+            self.reload = Reload::Pending;
+        }
+    }
+
+    /// Change TAC while the divider's counter is `counter`.
+    ///
+    /// Disabling the timer or selecting another bit of the counter makes the signal that TIMA follows fall if the old
+    /// bit was set and the new one isn't, which increments TIMA like a tick would.
+    pub fn write_control(&mut self, enabled: bool, frequency: TimerFrequency, counter: u16) {
+        let before = self.signal(counter);
+        self.enabled = enabled;
+        self.frequency = frequency;
+        if before && !self.signal(counter) {
+            self.increment();
+        }
+    }
+
+    pub fn write_tima(&mut self, byte: u8) {
+        match self.reload {
+            Reload::Pending => {
+                self.value = byte;
+                self.reload = Reload::Idle;
             }
+            Reload::Reloaded => {}
+            Reload::Idle => self.value = byte,
+        }
+    }
+
+    pub fn write_tma(&mut self, byte: u8) {
+        self.tma = byte;
+        if self.reload == Reload::Reloaded {
+            self.value = byte;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mmu::{InterruptKind, Memory, Mmu};
+
+    /// An MMU with the timer enabled at 256 KiHz, so that TIMA increments every 16 T-cycles
+    fn fast_timer() -> Mmu {
+        let mut mmu = Mmu::new(&[0; 0x8000]);
+        mmu.write_byte(0xFF07, 0x05);
+        mmu
+    }
+
+    #[test]
+    fn tima_follows_the_divider() {
+        let mut mmu = fast_timer();
+        for _ in 0..16 / 4 * 3 {
+            mmu.step(4);
+        }
+        assert_eq!(mmu.read_byte(0xFF05), 3);
+
+        // bit 3 of the counter is set, so resetting it is a falling edge
+        mmu.step(4);
+        mmu.step(4);
+        mmu.write_byte(0xFF04, 0);
+        assert_eq!(mmu.read_byte(0xFF05), 4);
+        // bit 3 is clear, so it isn't
+        mmu.step(4);
+        mmu.write_byte(0xFF04, 0);
+        assert_eq!(mmu.read_byte(0xFF05), 4);
+    }
+
+    #[test]
+    fn overflow_reloads_tma_an_m_cycle_later() {
+        let mut mmu = fast_timer();
+        mmu.write_byte(0xFF06, 0x80);
+        mmu.write_byte(0xFF05, 0xFF);
+        for _ in 0..4 {
+            mmu.step(4);
+        }
+        // TIMA reads 0 for the M-cycle after the overflow
+        assert_eq!(mmu.read_byte(0xFF05), 0x00);
+        assert!(!mmu.interrupts_requested().contains(InterruptKind::Timer));
+        mmu.step(4);
+        assert_eq!(mmu.read_byte(0xFF05), 0x80);
+        assert!(mmu.interrupts_requested().contains(InterruptKind::Timer));
+    }
+
+    #[test]
+    fn writes_during_the_reload() {
+        // writing TIMA in the M-cycle after the overflow cancels the reload and the interrupt
+        let mut mmu = fast_timer();
+        mmu.write_byte(0xFF06, 0x80);
+        mmu.write_byte(0xFF05, 0xFF);
+        for _ in 0..4 {
+            mmu.step(4);
+        }
+        mmu.write_byte(0xFF05, 0x42);
+        mmu.step(4);
+        assert_eq!(mmu.read_byte(0xFF05), 0x42);
+        assert!(!mmu.interrupts_requested().contains(InterruptKind::Timer));
+
+        // in the M-cycle that TMA is loaded, TIMA ignores writes, and takes the value written to TMA
+        let mut mmu = fast_timer();
+        mmu.write_byte(0xFF06, 0x80);
+        mmu.write_byte(0xFF05, 0xFF);
+        for _ in 0..5 {
+            mmu.step(4);
         }
-        false
+        mmu.write_byte(0xFF05, 0x42);
+        assert_eq!(mmu.read_byte(0xFF05), 0x80);
+        mmu.write_byte(0xFF06, 0x90);
+        assert_eq!(mmu.read_byte(0xFF05), 0x90);
+        // afterwards, writes behave normally again
+        mmu.step(4);
+        mmu.write_byte(0xFF05, 0x42);
+        assert_eq!(mmu.read_byte(0xFF05), 0x42);
+    }
+
+    #[test]
+    fn tac_writes_can_tick_tima() {
+        // 8 T-cycles in, bit 3 of the counter is set, but bit 5 isn't
+        let mut mmu = fast_timer();
+        mmu.step(4);
+        mmu.step(4);
+        assert_eq!(mmu.read_byte(0xFF05), 0);
+
+        // disabling the timer while the selected bit is set
+        mmu.write_byte(0xFF07, 0x01);
+        assert_eq!(mmu.read_byte(0xFF05), 1);
+        // enabling it isn't a falling edge
+        mmu.write_byte(0xFF07, 0x05);
+        assert_eq!(mmu.read_byte(0xFF05), 1);
+        // switching from a set bit to a clear bit
+        mmu.write_byte(0xFF07, 0x06);
+        assert_eq!(mmu.read_byte(0xFF05), 2);
+        // switching from a clear bit to a set bit
+        mmu.write_byte(0xFF07, 0x05);
+        assert_eq!(mmu.read_byte(0xFF05), 2);
     }
 }
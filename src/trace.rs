@@ -0,0 +1,278 @@
+//! An opt-in record of the last instructions executed, for finding out how a game got into a bad state.
+//!
+//! When tracing is enabled, the trace is printed to stderr, with each instruction disassembled, when the CPU hits an
+//! illegal opcode, or when the emulator panics, e.g. on a failed assertion.
+//!
+//! For comparing a whole run against another emulator, the state before every instruction can also be logged in the
+//! format of Gameboy Doctor, see [`Emulator::start_doctor_log`].
+use std::collections::VecDeque;
+use std::fmt::Display;
+use std::io::Write;
+use std::panic::{self, AssertUnwindSafe};
+
+use crate::cpu::Registers;
+use crate::mmu::Memory;
+use crate::Emulator;
+
+/// An executed instruction, with the registers before it executed. `regs.pc` is the address of the opcode.
+#[derive(Debug, Clone, Copy)]
+pub struct TraceEntry {
+    pub opcode: u8,
+    pub regs: Registers,
+}
+
+impl Display for TraceEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let regs = &self.regs;
+        write!(
+            f,
+            "PC:{:04X} OP:{:02X} A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X}",
+            regs.pc,
+            self.opcode,
+            regs.a,
+            regs.f,
+            regs.b,
+            regs.c,
+            regs.d,
+            regs.e,
+            regs.h,
+            regs.l,
+            regs.sp
+        )
+    }
+}
+
+/// A ring buffer of the last instructions, filled by the CPU as it fetches opcodes.
+pub(crate) struct ExecutionTrace {
+    entries: VecDeque<TraceEntry>,
+    capacity: usize,
+}
+
+impl ExecutionTrace {
+    fn new(capacity: usize) -> Self {
+        ExecutionTrace {
+            entries: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub(crate) fn record(&mut self, entry: TraceEntry) {
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+}
+
+/// Writes a line like `A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02` before every
+/// instruction.
+pub(crate) struct DoctorLog {
+    writer: Box<dyn Write + Send>,
+    /// The first write error, after which nothing more is written
+    error: Option<std::io::Error>,
+}
+
+impl DoctorLog {
+    pub(crate) fn log(&mut self, regs: &Registers, memory: &impl Memory) {
+        if self.error.is_some() {
+            return;
+        }
+        let pc_mem = |offset| memory.read_byte_unobserved(regs.pc.wrapping_add(offset));
+        if let Err(e) = writeln!(
+            self.writer,
+            "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+            regs.a,
+            regs.f,
+            regs.b,
+            regs.c,
+            regs.d,
+            regs.e,
+            regs.h,
+            regs.l,
+            regs.sp,
+            regs.pc,
+            pc_mem(0),
+            pc_mem(1),
+            pc_mem(2),
+            pc_mem(3)
+        ) {
+            self.error = Some(e);
+        }
+    }
+}
+
+impl Emulator {
+    /// Write the state before every instruction to `writer`, in the format that Gameboy Doctor compares against its
+    /// logs of known good emulators.
+    ///
+    /// Gameboy Doctor's logs start at the cartridge entry point, so start logging after the boot ROM has exited. The
+    /// writer isn't buffered, so pass a [`std::io::BufWriter`] when logging to a file.
+    pub fn start_doctor_log(&mut self, writer: impl Write + Send + 'static) {
+        self.cpu.doctor_log = Some(DoctorLog {
+            writer: Box::new(writer),
+            error: None,
+        });
+    }
+
+    /// Stop logging, and flush the log. Fails with the first error that writing the log ran into. Also done by
+    /// [`Emulator::shutdown`].
+    pub fn finish_doctor_log(&mut self) -> std::io::Result<()> {
+        let Some(mut doctor_log) = self.cpu.doctor_log.take() else {
+            return Ok(());
+        };
+        match doctor_log.error {
+            Some(e) => Err(e),
+            None => doctor_log.writer.flush(),
+        }
+    }
+
+    /// Record the last `capacity` instructions, see [`Emulator::recent_trace`].
+    ///
+    /// Panics if `capacity` is 0.
+    pub fn enable_trace(&mut self, capacity: usize) {
+        assert!(capacity > 0, "The trace must hold at least one instruction");
+        self.cpu.trace = Some(ExecutionTrace::new(capacity));
+    }
+
+    pub fn disable_trace(&mut self) {
+        self.cpu.trace = None;
+    }
+
+    /// The last instructions executed, oldest first. Empty unless tracing was enabled with
+    /// [`Emulator::enable_trace`].
+    pub fn recent_trace(&self) -> Vec<TraceEntry> {
+        self.cpu
+            .trace
+            .as_ref()
+            .map(|trace| trace.entries.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// Step the CPU, and print the trace before passing on a panic.
+    pub(crate) fn step_cpu_traced(&mut self) -> u8 {
+        match panic::catch_unwind(AssertUnwindSafe(|| self.cpu.step())) {
+            Ok(t_cycles) => t_cycles,
+            Err(payload) => {
+                self.dump_trace("The emulator panicked");
+                panic::resume_unwind(payload)
+            }
+        }
+    }
+
+    /// Print the trace to stderr, after `reason`.
+    pub(crate) fn dump_trace(&self, reason: &str) {
+        let trace = self.recent_trace();
+        if trace.is_empty() {
+            return;
+        }
+        eprintln!("{reason}. The last {} instructions:", trace.len());
+        for entry in trace {
+            eprintln!("  {}", self.trace_line(&entry));
+        }
+    }
+
+    /// `entry`, followed by its instruction. The instruction's operands are resolved with the registers of the entry,
+    /// but memory has changed since, so memory operands only show their address.
+    fn trace_line(&self, entry: &TraceEntry) -> String {
+        let mut description = self.describe_instruction_with(entry.regs.pc, &entry.regs);
+        description.memory_operand = description.memory_operand.map(|(addr, _)| (addr, None));
+        format!("{entry}  {description}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+
+    use crate::util::emulator_running;
+
+    #[test]
+    fn keeps_the_last_instructions() {
+        let mut emu = emulator_running(&[
+            0x3C, // INC A
+            0x04, // INC B
+            0x0C, // INC C
+            0xD3, // illegal
+        ]);
+        emu.cpu.regs.a = 0;
+        emu.enable_trace(3);
+        for _ in 0..10 {
+            emu.step().unwrap();
+        }
+        let trace = emu.recent_trace();
+        let pcs: Vec<u16> = trace.iter().map(|entry| entry.regs.pc).collect();
+        assert_eq!(pcs, [0x0001, 0x0002, 0x0003]);
+        assert_eq!(trace[2].opcode, 0xD3);
+        // the registers before each instruction
+        assert_eq!(trace[0].regs.a, 1);
+        assert!(trace[0].to_string().starts_with("PC:0001 OP:04 A:01 "));
+    }
+
+    #[test]
+    fn trace_lines_describe_instructions() {
+        let mut emu = emulator_running(&[
+            0x21, 0x23, 0xC1, // LD HL,$C123
+            0x7E, // LD A,[HL]
+            0x21, 0x00, 0xC0, // LD HL,$C000
+            0xE9, // JP HL
+        ]);
+        emu.enable_trace(4);
+        for _ in 0..4 {
+            emu.step().unwrap();
+        }
+        let lines: Vec<String> = emu
+            .recent_trace()
+            .iter()
+            .map(|entry| emu.trace_line(entry))
+            .collect();
+        assert!(lines[0].starts_with("PC:0000 OP:21 "));
+        assert!(lines[0].ends_with("  LD HL,$C123"));
+        // HL as it was when the instruction executed
+        assert!(lines[1].ends_with("  LD A,[HL]  ; [$C123]"));
+        assert!(lines[3].ends_with("  JP HL"));
+    }
+
+    /// A writer that the test can read back after handing it to the emulator
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn doctor_log_lines() {
+        let mut emu = emulator_running(&[
+            0x3C, // INC A
+            0xC3, 0x00, 0x00, // JP 0x0000
+        ]);
+        emu.cpu.regs.a = 0;
+        emu.cpu.regs.f = 0;
+        let log = SharedBuffer::default();
+        emu.start_doctor_log(log.clone());
+        emu.step().unwrap();
+        emu.step().unwrap();
+        emu.finish_doctor_log().unwrap();
+        emu.step().unwrap();
+        let log = String::from_utf8(log.0.lock().unwrap().clone()).unwrap();
+        let regs = emu.cpu.regs;
+        let expected = [
+            (0x00, 0x0000, "3C,C3,00,00"),
+            (0x01, 0x0001, "C3,00,00,00"),
+        ]
+        .map(|(a, pc, pc_mem)| {
+            format!(
+                "A:{a:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{pc:04X} PCMEM:{pc_mem}\n",
+                regs.f, regs.b, regs.c, regs.d, regs.e, regs.h, regs.l, regs.sp
+            )
+        });
+        assert_eq!(log, expected.concat());
+    }
+}
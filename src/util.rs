@@ -54,6 +54,59 @@ impl U8Ext for u8 {
     }
 }
 
+/// The proptest config for the core's tests.
+///
+/// Under miri, where tests can't touch the file system, failures aren't persisted to `proptest-regressions`, and fewer
+/// cases run to keep the interpreter's run time reasonable.
+#[cfg(test)]
+pub(crate) fn proptest_config() -> proptest::test_runner::Config {
+    let config = proptest::test_runner::Config::default();
+    if cfg!(miri) {
+        proptest::test_runner::Config {
+            cases: 8,
+            failure_persistence: None,
+            ..config
+        }
+    } else {
+        config
+    }
+}
+
+/// Run `f` on a thread with a 16 MiB stack. Deserializing the emulator in an unoptimized build needs more stack than
+/// the default test thread has.
+#[cfg(test)]
+pub(crate) fn with_large_stack(f: impl FnOnce() + Send + 'static) {
+    std::thread::Builder::new()
+        .stack_size(16 * 1024 * 1024)
+        .spawn(f)
+        .unwrap()
+        .join()
+        .unwrap();
+}
+
+/// A 32 KiB ROM without a mapper, with `program` at 0x0000 and a valid header checksum
+#[cfg(test)]
+pub(crate) fn rom_with_program(program: &[u8]) -> Vec<u8> {
+    let mut rom = vec![0; 0x8000];
+    rom[..program.len()].copy_from_slice(program);
+    rom[0x014D] = crate::cartridge::header_checksum(&rom);
+    rom
+}
+
+/// An emulator for `rom` that starts executing at 0x0000 instead of the entry point
+#[cfg(test)]
+pub(crate) fn emulator_for(rom: &[u8]) -> crate::Emulator {
+    let mut emu = crate::Emulator::for_rom(rom, std::path::Path::new("test.gb"), None).unwrap();
+    emu.cpu.regs.pc = 0x0000;
+    emu
+}
+
+/// An emulator that runs `program` from 0x0000, see [`rom_with_program`]
+#[cfg(test)]
+pub(crate) fn emulator_running(program: &[u8]) -> crate::Emulator {
+    emulator_for(&rom_with_program(program))
+}
+
 #[cfg(test)]
 mod tests {
     use super::U8Ext;
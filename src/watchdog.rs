@@ -0,0 +1,169 @@
+//! Detection of games that have locked up.
+//!
+//! A game that crashed, or that waits on hardware the emulator doesn't implement, usually ends up spinning in a tight
+//! loop (or halted) with interrupts disabled and without touching any IO register. Nothing can get it out of that
+//! state, so instead of silently spinning forever, the watchdog reports it with an [`Event::LockedUp`].
+use std::time::Duration;
+
+use crate::apu::T_CYCLES_PER_SECOND;
+use crate::cpu::ImeState;
+use crate::mmu::Memory;
+use crate::{Emulator, Event};
+
+/// Execution that stays within this many bytes of code counts as a tight loop
+const TIGHT_LOOP_BYTES: u16 = 32;
+
+pub struct LockupWatchdog {
+    timeout: Duration,
+    timeout_t_cycles: u64,
+    /// The current stretch of execution that looks like a lock-up
+    stretch: Option<Stretch>,
+}
+
+struct Stretch {
+    start_cycle: u64,
+    lowest_pc: u16,
+    highest_pc: u16,
+    /// The MMU's IO write count when the stretch started
+    io_writes: u64,
+    reported: bool,
+}
+
+/// A suspected lock-up, reported by [`Event::LockedUp`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lockup {
+    /// The lowest address executed since the lock-up started
+    pub lowest_pc: u16,
+    /// The highest address executed since the lock-up started
+    pub highest_pc: u16,
+    /// The cycle count when the lock-up started
+    pub since_cycle: u64,
+}
+
+impl LockupWatchdog {
+    fn new(timeout: Duration) -> Self {
+        LockupWatchdog {
+            timeout,
+            timeout_t_cycles: (timeout.as_secs_f64() * T_CYCLES_PER_SECOND as f64) as u64,
+            stretch: None,
+        }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+}
+
+impl Emulator {
+    /// Emit an [`Event::LockedUp`] when the game spends `timeout` of emulated time in a tight loop with interrupts
+    /// disabled and without writing to any IO register. Each lock-up is reported once.
+    pub fn enable_lockup_watchdog(&mut self, timeout: Duration) {
+        self.watchdog = Some(LockupWatchdog::new(timeout));
+    }
+
+    pub fn disable_lockup_watchdog(&mut self) {
+        self.watchdog = None;
+    }
+
+    pub fn lockup_watchdog(&self) -> Option<&LockupWatchdog> {
+        self.watchdog.as_ref()
+    }
+
+    /// Called after every instruction.
+    pub(crate) fn check_lockup(&mut self) {
+        let Some(watchdog) = &mut self.watchdog else {
+            return;
+        };
+        let pc = self.cpu.regs.pc;
+        let io_writes = self.cpu.mmu.io_writes;
+        // a stopped CPU waits for a button press, so it isn't locked up, unlike a CPU hung by an illegal opcode
+        let can_resume = self.cpu.locked_up.is_none()
+            && (self.cpu.is_stopped
+                || (self.cpu.ime != ImeState::Disabled
+                    && !self.cpu.mmu.interrupts_enabled().is_empty()));
+        let stretch = match &mut watchdog.stretch {
+            Some(stretch)
+                if !can_resume
+                    && stretch.io_writes == io_writes
+                    && stretch
+                        .lowest_pc
+                        .min(pc)
+                        .abs_diff(stretch.highest_pc.max(pc))
+                        < TIGHT_LOOP_BYTES =>
+            {
+                stretch.lowest_pc = stretch.lowest_pc.min(pc);
+                stretch.highest_pc = stretch.highest_pc.max(pc);
+                stretch
+            }
+            _ => {
+                watchdog.stretch = Some(Stretch {
+                    start_cycle: self.cycle_count,
+                    lowest_pc: pc,
+                    highest_pc: pc,
+                    io_writes,
+                    reported: false,
+                });
+                return;
+            }
+        };
+        if !stretch.reported && self.cycle_count - stretch.start_cycle >= watchdog.timeout_t_cycles
+        {
+            stretch.reported = true;
+            self.events.push(Event::LockedUp(Lockup {
+                lowest_pc: stretch.lowest_pc,
+                highest_pc: stretch.highest_pc,
+                since_cycle: stretch.start_cycle,
+            }));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::Lockup;
+    use crate::cartridge::header_checksum;
+    use crate::util::emulator_for;
+    use crate::Event;
+
+    fn run_with_watchdog(program: &[u8]) -> Vec<Event> {
+        let mut rom = vec![0; 0x8000];
+        rom[..program.len()].copy_from_slice(program);
+        rom[0x014D] = header_checksum(&rom);
+        let mut emu = emulator_for(&rom);
+        emu.enable_lockup_watchdog(Duration::from_millis(100));
+        for _ in 0..20 {
+            emu.run_frame().unwrap();
+        }
+        emu.take_events()
+    }
+
+    #[test]
+    fn reports_tight_loop_with_interrupts_disabled_once() {
+        let events = run_with_watchdog(&[
+            0xF3, // DI
+            0x00, // 0x0001: NOP
+            0x18, 0xFD, // JR 0x0001
+        ]);
+        assert_eq!(
+            events,
+            [Event::LockedUp(Lockup {
+                lowest_pc: 0x0001,
+                highest_pc: 0x0002,
+                since_cycle: 4,
+            })]
+        );
+    }
+
+    #[test]
+    fn ignores_loop_that_writes_io() {
+        let events = run_with_watchdog(&[
+            0xF3, // DI
+            0x3E, 0x30, // LD A,0x30
+            0xE0, 0x00, // 0x0003: LDH [0x00],A   (select no joypad buttons)
+            0x18, 0xFC, // JR 0x0003
+        ]);
+        assert_eq!(events, []);
+    }
+}
@@ -0,0 +1,96 @@
+//! Writes 16-bit PCM WAV files, used to capture the APU's output.
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const HEADER_LEN: u32 = 44;
+
+#[derive(Debug)]
+pub(crate) struct WavWriter<W: Write + Seek = BufWriter<File>> {
+    out: W,
+    /// The number of bytes of sample data written so far
+    data_len: u32,
+    /// The first error encountered while writing samples, reported by `finish`.
+    ///
+    /// Samples are generated while stepping the emulator, which has no way to report errors.
+    error: Option<io::Error>,
+}
+
+impl WavWriter {
+    pub(crate) fn create(path: &Path, sample_rate: u32, channels: u16) -> io::Result<Self> {
+        WavWriter::new(BufWriter::new(File::create(path)?), sample_rate, channels)
+    }
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    /// Write the header to `out`. The lengths in the header are filled in by `finish`.
+    pub(crate) fn new(mut out: W, sample_rate: u32, channels: u16) -> io::Result<Self> {
+        let block_align = channels * 2;
+        out.write_all(b"RIFF")?;
+        out.write_all(&(HEADER_LEN - 8).to_le_bytes())?;
+        out.write_all(b"WAVE")?;
+        out.write_all(b"fmt ")?;
+        out.write_all(&16u32.to_le_bytes())?;
+        // PCM
+        out.write_all(&1u16.to_le_bytes())?;
+        out.write_all(&channels.to_le_bytes())?;
+        out.write_all(&sample_rate.to_le_bytes())?;
+        out.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+        out.write_all(&block_align.to_le_bytes())?;
+        // bits per sample
+        out.write_all(&16u16.to_le_bytes())?;
+        out.write_all(b"data")?;
+        out.write_all(&0u32.to_le_bytes())?;
+        Ok(WavWriter {
+            out,
+            data_len: 0,
+            error: None,
+        })
+    }
+
+    pub(crate) fn write_sample(&mut self, sample: i16) {
+        if self.error.is_some() {
+            return;
+        }
+        match self.out.write_all(&sample.to_le_bytes()) {
+            Ok(()) => self.data_len += 2,
+            Err(e) => self.error = Some(e),
+        }
+    }
+
+    /// Fill in the lengths in the header and flush the file.
+    pub(crate) fn finish(mut self) -> io::Result<W> {
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+        self.out.seek(SeekFrom::Start(4))?;
+        self.out
+            .write_all(&(HEADER_LEN - 8 + self.data_len).to_le_bytes())?;
+        self.out.seek(SeekFrom::Start(HEADER_LEN as u64 - 4))?;
+        self.out.write_all(&self.data_len.to_le_bytes())?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::WavWriter;
+
+    #[test]
+    fn finish_fills_in_lengths() {
+        let mut wav = WavWriter::new(Cursor::new(Vec::new()), 44100, 1).unwrap();
+        wav.write_sample(1);
+        wav.write_sample(-2);
+        let bytes = wav.finish().unwrap().into_inner();
+        assert_eq!(bytes.len(), 48);
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(bytes[4..8], 40u32.to_le_bytes());
+        assert_eq!(bytes[24..28], 44100u32.to_le_bytes());
+        assert_eq!(bytes[28..32], 88200u32.to_le_bytes());
+        assert_eq!(bytes[40..44], 4u32.to_le_bytes());
+        assert_eq!(bytes[44..], [0x01, 0x00, 0xFE, 0xFF]);
+    }
+}